@@ -8,7 +8,9 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
-use crate::request::drive::{parse_patch_drive, parse_put_drive};
+use crate::request::drive::{
+    parse_get_drive, parse_patch_drive, parse_patch_drive_dirty_bitmap, parse_put_drive,
+};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
@@ -16,7 +18,7 @@ use crate::request::machine_configuration::{
 };
 use crate::request::metrics::parse_put_metrics;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::net::{parse_get_net, parse_patch_net, parse_put_net, parse_put_net_capture};
 use crate::request::snapshot::parse_patch_vm_state;
 use crate::request::snapshot::parse_put_snapshot;
 use crate::request::vsock::parse_put_vsock;
@@ -59,11 +61,17 @@ impl ParsedRequest {
         match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "", None) => parse_get_instance_info(),
             (Method::Get, "balloon", None) => parse_get_balloon(path_tokens.get(1)),
+            (Method::Get, "drives", None) => {
+                parse_get_drive(path_tokens.get(1), path_tokens.get(2))
+            }
             (Method::Get, "vm", None) if path_tokens.get(1) == Some(&"config") => {
                 Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
             }
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "network-interfaces", None) => {
+                parse_get_net(path_tokens.get(1), path_tokens.get(2))
+            }
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
@@ -73,6 +81,11 @@ impl ParsedRequest {
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
+            (Method::Put, "network-interfaces", Some(body))
+                if path_tokens.get(2) == Some(&"capture") =>
+            {
+                parse_put_net_capture(body, path_tokens.get(1))
+            }
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
             }
@@ -81,6 +94,11 @@ impl ParsedRequest {
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
+            (Method::Patch, "drives", Some(body))
+                if path_tokens.get(2) == Some(&"dirty_bitmap") =>
+            {
+                parse_patch_drive_dirty_bitmap(body, path_tokens.get(1))
+            }
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
             (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
@@ -121,8 +139,10 @@ impl ParsedRequest {
                     Self::success_response_with_data(balloon_config)
                 }
                 VmmData::BalloonStats(stats) => Self::success_response_with_data(stats),
+                VmmData::BlockDirtyBitmap(bitmap) => Self::success_response_with_data(bitmap),
                 VmmData::InstanceInformation(info) => Self::success_response_with_data(info),
                 VmmData::FullVmConfig(config) => Self::success_response_with_data(config),
+                VmmData::NetworkInterfaceStats(stats) => Self::success_response_with_data(stats),
             },
             Err(vmm_action_error) => {
                 error!(
@@ -271,8 +291,10 @@ pub(crate) mod tests {
     use vmm::resources::VmmConfig;
     use vmm::rpc_interface::VmmActionError;
     use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
+    use vmm::vmm_config::drive::BlockDirtyBitmap;
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::VmConfig;
+    use vmm::vmm_config::net::NetworkInterfaceStats;
 
     impl PartialEq for ParsedRequest {
         fn eq(&self, other: &ParsedRequest) -> bool {
@@ -504,6 +526,9 @@ pub(crate) mod tests {
                 VmmData::BalloonStats(stats) => {
                     http_response(&serde_json::to_string(stats).unwrap(), 200)
                 }
+                VmmData::BlockDirtyBitmap(bitmap) => {
+                    http_response(&serde_json::to_string(bitmap).unwrap(), 200)
+                }
                 VmmData::Empty => http_response("", 204),
                 VmmData::FullVmConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
@@ -514,6 +539,9 @@ pub(crate) mod tests {
                 VmmData::InstanceInformation(info) => {
                     http_response(&serde_json::to_string(info).unwrap(), 200)
                 }
+                VmmData::NetworkInterfaceStats(stats) => {
+                    http_response(&serde_json::to_string(stats).unwrap(), 200)
+                }
             };
             let response = ParsedRequest::convert_to_response(&data);
             assert!(response.write_all(&mut buf).is_ok());
@@ -526,10 +554,14 @@ pub(crate) mod tests {
             swap_out: Some(1),
             ..Default::default()
         }));
+        verify_ok_response_with(VmmData::BlockDirtyBitmap(BlockDirtyBitmap::from(vec![1])));
         verify_ok_response_with(VmmData::Empty);
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(VmConfig::default()));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
+        verify_ok_response_with(VmmData::NetworkInterfaceStats(
+            NetworkInterfaceStats::default(),
+        ));
 
         // Error.
         let error = VmmActionError::StartMicrovm(StartMicrovmError::MissingKernelConfig);
@@ -578,6 +610,30 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_drive_dirty_bitmap() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/drives/root/dirty_bitmap", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_get_net_stats() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/network-interfaces/eth0/stats", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -661,6 +717,8 @@ pub(crate) mod tests {
             \"partuuid\": \"string\", \
             \"is_read_only\": true, \
             \"cache_type\": \"Unsafe\", \
+            \"image_format\": \"Raw\", \
+            \"io_engine\": \"Async\", \
             \"rate_limiter\": { \
                 \"bandwidth\": { \
                     \"size\": 0, \
@@ -793,6 +851,25 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_netif_capture() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"iface_id\": \"string\", \
+            \"path_on_host\": \"string.pcap\", \
+            \"max_size_bytes\": 1048576 \
+        }";
+        sender
+            .write_all(
+                http_request("PUT", "/network-interfaces/string/capture", Some(&body)).as_bytes(),
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_snapshot() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -907,6 +984,22 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_patch_drive_dirty_bitmap() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"chunk_size\": 65536, \
+            \"bitmap\": [5] \
+        }";
+        sender
+            .write_all(http_request("PATCH", "/drives/root/dirty_bitmap", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -5,7 +5,34 @@ use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
 use crate::request::{Body, StatusCode};
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig};
+use vmm::vmm_config::drive::{
+    BlockDeviceConfig, BlockDeviceUpdateConfig, BlockDirtyBitmap, VhostUserBlockDeviceConfig,
+};
+
+pub(crate) fn parse_get_drive(
+    id_from_path: Option<&&str>,
+    path_second_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        return Err(Error::EmptyID);
+    };
+
+    match path_second_token {
+        Some(&"dirty_bitmap") => Ok(ParsedRequest::new_sync(VmmAction::GetBlockDirtyBitmap(
+            id.to_string(),
+        ))),
+        Some(unrecognized) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized GET request path `{}`.", unrecognized),
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("Drive configurations can only be retrieved through `/vm/config`."),
+        )),
+    }
+}
 
 pub(crate) fn parse_put_drive(
     body: &Body,
@@ -19,21 +46,41 @@ pub(crate) fn parse_put_drive(
         return Err(Error::EmptyID);
     };
 
-    let device_cfg = serde_json::from_slice::<BlockDeviceConfig>(body.raw()).map_err(|e| {
+    let body_json = serde_json::from_slice::<serde_json::Value>(body.raw()).map_err(|e| {
         METRICS.put_api_requests.drive_fails.inc();
         Error::SerdeJson(e)
     })?;
 
-    if id != device_cfg.drive_id {
+    // Drives served by vhost-user backends are described by the socket of their backend.
+    let (drive_id, action) = if body_json.get("socket").is_some() {
+        let device_cfg =
+            serde_json::from_value::<VhostUserBlockDeviceConfig>(body_json).map_err(|e| {
+                METRICS.put_api_requests.drive_fails.inc();
+                Error::SerdeJson(e)
+            })?;
+        (
+            device_cfg.drive_id.clone(),
+            VmmAction::InsertVhostUserBlockDevice(device_cfg),
+        )
+    } else {
+        let device_cfg = serde_json::from_value::<BlockDeviceConfig>(body_json).map_err(|e| {
+            METRICS.put_api_requests.drive_fails.inc();
+            Error::SerdeJson(e)
+        })?;
+        (
+            device_cfg.drive_id.clone(),
+            VmmAction::InsertBlockDevice(device_cfg),
+        )
+    };
+
+    if id != drive_id {
         METRICS.put_api_requests.drive_fails.inc();
         Err(Error::Generic(
             StatusCode::BadRequest,
             "The id from the path does not match the id from the body!".to_string(),
         ))
     } else {
-        Ok(ParsedRequest::new_sync(VmmAction::InsertBlockDevice(
-            device_cfg,
-        )))
+        Ok(ParsedRequest::new_sync(action))
     }
 }
 
@@ -65,15 +112,18 @@ pub(crate) fn parse_patch_drive(
 
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
+    // - size_bytes
     // - rate_limiter
     if block_device_update_cfg.path_on_host.is_none()
+        && block_device_update_cfg.size_bytes.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, size_bytes, \
+                 rate_limiter.",
             ),
         ));
     }
@@ -83,11 +133,80 @@ pub(crate) fn parse_patch_drive(
     )))
 }
 
+pub(crate) fn parse_patch_drive_dirty_bitmap(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.drive_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.patch_api_requests.drive_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let bitmap = serde_json::from_slice::<BlockDirtyBitmap>(body.raw()).map_err(|e| {
+        METRICS.patch_api_requests.drive_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+
+    Ok(ParsedRequest::new_sync(VmmAction::ClearBlockDirtyBitmap(
+        id.to_string(),
+        bitmap,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_drive_request() {
+        assert!(parse_get_drive(None, None).is_err());
+        assert!(parse_get_drive(Some(&"id"), None).is_err());
+        assert!(parse_get_drive(Some(&"id"), Some(&"unrelated")).is_err());
+        assert!(parse_get_drive(Some(&"invalid/id"), Some(&"dirty_bitmap")).is_err());
+
+        let parsed_req = parse_get_drive(Some(&"id"), Some(&"dirty_bitmap")).unwrap();
+        match vmm_action_from_request(parsed_req) {
+            VmmAction::GetBlockDirtyBitmap(drive_id) => assert_eq!(drive_id, "id"),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_parse_patch_drive_dirty_bitmap_request() {
+        let body = r#"{
+                "chunk_size": 65536,
+                "bitmap": [5, 0]
+              }"#;
+        assert!(parse_patch_drive_dirty_bitmap(&Body::new(body), None).is_err());
+        assert!(parse_patch_drive_dirty_bitmap(&Body::new(body), Some(&"invalid/id")).is_err());
+        assert!(
+            parse_patch_drive_dirty_bitmap(&Body::new("invalid_payload"), Some(&"id")).is_err()
+        );
+
+        // PATCH with unknown fields.
+        let invalid_body = r#"{
+                "chunk_size": 65536,
+                "bitmap": [5, 0],
+                "drive_id": "id"
+              }"#;
+        assert!(parse_patch_drive_dirty_bitmap(&Body::new(invalid_body), Some(&"id")).is_err());
+
+        match vmm_action_from_request(
+            parse_patch_drive_dirty_bitmap(&Body::new(body), Some(&"id")).unwrap(),
+        ) {
+            VmmAction::ClearBlockDirtyBitmap(drive_id, bitmap) => {
+                assert_eq!(drive_id, "id");
+                assert_eq!(bitmap.chunk_size, 65536);
+                assert_eq!(bitmap.bitmap, vec![5, 0]);
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        }
+    }
+
     #[test]
     fn test_parse_patch_drive_request() {
         assert!(parse_patch_drive(&Body::new("invalid_payload"), None).is_err());
@@ -198,6 +317,27 @@ mod tests {
         // Validate that updating both path and rate limiter succeds.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
 
+        let body = r#"{
+            "drive_id": "foo",
+            "size_bytes": 1048576
+        }"#;
+        // Validate that updating just the size works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert_eq!(cfg.size_bytes, Some(0x10_0000));
+                assert!(cfg.path_on_host.is_none());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
+        let body = r#"{
+            "drive_id": "foo",
+            "size_bytes": -1
+        }"#;
+        // Validate that parse_patch_drive fails for negative sizes.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
         let body = r#"{
             "drive_id": "foo",
             "path_on_host": "/there",
@@ -243,6 +383,10 @@ mod tests {
                 "partuuid": "string",
                 "is_read_only": true,
                 "cache_type": "Unsafe",
+                "image_format": "Qcow2",
+                "io_engine": "Sync",
+                "overlay_path_on_host": "overlay",
+                "num_queues": 4,
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -257,5 +401,28 @@ mod tests {
                 }
             }"#;
         assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT of a drive served by a vhost-user backend.
+        let body = r#"{
+                "drive_id": "1000",
+                "socket": "/tmp/vhost-user-blk.sock"
+            }"#;
+        match vmm_action_from_request(parse_put_drive(&Body::new(body), Some(&"1000")).unwrap()) {
+            VmmAction::InsertVhostUserBlockDevice(cfg) => {
+                assert_eq!(cfg.socket, "/tmp/vhost-user-blk.sock");
+                assert_eq!(cfg.num_queues, 1);
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        }
+        assert!(parse_put_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        // The vhost-user drives don't accept the fields of the regular drives.
+        let body = r#"{
+                "drive_id": "1000",
+                "socket": "/tmp/vhost-user-blk.sock",
+                "num_queues": 2,
+                "path_on_host": "dummy"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
     }
 }
@@ -5,7 +5,36 @@ use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
 use crate::request::{Body, StatusCode};
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::net::{
+    NetworkInterfaceCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig,
+};
+
+pub(crate) fn parse_get_net(
+    id_from_path: Option<&&str>,
+    path_second_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        return Err(Error::EmptyID);
+    };
+
+    match path_second_token {
+        Some(&"stats") => Ok(ParsedRequest::new_sync(
+            VmmAction::GetNetworkInterfaceStats(id.to_string()),
+        )),
+        Some(unrecognized) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized GET request path `{}`.", unrecognized),
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from(
+                "Network interface configurations can only be retrieved through `/vm/config`.",
+            ),
+        )),
+    }
+}
 
 pub(crate) fn parse_put_net(
     body: &Body,
@@ -35,6 +64,35 @@ pub(crate) fn parse_put_net(
     )))
 }
 
+pub(crate) fn parse_put_net_capture(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.network_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let capture_cfg =
+        serde_json::from_slice::<NetworkInterfaceCaptureConfig>(body.raw()).map_err(|e| {
+            METRICS.put_api_requests.network_fails.inc();
+            Error::SerdeJson(e)
+        })?;
+    if id != capture_cfg.iface_id {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The id from the path does not match the id from the body!".to_string(),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateNetworkCapture(
+        capture_cfg,
+    )))
+}
+
 pub(crate) fn parse_patch_net(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -69,13 +127,28 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_net_request() {
+        assert!(parse_get_net(None, None).is_err());
+        assert!(parse_get_net(Some(&"id"), None).is_err());
+        assert!(parse_get_net(Some(&"id"), Some(&"unrelated")).is_err());
+        assert!(parse_get_net(Some(&"invalid/id"), Some(&"stats")).is_err());
+
+        let parsed_req = parse_get_net(Some(&"id"), Some(&"stats")).unwrap();
+        match vmm_action_from_request(parsed_req) {
+            VmmAction::GetNetworkInterfaceStats(iface_id) => assert_eq!(iface_id, "id"),
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_parse_put_net_request() {
         let body = r#"{
                 "iface_id": "foo",
                 "host_dev_name": "bar",
                 "guest_mac": "12:34:56:78:9A:BC",
-                "allow_mmds_requests": false
+                "allow_mmds_requests": false,
+                "num_queue_pairs": 2
               }"#;
         // 1. Exercise infamous "The id from the path does not match id from the body!".
         assert!(parse_put_net(&Body::new(body), Some(&"bar")).is_err());
@@ -110,6 +183,38 @@ mod tests {
         assert!(parse_put_net(&Body::new(body), Some(&"foo")).is_err());
     }
 
+    #[test]
+    fn test_parse_put_net_capture_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "path_on_host": "foo.pcap",
+                "max_size_bytes": 1048576,
+                "snaplen": 128
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"bar")).is_err());
+        assert!(parse_put_net_capture(&Body::new(body), None).is_err());
+
+        let capture_cfg = serde_json::from_str::<NetworkInterfaceCaptureConfig>(body).unwrap();
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::UpdateNetworkCapture(cfg) => assert_eq!(cfg, capture_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // Stopping a capture only takes the interface ID.
+        let body = r#"{ "iface_id": "foo" }"#;
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::UpdateNetworkCapture(cfg) => assert!(cfg.path_on_host.is_none()),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{ "iface_id": "foo", "snaplen": "all" }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+    }
+
     #[test]
     fn test_parse_patch_net_request() {
         let body = r#"{
@@ -149,5 +254,15 @@ mod tests {
             }
         }"#;
         assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 5. Link status update.
+        let body = r#"{
+                "iface_id": "foo",
+                "link_up": false
+        }"#;
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => assert_eq!(netif.link_up, Some(false)),
+            _ => panic!("Test failed."),
+        }
     }
 }
@@ -15,6 +15,8 @@ pub(crate) fn parse_put_vsock(body: &Body) -> Result<ParsedRequest, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+    use vmm::vmm_config::vsock::{VsockPortAcl, VsockTcpPort};
 
     #[test]
     fn test_parse_put_vsock_request() {
@@ -25,6 +27,59 @@ mod tests {
               }"#;
         assert!(parse_put_vsock(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "vsock_id": "foo",
+                "guest_cid": 42,
+                "uds_path": "vsock.sock",
+                "tcp_forwards": [{"vsock_port": 52, "tcp_port": 8080}],
+                "tcp_listeners": [{"vsock_port": 1024, "tcp_port": 9000}]
+              }"#;
+        match vmm_action_from_request(parse_put_vsock(&Body::new(body)).unwrap()) {
+            VmmAction::SetVsockDevice(cfg) => {
+                assert_eq!(
+                    cfg.tcp_forwards,
+                    vec![VsockTcpPort {
+                        vsock_port: 52,
+                        tcp_port: 8080,
+                    }]
+                );
+                assert_eq!(
+                    cfg.tcp_listeners,
+                    vec![VsockTcpPort {
+                        vsock_port: 1024,
+                        tcp_port: 9000,
+                    }]
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // A missing list allows connections to any port.
+        let body = r#"{
+                "vsock_id": "foo",
+                "guest_cid": 42,
+                "uds_path": "vsock.sock",
+                "port_acl": {"guest_to_host": [52]}
+              }"#;
+        match vmm_action_from_request(parse_put_vsock(&Body::new(body)).unwrap()) {
+            VmmAction::SetVsockDevice(cfg) => assert_eq!(
+                cfg.port_acl,
+                VsockPortAcl {
+                    guest_to_host: Some(vec![52]),
+                    host_to_guest: None,
+                }
+            ),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "vsock_id": "foo",
+                "guest_cid": 42,
+                "uds_path": "vsock.sock",
+                "port_acl": {"guest_to_host": [52], "invalid_field": false}
+              }"#;
+        assert!(parse_put_vsock(&Body::new(body)).is_err());
+
         let body = r#"{
                 "vsock_id": "foo",
                 "guest_cid": 42,
@@ -129,7 +129,7 @@ impl Persist<'_> for Balloon {
             .build_queues_checked(&constructor_args.mem, TYPE_BALLOON, num_queues, QUEUE_SIZE)
             .map_err(|_| Self::Error::QueueRestoreError)?;
         balloon.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
-        balloon.avail_features = state.virtio_state.avail_features;
+        balloon.avail_features = state.virtio_state.all_avail_features();
         balloon.acked_features = state.virtio_state.acked_features;
         balloon.latest_stats = state.latest_stats.create_stats();
         balloon.config_space = ConfigSpace {
@@ -7,10 +7,10 @@
 
 use std::cmp;
 use std::convert::From;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::io::{self, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::Path;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -18,16 +18,25 @@ use std::sync::Arc;
 use logger::{error, warn, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 use utils::eventfd::EventFd;
+use utils::time::{get_time_us, ClockType};
 use virtio_gen::virtio_blk::*;
-use vm_memory::{Bytes, GuestMemoryMmap};
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 use super::{
-    super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK, VIRTIO_MMIO_INT_VRING},
+    super::{
+        ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK, VIRTIO_F_RING_PACKED,
+        VIRTIO_MMIO_INT_VRING,
+    },
+    dirty_chunks::DirtyChunks,
+    disk::{open_direct_disk_file, open_disk_file, open_overlay_disk_file, DiskFile, ImageFormat},
+    io::{AsyncFileEngine, FileEngineType},
+    metrics::BlockMetrics,
     request::*,
-    Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    Error, CONFIG_SPACE_SIZE, MAX_DISCARD_WRITE_ZEROES_SEGMENTS, MAX_NUM_QUEUES, QUEUE_SIZE,
+    SECTOR_SHIFT, SECTOR_SIZE,
 };
 
-use crate::virtio::VIRTIO_MMIO_INT_CONFIG;
 use crate::Error as DeviceError;
 
 use serde::{Deserialize, Serialize};
@@ -42,6 +51,10 @@ pub enum CacheType {
     /// flush requests coming from the guest will be performed using
     /// `fsync`.
     Writeback,
+    /// The backing file is opened with `O_DIRECT`, bypassing the host page
+    /// cache. Flush requests are performed using `fsync`, as in `Writeback`
+    /// mode. Only supported for raw images, without overlay.
+    Direct,
 }
 
 impl Default for CacheType {
@@ -50,13 +63,41 @@ impl Default for CacheType {
     }
 }
 
+/// Layout of the virtio block configuration space, as defined by the virtio spec.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ConfigSpace {
+    pub capacity: u64,
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub geometry: u32,
+    pub blk_size: u32,
+    pub topology: [u8; 8],
+    pub writeback: u8,
+    pub unused0: u8,
+    pub num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    pub unused1: [u8; 3],
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
+    image_format: ImageFormat,
     file_path: String,
-    file: File,
+    overlay_path: Option<String>,
+    file: Box<dyn DiskFile>,
     nsectors: u64,
     image_id: Vec<u8>,
+    dirty_chunks: DirtyChunks,
 }
 
 impl DiskProperties {
@@ -64,14 +105,41 @@ impl DiskProperties {
         disk_image_path: String,
         is_disk_read_only: bool,
         cache_type: CacheType,
+        image_format: ImageFormat,
+        overlay_path: Option<String>,
     ) -> io::Result<Self> {
-        let mut disk_image = OpenOptions::new()
-            .read(true)
-            .write(!is_disk_read_only)
-            .open(PathBuf::from(&disk_image_path))?;
-        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+        // Bypassing the page cache needs every access to the host files to be aligned, which
+        // only the raw backend takes care of.
+        if cache_type == CacheType::Direct
+            && (image_format != ImageFormat::Raw || overlay_path.is_some())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The Direct cache type only supports Raw images, without overlay.",
+            ));
+        }
+
+        let mut disk_image = match overlay_path {
+            // The guest writes land in the overlay, so it cannot be read only.
+            Some(_) if is_disk_read_only => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Overlay drives cannot be read only.",
+                ));
+            }
+            Some(ref overlay_path) => open_overlay_disk_file(
+                Path::new(&disk_image_path),
+                image_format,
+                Path::new(overlay_path),
+            )?,
+            None if cache_type == CacheType::Direct => {
+                open_direct_disk_file(Path::new(&disk_image_path), is_disk_read_only)?
+            }
+            None => open_disk_file(Path::new(&disk_image_path), is_disk_read_only, image_format)?,
+        };
+        let disk_size = disk_image.virtual_size()?;
 
-        // We only support disk size, which uses the first two words of the configuration space.
+        // The disk size is exposed in sectors, in the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
         if disk_size % SECTOR_SIZE != 0 {
             warn!(
@@ -83,18 +151,23 @@ impl DiskProperties {
 
         Ok(Self {
             cache_type,
+            image_format,
             nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
+            image_id: Self::build_disk_image_id(disk_image.file()),
+            dirty_chunks: DirtyChunks::new(disk_size),
             file_path: disk_image_path,
+            overlay_path,
             file: disk_image,
         })
     }
 
+    /// Host file receiving the guest writes.
     pub fn file(&self) -> &File {
-        &self.file
+        self.file.file()
     }
 
-    pub fn file_mut(&mut self) -> &mut File {
+    /// Guest-visible view of the disk image.
+    pub fn file_mut(&mut self) -> &mut Box<dyn DiskFile> {
         &mut self.file
     }
 
@@ -102,6 +175,28 @@ impl DiskProperties {
         self.nsectors
     }
 
+    /// Resizes the disk image to `size` bytes.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_virtual_size(size)?;
+        self.nsectors = size >> SECTOR_SHIFT;
+        self.dirty_chunks.resize(size);
+        Ok(())
+    }
+
+    /// Records the modification of the `len` bytes found at `offset`.
+    pub fn mark_dirty(&mut self, offset: u64, len: u64) {
+        self.dirty_chunks.mark(offset, len);
+    }
+
+    /// Chunks of the disk modified by the guest.
+    pub fn dirty_chunks(&self) -> &DirtyChunks {
+        &self.dirty_chunks
+    }
+
+    pub fn dirty_chunks_mut(&mut self) -> &mut DirtyChunks {
+        &mut self.dirty_chunks
+    }
+
     pub fn image_id(&self) -> &[u8] {
         &self.image_id
     }
@@ -140,33 +235,55 @@ impl DiskProperties {
         &self.file_path
     }
 
+    /// Path of the copy-on-write overlay, if any.
+    pub fn overlay_path(&self) -> Option<&String> {
+        self.overlay_path.as_ref()
+    }
+
+    /// Bitmap of the clusters found in the copy-on-write overlay, if any.
+    pub fn overlay_bitmap(&self) -> Option<&[u64]> {
+        self.file.overlay_bitmap()
+    }
+
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
-    pub fn virtio_block_config_space(&self) -> Vec<u8> {
+    /// on the backing file size, with the number of request queues and
+    /// with the discard and write zeroes limits.
+    pub fn virtio_block_config_space(&self, num_queues: u16) -> Vec<u8> {
         // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
-        }
-        config
+        let config = ConfigSpace {
+            capacity: self.nsectors.to_le(),
+            num_queues: num_queues.to_le(),
+            max_discard_sectors: u32::MAX.to_le(),
+            max_discard_seg: MAX_DISCARD_WRITE_ZEROES_SEGMENTS.to_le(),
+            discard_sector_alignment: 1u32.to_le(),
+            max_write_zeroes_sectors: u32::MAX.to_le(),
+            max_write_zeroes_seg: MAX_DISCARD_WRITE_ZEROES_SEGMENTS.to_le(),
+            write_zeroes_may_unmap: 1,
+            ..Default::default()
+        };
+        config.as_slice().to_vec()
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format
+    }
 }
 
 impl Drop for DiskProperties {
     fn drop(&mut self) {
         match self.cache_type {
-            CacheType::Writeback => {
+            CacheType::Writeback | CacheType::Direct => {
                 // flush() first to force any cached data out.
                 if self.file.flush().is_err() {
                     error!("Failed to flush block data on drop.");
                 }
                 // Sync data out to physical media on host.
-                if self.file.sync_all().is_err() {
+                if self.file().sync_all().is_err() {
                     error!("Failed to sync block data on drop.")
                 }
                 METRICS.block.flush_count.inc();
@@ -178,6 +295,87 @@ impl Drop for DiskProperties {
     }
 }
 
+/// Engine executing the I/O requests on the backing file.
+pub(crate) enum FileEngine {
+    /// Requests are executed right away, on the device thread.
+    Sync,
+    /// Data transfers and flushes are handed over to `io_uring`.
+    Async(AsyncFileEngine<PendingRequest>),
+}
+
+impl FileEngine {
+    fn new(file_engine_type: FileEngineType, disk: &DiskProperties) -> io::Result<Self> {
+        match file_engine_type {
+            FileEngineType::Sync => Ok(FileEngine::Sync),
+            FileEngineType::Async => {
+                // The image format translation is only implemented on top of the
+                // synchronous file interface.
+                if disk.image_format() != ImageFormat::Raw {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The Async engine only supports Raw images.",
+                    ));
+                }
+                // So is the copy-on-write logic.
+                if disk.overlay_path().is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The Async engine does not support overlay drives.",
+                    ));
+                }
+                // And the bounce buffers used for unaligned direct I/O.
+                if disk.cache_type() == CacheType::Direct {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The Async engine does not support the Direct cache type.",
+                    ));
+                }
+
+                match AsyncFileEngine::new(u32::from(QUEUE_SIZE)) {
+                    Ok(engine) => Ok(FileEngine::Async(engine)),
+                    Err(e) => {
+                        warn!("{}. Falling back to the Sync engine.", e);
+                        Ok(FileEngine::Sync)
+                    }
+                }
+            }
+        }
+    }
+
+    fn file_engine_type(&self) -> FileEngineType {
+        match self {
+            FileEngine::Sync => FileEngineType::Sync,
+            FileEngine::Async(_) => FileEngineType::Async,
+        }
+    }
+}
+
+/// Writes the status of a finished request to guest memory and returns the number
+/// of bytes to report in the used ring.
+fn finish_request(
+    metrics: &BlockMetrics,
+    mem: &GuestMemoryMmap,
+    request_type: RequestType,
+    status_addr: GuestAddress,
+    status: Status,
+) -> u32 {
+    let virtio_blk_status = status.virtio_blk_status();
+    let num_used_bytes = status.num_used_bytes();
+    if let Status::Err(err_status) = status {
+        metrics.inc(|m| &m.invalid_reqs_count);
+        error!(
+            "Failed to execute {:?} virtio block request: {:?}",
+            request_type, err_status
+        );
+    }
+
+    if let Err(e) = mem.write_obj(virtio_blk_status, status_addr) {
+        error!("Failed to write virtio block status: {:?}", e)
+    }
+
+    num_used_bytes
+}
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     // Host file and properties.
@@ -193,7 +391,7 @@ pub struct Block {
     pub(crate) queues: Vec<Queue>,
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     pub(crate) interrupt_evt: EventFd,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
 
     // Implementation specific fields.
@@ -201,39 +399,82 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
+    pub(crate) file_engine: FileEngine,
+    pub(crate) metrics: BlockMetrics,
 }
 
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
     /// The given file must be seekable and sizable.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         partuuid: Option<String>,
         cache_type: CacheType,
+        image_format: ImageFormat,
+        file_engine_type: FileEngineType,
         disk_image_path: String,
+        overlay_path: Option<String>,
         is_disk_read_only: bool,
         is_disk_root: bool,
+        num_queues: u16,
         rate_limiter: RateLimiter,
     ) -> io::Result<Block> {
-        let disk_properties = DiskProperties::new(disk_image_path, is_disk_read_only, cache_type)?;
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The number of queues must be between 1 and {}.",
+                    MAX_NUM_QUEUES
+                ),
+            ));
+        }
+
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            is_disk_read_only,
+            cache_type,
+            image_format,
+            overlay_path,
+        )?;
+        let file_engine = FileEngine::new(file_engine_type, &disk_properties)?;
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_F_RING_PACKED);
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            if disk_properties.file.can_discard() {
+                avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+            }
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK)?];
+        // Single queue devices keep exposing the same features as before.
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let mut queue_evts = Vec::new();
+        let mut queues = Vec::new();
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK)?);
+            queues.push(Queue::new(QUEUE_SIZE));
+        }
 
         Ok(Block {
+            metrics: BlockMetrics::new(&id),
             id,
             root_device: is_disk_root,
             partuuid,
             rate_limiter,
-            config_space: disk_properties.virtio_block_config_space(),
+            file_engine,
+            config_space: disk_properties.virtio_block_config_space(num_queues),
             disk: disk_properties,
             avail_features,
             acked_features: 0u64,
@@ -246,30 +487,42 @@ impl Block {
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
-        METRICS.block.queue_event_count.inc();
-        if let Err(e) = self.queue_evts[0].read() {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
+        self.metrics.inc(|m| &m.queue_event_count);
+        if let Err(e) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", e);
-            METRICS.block.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
         } else if self.rate_limiter.is_blocked() {
-            METRICS.block.rate_limiter_throttled_events.inc();
-        } else {
-            self.process_virtio_queues();
+            self.metrics.inc(|m| &m.rate_limiter_throttled_events);
+        } else if self.process_queue(queue_index) {
+            let _ = self.signal_used_queue();
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        if self.process_queue(0) {
+        if self.process_all_queues() {
             let _ = self.signal_used_queue();
         }
     }
 
+    // Processes every request queue, until the rate limiter kicks in.
+    fn process_all_queues(&mut self) -> bool {
+        let mut used_any = false;
+        for queue_index in 0..self.queues.len() {
+            if self.rate_limiter.is_blocked() {
+                break;
+            }
+            used_any |= self.process_queue(queue_index);
+        }
+        used_any
+    }
+
     pub(crate) fn process_rate_limiter_event(&mut self) {
-        METRICS.block.rate_limiter_event_count.inc();
+        self.metrics.inc(|m| &m.rate_limiter_event_count);
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
-        if self.rate_limiter.event_handler().is_ok() && self.process_queue(0) {
+        // and restart processing the queues.
+        if self.rate_limiter.event_handler().is_ok() && self.process_all_queues() {
             let _ = self.signal_used_queue();
         }
     }
@@ -281,9 +534,24 @@ impl Block {
             DeviceState::Inactive => unreachable!(),
         };
         let queue = &mut self.queues[queue_index];
+        // The driver can leave some of the request queues unused.
+        if !queue.ready {
+            return false;
+        }
         let mut used_any = false;
-        while let Some(head) = queue.pop(mem) {
-            let len = match Request::parse(&head, mem) {
+        let mut submitted_any = false;
+        while let Some(head) = queue.pop_or_enable_notification(mem) {
+            if let FileEngine::Async(ref engine) = self.file_engine {
+                if engine.is_full() {
+                    // Leave the descriptor chain in the avail ring, the queue gets
+                    // processed again once some of the in flight requests complete.
+                    queue.undo_pop();
+                    break;
+                }
+            }
+
+            let start_us = get_time_us(ClockType::Monotonic);
+            let (len, request_type) = match Request::parse(&head, mem) {
                 Ok(request) => {
                     // If limiter.consume() fails it means there is no more TokenType::Ops
                     // budget and rate limiting is in effect.
@@ -291,7 +559,7 @@ impl Block {
                         // Stop processing the queue and return this descriptor chain to the
                         // avail ring, for later processing.
                         queue.undo_pop();
-                        METRICS.block.rate_limiter_throttled_events.inc();
+                        self.metrics.inc(|m| &m.rate_limiter_throttled_events);
                         break;
                     }
                     // Exercise the rate limiter only if this request is of data transfer type.
@@ -309,32 +577,69 @@ impl Block {
                             // Stop processing the queue and return this descriptor chain to the
                             // avail ring, for later processing.
                             queue.undo_pop();
-                            METRICS.block.rate_limiter_throttled_events.inc();
+                            self.metrics.inc(|m| &m.rate_limiter_throttled_events);
                             break;
                         }
                     }
 
-                    let status = Status::from_result(request.execute(&mut self.disk, mem));
-                    let virtio_blk_status = status.virtio_blk_status();
-                    let num_used_bytes = status.num_used_bytes();
-                    if let Status::Err(err_status) = status {
-                        METRICS.block.invalid_reqs_count.inc();
-                        error!(
-                            "Failed to execute {:?} virtio block request: {:?}",
-                            request.request_type, err_status
-                        );
-                    }
-
-                    if let Err(e) = mem.write_obj(virtio_blk_status, request.status_addr) {
-                        error!("Failed to write virtio block status: {:?}", e)
-                    }
-
-                    num_used_bytes
+                    let status = match self.file_engine {
+                        FileEngine::Async(ref mut engine)
+                            if request.is_async(self.disk.cache_type()) =>
+                        {
+                            match request.submit_async(
+                                engine,
+                                &mut self.disk,
+                                mem,
+                                queue_index,
+                                head.index,
+                                start_us,
+                            ) {
+                                Ok(()) => {
+                                    // The descriptor chain is handed back to the driver
+                                    // when the request completes.
+                                    submitted_any = true;
+                                    continue;
+                                }
+                                Err(err_status) => Status::Err(err_status),
+                            }
+                        }
+                        FileEngine::Async(ref mut engine)
+                            if (request.request_type == RequestType::Discard
+                                || request.request_type == RequestType::WriteZeroes)
+                                && engine.in_flight() > 0 =>
+                        {
+                            // The synchronous requests must not be overtaken by the writes
+                            // still in flight. Their completions are handed back to the
+                            // driver on the next completion event.
+                            match engine.drain() {
+                                Ok(()) => Status::from_result(request.execute(
+                                    &mut self.disk,
+                                    mem,
+                                    &self.metrics,
+                                )),
+                                Err(e) => {
+                                    Status::Err(ErrStatus::IoErr(IoErrStatus::AsyncSubmit(e)))
+                                }
+                            }
+                        }
+                        _ => {
+                            Status::from_result(request.execute(&mut self.disk, mem, &self.metrics))
+                        }
+                    };
+
+                    let len = finish_request(
+                        &self.metrics,
+                        mem,
+                        request.request_type,
+                        request.status_addr,
+                        status,
+                    );
+                    (len, Some(request.request_type))
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
-                    METRICS.block.execute_fails.inc();
-                    0
+                    self.metrics.inc(|m| &m.execute_fails);
+                    (0, None)
                 }
             };
 
@@ -344,23 +649,128 @@ impl Block {
                     head.index, e
                 )
             });
+            if let Some(request_type) = request_type {
+                self.metrics.record_latency(request_type, start_us);
+            }
             used_any = true;
         }
 
-        if !used_any {
-            METRICS.block.no_avail_buffer.inc();
+        if submitted_any {
+            if let FileEngine::Async(ref mut engine) = self.file_engine {
+                if let Err(e) = engine.kick_submission_queue() {
+                    error!("Failed to submit block requests: {}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                }
+            }
+        } else if !used_any {
+            self.metrics.inc(|m| &m.no_avail_buffer);
+        }
+
+        used_any
+    }
+
+    // Hands the completed asynchronous requests back to the driver.
+    fn process_async_completions(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // Nothing can be in flight before activation.
+            DeviceState::Inactive => return false,
+        };
+        let engine = match self.file_engine {
+            FileEngine::Async(ref mut engine) => engine,
+            FileEngine::Sync => return false,
+        };
+        let mut used_any = false;
+        while let Some(completion) = engine.pop(mem) {
+            let (pending, result) = match completion {
+                Ok(completion) => completion,
+                Err(e) => {
+                    // There is no request to hand back to the driver.
+                    error!("Failed to retrieve async block completion: {}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                    continue;
+                }
+            };
+            let status = pending.status(result, &self.metrics);
+            let len = finish_request(
+                &self.metrics,
+                mem,
+                pending.request_type,
+                pending.status_addr,
+                status,
+            );
+            self.queues[pending.queue_index]
+                .add_used(mem, pending.desc_idx, len)
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to add available descriptor head {}: {}",
+                        pending.desc_idx, e
+                    )
+                });
+            self.metrics
+                .record_latency(pending.request_type, pending.start_us);
+            used_any = true;
         }
 
         used_any
     }
 
-    pub(crate) fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    pub(crate) fn process_async_completion_event(&mut self) {
+        self.metrics.inc(|m| &m.async_completion_event_count);
+        let engine = match self.file_engine {
+            FileEngine::Async(ref engine) => engine,
+            FileEngine::Sync => return,
+        };
+        if let Err(e) = engine.completion_evt().read() {
+            error!("Failed to get async completion event: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+            return;
+        }
+        // If the engine was full, there might be requests left in the queue.
+        let was_full = engine.is_full();
+
+        let mut used_any = self.process_async_completions();
+        if was_full {
+            used_any |= self.process_all_queues();
+        }
+        if used_any {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    /// Waits for all the in flight asynchronous requests to complete and hands them back to
+    /// the driver. The device state can only be saved, or the backing file swapped, after
+    /// the requests are drained.
+    pub fn drain_async_requests(&mut self) {
+        if let FileEngine::Async(ref mut engine) = self.file_engine {
+            if let Err(e) = engine.drain() {
+                error!("Failed to drain async block requests: {}", e);
+                self.metrics.inc(|m| &m.event_fails);
+            }
+        }
+        if self.process_async_completions() {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    pub(crate) fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
+        // The driver may only want to be notified once more requests complete.
+        if let DeviceState::Activated(ref mem) = self.device_state {
+            let mut needs_notification = false;
+            for queue in self.queues.iter_mut() {
+                needs_notification |= queue.needs_notification(mem);
+            }
+            if !needs_notification {
+                return Ok(());
+            }
+        }
+
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
 
         self.interrupt_evt.write(1).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
-            METRICS.block.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
             DeviceError::FailedSignalingUsedQueue(e)
         })?;
         Ok(())
@@ -368,17 +778,63 @@ impl Block {
 
     /// Update the backing file and the config space of the block device.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> io::Result<()> {
-        let disk_properties =
-            DiskProperties::new(disk_image_path, self.is_read_only(), self.cache_type())?;
+        // The overlay only makes sense on top of the base image it was created for.
+        if self.overlay_path().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The base image of overlay drives cannot be updated.",
+            ));
+        }
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            self.is_read_only(),
+            self.cache_type(),
+            self.image_format(),
+            None,
+        )?;
+        // In flight requests still reference the old backing file.
+        self.drain_async_requests();
         self.disk = disk_properties;
-        self.config_space = self.disk.virtio_block_config_space();
+        self.config_space = self.disk.virtio_block_config_space(self.num_queues());
+        // None of the new image content was backed up.
+        self.disk.dirty_chunks_mut().mark_all();
 
-        // Kick the driver to pick up the changes.
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).unwrap();
+        self.metrics.inc(|m| &m.update_count);
+        Ok(())
+    }
+
+    /// Grows the disk image to `size_bytes` and updates the virtio configuration.
+    pub fn resize(&mut self, size_bytes: u64) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Read only drives cannot be resized.",
+            ));
+        }
+        // The overlay and its cluster bitmap are bound to the size of the base image.
+        if self.overlay_path().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Overlay drives cannot be resized.",
+            ));
+        }
+        if size_bytes % SECTOR_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The drive size must be a multiple of the sector size.",
+            ));
+        }
+        // Shrinking would discard data the guest may still be using.
+        if size_bytes < self.disk.file_mut().virtual_size()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Drives cannot be shrunk.",
+            ));
+        }
+        self.disk.resize(size_bytes)?;
+        self.config_space = self.disk.virtio_block_config_space(self.num_queues());
 
-        METRICS.block.update_count.inc();
+        self.metrics.inc(|m| &m.update_count);
         Ok(())
     }
 
@@ -387,6 +843,19 @@ impl Block {
         self.rate_limiter.update_buckets(bytes, ops);
     }
 
+    /// Returns the bitmap of the disk chunks, of `DIRTY_CHUNK_SIZE` bytes, modified since they
+    /// were last cleared.
+    pub fn dirty_chunks(&mut self) -> Vec<u64> {
+        // The returned chunks are about to be backed up, so their writes must have completed.
+        self.drain_async_requests();
+        self.disk.dirty_chunks().bitmap().to_vec()
+    }
+
+    /// Clears the dirty chunks set in `bitmap`, once they are backed up.
+    pub fn clear_dirty_chunks(&mut self, bitmap: &[u64]) -> io::Result<()> {
+        self.disk.dirty_chunks_mut().clear(bitmap)
+    }
+
     /// Provides the ID of this block device.
     pub fn id(&self) -> &String {
         &self.id
@@ -397,6 +866,11 @@ impl Block {
         self.disk.file_path()
     }
 
+    /// Provides the copy-on-write overlay path of this block device, if any.
+    pub fn overlay_path(&self) -> Option<&String> {
+        self.disk.overlay_path()
+    }
+
     /// Provides the PARTUUID of this block device.
     pub fn partuuid(&self) -> Option<&String> {
         self.partuuid.as_ref()
@@ -417,6 +891,21 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Specifies the format of the block device backing file.
+    pub fn image_format(&self) -> ImageFormat {
+        self.disk.image_format()
+    }
+
+    /// Specifies the engine executing the block device I/O requests.
+    pub fn file_engine_type(&self) -> FileEngineType {
+        self.file_engine.file_engine_type()
+    }
+
+    /// Provides the number of request queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
@@ -440,6 +929,11 @@ impl VirtioDevice for Block {
         &self.queue_evts
     }
 
+    fn num_required_queues(&self) -> usize {
+        // Drivers set up at most one request queue per vCPU.
+        1
+    }
+
     fn interrupt_evt(&self) -> &EventFd {
         &self.interrupt_evt
     }
@@ -465,7 +959,7 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
@@ -480,7 +974,7 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
 
@@ -495,6 +989,11 @@ impl VirtioDevice for Block {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0 {
+            for queue in self.queues.iter_mut() {
+                queue.enable_notif_suppression();
+            }
+        }
         if self.activate_evt.write(1).is_err() {
             error!("Block: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
@@ -507,20 +1006,23 @@ impl VirtioDevice for Block {
 #[cfg(test)]
 pub(crate) mod tests {
     use std::fs::metadata;
-    use std::io::Read;
+    use std::io::{Read, Seek, SeekFrom};
     use std::os::unix::ffi::OsStrExt;
     use std::thread;
     use std::time::Duration;
     use std::u32;
 
     use super::*;
+    use crate::virtio::block::disk::qcow2::tests::create_qcow2_image;
+    use crate::virtio::block::{DIRTY_CHUNK_SIZE, NUM_QUEUES};
     use crate::virtio::queue::tests::*;
     use utils::tempfile::TempFile;
     use vm_memory::GuestAddress;
 
     use crate::check_metric_after_block;
     use crate::virtio::block::test_utils::{
-        default_block, invoke_handler_for_queue_event, set_queue, set_rate_limiter,
+        default_block, default_block_with_engine, invoke_handler_for_queue_event, set_queue,
+        set_rate_limiter,
     };
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
 
@@ -535,22 +1037,150 @@ pub(crate) mod tests {
             String::from(f.as_path().to_str().unwrap()),
             true,
             CacheType::Unsafe,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
         assert_eq!(size, SECTOR_SIZE * num_sectors);
         assert_eq!(disk_properties.nsectors, num_sectors);
-        let cfg = disk_properties.virtio_block_config_space();
+        let cfg = disk_properties.virtio_block_config_space(4);
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
+        for (i, byte) in cfg[..8].iter().enumerate() {
             assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
         }
+        let cfg = ConfigSpace::from_slice(&cfg).unwrap();
+        assert_eq!({ cfg.num_queues }, 4);
+        assert_eq!({ cfg.max_discard_sectors }, u32::MAX);
+        assert_eq!({ cfg.max_discard_seg }, MAX_DISCARD_WRITE_ZEROES_SEGMENTS);
+        assert_eq!({ cfg.discard_sector_alignment }, 1);
+        assert_eq!({ cfg.max_write_zeroes_sectors }, u32::MAX);
+        assert_eq!(
+            { cfg.max_write_zeroes_seg },
+            MAX_DISCARD_WRITE_ZEROES_SEGMENTS
+        );
+        assert_eq!({ cfg.write_zeroes_may_unmap }, 1);
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
-        assert!(
-            DiskProperties::new("invalid-disk-path".to_string(), true, CacheType::Unsafe).is_err()
+        assert!(DiskProperties::new(
+            "invalid-disk-path".to_string(),
+            true,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_qcow2_disk_backing_file_helper() {
+        let num_sectors = 0x1000;
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(
+            &mut f.as_file().try_clone().unwrap(),
+            SECTOR_SIZE * num_sectors,
+            None,
         );
+        let path = String::from(f.as_path().to_str().unwrap());
+
+        // The disk size is the qcow2 virtual size, not the image file size.
+        let disk_properties = DiskProperties::new(
+            path.clone(),
+            false,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            None,
+        )
+        .unwrap();
+        assert_eq!(disk_properties.nsectors, num_sectors);
+        assert_eq!(disk_properties.image_format(), ImageFormat::Qcow2);
+
+        // The qcow2 header is exposed as is when the image is opened as raw.
+        let disk_properties =
+            DiskProperties::new(path, false, CacheType::Unsafe, ImageFormat::Raw, None).unwrap();
+        assert_ne!(disk_properties.nsectors, num_sectors);
+
+        // Raw images are not valid qcow2 images.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(SECTOR_SIZE * num_sectors).unwrap();
+        assert!(DiskProperties::new(
+            String::from(f.as_path().to_str().unwrap()),
+            false,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_overlay_disk_backing_file_helper() {
+        let num_sectors = 0x100;
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(SECTOR_SIZE * num_sectors).unwrap();
+        let base_path = String::from(base.as_path().to_str().unwrap());
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = String::from(overlay.as_path().to_str().unwrap());
+
+        let disk_properties = DiskProperties::new(
+            base_path.clone(),
+            false,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            Some(overlay_path.clone()),
+        )
+        .unwrap();
+        // The disk size is the base image size, not the overlay file size.
+        assert_eq!(disk_properties.nsectors, num_sectors);
+        assert_eq!(disk_properties.file_path(), &base_path);
+        assert_eq!(disk_properties.overlay_path(), Some(&overlay_path));
+        assert!(disk_properties.file.overlay_bitmap().is_some());
+
+        // Overlay drives are always writable.
+        assert!(DiskProperties::new(
+            base_path.clone(),
+            true,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            Some(overlay_path.clone())
+        )
+        .is_err());
+        // The Async engine does not support overlays.
+        assert!(Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Async,
+            base_path.clone(),
+            Some(overlay_path.clone()),
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .is_err());
+
+        // The base image of overlay drives cannot be swapped nor resized.
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            base_path.clone(),
+            Some(overlay_path),
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert!(!block.is_read_only());
+        assert!(block.update_disk_image(base_path).is_err());
+        assert!(block.resize(2 * SECTOR_SIZE * num_sectors).is_err());
+        assert_eq!(block.disk.nsectors(), num_sectors);
     }
 
     #[test]
@@ -559,7 +1189,13 @@ pub(crate) mod tests {
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_F_RING_PACKED)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -574,22 +1210,47 @@ pub(crate) mod tests {
         assert_eq!(block.acked_features, features);
     }
 
+    #[test]
+    fn test_virtio_features_no_discard() {
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(&mut f.as_file().try_clone().unwrap(), 0x10_0000, None);
+
+        // Qcow2 images never deallocate discarded ranges, so discard is not offered.
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert_eq!(block.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(
+            block.avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+    }
+
     #[test]
     fn test_virtio_read_config() {
         let block = default_block();
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
-        // This will read the number of sectors.
+        // This will read the number of sectors, found in the first 8 bytes of the config space.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -601,11 +1262,10 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block();
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -619,7 +1279,7 @@ pub(crate) mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 3, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -945,7 +1605,7 @@ pub(crate) mod tests {
             mem.write_slice(empty_data.as_slice(), data_addr).unwrap();
 
             let size = block.disk.file.seek(SeekFrom::End(0)).unwrap();
-            block.disk.file.set_len(size / 2).unwrap();
+            block.disk.file().set_len(size / 2).unwrap();
             mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
@@ -979,7 +1639,7 @@ pub(crate) mod tests {
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
             let size = block.disk.file.seek(SeekFrom::End(0)).unwrap();
-            block.disk.file.set_len(size / 2).unwrap();
+            block.disk.file().set_len(size / 2).unwrap();
             // Update sector number: stored at `request_type_addr.0 + 8`
             mem.write_obj(5, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
@@ -1041,8 +1701,10 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn test_flush() {
+    fn test_drive_metrics() {
         let mut block = default_block();
+        // Use a dedicated drive id, the default one is shared by all the tests.
+        block.metrics = BlockMetrics::new("test_drive_metrics");
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -1050,53 +1712,606 @@ pub(crate) mod tests {
         initialize_virtqueue(&vq);
 
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
-        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        vq.dtable[1].len.set(512);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
 
-        // Flush completes successfully without a data descriptor.
-        {
-            vq.dtable[0].next.set(2);
+        check_metric_after_block!(
+            &METRICS.block.read_count,
+            1,
+            invoke_handler_for_queue_event(&mut block)
+        );
 
-            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
-                .unwrap();
+        let drive = block.metrics.device();
+        assert_eq!(drive.read_count.count(), 1);
+        assert_eq!(drive.read_bytes.count(), 512);
+        assert_eq!(drive.read_latency_us.count(), 1);
+        assert_eq!(drive.write_latency_us.count(), 0);
 
-            invoke_handler_for_queue_event(&mut block);
-            assert_eq!(vq.used.idx.get(), 1);
-            assert_eq!(vq.used.ring[0].get().id, 0);
-            assert_eq!(vq.used.ring[0].get().len, 1);
-            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
-        }
+        // Unsupported requests are not part of the latency histograms.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u32>(42, request_type_addr).unwrap();
+        invoke_handler_for_queue_event(&mut block);
 
-        // Flush completes successfully even with a data descriptor.
-        {
-            vq.used.idx.set(0);
-            set_queue(&mut block, 0, vq.create_queue());
-            vq.dtable[0].next.set(1);
+        let drive = block.metrics.device();
+        assert_eq!(drive.invalid_reqs_count.count(), 1);
+        assert_eq!(drive.read_latency_us.count(), 1);
+    }
 
-            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
-                .unwrap();
+    #[test]
+    fn test_indirect_descriptors() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
 
-            invoke_handler_for_queue_event(&mut block);
-            assert_eq!(vq.used.idx.get(), 1);
-            assert_eq!(vq.used.ring[0].get().id, 0);
-            // status byte length.
-            assert_eq!(vq.used.ring[0].get().len, 1);
-            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
-        }
+        // The whole request is described by a table of indirect descriptors.
+        let request_type_addr = GuestAddress(0x1000);
+        let data_addr = GuestAddress(0x2000);
+        let status_addr = GuestAddress(0x3000);
+        let table = vq.set_indirect_table(5, GuestAddress(0x8000), 3);
+        table[0].set(request_type_addr.0, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        table[1].set(data_addr.0, 512, VIRTQ_DESC_F_NEXT, 2);
+        table[2].set(status_addr.0, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(5);
+        vq.avail.idx.set(1);
+
+        let rand_data = utils::rand::rand_alphanumerics(512).as_bytes().to_vec();
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        mem.write_slice(&rand_data, data_addr).unwrap();
+
+        check_metric_after_block!(
+            &METRICS.block.write_count,
+            1,
+            invoke_handler_for_queue_event(&mut block)
+        );
+
+        // The descriptor referring to the table is handed back.
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 5);
+        assert_eq!(vq.used.ring[0].get().len, 1);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        let mut buf = [0u8; 512];
+        block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+        block.disk.file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], rand_data.as_slice());
     }
 
     #[test]
-    fn test_get_device_id() {
+    fn test_dirty_chunks() {
         let mut block = default_block();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
         block.activate(mem.clone()).unwrap();
         initialize_virtqueue(&vq);
+        assert_eq!(block.dirty_chunks(), vec![0]);
 
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
-        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        invoke_handler_for_queue_event(&mut block);
+
+        // Retrieving the bitmap doesn't reset it, clearing the retrieved chunks does.
+        assert_eq!(block.dirty_chunks(), vec![1]);
+        assert_eq!(block.dirty_chunks(), vec![1]);
+        assert!(block.clear_dirty_chunks(&[1, 0]).is_err());
+        block.clear_dirty_chunks(&[1]).unwrap();
+        assert_eq!(block.dirty_chunks(), vec![0]);
+
+        // The chunks added by a resize are dirty.
+        block.resize(DIRTY_CHUNK_SIZE + 0x200).unwrap();
+        assert_eq!(block.dirty_chunks(), vec![0b11]);
+        block.clear_dirty_chunks(&[0b11]).unwrap();
+
+        // So are all the chunks of a new backing file.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        block
+            .update_disk_image(f.as_path().to_str().unwrap().to_string())
+            .unwrap();
+        assert_eq!(block.dirty_chunks(), vec![1]);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Flush completes successfully without a data descriptor.
+        {
+            vq.dtable[0].next.set(2);
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Flush completes successfully even with a data descriptor.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(1);
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            // status byte length.
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn test_direct_cache_type() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let path = f.as_path().to_str().unwrap().to_string();
+        let new_block = |image_format, file_engine_type, overlay_path| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Direct,
+                image_format,
+                file_engine_type,
+                path.clone(),
+                overlay_path,
+                false,
+                false,
+                NUM_QUEUES,
+                RateLimiter::default(),
+            )
+        };
+
+        // Only raw images without overlay, on the Sync engine, are supported.
+        assert!(new_block(ImageFormat::Qcow2, FileEngineType::Sync, None).is_err());
+        assert!(new_block(
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            Some(path.clone() + ".overlay")
+        )
+        .is_err());
+        assert!(new_block(ImageFormat::Raw, FileEngineType::Async, None).is_err());
+
+        let mut block = new_block(ImageFormat::Raw, FileEngineType::Sync, None).unwrap();
+        block.metrics = BlockMetrics::new("test_direct_cache_type");
+        assert_eq!(block.cache_type(), CacheType::Direct);
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let rand_data = utils::rand::rand_alphanumerics(512).as_bytes().to_vec();
+
+        // Write, going through the bounce buffer if the guest buffer is unaligned.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(512);
+            mem.write_slice(&rand_data, data_addr).unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 512];
+            f.as_file().read_exact(&mut buf).unwrap();
+            assert_eq!(buf.to_vec(), rand_data);
+        }
+
+        // Flushes are performed.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(2);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            assert_eq!(block.metrics.device().flush_count.count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let path = f.as_path().to_str().unwrap().to_string();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                ImageFormat::Raw,
+                FileEngineType::Sync,
+                path.clone(),
+                None,
+                false,
+                false,
+                num_queues,
+                RateLimiter::default(),
+            )
+        };
+
+        assert!(new_block(0).is_err());
+        assert!(new_block(MAX_NUM_QUEUES + 1).is_err());
+
+        // Single queue devices do not advertise multiple queues.
+        let block = new_block(NUM_QUEUES).unwrap();
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+
+        let mut block = new_block(2).unwrap();
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        // The number of queues follows the topology fields in the config space.
+        let mut num_queues = [0u8; 2];
+        block.read_config(34, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        // Requests are handed back on the queue they were received on.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 1, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        vq.dtable[0].next.set(2);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+            .unwrap();
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(block.queues()[0].next_used.0, 0);
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Segments are read from the data descriptor, which must be readable.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+        block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+        block.disk.file.write_all(&[0xab; 0x1000]).unwrap();
+
+        // Write zeroes.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(1, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_count,
+                1,
+                invoke_handler_for_queue_event(&mut block)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 0x800];
+            block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..0x200], [0xab; 0x200][..]);
+            assert_eq!(buf[0x200..0x600], [0; 0x400][..]);
+            assert_eq!(buf[0x600..], [0xab; 0x200][..]);
+        }
+
+        // Write zeroes with the unmap flag.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(4, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 0x200];
+            block.disk.file.seek(SeekFrom::Start(0x800)).unwrap();
+            block.disk.file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [0; 0x200]);
+        }
+
+        // Discard.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(6, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.discard_count,
+                1,
+                invoke_handler_for_queue_event(&mut block)
+            );
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            // Discarding never changes the disk size.
+            assert_eq!(block.disk.file.virtual_size().unwrap(), 0x1000);
+        }
+
+        // Discard with the unmap flag is not supported.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(6, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+        }
+
+        // Segment going past the end of the disk.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj(DiscardWriteZeroesSegment::new(7, 2, 0), data_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // Data length not a multiple of the segment size.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 1, 0), data_addr)
+                .unwrap();
+            vq.dtable[1].len.set(15);
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
+    #[test]
+    fn test_async_read_write() {
+        let mut block = default_block_with_engine(FileEngineType::Async);
+        // Hosts without io_uring support fall back to the Sync engine.
+        if block.file_engine_type() != FileEngineType::Async {
+            return;
+        }
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let rand_data = utils::rand::rand_alphanumerics(512).as_bytes().to_vec();
+
+        // Write, completed when draining the engine.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(512);
+            mem.write_slice(&rand_data, data_addr).unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process_queue_event(0);
+            // The request is only handed back to the driver on completion.
+            assert_eq!(vq.used.idx.get(), 0);
+
+            check_metric_after_block!(&METRICS.block.write_count, 1, block.drain_async_requests());
+            assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Read, completed through the completion event.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            mem.write_slice(&[0; 512], data_addr).unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process_queue_event(0);
+            if let FileEngine::Async(ref mut engine) = block.file_engine {
+                engine.drain().unwrap();
+            }
+            check_metric_after_block!(
+                &METRICS.block.read_count,
+                1,
+                block.process_async_completion_event()
+            );
+
+            assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            // Added status byte length.
+            assert_eq!(vq.used.ring[0].get().len, 513);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 512];
+            mem.read_slice(&mut buf, data_addr).unwrap();
+            assert_eq!(&buf[..], rand_data.as_slice());
+        }
+
+        // Invalid requests fail right away, without reaching the engine.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            // The disk only has 8 sectors.
+            mem.write_obj(RequestHeader::new(VIRTIO_BLK_T_IN, 8), request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // Write zeroes, executed once the write submitted before it completes.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj(RequestHeader::new(VIRTIO_BLK_T_OUT, 0), request_type_addr)
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            mem.write_slice(&rand_data, data_addr).unwrap();
+
+            // Second descriptor chain, zeroing the sector written by the first one.
+            vq.dtable[3].set(0x4000, 0x10, VIRTQ_DESC_F_NEXT, 4);
+            vq.dtable[4].set(
+                0x5000,
+                std::mem::size_of::<DiscardWriteZeroesSegment>() as u32,
+                VIRTQ_DESC_F_NEXT,
+                5,
+            );
+            vq.dtable[5].set(0x6000, 1, VIRTQ_DESC_F_WRITE, 0);
+            mem.write_obj(
+                RequestHeader::new(VIRTIO_BLK_T_WRITE_ZEROES, 0),
+                GuestAddress(0x4000),
+            )
+            .unwrap();
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(0, 1, 0),
+                GuestAddress(0x5000),
+            )
+            .unwrap();
+            vq.avail.ring[1].set(3);
+            vq.avail.idx.set(2);
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process_queue_event(0);
+            // Only the write zeroes request is handed back right away.
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 3);
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(0x6000)).unwrap(),
+                VIRTIO_BLK_S_OK as u8
+            );
+
+            block.drain_async_requests();
+            assert_eq!(vq.used.idx.get(), 2);
+            assert_eq!(vq.used.ring[1].get().id, 0);
+
+            let mut buf = [0xffu8; 512];
+            block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [0; 512]);
+        }
+    }
+
+    #[test]
+    fn test_async_engine_image_format() {
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(&mut f.as_file().try_clone().unwrap(), 0x10_0000, None);
+
+        assert!(Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            FileEngineType::Async,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_get_device_id() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
-        let blk_metadata = block.disk.file.metadata();
+        let blk_metadata = block.disk.file().metadata();
 
         // Test that the driver receives the correct device id.
         {
@@ -1184,7 +2399,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1254,7 +2469,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1272,7 +2487,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1323,7 +2538,71 @@ pub(crate) mod tests {
             .update_disk_image(String::from(path.to_str().unwrap()))
             .unwrap();
 
-        assert_eq!(block.disk.file.metadata().unwrap().st_ino(), mdata.st_ino());
+        assert_eq!(
+            block.disk.file().metadata().unwrap().st_ino(),
+            mdata.st_ino()
+        );
         assert_eq!(block.disk.image_id, id);
     }
+
+    #[test]
+    fn test_resize() {
+        let mut block = default_block();
+        let mut config_space = [0u8; 8];
+
+        // Unaligned sizes are rejected and drives cannot be shrunk.
+        assert!(block.resize(0x2001).is_err());
+        assert!(block.resize(0x800).is_err());
+
+        check_metric_after_block!(
+            &METRICS.block.update_count,
+            1,
+            block.resize(0x2000).unwrap()
+        );
+        assert_eq!(block.disk.nsectors(), 0x10);
+        assert_eq!(block.disk.file_mut().virtual_size().unwrap(), 0x2000);
+        // The new capacity is exposed through the config space.
+        block.read_config(0, &mut config_space);
+        assert_eq!(u64::from_le_bytes(config_space), 0x10);
+
+        // Read only drives cannot be resized.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            true,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert!(block.resize(0x2000).is_err());
+        assert_eq!(block.disk.nsectors(), 0x8);
+
+        // Only raw images can be resized.
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(&mut f.as_file().try_clone().unwrap(), 0x1000, None);
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert!(block.resize(0x2000).is_err());
+        assert_eq!(block.disk.nsectors(), 0x8);
+    }
 }
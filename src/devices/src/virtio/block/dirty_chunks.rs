@@ -0,0 +1,165 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of the disk chunks modified by the guest, for incremental backups.
+//!
+//! The disk is split in chunks of `DIRTY_CHUNK_SIZE` bytes, each one backed by a bit of the
+//! bitmap. Bit `i % 64` of the word `i / 64` is set once chunk `i` is modified, until the
+//! chunk is cleared.
+
+use std::io;
+
+/// Granularity of the block dirty tracking, in bytes.
+pub const DIRTY_CHUNK_SIZE: u64 = 0x1_0000;
+
+/// Bitmap of the disk chunks modified since they were last cleared.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirtyChunks {
+    bitmap: Vec<u64>,
+    disk_size: u64,
+}
+
+impl DirtyChunks {
+    /// Creates a clean bitmap covering `disk_size` bytes.
+    pub fn new(disk_size: u64) -> Self {
+        DirtyChunks {
+            bitmap: vec![0; Self::bitmap_len(disk_size)],
+            disk_size,
+        }
+    }
+
+    fn num_chunks(&self) -> u64 {
+        (self.disk_size + DIRTY_CHUNK_SIZE - 1) / DIRTY_CHUNK_SIZE
+    }
+
+    fn bitmap_len(disk_size: u64) -> usize {
+        let num_chunks = (disk_size + DIRTY_CHUNK_SIZE - 1) / DIRTY_CHUNK_SIZE;
+        ((num_chunks + 63) / 64) as usize
+    }
+
+    /// Marks the chunks overlapping the `len` bytes found at `offset` as dirty.
+    pub fn mark(&mut self, offset: u64, len: u64) {
+        // Accesses past the end of the disk are ignored.
+        let end = std::cmp::min(offset.saturating_add(len), self.disk_size);
+        if offset >= end {
+            return;
+        }
+        for chunk in offset / DIRTY_CHUNK_SIZE..=(end - 1) / DIRTY_CHUNK_SIZE {
+            self.bitmap[(chunk / 64) as usize] |= 1u64 << (chunk % 64);
+        }
+    }
+
+    /// Marks the whole disk as dirty.
+    pub fn mark_all(&mut self) {
+        self.mark(0, self.disk_size);
+    }
+
+    /// Changes the size of the tracked disk. The bytes added by a growth are dirty.
+    pub fn resize(&mut self, disk_size: u64) {
+        let old_disk_size = self.disk_size;
+        self.disk_size = disk_size;
+        self.bitmap.resize(Self::bitmap_len(disk_size), 0);
+        if disk_size > old_disk_size {
+            self.mark(old_disk_size, disk_size - old_disk_size);
+        } else if self.num_chunks() % 64 != 0 {
+            // Chunks past the end of a shrunk disk are not tracked.
+            let last_word = self.bitmap.len() - 1;
+            self.bitmap[last_word] &= (1u64 << (self.num_chunks() % 64)) - 1;
+        }
+    }
+
+    fn check_bitmap_len(&self, bitmap: &[u64]) -> io::Result<()> {
+        if bitmap.len() != self.bitmap.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid dirty bitmap length: {}, expected {}.",
+                    bitmap.len(),
+                    self.bitmap.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Clears the chunks set in `bitmap`, which needs to cover the same number of chunks. The
+    /// other chunks stay dirty, so the writes following the retrieval of `bitmap` are not lost.
+    pub fn clear(&mut self, bitmap: &[u64]) -> io::Result<()> {
+        self.check_bitmap_len(bitmap)?;
+        for (word, cleared) in self.bitmap.iter_mut().zip(bitmap) {
+            *word &= !cleared;
+        }
+        Ok(())
+    }
+
+    /// Current bitmap.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    /// Replaces the bitmap, which needs to cover the same number of chunks.
+    pub fn set_bitmap(&mut self, bitmap: &[u64]) -> io::Result<()> {
+        self.check_bitmap_len(bitmap)?;
+        self.bitmap.copy_from_slice(bitmap);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark_clear() {
+        // 130 chunks, the last one being partial.
+        let disk_size = 129 * DIRTY_CHUNK_SIZE + 0x200;
+        let mut chunks = DirtyChunks::new(disk_size);
+        assert_eq!(chunks.bitmap(), &[0, 0, 0]);
+
+        chunks.mark(0x200, 0x200);
+        chunks.mark(DIRTY_CHUNK_SIZE - 1, 2);
+        chunks.mark(64 * DIRTY_CHUNK_SIZE, 0);
+        chunks.mark(129 * DIRTY_CHUNK_SIZE, 0x200);
+        // Out of range accesses are ignored.
+        chunks.mark(disk_size + DIRTY_CHUNK_SIZE, 0x200);
+        chunks.mark(u64::MAX, 1);
+        assert_eq!(chunks.bitmap(), &[0b11, 0, 0b10]);
+
+        // Only the given chunks are cleared.
+        assert!(chunks.clear(&[0b1, 0]).is_err());
+        chunks.clear(&[0b1, 0, 0b10]).unwrap();
+        assert_eq!(chunks.bitmap(), &[0b10, 0, 0]);
+        chunks.clear(&[0b11, 0, 0b11]).unwrap();
+        assert_eq!(chunks.bitmap(), &[0, 0, 0]);
+
+        chunks.mark_all();
+        assert_eq!(chunks.bitmap(), &[u64::MAX, u64::MAX, 0b11]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut chunks = DirtyChunks::new(DIRTY_CHUNK_SIZE + 0x200);
+        chunks.mark(0, 1);
+
+        // The grown chunks are dirty, including the partial last chunk of the old disk.
+        chunks.resize(66 * DIRTY_CHUNK_SIZE);
+        assert_eq!(chunks.bitmap(), &[u64::MAX, 0b11]);
+        chunks.clear(&[u64::MAX, 0b11]).unwrap();
+        chunks.resize(67 * DIRTY_CHUNK_SIZE);
+        assert_eq!(chunks.bitmap(), &[0, 0b100]);
+        chunks.clear(&[0, 0b100]).unwrap();
+
+        chunks.mark(65 * DIRTY_CHUNK_SIZE, 1);
+        chunks.mark(1, 1);
+        chunks.resize(DIRTY_CHUNK_SIZE);
+        assert_eq!(chunks.bitmap(), &[1]);
+    }
+
+    #[test]
+    fn test_set_bitmap() {
+        let mut chunks = DirtyChunks::new(DIRTY_CHUNK_SIZE);
+        assert!(chunks.set_bitmap(&[1, 0]).is_err());
+        chunks.set_bitmap(&[1]).unwrap();
+        assert_eq!(chunks.bitmap(), &[1]);
+    }
+}
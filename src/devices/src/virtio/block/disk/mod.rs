@@ -0,0 +1,307 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk image backends for the virtio block device.
+//!
+//! The block device only ever sees the guest-visible, flat view of a disk: a byte addressable
+//! image of `virtual_size()` bytes that can be read, written and seeked. Each supported image
+//! format implements the `DiskFile` trait and is in charge of translating accesses to that
+//! flat view into accesses to the host backing file(s).
+
+pub mod direct;
+pub mod overlay;
+pub mod qcow2;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use self::direct::DirectFile;
+use self::overlay::OverlayFile;
+use self::qcow2::QcowFile;
+
+// Size of the buffer used for writing zeroes when the backend cannot deallocate ranges.
+const ZEROES_BUFFER_SIZE: usize = 0x1_0000;
+
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ImageFormat {
+    /// The backing file is a flat image, exposed to the guest as is.
+    Raw,
+    /// The backing file is a qcow2 image, optionally layered on top of a backing file chain.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> ImageFormat {
+        ImageFormat::Raw
+    }
+}
+
+/// Guest-visible view of a disk image.
+///
+/// Reads, writes and seeks operate on the virtual disk offsets, as requested by the guest.
+pub trait DiskFile: Read + Write + Seek + Send {
+    /// Host file which receives the guest writes.
+    fn file(&self) -> &File;
+
+    /// Size of the disk, in bytes, as seen by the guest.
+    fn virtual_size(&mut self) -> io::Result<u64>;
+
+    /// Changes the size of the disk, as seen by the guest, to `size` bytes.
+    fn set_virtual_size(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The disk image format does not support resizing.",
+        ))
+    }
+
+    /// Lets the backend deallocate the `len` bytes found at `offset`. Discarding is only a hint
+    /// and the contents of the range are unspecified afterwards, so by default this is a noop.
+    fn discard(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Says if `discard()` actually deallocates ranges. Discarding is not offered to the
+    /// guest otherwise.
+    fn can_discard(&self) -> bool {
+        false
+    }
+
+    /// Fills the `len` bytes found at `offset` with zeroes. If `unmap` is set, the backend
+    /// can also deallocate the range.
+    fn write_zeroes(&mut self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        write_zeroes_at(self, offset, len)
+    }
+
+    /// Bitmap of the clusters found in the overlay, for copy-on-write overlay disks.
+    fn overlay_bitmap(&self) -> Option<&[u64]> {
+        None
+    }
+}
+
+impl DiskFile for File {
+    fn file(&self) -> &File {
+        self
+    }
+
+    fn virtual_size(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn set_virtual_size(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fallocate(
+            self,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        ) {
+            // The host filesystem cannot deallocate ranges, which is fine for a hint.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        match fallocate_zeroes(self, offset, len, unmap) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zeroes_at(self, offset, len)
+            }
+            result => result,
+        }
+    }
+}
+
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // Safe because the file descriptor is valid and we check the return value.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Lets the host filesystem zero a range of `file`, deallocating it if `unmap` is set.
+fn fallocate_zeroes(file: &File, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+    let mode = if unmap {
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+    } else {
+        libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE
+    };
+    fallocate(file, mode, offset, len)
+}
+
+// Fills a range of `disk` with zeroes, by writing them.
+fn write_zeroes_at<T: Write + Seek + ?Sized>(
+    disk: &mut T,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let zeroes = vec![0u8; ZEROES_BUFFER_SIZE];
+    disk.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = cmp::min(remaining, ZEROES_BUFFER_SIZE as u64) as usize;
+        disk.write_all(&zeroes[..count])?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+/// Opens the disk image found at `path` with the backend matching `image_format`.
+pub(crate) fn open_disk_file(
+    path: &Path,
+    is_read_only: bool,
+    image_format: ImageFormat,
+) -> io::Result<Box<dyn DiskFile>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!is_read_only)
+        .open(path)?;
+
+    match image_format {
+        ImageFormat::Raw => Ok(Box::new(file)),
+        ImageFormat::Qcow2 => Ok(Box::new(QcowFile::new(file, path, is_read_only)?)),
+    }
+}
+
+/// Opens the raw disk image found at `path` with direct I/O, bypassing the host page cache.
+pub(crate) fn open_direct_disk_file(
+    path: &Path,
+    is_read_only: bool,
+) -> io::Result<Box<dyn DiskFile>> {
+    Ok(Box::new(DirectFile::open(path, is_read_only)?))
+}
+
+/// Opens the disk image found at `base_path` as the read-only base of the copy-on-write
+/// overlay found at `overlay_path`. The overlay file is created if missing.
+pub(crate) fn open_overlay_disk_file(
+    base_path: &Path,
+    image_format: ImageFormat,
+    overlay_path: &Path,
+) -> io::Result<Box<dyn DiskFile>> {
+    let base = open_disk_file(base_path, true, image_format)?;
+    let overlay = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // Existing overlays hold the guest data.
+        .truncate(false)
+        .open(overlay_path)?;
+
+    Ok(Box::new(OverlayFile::new(base, overlay)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_open_raw_disk_file() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let mut disk = open_disk_file(f.as_path(), false, ImageFormat::Raw).unwrap();
+        assert_eq!(disk.virtual_size().unwrap(), 0x1000);
+
+        disk.seek(SeekFrom::Start(0x200)).unwrap();
+        disk.write_all(&[0xab; 0x200]).unwrap();
+        let mut buf = [0u8; 0x200];
+        disk.seek(SeekFrom::Start(0x200)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), vec![0xab; 0x200]);
+
+        // A raw image is not a valid qcow2 image.
+        assert!(open_disk_file(f.as_path(), false, ImageFormat::Qcow2).is_err());
+        // Raw images are resized in place.
+        disk.set_virtual_size(0x2000).unwrap();
+        assert_eq!(disk.virtual_size().unwrap(), 0x2000);
+        assert_eq!(f.as_file().metadata().unwrap().len(), 0x2000);
+
+        // Missing files cannot be opened, whatever the format.
+        let path = Path::new("/invalid/disk/path");
+        assert!(open_disk_file(path, false, ImageFormat::Raw).is_err());
+        assert!(open_disk_file(path, false, ImageFormat::Qcow2).is_err());
+    }
+
+    #[test]
+    fn test_open_overlay_disk_file() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x1000).unwrap();
+        let mut overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_path_buf();
+        overlay.remove().unwrap();
+
+        // The overlay is created on first use.
+        let mut disk =
+            open_overlay_disk_file(base.as_path(), ImageFormat::Raw, &overlay_path).unwrap();
+        assert_eq!(disk.virtual_size().unwrap(), 0x1000);
+        assert_eq!(disk.overlay_bitmap().unwrap(), &[0]);
+        disk.write_all(&[0xab; 0x200]).unwrap();
+        assert_eq!(disk.overlay_bitmap().unwrap(), &[1]);
+        assert!(overlay_path.exists());
+
+        // Only overlays track clusters.
+        let mut disk = open_disk_file(base.as_path(), false, ImageFormat::Raw).unwrap();
+        assert!(disk.overlay_bitmap().is_none());
+
+        // Files which are neither empty nor overlays are rejected.
+        assert!(open_overlay_disk_file(&overlay_path, ImageFormat::Raw, base.as_path()).is_err());
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x4000).unwrap();
+        let mut disk = open_disk_file(f.as_path(), false, ImageFormat::Raw).unwrap();
+        let mut buf = vec![0u8; 0x4000];
+
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&[0xab; 0x4000]).unwrap();
+
+        disk.write_zeroes(0x200, 0x200, false).unwrap();
+        disk.write_zeroes(0x1000, 0x1000, true).unwrap();
+        disk.discard(0x3000, 0x1000).unwrap();
+
+        // Discarding and writing zeroes never changes the disk size.
+        assert_eq!(disk.virtual_size().unwrap(), 0x4000);
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..0x200], [0xab; 0x200][..]);
+        assert_eq!(buf[0x200..0x400], [0; 0x200][..]);
+        assert_eq!(buf[0x400..0x1000], [0xab; 0xc00][..]);
+        assert_eq!(buf[0x1000..0x2000], [0; 0x1000][..]);
+        assert_eq!(buf[0x2000..0x3000], [0xab; 0x1000][..]);
+
+        // The fallback writes the zeroes, extending the disk if needed.
+        let mut disk = Cursor::new(vec![0xabu8; 0x4000]);
+        write_zeroes_at(&mut disk, 0x200, 0x1_0000 + 0x200).unwrap();
+        let buf = disk.into_inner();
+        assert_eq!(buf.len(), 0x1_0400);
+        assert_eq!(buf[..0x200], [0xab; 0x200][..]);
+        assert!(buf[0x200..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy-on-write overlay on top of a read-only base image.
+//!
+//! The base image can be shared by many drives, as it is never written to. Each drive owns a
+//! sparse overlay file, laid out as:
+//!   * header: magic, version, cluster size, disk size, bitmap and data offsets, little endian.
+//!   * cluster bitmap: one bit per cluster of the disk, set once the cluster is copied to the
+//!     overlay.
+//!   * data area: clusters are stored at their guest offset, relative to the data area start,
+//!     so clusters that were never written are holes in the overlay file.
+//!
+//! Reads of clusters that are not in the overlay fall through to the base image. The first
+//! write to a cluster copies it from the base image to the overlay. Bitmap updates are written
+//! through to the overlay file, after the cluster data.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::result;
+
+use super::DiskFile;
+
+const OVERLAY_MAGIC: &[u8; 4] = b"FCOW";
+const OVERLAY_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+// The bitmap starts right after the (padded) header.
+const BITMAP_OFFSET: u64 = 0x1000;
+
+// Clusters are 64 KiB in new overlays; existing ones can use anything between 4 KiB and 2 MiB.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+const MIN_CLUSTER_BITS: u32 = 12;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+/// Errors encountered while opening or accessing an overlay.
+#[derive(Debug)]
+pub enum Error {
+    /// The cluster size is out of the supported range.
+    InvalidClusterBits(u32),
+    /// The bitmap or the data area overlap, or the data area is not aligned to a cluster.
+    InvalidLayout,
+    /// The overlay does not start with the overlay magic.
+    InvalidMagic,
+    /// I/O error while accessing the overlay or the base image.
+    Io(io::Error),
+    /// The overlay was created for a base image of a different size.
+    SizeMismatch(u64, u64),
+    /// The overlay version is not supported.
+    UnsupportedVersion(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidClusterBits(bits) => write!(f, "Invalid overlay cluster bits: {}", bits),
+            InvalidLayout => write!(f, "Invalid overlay layout."),
+            InvalidMagic => write!(f, "Invalid overlay magic."),
+            Io(err) => write!(f, "Overlay I/O error: {}", err),
+            SizeMismatch(overlay_size, base_size) => write!(
+                f,
+                "The overlay disk size ({}) does not match the base image size ({}).",
+                overlay_size, base_size
+            ),
+            UnsupportedVersion(version) => write!(f, "Unsupported overlay version: {}", version),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    // The slice length always matches, so the conversion cannot fail.
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    // The slice length always matches, so the conversion cannot fail.
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// Number of 64 bit bitmap words needed for tracking the clusters of a `size` bytes disk.
+fn bitmap_words(size: u64, cluster_bits: u32) -> usize {
+    let clusters = (size + (1u64 << cluster_bits) - 1) >> cluster_bits;
+    ((clusters + 63) / 64) as usize
+}
+
+#[derive(Debug)]
+struct OverlayHeader {
+    cluster_bits: u32,
+    size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+}
+
+impl OverlayHeader {
+    fn new(size: u64) -> OverlayHeader {
+        let cluster_size = 1u64 << DEFAULT_CLUSTER_BITS;
+        let bitmap_len = bitmap_words(size, DEFAULT_CLUSTER_BITS) as u64 * 8;
+        OverlayHeader {
+            cluster_bits: DEFAULT_CLUSTER_BITS,
+            size,
+            bitmap_offset: BITMAP_OFFSET,
+            data_offset: align_up(BITMAP_OFFSET + bitmap_len, cluster_size),
+        }
+    }
+
+    fn read_from(file: &mut File) -> Result<OverlayHeader> {
+        let mut buf = [0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        file.read_exact(&mut buf).map_err(Error::Io)?;
+
+        if &buf[0..4] != OVERLAY_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = le_u32(&buf, 4);
+        if version != OVERLAY_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let header = OverlayHeader {
+            cluster_bits: le_u32(&buf, 8),
+            size: le_u64(&buf, 16),
+            bitmap_offset: le_u64(&buf, 24),
+            data_offset: le_u64(&buf, 32),
+        };
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    fn write_to(&self, file: &mut File) -> Result<()> {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(OVERLAY_MAGIC);
+        buf[4..8].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.cluster_bits.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.bitmap_offset.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.data_offset.to_le_bytes());
+
+        file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        file.write_all(&buf).map_err(Error::Io)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(Error::InvalidClusterBits(self.cluster_bits));
+        }
+        let cluster_size = 1u64 << self.cluster_bits;
+        let bitmap_len = bitmap_words(self.size, self.cluster_bits) as u64 * 8;
+
+        if self.bitmap_offset < HEADER_SIZE as u64
+            || self
+                .bitmap_offset
+                .checked_add(bitmap_len)
+                .map_or(true, |bitmap_end| bitmap_end > self.data_offset)
+            || self.data_offset % cluster_size != 0
+            || self.data_offset.checked_add(self.size).is_none()
+        {
+            return Err(Error::InvalidLayout);
+        }
+
+        Ok(())
+    }
+}
+
+/// A read-only base image and the overlay file receiving the guest writes.
+pub struct OverlayFile {
+    base: Box<dyn DiskFile>,
+    file: File,
+    header: OverlayHeader,
+    cluster_size: u64,
+    // Bit `n` is set if cluster `n` is in the overlay.
+    bitmap: Vec<u64>,
+    // Current guest-visible offset.
+    position: u64,
+}
+
+impl OverlayFile {
+    /// Builds an overlay disk out of the `base` image and the overlay `file`.
+    ///
+    /// An empty overlay file is initialized for the base image. Otherwise, the overlay must
+    /// have been created for a base image of the same size.
+    pub fn new(mut base: Box<dyn DiskFile>, mut file: File) -> Result<OverlayFile> {
+        let base_size = base.virtual_size().map_err(Error::Io)?;
+
+        let header = if file.metadata().map_err(Error::Io)?.len() == 0 {
+            let header = OverlayHeader::new(base_size);
+            header.write_to(&mut file)?;
+            // The data area is sparse, only the clusters written by the guest take up space.
+            file.set_len(header.data_offset + base_size)
+                .map_err(Error::Io)?;
+            header
+        } else {
+            OverlayHeader::read_from(&mut file)?
+        };
+        if header.size != base_size {
+            return Err(Error::SizeMismatch(header.size, base_size));
+        }
+
+        let mut buf = vec![0u8; bitmap_words(header.size, header.cluster_bits) * 8];
+        file.seek(SeekFrom::Start(header.bitmap_offset))
+            .map_err(Error::Io)?;
+        file.read_exact(&mut buf).map_err(Error::Io)?;
+        let bitmap = buf.chunks_exact(8).map(|word| le_u64(word, 0)).collect();
+
+        Ok(OverlayFile {
+            base,
+            file,
+            cluster_size: 1u64 << header.cluster_bits,
+            header,
+            bitmap,
+            position: 0,
+        })
+    }
+
+    fn is_allocated(&self, address: u64) -> bool {
+        let cluster = (address >> self.header.cluster_bits) as usize;
+        self.bitmap[cluster / 64] & (1u64 << (cluster % 64)) != 0
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn write_bitmap_word(&mut self, index: usize) -> io::Result<()> {
+        let offset = self.header.bitmap_offset + index as u64 * 8;
+        self.write_at(offset, &self.bitmap[index].to_le_bytes())
+    }
+
+    // Reads `buf.len()` bytes at `address`. The range must not cross a cluster boundary.
+    fn read_cluster(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.is_allocated(address) {
+            self.file
+                .seek(SeekFrom::Start(self.header.data_offset + address))?;
+            self.file.read_exact(buf)
+        } else {
+            self.base.seek(SeekFrom::Start(address))?;
+            self.base.read_exact(buf)
+        }
+    }
+
+    // Writes `buf` at `address`. The range must not cross a cluster boundary.
+    fn write_cluster(&mut self, address: u64, buf: &[u8]) -> io::Result<()> {
+        if self.is_allocated(address) {
+            return self.write_at(self.header.data_offset + address, buf);
+        }
+
+        // The last cluster can be shorter, if the disk size is not cluster aligned.
+        let cluster_address = address - address % self.cluster_size;
+        let cluster_len = cmp::min(self.cluster_size, self.header.size - cluster_address);
+        if buf.len() as u64 == cluster_len {
+            self.write_at(self.header.data_offset + address, buf)?;
+        } else {
+            // Copy the base data that this write does not overwrite.
+            let mut data = vec![0u8; cluster_len as usize];
+            self.base.seek(SeekFrom::Start(cluster_address))?;
+            self.base.read_exact(&mut data)?;
+            let start = (address - cluster_address) as usize;
+            data[start..start + buf.len()].copy_from_slice(buf);
+            self.write_at(self.header.data_offset + cluster_address, &data)?;
+        }
+
+        // Only mark the cluster once its data has been written.
+        let cluster = (address >> self.header.cluster_bits) as usize;
+        self.bitmap[cluster / 64] |= 1u64 << (cluster % 64);
+        self.write_bitmap_word(cluster / 64)
+    }
+
+    fn remaining_len(&self, len: usize) -> usize {
+        cmp::min(len as u64, self.header.size.saturating_sub(self.position)) as usize
+    }
+
+    fn cluster_chunk_len(&self, address: u64, len: usize) -> usize {
+        cmp::min(len as u64, self.cluster_size - address % self.cluster_size) as usize
+    }
+}
+
+impl DiskFile for OverlayFile {
+    fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn virtual_size(&mut self) -> io::Result<u64> {
+        Ok(self.header.size)
+    }
+
+    fn overlay_bitmap(&self) -> Option<&[u64]> {
+        Some(&self.bitmap)
+    }
+}
+
+impl Read for OverlayFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.remaining_len(buf.len());
+        let mut done = 0;
+        while done < len {
+            let address = self.position + done as u64;
+            let chunk_len = self.cluster_chunk_len(address, len - done);
+            self.read_cluster(address, &mut buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for OverlayFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.remaining_len(buf.len());
+        let mut done = 0;
+        while done < len {
+            let address = self.position + done as u64;
+            let chunk_len = self.cluster_chunk_len(address, len - done);
+            self.write_cluster(address, &buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The bitmap is written through, there is nothing cached to flush.
+        self.file.flush()
+    }
+}
+
+impl Seek for OverlayFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => {
+                if offset >= 0 {
+                    self.position.checked_add(offset as u64)
+                } else {
+                    self.position.checked_sub(offset.unsigned_abs())
+                }
+            }
+            SeekFrom::End(offset) => {
+                if offset >= 0 {
+                    self.header.size.checked_add(offset as u64)
+                } else {
+                    self.header.size.checked_sub(offset.unsigned_abs())
+                }
+            }
+        };
+
+        self.position = new_position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+
+    use utils::tempfile::TempFile;
+
+    const TEST_CLUSTER_SIZE: u64 = 1 << DEFAULT_CLUSTER_BITS;
+
+    fn base_tempfile(size: u64, byte: u8) -> TempFile {
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&vec![byte; size as usize]).unwrap();
+        f
+    }
+
+    fn open_overlay(base: &TempFile, overlay: &TempFile) -> Result<OverlayFile> {
+        let base = OpenOptions::new().read(true).open(base.as_path()).unwrap();
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(overlay.as_path())
+            .unwrap();
+        OverlayFile::new(Box::new(base), overlay)
+    }
+
+    #[test]
+    fn test_invalid_overlay() {
+        let base = base_tempfile(2 * TEST_CLUSTER_SIZE, 0xab);
+        let overlay = TempFile::new().unwrap();
+        open_overlay(&base, &overlay).unwrap();
+
+        // The overlay is bound to the size of its base image.
+        let other_base = base_tempfile(TEST_CLUSTER_SIZE, 0xab);
+        assert!(matches!(
+            open_overlay(&other_base, &overlay),
+            Err(Error::SizeMismatch(size, base_size))
+                if size == 2 * TEST_CLUSTER_SIZE && base_size == TEST_CLUSTER_SIZE
+        ));
+
+        let mut file = overlay.as_file().try_clone().unwrap();
+        let mut header = OverlayHeader::read_from(&mut file).unwrap();
+        header.data_offset = BITMAP_OFFSET;
+        header.write_to(&mut file).unwrap();
+        assert!(matches!(
+            open_overlay(&base, &overlay),
+            Err(Error::InvalidLayout)
+        ));
+
+        header.cluster_bits = MAX_CLUSTER_BITS + 1;
+        header.write_to(&mut file).unwrap();
+        assert!(matches!(
+            open_overlay(&base, &overlay),
+            Err(Error::InvalidClusterBits(bits)) if bits == MAX_CLUSTER_BITS + 1
+        ));
+
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&2u32.to_le_bytes()).unwrap();
+        assert!(matches!(
+            open_overlay(&base, &overlay),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0u8; 4]).unwrap();
+        assert!(matches!(
+            open_overlay(&base, &overlay),
+            Err(Error::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        // The last cluster is not a full one.
+        let size = 2 * TEST_CLUSTER_SIZE + 0x200;
+        let base = base_tempfile(size, 0xab);
+        let overlay_file = TempFile::new().unwrap();
+        let mut overlay = open_overlay(&base, &overlay_file).unwrap();
+        assert_eq!(overlay.virtual_size().unwrap(), size);
+        assert_eq!(overlay.overlay_bitmap().unwrap(), &[0]);
+
+        // Reads fall through to the base image.
+        let mut buf = vec![0u8; size as usize];
+        overlay.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xab));
+
+        // A write crossing the first two clusters, and one to the last cluster.
+        overlay
+            .seek(SeekFrom::Start(TEST_CLUSTER_SIZE - 0x200))
+            .unwrap();
+        overlay.write_all(&[0xcd; 0x400]).unwrap();
+        overlay.seek(SeekFrom::Start(size - 0x100)).unwrap();
+        overlay.write_all(&[0xef; 0x100]).unwrap();
+        // Writes past the end of the disk are truncated.
+        assert_eq!(overlay.write(&[0xef; 0x100]).unwrap(), 0);
+        assert_eq!(overlay.overlay_bitmap().unwrap(), &[0b111]);
+
+        let check = |overlay: &mut OverlayFile| {
+            let mut buf = vec![0u8; size as usize];
+            overlay.seek(SeekFrom::Start(0)).unwrap();
+            overlay.read_exact(&mut buf).unwrap();
+            let first = (TEST_CLUSTER_SIZE - 0x200) as usize;
+            assert!(buf[..first].iter().all(|b| *b == 0xab));
+            assert!(buf[first..first + 0x400].iter().all(|b| *b == 0xcd));
+            let last = (size - 0x100) as usize;
+            assert!(buf[first + 0x400..last].iter().all(|b| *b == 0xab));
+            assert!(buf[last..].iter().all(|b| *b == 0xef));
+        };
+        check(&mut overlay);
+
+        // The base image is left untouched.
+        let mut buf = Vec::new();
+        base.as_file().seek(SeekFrom::Start(0)).unwrap();
+        base.as_file().read_to_end(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xab));
+
+        // The cluster bitmap is persisted in the overlay file.
+        let mut overlay = open_overlay(&base, &overlay_file).unwrap();
+        assert_eq!(overlay.overlay_bitmap().unwrap(), &[0b111]);
+        check(&mut overlay);
+    }
+}
@@ -0,0 +1,951 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for qcow2 disk images.
+//!
+//! Guest offsets are translated to host offsets through the two level L1/L2 table lookup
+//! described by the qcow2 specification. Clusters that are not allocated in the image are
+//! read from the backing file, if any, or as zeros otherwise. Writes to such clusters allocate
+//! a new cluster at the end of the image, which is first populated with the backing file
+//! contents.
+//!
+//! All metadata updates are written through to the image file, so syncing the host file is
+//! enough to persist the image. Compressed clusters, encryption, internal snapshots (on
+//! writable images) and refcount widths other than 16 bits are not supported.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+
+use super::DiskFile;
+
+/// Magic value found at the start of every qcow2 image ("QFI\xfb").
+pub const QCOW_MAGIC: u32 = 0x5146_49fb;
+
+// Size of the version 2 header and of the version 3 header, up to the `header_length` field.
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+
+// Supported cluster sizes range from 512 bytes to 2 MiB.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+// Upper bounds for the in-memory tables, mirroring the ones enforced by QEMU.
+const MAX_L1_TABLE_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 * 1024 * 1024;
+
+// Only 16 bit refcounts are supported.
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNT_BYTES: u64 = 2;
+
+// The backing file name is stored in the image header area and is limited to 1023 bytes.
+const MAX_BACKING_FILE_NAME_SIZE: u32 = 1023;
+// Maximum number of images in a backing file chain, the top image excluded.
+const MAX_BACKING_CHAIN_DEPTH: u32 = 16;
+
+// Bits 9-55 of the L1 and L2 entries hold host offsets.
+const TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+// Bits 9-63 of the refcount table entries hold host offsets.
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The cluster is referenced exactly once, so it can be written in place.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// Version 3 only: the cluster reads as zeros.
+const ZERO_FLAG: u64 = 1;
+
+/// Errors encountered while opening or accessing a qcow2 image.
+#[derive(Debug)]
+pub enum Error {
+    /// The backing file chain is deeper than supported.
+    BackingChainTooDeep,
+    /// The image references a compressed cluster.
+    CompressedCluster,
+    /// The image is encrypted.
+    EncryptedImage,
+    /// The backing file name is not valid.
+    InvalidBackingFileName,
+    /// The cluster size is out of the supported range.
+    InvalidClusterBits(u32),
+    /// The L1 table is too small to cover the virtual disk size, or too large.
+    InvalidL1TableSize(u32),
+    /// The image does not start with the qcow2 magic.
+    InvalidMagic,
+    /// A metadata table is not aligned to a cluster boundary.
+    InvalidTableOffset(u64),
+    /// I/O error while accessing the image.
+    Io(io::Error),
+    /// Cannot open the backing file.
+    OpenBackingFile(io::Error),
+    /// The refcount table is too large.
+    RefcountTableTooLarge(u32),
+    /// The refcount table cannot address any more clusters.
+    RefcountTableFull,
+    /// Writable images cannot contain internal snapshots.
+    SnapshotsNotSupported,
+    /// The image uses incompatible features that are not supported.
+    UnsupportedFeatures(u64),
+    /// The image uses a refcount width that is not supported.
+    UnsupportedRefcountOrder(u32),
+    /// The image version is not supported.
+    UnsupportedVersion(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            BackingChainTooDeep => write!(f, "The qcow2 backing file chain is too deep."),
+            CompressedCluster => write!(f, "Compressed qcow2 clusters are not supported."),
+            EncryptedImage => write!(f, "Encrypted qcow2 images are not supported."),
+            InvalidBackingFileName => write!(f, "Invalid qcow2 backing file name."),
+            InvalidClusterBits(bits) => write!(f, "Invalid qcow2 cluster bits: {}", bits),
+            InvalidL1TableSize(size) => write!(f, "Invalid qcow2 L1 table size: {}", size),
+            InvalidMagic => write!(f, "Invalid qcow2 magic."),
+            InvalidTableOffset(offset) => {
+                write!(f, "Unaligned qcow2 metadata table offset: {:#x}", offset)
+            }
+            Io(err) => write!(f, "Qcow2 image I/O error: {}", err),
+            OpenBackingFile(err) => write!(f, "Cannot open qcow2 backing file: {}", err),
+            RefcountTableTooLarge(clusters) => write!(
+                f,
+                "The qcow2 refcount table is too large: {} clusters",
+                clusters
+            ),
+            RefcountTableFull => write!(f, "The qcow2 refcount table is full."),
+            SnapshotsNotSupported => write!(
+                f,
+                "Writable qcow2 images with internal snapshots are not supported."
+            ),
+            UnsupportedFeatures(features) => write!(
+                f,
+                "Unsupported qcow2 incompatible features: {:#x}",
+                features
+            ),
+            UnsupportedRefcountOrder(order) => {
+                write!(f, "Unsupported qcow2 refcount order: {}", order)
+            }
+            UnsupportedVersion(version) => write!(f, "Unsupported qcow2 version: {}", version),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    // The slice length always matches, so the conversion cannot fail.
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    // The slice length always matches, so the conversion cannot fail.
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// The fields of the qcow2 header used by this implementation.
+#[derive(Debug)]
+struct QcowHeader {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+}
+
+impl QcowHeader {
+    fn read_from(file: &mut File) -> Result<QcowHeader> {
+        let mut buf = [0u8; V3_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        file.read_exact(&mut buf[..V2_HEADER_SIZE])
+            .map_err(Error::Io)?;
+
+        if be_u32(&buf, 0) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = be_u32(&buf, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if be_u32(&buf, 32) != 0 {
+            return Err(Error::EncryptedImage);
+        }
+
+        if version == 3 {
+            file.read_exact(&mut buf[V2_HEADER_SIZE..])
+                .map_err(Error::Io)?;
+            let incompatible_features = be_u64(&buf, 72);
+            if incompatible_features != 0 {
+                return Err(Error::UnsupportedFeatures(incompatible_features));
+            }
+            let refcount_order = be_u32(&buf, 96);
+            if refcount_order != REFCOUNT_ORDER {
+                return Err(Error::UnsupportedRefcountOrder(refcount_order));
+            }
+        }
+
+        let header = QcowHeader {
+            version,
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            cluster_bits: be_u32(&buf, 20),
+            size: be_u64(&buf, 24),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            nb_snapshots: be_u32(&buf, 60),
+        };
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(Error::InvalidClusterBits(self.cluster_bits));
+        }
+        let cluster_size = 1u64 << self.cluster_bits;
+
+        // Each L2 table fills a cluster with 8 byte entries.
+        let l2_coverage = cluster_size * (cluster_size / 8);
+        // The size comes from the image, so rounding it up can overflow.
+        let min_l1_size = self
+            .size
+            .checked_add(l2_coverage - 1)
+            .ok_or(Error::InvalidL1TableSize(self.l1_size))?
+            / l2_coverage;
+        if u64::from(self.l1_size) < min_l1_size || u64::from(self.l1_size) * 8 > MAX_L1_TABLE_SIZE
+        {
+            return Err(Error::InvalidL1TableSize(self.l1_size));
+        }
+        if u64::from(self.refcount_table_clusters) * cluster_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(Error::RefcountTableTooLarge(self.refcount_table_clusters));
+        }
+
+        for &offset in &[self.l1_table_offset, self.refcount_table_offset] {
+            if offset % cluster_size != 0 {
+                return Err(Error::InvalidTableOffset(offset));
+            }
+        }
+
+        if self.backing_file_offset != 0
+            && (self.backing_file_size == 0 || self.backing_file_size > MAX_BACKING_FILE_NAME_SIZE)
+        {
+            return Err(Error::InvalidBackingFileName);
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the data of a guest cluster lives.
+enum ClusterMapping {
+    /// The cluster is not allocated in this image.
+    Unallocated,
+    /// The cluster reads as zeros. The host offset is 0 if no cluster was preallocated.
+    Zero(u64),
+    /// The cluster data is found at this host offset.
+    Data(u64),
+}
+
+/// A qcow2 image exposing the guest-visible view of the disk.
+pub struct QcowFile {
+    file: File,
+    header: QcowHeader,
+    cluster_size: u64,
+    l2_entries: u64,
+    refcount_block_entries: u64,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    backing: Option<Box<dyn DiskFile>>,
+    backing_size: u64,
+    // Current guest-visible offset.
+    position: u64,
+}
+
+impl QcowFile {
+    /// Builds a qcow2 disk from an already opened image `file`, found at `path`.
+    ///
+    /// Relative backing file names are resolved against the directory holding the image.
+    pub fn new(file: File, path: &Path, read_only: bool) -> Result<QcowFile> {
+        Self::open_with_depth(file, path, read_only, 0)
+    }
+
+    fn open_with_depth(mut file: File, path: &Path, read_only: bool, depth: u32) -> Result<Self> {
+        let header = QcowHeader::read_from(&mut file)?;
+        if !read_only && header.nb_snapshots != 0 {
+            return Err(Error::SnapshotsNotSupported);
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let (backing, backing_size) = if header.backing_file_offset != 0 {
+            let mut backing = Self::open_backing_file(&mut file, &header, path, depth)?;
+            let backing_size = backing.virtual_size().map_err(Error::Io)?;
+            (Some(backing), backing_size)
+        } else {
+            (None, 0)
+        };
+
+        let l1_table =
+            Self::read_table(&mut file, header.l1_table_offset, u64::from(header.l1_size))?;
+        let refcount_table = Self::read_table(
+            &mut file,
+            header.refcount_table_offset,
+            u64::from(header.refcount_table_clusters) * cluster_size / 8,
+        )?;
+
+        Ok(QcowFile {
+            file,
+            header,
+            cluster_size,
+            l2_entries: cluster_size / 8,
+            refcount_block_entries: cluster_size / REFCOUNT_BYTES,
+            l1_table,
+            refcount_table,
+            backing,
+            backing_size,
+            position: 0,
+        })
+    }
+
+    fn open_backing_file(
+        file: &mut File,
+        header: &QcowHeader,
+        path: &Path,
+        depth: u32,
+    ) -> Result<Box<dyn DiskFile>> {
+        if depth >= MAX_BACKING_CHAIN_DEPTH {
+            return Err(Error::BackingChainTooDeep);
+        }
+
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.seek(SeekFrom::Start(header.backing_file_offset))
+            .map_err(Error::Io)?;
+        file.read_exact(&mut name).map_err(Error::Io)?;
+        let name = String::from_utf8(name).map_err(|_| Error::InvalidBackingFileName)?;
+
+        let mut backing_path = PathBuf::from(&name);
+        if backing_path.is_relative() {
+            if let Some(parent) = path.parent() {
+                backing_path = parent.join(backing_path);
+            }
+        }
+
+        // Backing files are never written to.
+        let mut backing_file = OpenOptions::new()
+            .read(true)
+            .open(&backing_path)
+            .map_err(Error::OpenBackingFile)?;
+
+        // The backing file format is probed, so that both raw and qcow2 images can be chained.
+        let mut magic = [0u8; 4];
+        let is_qcow = match backing_file.read_exact(&mut magic) {
+            Ok(()) => u32::from_be_bytes(magic) == QCOW_MAGIC,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        if is_qcow {
+            Ok(Box::new(Self::open_with_depth(
+                backing_file,
+                &backing_path,
+                true,
+                depth + 1,
+            )?))
+        } else {
+            Ok(Box::new(backing_file))
+        }
+    }
+
+    fn read_table(file: &mut File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+        let mut buf = vec![0u8; (entries * 8) as usize];
+        file.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+        file.read_exact(&mut buf).map_err(Error::Io)?;
+
+        Ok(buf.chunks_exact(8).map(|entry| be_u64(entry, 0)).collect())
+    }
+
+    fn read_u64_at(&mut self, offset: u64) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn l1_index(&self, address: u64) -> io::Result<usize> {
+        let index = address / self.cluster_size / self.l2_entries;
+        // The validated L1 table covers the whole disk, but not the addresses past its end.
+        if index >= self.l1_table.len() as u64 {
+            return Err(Error::InvalidL1TableSize(self.header.l1_size).into());
+        }
+        Ok(index as usize)
+    }
+
+    fn l2_index(&self, address: u64) -> u64 {
+        (address / self.cluster_size) % self.l2_entries
+    }
+
+    fn map_cluster(&mut self, address: u64) -> io::Result<ClusterMapping> {
+        let l2_table_offset = self.l1_table[self.l1_index(address)?] & TABLE_OFFSET_MASK;
+        if l2_table_offset == 0 {
+            return Ok(ClusterMapping::Unallocated);
+        }
+
+        let l2_entry = self.read_u64_at(l2_table_offset + self.l2_index(address) * 8)?;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Error::CompressedCluster.into());
+        }
+
+        let host_offset = l2_entry & TABLE_OFFSET_MASK;
+        if self.header.version >= 3 && l2_entry & ZERO_FLAG != 0 {
+            Ok(ClusterMapping::Zero(host_offset))
+        } else if host_offset == 0 {
+            Ok(ClusterMapping::Unallocated)
+        } else {
+            Ok(ClusterMapping::Data(host_offset))
+        }
+    }
+
+    fn read_backing(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+
+        if let Some(backing) = self.backing.as_mut() {
+            // The backing file might be smaller than this image; the remainder reads as zeros.
+            if address < self.backing_size {
+                let len = cmp::min(buf.len() as u64, self.backing_size - address) as usize;
+                backing.seek(SeekFrom::Start(address))?;
+                backing.read_exact(&mut buf[..len])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads `buf.len()` bytes at `address`. The range must not cross a cluster boundary.
+    fn read_cluster(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.map_cluster(address)? {
+            ClusterMapping::Data(host_offset) => {
+                self.file
+                    .seek(SeekFrom::Start(host_offset + address % self.cluster_size))?;
+                self.file.read_exact(buf)
+            }
+            ClusterMapping::Zero(_) => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(())
+            }
+            ClusterMapping::Unallocated => self.read_backing(address, buf),
+        }
+    }
+
+    // Writes `buf` at `address`. The range must not cross a cluster boundary.
+    fn write_cluster(&mut self, address: u64, buf: &[u8]) -> io::Result<()> {
+        let cluster_address = address - address % self.cluster_size;
+        let full_cluster = buf.len() as u64 == self.cluster_size;
+
+        let (host_offset, needs_mapping) = match self.map_cluster(address)? {
+            ClusterMapping::Data(host_offset) => (host_offset, false),
+            ClusterMapping::Zero(host_offset) => {
+                let host_offset = if host_offset == 0 {
+                    // Freshly allocated clusters are already zeroed.
+                    self.allocate_cluster()?
+                } else {
+                    if !full_cluster {
+                        let zeros = vec![0u8; self.cluster_size as usize];
+                        self.write_at(host_offset, &zeros)?;
+                    }
+                    host_offset
+                };
+                (host_offset, true)
+            }
+            ClusterMapping::Unallocated => {
+                let host_offset = self.allocate_cluster()?;
+                // Copy the backing data that this write does not overwrite.
+                if !full_cluster && self.backing.is_some() {
+                    let mut data = vec![0u8; self.cluster_size as usize];
+                    self.read_backing(cluster_address, &mut data)?;
+                    self.write_at(host_offset, &data)?;
+                }
+                (host_offset, true)
+            }
+        };
+
+        self.write_at(host_offset + address % self.cluster_size, buf)?;
+        // Only link the cluster once its data has been written.
+        if needs_mapping {
+            self.set_l2_entry(address, host_offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_l2_entry(&mut self, address: u64, host_offset: u64) -> io::Result<()> {
+        let l1_index = self.l1_index(address)?;
+        let mut l2_table_offset = self.l1_table[l1_index] & TABLE_OFFSET_MASK;
+        if l2_table_offset == 0 {
+            l2_table_offset = self.allocate_cluster()?;
+            self.l1_table[l1_index] = l2_table_offset | COPIED_FLAG;
+            let l1_entry_offset = self.header.l1_table_offset + l1_index as u64 * 8;
+            self.write_at(l1_entry_offset, &self.l1_table[l1_index].to_be_bytes())?;
+        }
+
+        let l2_entry_offset = l2_table_offset + self.l2_index(address) * 8;
+        self.write_at(l2_entry_offset, &(host_offset | COPIED_FLAG).to_be_bytes())
+    }
+
+    // Appends a zeroed cluster to the image file and returns its host offset.
+    fn append_cluster(&mut self) -> io::Result<u64> {
+        let file_size = self.file.seek(SeekFrom::End(0))?;
+        let host_offset = align_up(file_size, self.cluster_size);
+        self.file.set_len(host_offset + self.cluster_size)?;
+        Ok(host_offset)
+    }
+
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let host_offset = self.append_cluster()?;
+        self.set_refcount(host_offset, 1)?;
+        Ok(host_offset)
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, refcount: u16) -> io::Result<()> {
+        let cluster_index = host_offset / self.cluster_size;
+        let table_index = (cluster_index / self.refcount_block_entries) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(Error::RefcountTableFull.into());
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.append_cluster()?;
+            self.refcount_table[table_index] = block_offset;
+            let entry_offset = self.header.refcount_table_offset + table_index as u64 * 8;
+            self.write_at(entry_offset, &block_offset.to_be_bytes())?;
+            // The new refcount block needs to be accounted for as well.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let entry_offset =
+            block_offset + (cluster_index % self.refcount_block_entries) * REFCOUNT_BYTES;
+        self.write_at(entry_offset, &refcount.to_be_bytes())
+    }
+
+    fn remaining_len(&self, len: usize) -> usize {
+        cmp::min(len as u64, self.header.size.saturating_sub(self.position)) as usize
+    }
+
+    fn cluster_chunk_len(&self, address: u64, len: usize) -> usize {
+        cmp::min(len as u64, self.cluster_size - address % self.cluster_size) as usize
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn virtual_size(&mut self) -> io::Result<u64> {
+        Ok(self.header.size)
+    }
+}
+
+impl Read for QcowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.remaining_len(buf.len());
+        let mut done = 0;
+        while done < len {
+            let address = self.position + done as u64;
+            let chunk_len = self.cluster_chunk_len(address, len - done);
+            self.read_cluster(address, &mut buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for QcowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.remaining_len(buf.len());
+        let mut done = 0;
+        while done < len {
+            let address = self.position + done as u64;
+            let chunk_len = self.cluster_chunk_len(address, len - done);
+            self.write_cluster(address, &buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Metadata is written through, there is nothing cached to flush.
+        self.file.flush()
+    }
+}
+
+impl Seek for QcowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => {
+                if offset >= 0 {
+                    self.position.checked_add(offset as u64)
+                } else {
+                    self.position.checked_sub(offset.unsigned_abs())
+                }
+            }
+            SeekFrom::End(offset) => {
+                if offset >= 0 {
+                    self.header.size.checked_add(offset as u64)
+                } else {
+                    self.header.size.checked_sub(offset.unsigned_abs())
+                }
+            }
+        };
+
+        self.position = new_position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    const TEST_CLUSTER_BITS: u32 = 16;
+    const TEST_CLUSTER_SIZE: u64 = 1 << TEST_CLUSTER_BITS;
+
+    /// Writes an empty version 3 qcow2 image of `size` bytes in `file`.
+    ///
+    /// The image uses 64 KiB clusters, laid out as: header (and backing file name), L1 table,
+    /// refcount table and the first refcount block.
+    pub(crate) fn create_qcow2_image(file: &mut File, size: u64, backing_file: Option<&str>) {
+        let l2_coverage = TEST_CLUSTER_SIZE * (TEST_CLUSTER_SIZE / 8);
+        let l1_size = cmp::max((size + l2_coverage - 1) / l2_coverage, 1);
+        let l1_table_offset = TEST_CLUSTER_SIZE;
+        let refcount_table_offset = 2 * TEST_CLUSTER_SIZE;
+        let refcount_block_offset = 3 * TEST_CLUSTER_SIZE;
+
+        let mut header = vec![0u8; V3_HEADER_SIZE];
+        header[0..4].copy_from_slice(&QCOW_MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        if let Some(name) = backing_file {
+            header[8..16].copy_from_slice(&(V3_HEADER_SIZE as u64).to_be_bytes());
+            header[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+            header.extend_from_slice(name.as_bytes());
+        }
+        header[20..24].copy_from_slice(&TEST_CLUSTER_BITS.to_be_bytes());
+        header[24..32].copy_from_slice(&size.to_be_bytes());
+        header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&l1_table_offset.to_be_bytes());
+        header[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+        header[56..60].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+        header[100..104].copy_from_slice(&(V3_HEADER_SIZE as u32).to_be_bytes());
+
+        file.set_len(0).unwrap();
+        file.set_len(4 * TEST_CLUSTER_SIZE).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&header).unwrap();
+        file.seek(SeekFrom::Start(refcount_table_offset)).unwrap();
+        file.write_all(&refcount_block_offset.to_be_bytes())
+            .unwrap();
+        // The four metadata clusters are in use.
+        file.seek(SeekFrom::Start(refcount_block_offset)).unwrap();
+        file.write_all(&[0, 1, 0, 1, 0, 1, 0, 1]).unwrap();
+    }
+
+    fn qcow2_tempfile(size: u64, backing_file: Option<&str>) -> TempFile {
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(&mut f.as_file().try_clone().unwrap(), size, backing_file);
+        f
+    }
+
+    fn open_qcow2(f: &TempFile, read_only: bool) -> QcowFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(f.as_path())
+            .unwrap();
+        QcowFile::new(file, f.as_path(), read_only).unwrap()
+    }
+
+    fn refcount(qcow: &mut QcowFile, host_offset: u64) -> u16 {
+        let cluster_index = host_offset / qcow.cluster_size;
+        let block_offset = qcow.refcount_table
+            [(cluster_index / qcow.refcount_block_entries) as usize]
+            & REFCOUNT_TABLE_OFFSET_MASK;
+        let mut buf = [0u8; 2];
+        qcow.file
+            .seek(SeekFrom::Start(
+                block_offset + (cluster_index % qcow.refcount_block_entries) * REFCOUNT_BYTES,
+            ))
+            .unwrap();
+        qcow.file.read_exact(&mut buf).unwrap();
+        u16::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let f = qcow2_tempfile(0x10_0000, None);
+        let mut file = f.as_file().try_clone().unwrap();
+
+        let mut corrupt = |offset: u64, data: &[u8]| {
+            create_qcow2_image(&mut file, 0x10_0000, None);
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(data).unwrap();
+            QcowFile::new(f.as_file().try_clone().unwrap(), f.as_path(), false)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(corrupt(0, &[0, 0, 0, 0]), Error::InvalidMagic.to_string());
+        assert_eq!(
+            corrupt(4, &1u32.to_be_bytes()),
+            Error::UnsupportedVersion(1).to_string()
+        );
+        assert_eq!(
+            corrupt(20, &8u32.to_be_bytes()),
+            Error::InvalidClusterBits(8).to_string()
+        );
+        assert_eq!(
+            corrupt(32, &1u32.to_be_bytes()),
+            Error::EncryptedImage.to_string()
+        );
+        assert_eq!(
+            corrupt(36, &0u32.to_be_bytes()),
+            Error::InvalidL1TableSize(0).to_string()
+        );
+        // Rounding up an oversized disk does not wrap around to a small L1 table.
+        assert_eq!(
+            corrupt(24, &u64::MAX.to_be_bytes()),
+            Error::InvalidL1TableSize(1).to_string()
+        );
+        assert_eq!(
+            corrupt(40, &0x200u64.to_be_bytes()),
+            Error::InvalidTableOffset(0x200).to_string()
+        );
+        assert_eq!(
+            corrupt(60, &1u32.to_be_bytes()),
+            Error::SnapshotsNotSupported.to_string()
+        );
+        assert_eq!(
+            corrupt(72, &1u64.to_be_bytes()),
+            Error::UnsupportedFeatures(1).to_string()
+        );
+        assert_eq!(
+            corrupt(96, &5u32.to_be_bytes()),
+            Error::UnsupportedRefcountOrder(5).to_string()
+        );
+        assert_eq!(
+            corrupt(8, &0x1000u64.to_be_bytes()),
+            Error::InvalidBackingFileName.to_string()
+        );
+
+        // Images with internal snapshots can still be used read-only.
+        create_qcow2_image(&mut file, 0x10_0000, None);
+        file.seek(SeekFrom::Start(60)).unwrap();
+        file.write_all(&1u32.to_be_bytes()).unwrap();
+        open_qcow2(&f, true);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let size = 4 * TEST_CLUSTER_SIZE;
+        let f = qcow2_tempfile(size, None);
+        let mut qcow = open_qcow2(&f, false);
+        assert_eq!(qcow.virtual_size().unwrap(), size);
+
+        // Unallocated clusters read as zeros.
+        let mut buf = vec![0xffu8; size as usize];
+        assert_eq!(qcow.read(&mut buf).unwrap(), size as usize);
+        assert!(buf.iter().all(|&b| b == 0));
+        // Reads are capped at the virtual disk size.
+        assert_eq!(qcow.read(&mut buf).unwrap(), 0);
+        // The clusters past the L1 table are never looked up.
+        assert!(qcow.map_cluster(u64::MAX).is_err());
+
+        // Write a buffer spanning two clusters.
+        let data: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+        let address = TEST_CLUSTER_SIZE - 0x1000;
+        let file_size = f.as_file().metadata().unwrap().len();
+        qcow.seek(SeekFrom::Start(address)).unwrap();
+        qcow.write_all(&data).unwrap();
+        // One L2 table and two data clusters were allocated.
+        assert_eq!(
+            f.as_file().metadata().unwrap().len(),
+            file_size + 3 * TEST_CLUSTER_SIZE
+        );
+        for i in 0..3 {
+            assert_eq!(refcount(&mut qcow, file_size + i * TEST_CLUSTER_SIZE), 1);
+        }
+
+        let mut read_data = vec![0u8; data.len()];
+        qcow.seek(SeekFrom::Start(address)).unwrap();
+        qcow.read_exact(&mut read_data).unwrap();
+        assert_eq!(read_data, data);
+
+        // Overwriting allocated clusters does not allocate anything.
+        qcow.seek(SeekFrom::Start(address)).unwrap();
+        qcow.write_all(&data).unwrap();
+        assert_eq!(
+            f.as_file().metadata().unwrap().len(),
+            file_size + 3 * TEST_CLUSTER_SIZE
+        );
+
+        // The rest of the allocated clusters still reads as zeros.
+        let mut buf = vec![0xffu8; TEST_CLUSTER_SIZE as usize - 0x1000];
+        qcow.seek(SeekFrom::Start(TEST_CLUSTER_SIZE + 0x1000))
+            .unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Writes are capped at the virtual disk size.
+        assert_eq!(qcow.seek(SeekFrom::End(-1)).unwrap(), size - 1);
+        assert_eq!(qcow.write(&[1, 2]).unwrap(), 1);
+        assert!(qcow.seek(SeekFrom::Current(-(size as i64) - 1)).is_err());
+
+        // The metadata is persisted.
+        drop(qcow);
+        let mut qcow = open_qcow2(&f, false);
+        qcow.seek(SeekFrom::Start(address)).unwrap();
+        qcow.read_exact(&mut read_data).unwrap();
+        assert_eq!(read_data, data);
+    }
+
+    #[test]
+    fn test_zero_clusters() {
+        let f = qcow2_tempfile(2 * TEST_CLUSTER_SIZE, None);
+        let mut qcow = open_qcow2(&f, false);
+        qcow.write_all(&vec![0xaa; 2 * TEST_CLUSTER_SIZE as usize])
+            .unwrap();
+
+        // Mark the first cluster as reading zeros, keeping its preallocated data cluster.
+        let l2_table_offset = qcow.l1_table[0] & TABLE_OFFSET_MASK;
+        let l2_entry = qcow.read_u64_at(l2_table_offset).unwrap();
+        qcow.write_at(l2_table_offset, &(l2_entry | ZERO_FLAG).to_be_bytes())
+            .unwrap();
+
+        let mut buf = vec![0xffu8; TEST_CLUSTER_SIZE as usize];
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // A partial write reuses the preallocated cluster and zeroes the rest of it.
+        qcow.seek(SeekFrom::Start(0x200)).unwrap();
+        qcow.write_all(&[0xbb; 0x200]).unwrap();
+        assert_eq!(
+            qcow.read_u64_at(l2_table_offset).unwrap(),
+            (l2_entry & TABLE_OFFSET_MASK) | COPIED_FLAG
+        );
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x200].iter().all(|&b| b == 0));
+        assert!(buf[0x200..0x400].iter().all(|&b| b == 0xbb));
+        assert!(buf[0x400..].iter().all(|&b| b == 0));
+
+        // Compressed clusters are rejected.
+        qcow.write_at(l2_table_offset, &(l2_entry | COMPRESSED_FLAG).to_be_bytes())
+            .unwrap();
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        assert!(qcow.read_exact(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_backing_file_chain() {
+        let size = 2 * TEST_CLUSTER_SIZE;
+        let base = TempFile::new().unwrap();
+        let base_data: Vec<u8> = (0..TEST_CLUSTER_SIZE + 0x1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        base.as_file().write_all(&base_data).unwrap();
+        let base_path = base.as_path().to_str().unwrap();
+
+        // base (raw) <- middle (qcow2) <- top (qcow2)
+        let middle = qcow2_tempfile(size, Some(base_path));
+        let middle_name = middle.as_path().file_name().unwrap().to_str().unwrap();
+        // Relative backing file names are resolved against the image directory.
+        let top = qcow2_tempfile(size, Some(middle_name));
+        let mut qcow = open_qcow2(&top, false);
+
+        // Reads fall through to the base image, past its end they return zeros.
+        let mut buf = vec![0xffu8; size as usize];
+        qcow.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..base_data.len()], base_data.as_slice());
+        assert!(buf[base_data.len()..].iter().all(|&b| b == 0));
+
+        // A partial cluster write preserves the backing data around it.
+        qcow.seek(SeekFrom::Start(0x1000)).unwrap();
+        qcow.write_all(&[0xcc; 0x1000]).unwrap();
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..0x1000], &base_data[..0x1000]);
+        assert!(buf[0x1000..0x2000].iter().all(|&b| b == 0xcc));
+        assert_eq!(&buf[0x2000..base_data.len()], &base_data[0x2000..]);
+
+        // The backing files are left untouched.
+        let mut base_file = base.as_file().try_clone().unwrap();
+        let mut read_base = vec![0u8; base_data.len()];
+        base_file.seek(SeekFrom::Start(0)).unwrap();
+        base_file.read_exact(&mut read_base).unwrap();
+        assert_eq!(read_base, base_data);
+        let middle_qcow = open_qcow2(&middle, true);
+        assert_eq!(middle_qcow.l1_table[0], 0);
+
+        // Missing backing files are reported.
+        let orphan = qcow2_tempfile(size, Some("/invalid/backing/file"));
+        let file = File::open(orphan.as_path()).unwrap();
+        match QcowFile::new(file, orphan.as_path(), true) {
+            Err(Error::OpenBackingFile(_)) => (),
+            _ => panic!("Expected an OpenBackingFile error."),
+        }
+
+        // Backing file loops are detected.
+        let looped = qcow2_tempfile(size, None);
+        let looped_path = looped.as_path().to_str().unwrap();
+        create_qcow2_image(
+            &mut looped.as_file().try_clone().unwrap(),
+            size,
+            Some(looped_path),
+        );
+        let file = File::open(looped.as_path()).unwrap();
+        match QcowFile::new(file, looped.as_path(), true) {
+            Err(Error::BackingChainTooDeep) => (),
+            _ => panic!("Expected a BackingChainTooDeep error."),
+        }
+    }
+}
@@ -6,17 +6,24 @@ use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn};
 use utils::epoll::EventSet;
 
-use crate::virtio::block::device::Block;
+use crate::virtio::block::device::{Block, FileEngine};
 use crate::virtio::VirtioDevice;
 
 impl Block {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[0], EventSet::IN)) {
-            error!("Failed to register queue event: {}", e);
+        for queue_evt in self.queue_evts.iter() {
+            if let Err(e) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", e);
+            }
         }
         if let Err(e) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
             error!("Failed to register ratelimiter event: {}", e);
         }
+        if let FileEngine::Async(ref engine) = self.file_engine {
+            if let Err(e) = ops.add(Events::new(engine.completion_evt(), EventSet::IN)) {
+                error!("Failed to register async completion event: {}", e);
+            }
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -38,7 +45,7 @@ impl Block {
 }
 
 impl MutEventSubscriber for Block {
-    // Handle an event for queue or rate limiter.
+    // Handle an event for queue, rate limiter or async completions.
     fn process(&mut self, event: Events, ops: &mut EventOps) {
         let source = event.fd();
         let event_set = event.event_set();
@@ -55,16 +62,26 @@ impl MutEventSubscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let completion_fd = match self.file_engine {
+                FileEngine::Async(ref engine) => engine.completion_evt().as_raw_fd(),
+                FileEngine::Sync => -1,
+            };
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
-                _ => warn!("Block: Spurious event received: {:?}", source),
+                _ if completion_fd == source => self.process_async_completion_event(),
+                _ => match self
+                    .queue_evts
+                    .iter()
+                    .position(|queue_evt| queue_evt.as_raw_fd() == source)
+                {
+                    Some(queue_index) => self.process_queue_event(queue_index),
+                    None => warn!("Block: Spurious event received: {:?}", source),
+                },
             }
         } else {
             warn!(
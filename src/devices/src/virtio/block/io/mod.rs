@@ -0,0 +1,337 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Engines used by the block device for executing I/O requests on its backing file.
+
+mod uring;
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use serde::{Deserialize, Serialize};
+use utils::eventfd::EventFd;
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    MemoryRegionAddress,
+};
+
+use self::uring::{IoUring, Sqe};
+
+/// Type of engine used for executing the block device I/O requests.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum FileEngineType {
+    /// Requests are executed synchronously, on the device thread.
+    Sync,
+    /// Requests are submitted to an `io_uring` instance and complete asynchronously.
+    /// Only available for raw images and on host kernels with `io_uring` support.
+    Async,
+}
+
+impl Default for FileEngineType {
+    fn default() -> FileEngineType {
+        FileEngineType::Sync
+    }
+}
+
+/// Errors triggered by the asynchronous engine.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the completion eventfd.
+    EventFd(io::Error),
+    /// The guest buffer is not contiguous in the host address space.
+    GuestMemory(GuestMemoryError),
+    /// Failed to set up the `io_uring` instance.
+    IoUring(io::Error),
+    /// There is no room left for another in flight request.
+    QueueFull,
+    /// Failed to hand the requests over to the kernel.
+    Submit(io::Error),
+    /// The kernel reported the completion of a request which is not in flight.
+    UnknownCompletion(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            EventFd(e) => write!(f, "Failed to create the completion eventfd: {}", e),
+            GuestMemory(e) => write!(f, "Invalid guest buffer: {:?}", e),
+            IoUring(e) => write!(f, "Failed to set up io_uring: {}", e),
+            QueueFull => write!(f, "The io_uring submission queue is full."),
+            Submit(e) => write!(f, "Failed to submit requests to io_uring: {}", e),
+            UnknownCompletion(user_data) => {
+                write!(f, "Unknown io_uring completion: {}", user_data)
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Bookkeeping for a request submitted to the kernel.
+struct InFlight<T> {
+    user_data: T,
+    // Start of the guest buffer written by the kernel, to be marked dirty on completion.
+    dirty_addr: Option<GuestAddress>,
+}
+
+/// Asynchronous engine, backed by `io_uring`.
+///
+/// Each submitted request carries some caller provided `user_data`, which is handed back
+/// along with the request result when the request completes. The engine signals completions
+/// through `completion_evt()`.
+pub struct AsyncFileEngine<T> {
+    ring: IoUring,
+    completion_evt: EventFd,
+    slots: Vec<Option<InFlight<T>>>,
+    free_slots: Vec<usize>,
+}
+
+impl<T> AsyncFileEngine<T> {
+    /// Creates an engine allowing up to `queue_depth` requests in flight.
+    pub fn new(queue_depth: u32) -> Result<AsyncFileEngine<T>> {
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let ring = IoUring::new(queue_depth).map_err(Error::IoUring)?;
+        ring.register_eventfd(completion_evt.as_raw_fd())
+            .map_err(Error::IoUring)?;
+
+        // The kernel can round up the number of entries, but we never keep more than
+        // `sq_entries` requests in flight, so the completion queue cannot overflow.
+        let depth = ring.sq_entries() as usize;
+        let mut slots = Vec::with_capacity(depth);
+        slots.resize_with(depth, || None);
+
+        Ok(AsyncFileEngine {
+            ring,
+            completion_evt,
+            slots,
+            free_slots: (0..depth).rev().collect(),
+        })
+    }
+
+    /// Eventfd signaled by the kernel whenever a request completes.
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Number of requests submitted and not yet retrieved through `pop()`.
+    pub fn in_flight(&self) -> usize {
+        self.slots.len() - self.free_slots.len()
+    }
+
+    /// Specifies if another request can be pushed.
+    pub fn is_full(&self) -> bool {
+        self.free_slots.is_empty()
+    }
+
+    // Translates a guest buffer into a host pointer, making sure the whole buffer lives
+    // within a single guest memory region.
+    fn host_buffer(mem: &GuestMemoryMmap, addr: GuestAddress, len: u32) -> Result<*mut u8> {
+        let region = mem.find_region(addr).ok_or(Error::GuestMemory(
+            GuestMemoryError::InvalidGuestAddress(addr),
+        ))?;
+        let offset = addr.unchecked_offset_from(region.start_addr());
+        if offset + u64::from(len) > region.len() {
+            return Err(Error::GuestMemory(GuestMemoryError::InvalidBackendAddress));
+        }
+        region
+            .get_host_address(MemoryRegionAddress(offset))
+            .map_err(Error::GuestMemory)
+    }
+
+    fn push(&mut self, in_flight: InFlight<T>, build_sqe: impl FnOnce(u64) -> Sqe) -> Result<()> {
+        let slot = self.free_slots.pop().ok_or(Error::QueueFull)?;
+        // Safe because the buffers only point to guest memory, which outlives the device,
+        // and we drain all in flight requests before dropping the disk file.
+        match unsafe { self.ring.push(build_sqe(slot as u64)) } {
+            Ok(()) => {
+                self.slots[slot] = Some(in_flight);
+                Ok(())
+            }
+            Err(_) => {
+                self.free_slots.push(slot);
+                Err(Error::QueueFull)
+            }
+        }
+    }
+
+    /// Queues a read of `count` bytes from `file`, at `offset`, into guest memory at `addr`.
+    pub fn push_read(
+        &mut self,
+        file: &File,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> Result<()> {
+        let buf = Self::host_buffer(mem, addr, count)?;
+        let in_flight = InFlight {
+            user_data,
+            dirty_addr: Some(addr),
+        };
+        self.push(in_flight, |slot| Sqe::read(file, offset, buf, count, slot))
+    }
+
+    /// Queues a write of `count` bytes from guest memory at `addr` to `file`, at `offset`.
+    pub fn push_write(
+        &mut self,
+        file: &File,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> Result<()> {
+        let buf = Self::host_buffer(mem, addr, count)?;
+        let in_flight = InFlight {
+            user_data,
+            dirty_addr: None,
+        };
+        self.push(in_flight, |slot| Sqe::write(file, offset, buf, count, slot))
+    }
+
+    /// Queues a sync of `file` to the physical media.
+    pub fn push_fsync(&mut self, file: &File, user_data: T) -> Result<()> {
+        let in_flight = InFlight {
+            user_data,
+            dirty_addr: None,
+        };
+        self.push(in_flight, |slot| Sqe::fsync(file, slot))
+    }
+
+    /// Hands all the queued requests over to the kernel.
+    pub fn kick_submission_queue(&mut self) -> Result<()> {
+        self.ring.submit(0).map(|_| ()).map_err(Error::Submit)
+    }
+
+    /// Blocks until all the in flight requests complete. The completions still
+    /// need to be retrieved through `pop()`.
+    pub fn drain(&mut self) -> Result<()> {
+        let in_flight = self.in_flight() as u32;
+        if in_flight > 0 {
+            self.ring.submit(in_flight).map_err(Error::Submit)?;
+        }
+        Ok(())
+    }
+
+    /// Retrieves a completed request, along with its result: the number of bytes
+    /// transferred or the error the request failed with. Completions which don't match
+    /// any request in flight are consumed and reported as errors.
+    pub fn pop(&mut self, mem: &GuestMemoryMmap) -> Option<Result<(T, io::Result<u32>)>> {
+        let cqe = self.ring.pop()?;
+        let slot = cqe.user_data as usize;
+        // The kernel should only hand back the `user_data` we have provided.
+        let in_flight = match self.slots.get_mut(slot).and_then(Option::take) {
+            Some(in_flight) => in_flight,
+            None => return Some(Err(Error::UnknownCompletion(cqe.user_data))),
+        };
+        self.free_slots.push(slot);
+
+        let result = if cqe.res < 0 {
+            Err(io::Error::from_raw_os_error(-cqe.res))
+        } else {
+            Ok(cqe.res as u32)
+        };
+
+        if let (Ok(count), Some(addr)) = (&result, in_flight.dirty_addr) {
+            // The kernel wrote directly to guest memory, so the dirty pages
+            // are not tracked automatically.
+            if let Some(region) = mem.find_region(addr) {
+                let offset = addr.unchecked_offset_from(region.start_addr());
+                region.mark_dirty_pages(offset as usize, *count as usize);
+            }
+        }
+
+        Some(Ok((in_flight.user_data, result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use utils::tempfile::TempFile;
+    use vm_memory::Bytes;
+
+    #[test]
+    fn test_async_engine() {
+        let mem = GuestMemoryMmap::from_ranges_with_tracking(&[
+            (GuestAddress(0), 0x4000),
+            (GuestAddress(0x4000), 0x4000),
+        ])
+        .unwrap();
+        let f = TempFile::new().unwrap();
+        let mut file = f.as_file();
+        file.set_len(0x2000).unwrap();
+
+        let mut engine = match AsyncFileEngine::<u32>::new(2) {
+            Ok(engine) => engine,
+            // The host kernel does not support io_uring.
+            Err(Error::IoUring(e)) if e.raw_os_error() == Some(libc::ENOSYS) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(engine.in_flight(), 0);
+
+        // Buffers must not cross memory regions.
+        assert!(matches!(
+            engine.push_read(file, 0, &mem, GuestAddress(0x3e00), 0x400, 0),
+            Err(Error::GuestMemory(_))
+        ));
+        assert!(matches!(
+            engine.push_write(file, 0, &mem, GuestAddress(0x10000), 0x200, 0),
+            Err(Error::GuestMemory(_))
+        ));
+        assert_eq!(engine.in_flight(), 0);
+
+        mem.write_slice(&[0xab; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        engine
+            .push_write(file, 0x200, &mem, GuestAddress(0x1000), 0x200, 1)
+            .unwrap();
+        engine.push_fsync(file, 2).unwrap();
+        assert!(engine.is_full());
+        assert!(matches!(engine.push_fsync(file, 3), Err(Error::QueueFull)));
+        engine.kick_submission_queue().unwrap();
+        engine.drain().unwrap();
+        assert!(engine.completion_evt().read().unwrap() >= 1);
+
+        let mut completed = Vec::new();
+        while let Some(completion) = engine.pop(&mem) {
+            let (user_data, result) = completion.unwrap();
+            completed.push((user_data, result.unwrap()));
+        }
+        completed.sort_unstable();
+        assert_eq!(completed, vec![(1, 0x200), (2, 0)]);
+        assert_eq!(engine.in_flight(), 0);
+
+        let mut buf = [0u8; 0x200];
+        file.seek(SeekFrom::Start(0x200)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), vec![0xab; 0x200]);
+
+        // Reads land in guest memory and mark the pages as dirty.
+        file.seek(SeekFrom::Start(0x1000)).unwrap();
+        file.write_all(&[0xcd; 0x200]).unwrap();
+        engine
+            .push_read(file, 0x1000, &mem, GuestAddress(0x5000), 0x200, 4)
+            .unwrap();
+        engine.drain().unwrap();
+        let (user_data, result) = engine.pop(&mem).unwrap().unwrap();
+        assert_eq!(user_data, 4);
+        assert_eq!(result.unwrap(), 0x200);
+        assert!(engine.pop(&mem).is_none());
+
+        mem.read_slice(&mut buf, GuestAddress(0x5000)).unwrap();
+        assert_eq!(buf.to_vec(), vec![0xcd; 0x200]);
+        let region = mem.find_region(GuestAddress(0x4000)).unwrap();
+        let bitmap = region.dirty_bitmap().unwrap();
+        assert!(bitmap.is_addr_set(0x1000));
+        assert!(!bitmap.is_addr_set(0));
+    }
+}
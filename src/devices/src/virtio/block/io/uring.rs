@@ -0,0 +1,468 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal `io_uring` support, covering only what the block device needs: a single ring,
+//! driven from the device thread, running plain read, write and fsync operations.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use utils::syscall::SyscallReturnCode;
+
+// The io_uring syscall numbers are the same on x86_64 and aarch64.
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+
+// Magic offsets used for mapping the ring buffers.
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_EVENTFD: libc::c_uint = 4;
+
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct IoUringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// Submission queue entry, as defined by the kernel ABI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+impl Sqe {
+    /// Reads `len` bytes from `file`, at `offset`, into the buffer starting at `buf`.
+    pub fn read(file: &File, offset: u64, buf: *mut u8, len: u32, user_data: u64) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_READ,
+            fd: file.as_raw_fd(),
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        }
+    }
+
+    /// Writes `len` bytes from the buffer starting at `buf` to `file`, at `offset`.
+    pub fn write(file: &File, offset: u64, buf: *const u8, len: u32, user_data: u64) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_WRITE,
+            fd: file.as_raw_fd(),
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        }
+    }
+
+    /// Syncs the data and metadata of `file` to the physical media.
+    pub fn fsync(file: &File, user_data: u64) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_FSYNC,
+            fd: file.as_raw_fd(),
+            user_data,
+            ..Default::default()
+        }
+    }
+}
+
+/// Completion queue entry, as defined by the kernel ABI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cqe {
+    /// The `user_data` of the matching submission queue entry.
+    pub user_data: u64,
+    /// Result of the operation: number of bytes transferred or negated errno.
+    pub res: i32,
+    flags: u32,
+}
+
+// Memory area shared with the kernel, holding (parts of) the ring.
+struct SharedArea {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl SharedArea {
+    fn new(ring_fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // Safe because we check the return value and we only access the mapping within
+        // its bounds, as advertised by the kernel.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                ring_fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SharedArea { addr, len })
+    }
+
+    // Returns a pointer to the object of type `T` found `offset` bytes into the area.
+    // The caller needs to make sure the offset comes from the kernel provided ring layout.
+    fn ptr<T>(&self, offset: u32) -> *mut T {
+        // Safe because the offsets are provided by the kernel and are within the mapping.
+        unsafe { (self.addr as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for SharedArea {
+    fn drop(&mut self) {
+        // Safe because we own the mapping and nobody uses it past this point.
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+/// An `io_uring` instance.
+///
+/// Submissions are staged in the submission queue with `push()` and handed over to the
+/// kernel with `submit()`. Completions are retrieved with `pop()`.
+pub struct IoUring {
+    fd: File,
+    // The mappings are only kept alive for the raw pointers below.
+    _sq_area: SharedArea,
+    _cq_area: Option<SharedArea>,
+    _sqes_area: SharedArea,
+
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    // Local copy of the submission queue tail.
+    sq_local_tail: u32,
+    // Number of entries pushed but not yet consumed by the kernel.
+    to_submit: u32,
+
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+}
+
+// Safe because the ring is only ever used by the thread owning it, and all the raw pointers
+// point into mappings owned by the ring itself.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Sets up a ring with room for (at least) `entries` in flight operations.
+    pub fn new(entries: u32) -> io::Result<IoUring> {
+        let mut params = IoUringParams::default();
+        // Safe because the kernel only writes within the bounds of `params` and
+        // we check the return value.
+        let fd = SyscallReturnCode(unsafe {
+            libc::syscall(
+                SYS_IO_URING_SETUP,
+                entries,
+                &mut params as *mut IoUringParams,
+            )
+        } as libc::c_int)
+        .into_result()?;
+        // Safe because we have just created the fd and nobody else owns it.
+        let fd = unsafe { File::from_raw_fd(fd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+
+        let (sq_area, cq_area) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let area = SharedArea::new(
+                fd.as_raw_fd(),
+                std::cmp::max(sq_len, cq_len),
+                IORING_OFF_SQ_RING,
+            )?;
+            (area, None)
+        } else {
+            (
+                SharedArea::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?,
+                Some(SharedArea::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?),
+            )
+        };
+        let sqes_area = SharedArea::new(fd.as_raw_fd(), sqes_len, IORING_OFF_SQES)?;
+
+        let sq = &params.sq_off;
+        let cq = &params.cq_off;
+        let cq_ring = cq_area.as_ref().unwrap_or(&sq_area);
+        // Safe because the offsets come from the kernel and point within the mappings.
+        let (sq_mask, cq_mask) = unsafe {
+            (
+                *sq_area.ptr::<u32>(sq.ring_mask),
+                *cq_ring.ptr::<u32>(cq.ring_mask),
+            )
+        };
+        let sq_tail: *const AtomicU32 = sq_area.ptr(sq.tail);
+        // Safe because `sq_tail` points within the submission queue ring.
+        let sq_local_tail = unsafe { (*sq_tail).load(Ordering::Acquire) };
+
+        Ok(IoUring {
+            sq_head: sq_area.ptr(sq.head),
+            sq_tail,
+            sq_mask,
+            sq_entries: params.sq_entries,
+            sq_array: sq_area.ptr(sq.array),
+            sqes: sqes_area.ptr(0),
+            sq_local_tail,
+            to_submit: 0,
+            cq_head: cq_ring.ptr(cq.head),
+            cq_tail: cq_ring.ptr(cq.tail),
+            cq_mask,
+            cqes: cq_ring.ptr(cq.cqes),
+            fd,
+            _sq_area: sq_area,
+            _cq_area: cq_area,
+            _sqes_area: sqes_area,
+        })
+    }
+
+    /// Number of entries of the submission queue.
+    pub fn sq_entries(&self) -> u32 {
+        self.sq_entries
+    }
+
+    /// Asks the kernel to signal `evt_fd` whenever a completion is posted.
+    pub fn register_eventfd(&self, evt_fd: RawFd) -> io::Result<()> {
+        // Safe because the kernel only reads one fd from the provided address and
+        // we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::syscall(
+                SYS_IO_URING_REGISTER,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &evt_fd as *const RawFd,
+                1,
+            )
+        } as libc::c_int)
+        .into_empty_result()
+    }
+
+    /// Stages `sqe` in the submission queue. The entry is handed back if the queue is full.
+    ///
+    /// # Safety
+    ///
+    /// The buffer described by `sqe` must stay valid until the operation completes.
+    pub unsafe fn push(&mut self, sqe: Sqe) -> Result<(), Sqe> {
+        let head = (*self.sq_head).load(Ordering::Acquire);
+        if self.sq_local_tail.wrapping_sub(head) >= self.sq_entries {
+            return Err(sqe);
+        }
+
+        let index = self.sq_local_tail & self.sq_mask;
+        self.sqes.add(index as usize).write_volatile(sqe);
+        self.sq_array.add(index as usize).write_volatile(index);
+        self.sq_local_tail = self.sq_local_tail.wrapping_add(1);
+        // Make the entry visible to the kernel.
+        (*self.sq_tail).store(self.sq_local_tail, Ordering::Release);
+        self.to_submit += 1;
+
+        Ok(())
+    }
+
+    /// Hands the staged entries over to the kernel and waits until at least `min_complete`
+    /// completions are available. Returns the number of submitted entries.
+    pub fn submit(&mut self, min_complete: u32) -> io::Result<u32> {
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        loop {
+            // Safe because we pass no signal mask and we check the return value.
+            let ret = SyscallReturnCode(unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd.as_raw_fd(),
+                    self.to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            } as libc::c_int)
+            .into_result();
+
+            match ret {
+                Ok(submitted) => {
+                    // The kernel never consumes more entries than requested.
+                    self.to_submit -= submitted as u32;
+                    return Ok(submitted as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Retrieves the oldest available completion, if any.
+    pub fn pop(&mut self) -> Option<Cqe> {
+        // Safe because the head and tail pointers point within the completion queue ring,
+        // and the kernel only writes entries up to the tail it publishes.
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            if head == (*self.cq_tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = self
+                .cqes
+                .add((head & self.cq_mask) as usize)
+                .read_volatile();
+            // Give the entry back to the kernel.
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(cqe)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use utils::eventfd::EventFd;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_abi_sizes() {
+        assert_eq!(std::mem::size_of::<Sqe>(), 64);
+        assert_eq!(std::mem::size_of::<Cqe>(), 16);
+        assert_eq!(std::mem::size_of::<IoUringParams>(), 120);
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut ring = match IoUring::new(4) {
+            Ok(ring) => ring,
+            // The host kernel does not support io_uring.
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => return,
+            Err(e) => panic!("Failed to set up io_uring: {}", e),
+        };
+        assert_eq!(ring.sq_entries(), 4);
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        ring.register_eventfd(evt.as_raw_fd()).unwrap();
+
+        let f = TempFile::new().unwrap();
+        let mut file = f.as_file();
+        let data = [0xabu8; 512];
+        let mut buf = [0u8; 512];
+
+        // Fill up the submission queue.
+        unsafe {
+            ring.push(Sqe::write(file, 512, data.as_ptr(), 512, 1))
+                .unwrap();
+            ring.push(Sqe::fsync(file, 2)).unwrap();
+            ring.push(Sqe::fsync(file, 3)).unwrap();
+            ring.push(Sqe::fsync(file, 4)).unwrap();
+            assert!(ring.push(Sqe::fsync(file, 5)).is_err());
+        }
+        assert!(ring.pop().is_none());
+        assert_eq!(ring.submit(4).unwrap(), 4);
+        assert!(evt.read().unwrap() >= 1);
+
+        let mut completed = Vec::new();
+        while let Some(cqe) = ring.pop() {
+            assert!(cqe.res >= 0);
+            completed.push(cqe.user_data);
+        }
+        completed.sort_unstable();
+        assert_eq!(completed, vec![1, 2, 3, 4]);
+
+        file.seek(SeekFrom::Start(512)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Read back through the ring.
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0xcd; 512]).unwrap();
+        unsafe {
+            ring.push(Sqe::read(file, 0, buf.as_mut_ptr(), 512, 6))
+                .unwrap();
+        }
+        assert_eq!(ring.submit(1).unwrap(), 1);
+        let cqe = ring.pop().unwrap();
+        assert_eq!(cqe.user_data, 6);
+        assert_eq!(cqe.res, 512);
+        assert_eq!(buf, [0xcd; 512]);
+
+        // Errors are reported through the completion result.
+        let ro_file = File::open(f.as_path()).unwrap();
+        unsafe {
+            ring.push(Sqe::write(&ro_file, 0, data.as_ptr(), 512, 7))
+                .unwrap();
+        }
+        assert_eq!(ring.submit(1).unwrap(), 1);
+        let cqe = ring.pop().unwrap();
+        assert_eq!(cqe.user_data, 7);
+        assert_eq!(cqe.res, -libc::EBADF);
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use logger::{BlockDeviceMetrics, PerDeviceMetrics, METRICS};
+use utils::time::{get_time_us, ClockType};
+
+use super::request::RequestType;
+use crate::virtio::metrics::{DeviceMetrics, DeviceMetricsType};
+
+impl DeviceMetricsType for BlockDeviceMetrics {
+    fn global() -> &'static Self {
+        &METRICS.block
+    }
+
+    fn per_device() -> &'static PerDeviceMetrics<Self> {
+        &METRICS.block_drives
+    }
+}
+
+/// Metrics of a block device, accounted both in the global block metrics and
+/// in the metrics of the drive the device belongs to.
+pub type BlockMetrics = DeviceMetrics<BlockDeviceMetrics>;
+
+impl BlockMetrics {
+    /// Records the latency of a request, given the monotonic timestamp (in microseconds)
+    /// taken right before the request was parsed. Only data transfers and flushes are tracked.
+    pub fn record_latency(&self, request_type: RequestType, start_us: u64) {
+        let latency_us = get_time_us(ClockType::Monotonic).saturating_sub(start_us);
+        let histograms = match request_type {
+            RequestType::In => [
+                &METRICS.block.read_latency_us,
+                &self.device().read_latency_us,
+            ],
+            RequestType::Out => [
+                &METRICS.block.write_latency_us,
+                &self.device().write_latency_us,
+            ],
+            RequestType::Flush => [
+                &METRICS.block.flush_latency_us,
+                &self.device().flush_latency_us,
+            ],
+            _ => return,
+        };
+        for histogram in histograms.iter() {
+            histogram.record(latency_us);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use logger::IncMetric;
+
+    #[test]
+    fn test_block_metrics() {
+        let metrics = BlockMetrics::new("test_block_metrics");
+        let global_count = METRICS.block.discard_count.count();
+
+        metrics.inc(|m| &m.discard_count);
+        metrics.add(|m| &m.discard_count, 2);
+        assert_eq!(metrics.device().discard_count.count(), 3);
+        assert!(METRICS.block.discard_count.count() >= global_count + 3);
+        // Handles of the same drive share the counters.
+        assert_eq!(
+            BlockMetrics::new("test_block_metrics")
+                .device()
+                .discard_count
+                .count(),
+            3
+        );
+
+        let start_us = get_time_us(ClockType::Monotonic);
+        metrics.record_latency(RequestType::In, start_us);
+        metrics.record_latency(RequestType::Flush, start_us);
+        metrics.record_latency(RequestType::GetDeviceID, start_us);
+        assert_eq!(metrics.device().read_latency_us.count(), 1);
+        assert_eq!(metrics.device().write_latency_us.count(), 0);
+        assert_eq!(metrics.device().flush_latency_us.count(), 1);
+    }
+}
@@ -2,23 +2,35 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod device;
+pub mod dirty_chunks;
+pub mod disk;
 pub mod event_handler;
+pub mod io;
+pub mod metrics;
 pub mod persist;
 pub mod request;
 pub mod test_utils;
 
 pub use self::device::{Block, CacheType};
+pub use self::dirty_chunks::DIRTY_CHUNK_SIZE;
+pub use self::disk::ImageFormat;
 pub use self::event_handler::*;
+pub use self::io::FileEngineType;
+pub use self::metrics::BlockMetrics;
 pub use self::request::*;
 
 use vm_memory::GuestMemoryError;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = 60;
+// Maximum number of segments in a discard or write zeroes request.
+pub const MAX_DISCARD_WRITE_ZEROES_SEGMENTS: u32 = 32;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+// Default number of request queues.
+pub const NUM_QUEUES: u16 = 1;
+// Maximum number of request queues, enough for one queue per vCPU.
+pub const MAX_NUM_QUEUES: u16 = 32;
 
 #[derive(Debug)]
 pub enum Error {
@@ -38,4 +50,6 @@ pub enum Error {
     UnexpectedReadOnlyDescriptor,
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
+    /// The request type is not handled by the asynchronous engine.
+    UnsupportedAsyncRequest,
 }
@@ -25,6 +25,19 @@ use crate::virtio::{DeviceState, TYPE_BLOCK};
 pub enum CacheTypeState {
     Unsafe,
     Writeback,
+    #[version(start = 2, default_fn = "default_cache_type_writeback")]
+    Direct,
+}
+
+impl CacheTypeState {
+    fn default_cache_type_writeback(&self, _target_version: u16) -> CacheTypeState {
+        // Both modes flush the data to the host storage, only through the page cache here.
+        warn!(
+            "Target version does not implement the \"Direct\" cache type. \
+            Defaulting to \"Writeback\" mode."
+        );
+        CacheTypeState::Writeback
+    }
 }
 
 impl From<CacheType> for CacheTypeState {
@@ -32,6 +45,7 @@ impl From<CacheType> for CacheTypeState {
         match cache_type {
             CacheType::Unsafe => CacheTypeState::Unsafe,
             CacheType::Writeback => CacheTypeState::Writeback,
+            CacheType::Direct => CacheTypeState::Direct,
         }
     }
 }
@@ -41,10 +55,67 @@ impl From<CacheTypeState> for CacheType {
         match cache_type_state {
             CacheTypeState::Unsafe => CacheType::Unsafe,
             CacheTypeState::Writeback => CacheType::Writeback,
+            CacheTypeState::Direct => CacheType::Direct,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ImageFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<ImageFormat> for ImageFormatState {
+    fn from(image_format: ImageFormat) -> Self {
+        match image_format {
+            ImageFormat::Raw => ImageFormatState::Raw,
+            ImageFormat::Qcow2 => ImageFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<ImageFormatState> for ImageFormat {
+    fn from(image_format_state: ImageFormatState) -> Self {
+        match image_format_state {
+            ImageFormatState::Raw => ImageFormat::Raw,
+            ImageFormatState::Qcow2 => ImageFormat::Qcow2,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FileEngineTypeState {
+    Sync,
+    Async,
+}
+
+impl From<FileEngineType> for FileEngineTypeState {
+    fn from(file_engine_type: FileEngineType) -> Self {
+        match file_engine_type {
+            FileEngineType::Sync => FileEngineTypeState::Sync,
+            FileEngineType::Async => FileEngineTypeState::Async,
+        }
+    }
+}
+
+impl From<FileEngineTypeState> for FileEngineType {
+    fn from(file_engine_type_state: FileEngineTypeState) -> Self {
+        match file_engine_type_state {
+            FileEngineTypeState::Sync => FileEngineType::Sync,
+            FileEngineTypeState::Async => FileEngineType::Async,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct OverlayState {
+    path: String,
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
@@ -56,6 +127,36 @@ pub struct BlockState {
         default_fn = "default_cache_type_flush"
     )]
     cache_type: CacheTypeState,
+    #[version(
+        start = 3,
+        ser_fn = "block_image_format_ser",
+        default_fn = "default_image_format_raw"
+    )]
+    image_format: ImageFormatState,
+    #[version(
+        start = 3,
+        ser_fn = "block_file_engine_type_ser",
+        default_fn = "default_file_engine_type_sync"
+    )]
+    file_engine_type: FileEngineTypeState,
+    #[version(
+        start = 3,
+        ser_fn = "block_overlay_ser",
+        default_fn = "default_overlay_none"
+    )]
+    overlay: Option<OverlayState>,
+    #[version(
+        start = 3,
+        ser_fn = "block_num_queues_ser",
+        default_fn = "default_num_queues"
+    )]
+    num_queues: u16,
+    #[version(
+        start = 3,
+        ser_fn = "block_dirty_bitmap_ser",
+        default_fn = "default_dirty_bitmap"
+    )]
+    dirty_bitmap: Vec<u64>,
     root_device: bool,
     disk_path: String,
     virtio_state: VirtioDeviceState,
@@ -77,6 +178,81 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn block_image_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring a non raw image as raw would expose the image metadata to the guest.
+        if target_version < 3 && self.image_format != ImageFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the current image format.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_image_format_raw(_source_version: u16) -> ImageFormatState {
+        ImageFormatState::Raw
+    }
+
+    fn block_file_engine_type_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.file_engine_type != FileEngineTypeState::Sync {
+            warn!(
+                "Target version does not implement the current file engine. \
+                Defaulting to \"Sync\" mode."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn default_file_engine_type_sync(_source_version: u16) -> FileEngineTypeState {
+        FileEngineTypeState::Sync
+    }
+
+    fn block_overlay_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring without the overlay would write the guest data to the base image.
+        if target_version < 3 && self.overlay.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement overlay drives.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_overlay_none(_source_version: u16) -> Option<OverlayState> {
+        None
+    }
+
+    fn block_num_queues_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The request queues cannot be merged once the driver started using them.
+        if target_version < 3 && self.num_queues != NUM_QUEUES {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement multiple queues.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queues(_source_version: u16) -> u16 {
+        NUM_QUEUES
+    }
+
+    fn block_dirty_bitmap_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.dirty_bitmap.iter().any(|word| *word != 0) {
+            warn!(
+                "Target version does not implement block dirty tracking. \
+                The modified chunks of the disk will not be reported."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn default_dirty_bitmap(_source_version: u16) -> Vec<u64> {
+        Vec::new()
+    }
 }
 
 pub struct BlockConstructorArgs {
@@ -101,6 +277,13 @@ impl Persist<'_> for Block {
             id: self.id.clone(),
             partuuid: self.partuuid.clone(),
             cache_type: CacheTypeState::from(self.cache_type()),
+            image_format: ImageFormatState::from(self.image_format()),
+            file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            overlay: self
+                .overlay_path()
+                .map(|path| OverlayState { path: path.clone() }),
+            num_queues: self.num_queues(),
+            dirty_bitmap: self.disk.dirty_chunks().bitmap().to_vec(),
             root_device: self.root_device,
             disk_path: self.disk.file_path().clone(),
             virtio_state: VirtioDeviceState::from_device(self),
@@ -119,20 +302,39 @@ impl Persist<'_> for Block {
             state.id.clone(),
             state.partuuid.clone(),
             state.cache_type.into(),
+            state.image_format.into(),
+            state.file_engine_type.into(),
             state.disk_path.clone(),
+            state.overlay.as_ref().map(|overlay| overlay.path.clone()),
             is_disk_read_only,
             state.root_device,
+            state.num_queues,
             rate_limiter,
         )?;
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                usize::from(state.num_queues),
+                QUEUE_SIZE,
+            )
             .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
         block.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
-        block.avail_features = state.virtio_state.avail_features;
+        block.avail_features = state.virtio_state.all_avail_features();
         block.acked_features = state.virtio_state.acked_features;
 
+        if state.dirty_bitmap.is_empty() {
+            // The modifications made before the snapshot are unknown.
+            block.disk.dirty_chunks_mut().mark_all();
+        } else {
+            block
+                .disk
+                .dirty_chunks_mut()
+                .set_bitmap(&state.dirty_bitmap)?;
+        }
+
         if state.virtio_state.activated {
             block.device_state = DeviceState::Activated(constructor_args.mem);
         }
@@ -144,11 +346,16 @@ impl Persist<'_> for Block {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::virtio::block::disk::qcow2::tests::create_qcow2_image;
     use crate::virtio::device::VirtioDevice;
     use utils::tempfile::TempFile;
 
     use crate::virtio::test_utils::default_mem;
+    use crate::virtio::VIRTIO_F_RING_PACKED;
     use std::sync::atomic::Ordering;
+    use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
 
     #[test]
     fn test_cache_type_state_from() {
@@ -160,12 +367,17 @@ mod tests {
             CacheTypeState::Writeback,
             CacheTypeState::from(CacheType::Writeback)
         );
+        assert_eq!(
+            CacheTypeState::Direct,
+            CacheTypeState::from(CacheType::Direct)
+        );
     }
 
     #[test]
     fn test_cache_type_state_into() {
         assert_eq!(CacheType::Unsafe, CacheTypeState::Unsafe.into());
         assert_eq!(CacheType::Writeback, CacheTypeState::Writeback.into());
+        assert_eq!(CacheType::Direct, CacheTypeState::Direct.into());
     }
 
     #[test]
@@ -180,6 +392,345 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cache_type_direct_ser() {
+        let mut mem = vec![0; 16];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(CacheTypeState::type_id(), 2);
+
+        // Older versions restore the cache type as writeback.
+        CacheTypeState::Direct
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        assert_eq!(
+            CacheTypeState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+            CacheTypeState::Writeback
+        );
+
+        CacheTypeState::Direct
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        assert_eq!(
+            CacheTypeState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+            CacheTypeState::Direct
+        );
+    }
+
+    #[test]
+    fn test_image_format_state_from() {
+        assert_eq!(
+            ImageFormatState::Raw,
+            ImageFormatState::from(ImageFormat::Raw)
+        );
+        assert_eq!(
+            ImageFormatState::Qcow2,
+            ImageFormatState::from(ImageFormat::Qcow2)
+        );
+        assert_eq!(ImageFormat::Raw, ImageFormatState::Raw.into());
+        assert_eq!(ImageFormat::Qcow2, ImageFormatState::Qcow2.into());
+    }
+
+    #[test]
+    fn test_file_engine_type_state_from() {
+        assert_eq!(
+            FileEngineTypeState::Sync,
+            FileEngineTypeState::from(FileEngineType::Sync)
+        );
+        assert_eq!(
+            FileEngineTypeState::Async,
+            FileEngineTypeState::from(FileEngineType::Async)
+        );
+        assert_eq!(FileEngineType::Sync, FileEngineTypeState::Sync.into());
+        assert_eq!(FileEngineType::Async, FileEngineTypeState::Async.into());
+    }
+
+    #[test]
+    fn test_file_engine_type_persistence() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Async,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        // Hosts without io_uring support fall back to the Sync engine.
+        if block.file_engine_type() != FileEngineType::Async {
+            return;
+        }
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3);
+
+        // Older versions restore the device with the default engine.
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(state.file_engine_type, FileEngineTypeState::Sync);
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.file_engine_type(), FileEngineType::Async);
+    }
+
+    #[test]
+    fn test_image_format_semantic_ser() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(&mut f.as_file().try_clone().unwrap(), 0x10_0000, None);
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Qcow2,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3);
+
+        // A qcow2 drive cannot be saved in a version that does not know about image formats.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(restored_block.disk.nsectors(), block.disk.nsectors());
+    }
+
+    #[test]
+    fn test_overlay_persistence() {
+        // We create the backing files here so that they exist for the whole lifetime of the test.
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x4_0000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            base.as_path().to_str().unwrap().to_string(),
+            Some(overlay_path.clone()),
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        block.disk.file_mut().write_all(&[0xab; 0x200]).unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3);
+
+        // An overlay drive cannot be saved in a version that does not know about overlays.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        let state = <Block as Persist>::save(&block);
+        assert_eq!(
+            state.overlay,
+            Some(OverlayState {
+                path: overlay_path.clone()
+            })
+        );
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+
+        // A cluster copied to the overlay after the snapshot was taken. As for any other drive,
+        // the guest writes following the snapshot are not rolled back.
+        block
+            .disk
+            .file_mut()
+            .seek(SeekFrom::Start(0x3_0000))
+            .unwrap();
+        block.disk.file_mut().write_all(&[0xcd; 0x200]).unwrap();
+        assert_eq!(block.disk.overlay_bitmap().unwrap(), &[0b1001]);
+        drop(block);
+
+        let mut restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.overlay_path(), Some(&overlay_path));
+        // The cluster bitmap is rebuilt from the overlay file.
+        assert_eq!(restored_block.disk.overlay_bitmap().unwrap(), &[0b1001]);
+
+        let mut buf = [0u8; 0x200];
+        let disk = restored_block.disk.file_mut();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xab; 0x200]);
+        disk.seek(SeekFrom::Start(0x3_0000)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xcd; 0x200]);
+    }
+
+    #[test]
+    fn test_num_queues_persistence() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            4,
+            RateLimiter::default(),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3);
+
+        // Older versions only know about single queue devices.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap();
+        assert_eq!(state.num_queues, 4);
+        let restored_block =
+            Block::restore(BlockConstructorArgs { mem: default_mem() }, &state).unwrap();
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+
+        // The number of queues must match the saved queues.
+        let mut state = <Block as Persist>::save(&block);
+        state.num_queues = 2;
+        assert!(Block::restore(BlockConstructorArgs { mem: default_mem() }, &state).is_err());
+    }
+
+    #[test]
+    fn test_dirty_bitmap_persistence() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(2 * DIRTY_CHUNK_SIZE).unwrap();
+
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
+            f.as_path().to_str().unwrap().to_string(),
+            None,
+            false,
+            false,
+            NUM_QUEUES,
+            RateLimiter::default(),
+        )
+        .unwrap();
+        block.disk.mark_dirty(DIRTY_CHUNK_SIZE, 0x200);
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3);
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap();
+        let mut restored_block =
+            Block::restore(BlockConstructorArgs { mem: default_mem() }, &state).unwrap();
+        assert_eq!(restored_block.dirty_chunks(), vec![0b10]);
+
+        // Without a saved bitmap, the whole disk is reported as modified.
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert!(state.dirty_bitmap.is_empty());
+        let mut restored_block =
+            Block::restore(BlockConstructorArgs { mem: default_mem() }, &state).unwrap();
+        assert_eq!(restored_block.dirty_chunks(), vec![0b11]);
+
+        // The bitmap must cover the disk.
+        let mut state = <Block as Persist>::save(&block);
+        state.dirty_bitmap = vec![0; 2];
+        assert!(Block::restore(BlockConstructorArgs { mem: default_mem() }, &state).is_err());
+    }
+
     #[test]
     fn test_cache_semantic_ser() {
         // We create the backing file here so that it exists for the whole lifetime of the test.
@@ -191,9 +742,13 @@ mod tests {
             id,
             None,
             CacheType::Writeback,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
             f.as_path().to_str().unwrap().to_string(),
+            None,
             false,
             false,
+            NUM_QUEUES,
             RateLimiter::default(),
         )
         .unwrap();
@@ -222,9 +777,13 @@ mod tests {
             id,
             None,
             CacheType::Unsafe,
+            ImageFormat::Raw,
+            FileEngineType::Sync,
             f.as_path().to_str().unwrap().to_string(),
+            None,
             false,
             false,
+            NUM_QUEUES,
             RateLimiter::default(),
         )
         .unwrap();
@@ -245,9 +804,16 @@ mod tests {
         )
         .unwrap();
 
-        // Test that virtio specific fields are the same.
+        // Test that virtio specific fields are the same, except for the ring features left out
+        // of the older snapshots.
         assert_eq!(restored_block.device_type(), TYPE_BLOCK);
-        assert_eq!(restored_block.avail_features(), block.avail_features());
+        assert_eq!(
+            restored_block.avail_features(),
+            block.avail_features()
+                & !(1 << VIRTIO_RING_F_INDIRECT_DESC
+                    | 1 << VIRTIO_RING_F_EVENT_IDX
+                    | 1 << VIRTIO_F_RING_PACKED)
+        );
         assert_eq!(restored_block.acked_features(), block.acked_features());
         assert_eq!(restored_block.queues(), block.queues());
         assert_eq!(
@@ -7,25 +7,33 @@
 
 use std::convert::From;
 use std::io::{self, Seek, SeekFrom, Write};
+use std::mem::size_of;
 use std::result;
 
-use logger::{IncMetric, METRICS};
 use virtio_gen::virtio_blk::*;
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
 use super::device::{CacheType, DiskProperties};
-use super::{Error, SECTOR_SHIFT, SECTOR_SIZE};
+use super::io::{AsyncFileEngine, Error as AsyncIoError};
+use super::metrics::BlockMetrics;
+use super::{Error, MAX_DISCARD_WRITE_ZEROES_SEGMENTS, SECTOR_SHIFT, SECTOR_SIZE};
 
 #[derive(Debug)]
 pub enum IoErrStatus {
+    // Failure reported by the asynchronous engine on completion.
+    Async(io::Error),
+    // Failure to submit the request to the asynchronous engine.
+    AsyncSubmit(AsyncIoError),
     BadRequest(Error),
+    Discard(io::Error),
     Flush(io::Error),
     // Read(num_used_bytes, GuestMemoryError)
     Read(u32, GuestMemoryError),
     Seek(io::Error),
     SyncAll(io::Error),
     Write(GuestMemoryError),
+    WriteZeroes(io::Error),
 }
 
 #[derive(Debug)]
@@ -83,6 +91,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -93,11 +103,72 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
 }
 
+/// Request submitted to the asynchronous engine, waiting for completion.
+#[derive(Clone, Copy, Debug)]
+pub struct PendingRequest {
+    pub request_type: RequestType,
+    pub data_len: u32,
+    pub status_addr: GuestAddress,
+    pub queue_index: usize,
+    pub desc_idx: u16,
+    // Monotonic timestamp of the request parsing, in microseconds.
+    pub start_us: u64,
+}
+
+impl PendingRequest {
+    /// Builds the status of the request out of the result reported by the asynchronous engine.
+    pub(crate) fn status(&self, result: io::Result<u32>, metrics: &BlockMetrics) -> Status {
+        let result = result
+            .map_err(|e| ErrStatus::IoErr(IoErrStatus::Async(e)))
+            .and_then(|count| match self.request_type {
+                RequestType::In => {
+                    metrics.add(|m| &m.read_bytes, count as usize);
+                    if count < self.data_len {
+                        return Err(ErrStatus::IoErr(IoErrStatus::Read(
+                            count,
+                            GuestMemoryError::PartialBuffer {
+                                expected: self.data_len as usize,
+                                completed: count as usize,
+                            },
+                        )));
+                    }
+                    metrics.inc(|m| &m.read_count);
+                    Ok(self.data_len)
+                }
+                RequestType::Out => {
+                    metrics.add(|m| &m.write_bytes, count as usize);
+                    if count < self.data_len {
+                        return Err(ErrStatus::IoErr(IoErrStatus::Write(
+                            GuestMemoryError::PartialBuffer {
+                                expected: self.data_len as usize,
+                                completed: count as usize,
+                            },
+                        )));
+                    }
+                    metrics.inc(|m| &m.write_count);
+                    Ok(0)
+                }
+                RequestType::Flush => {
+                    metrics.inc(|m| &m.flush_count);
+                    Ok(0)
+                }
+                // Only data transfers and flushes go through the asynchronous engine.
+                _ => Err(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                    Error::UnsupportedAsyncRequest,
+                ))),
+            });
+
+        Status::from_result(result)
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct Request {
     pub request_type: RequestType,
@@ -127,6 +198,41 @@ pub struct RequestHeader {
 // Safe because RequestHeader only contains plain data.
 unsafe impl ByteValued for RequestHeader {}
 
+/// Range of sectors targeted by a discard or write zeroes request.
+///
+/// The data buffer of such requests holds one or more segments, each one made of:
+///   * sector: an u64 value representing the first sector of the range.
+///   * num_sectors: an u32 value representing the number of sectors in the range.
+///   * flags: an u32 value; only the unmap flag is defined, for write zeroes requests.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+
+    /// Offset and length, in bytes, of the disk range covered by the segment.
+    fn disk_range(&self) -> (u64, u64) {
+        (
+            self.sector << SECTOR_SHIFT,
+            u64::from(self.num_sectors) << SECTOR_SHIFT,
+        )
+    }
+}
+
 impl RequestHeader {
     pub fn new(request_type: u32, sector: u64) -> RequestHeader {
         RequestHeader {
@@ -186,7 +292,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -214,7 +324,8 @@ impl Request {
         Ok(req)
     }
 
-    fn execute_seek(&self, disk: &mut DiskProperties) -> result::Result<(), ErrStatus> {
+    // Validates the data transfer against the disk size and returns its offset in the disk.
+    fn disk_offset(&self, disk: &DiskProperties) -> result::Result<u64, ErrStatus> {
         // TODO: perform this logic at request parsing level in the future.
         // Check that the data length is a multiple of 512 as specified in the virtio standard.
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
@@ -234,17 +345,147 @@ impl Request {
             )));
         }
 
+        Ok(self.sector << SECTOR_SHIFT)
+    }
+
+    fn execute_seek(&self, disk: &mut DiskProperties) -> result::Result<(), ErrStatus> {
+        let offset = self.disk_offset(disk)?;
         disk.file_mut()
-            .seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
+            .seek(SeekFrom::Start(offset))
             .map_err(|e| ErrStatus::IoErr(IoErrStatus::Seek(e)))?;
 
         Ok(())
     }
 
+    // Reads the segments of a discard or write zeroes request and validates them against
+    // the disk size.
+    fn read_segments(
+        &self,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<Vec<DiscardWriteZeroesSegment>, ErrStatus> {
+        let segment_size = size_of::<DiscardWriteZeroesSegment>() as u32;
+        let num_segments = self.data_len / segment_size;
+        if self.data_len % segment_size != 0
+            || num_segments == 0
+            || num_segments > MAX_DISCARD_WRITE_ZEROES_SEGMENTS
+        {
+            return Err(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                Error::InvalidDataLength,
+            )));
+        }
+
+        let mut segments = Vec::with_capacity(num_segments as usize);
+        for i in 0..num_segments {
+            let addr = self
+                .data_addr
+                .checked_add(u64::from(i * segment_size))
+                .ok_or(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                    Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(self.data_addr)),
+                )))?;
+            let segment: DiscardWriteZeroesSegment = mem
+                .read_obj(addr)
+                .map_err(|e| ErrStatus::IoErr(IoErrStatus::BadRequest(Error::GuestMemory(e))))?;
+
+            // Discard requests don't support any flag.
+            let (op, unsupported_flags) = match self.request_type {
+                RequestType::WriteZeroes => (
+                    VIRTIO_BLK_T_WRITE_ZEROES,
+                    segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                ),
+                _ => (VIRTIO_BLK_T_DISCARD, segment.flags),
+            };
+            if unsupported_flags != 0 {
+                return Err(ErrStatus::Unsupported(op));
+            }
+
+            let top_sector = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                    Error::InvalidOffset,
+                )))?;
+            if top_sector > disk.nsectors() {
+                return Err(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                    Error::InvalidOffset,
+                )));
+            }
+
+            segments.push(segment);
+        }
+
+        Ok(segments)
+    }
+
+    /// Specifies if the request is executed by the asynchronous engine, when in use.
+    pub(crate) fn is_async(&self, cache_type: CacheType) -> bool {
+        match self.request_type {
+            RequestType::In | RequestType::Out => true,
+            // Flushes are a noop in unsafe mode.
+            RequestType::Flush => cache_type != CacheType::Unsafe,
+            _ => false,
+        }
+    }
+
+    /// Hands the request over to the asynchronous engine. The result is retrieved from
+    /// the engine, along with the returned `PendingRequest`, once the request completes.
+    pub(crate) fn submit_async(
+        &self,
+        engine: &mut AsyncFileEngine<PendingRequest>,
+        disk: &mut DiskProperties,
+        mem: &GuestMemoryMmap,
+        queue_index: usize,
+        desc_idx: u16,
+        start_us: u64,
+    ) -> result::Result<(), ErrStatus> {
+        let pending = PendingRequest {
+            request_type: self.request_type,
+            data_len: self.data_len,
+            status_addr: self.status_addr,
+            queue_index,
+            desc_idx,
+            start_us,
+        };
+
+        match self.request_type {
+            RequestType::In => {
+                let offset = self.disk_offset(disk)?;
+                engine.push_read(
+                    disk.file(),
+                    offset,
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    pending,
+                )
+            }
+            RequestType::Out => {
+                let offset = self.disk_offset(disk)?;
+                disk.mark_dirty(offset, u64::from(self.data_len));
+                engine.push_write(
+                    disk.file(),
+                    offset,
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    pending,
+                )
+            }
+            RequestType::Flush => engine.push_fsync(disk.file(), pending),
+            _ => {
+                return Err(ErrStatus::IoErr(IoErrStatus::BadRequest(
+                    Error::UnsupportedAsyncRequest,
+                )))
+            }
+        }
+        .map_err(|e| ErrStatus::IoErr(IoErrStatus::AsyncSubmit(e)))
+    }
+
     pub(crate) fn execute(
         &self,
         disk: &mut DiskProperties,
         mem: &GuestMemoryMmap,
+        metrics: &BlockMetrics,
     ) -> result::Result<u32, ErrStatus> {
         let cache_type = disk.cache_type();
 
@@ -253,14 +494,14 @@ impl Request {
                 self.execute_seek(disk)?;
                 mem.read_exact_from(self.data_addr, disk.file_mut(), self.data_len as usize)
                     .map(|_| {
-                        METRICS.block.read_bytes.add(self.data_len as usize);
-                        METRICS.block.read_count.inc();
+                        metrics.add(|m| &m.read_bytes, self.data_len as usize);
+                        metrics.inc(|m| &m.read_count);
                         self.data_len
                     })
                     .map_err(|e| {
                         let mut num_used_bytes = self.data_len;
                         if let GuestMemoryError::PartialBuffer { completed, .. } = e {
-                            METRICS.block.read_bytes.add(completed);
+                            metrics.add(|m| &m.read_bytes, completed);
                             // It's safe to cast to u32 since completed < data_len.
                             num_used_bytes = completed as u32;
                         }
@@ -269,31 +510,32 @@ impl Request {
             }
             RequestType::Out => {
                 self.execute_seek(disk)?;
+                disk.mark_dirty(self.sector << SECTOR_SHIFT, u64::from(self.data_len));
                 mem.write_all_to(self.data_addr, disk.file_mut(), self.data_len as usize)
                     .map(|_| {
-                        METRICS.block.write_bytes.add(self.data_len as usize);
-                        METRICS.block.write_count.inc();
+                        metrics.add(|m| &m.write_bytes, self.data_len as usize);
+                        metrics.inc(|m| &m.write_count);
                         0
                     })
                     .map_err(|e| {
                         if let GuestMemoryError::PartialBuffer { completed, .. } = e {
-                            METRICS.block.write_bytes.add(completed);
+                            metrics.add(|m| &m.write_bytes, completed);
                         }
                         ErrStatus::IoErr(IoErrStatus::Write(e))
                     })
             }
             RequestType::Flush => {
                 match cache_type {
-                    CacheType::Writeback => {
+                    CacheType::Writeback | CacheType::Direct => {
                         // flush() first to force any cached data out.
                         disk.file_mut()
                             .flush()
                             .map_err(|e| ErrStatus::IoErr(IoErrStatus::Flush(e)))?;
                         // Sync data out to physical media on host.
-                        disk.file_mut()
+                        disk.file()
                             .sync_all()
                             .map_err(|e| ErrStatus::IoErr(IoErrStatus::SyncAll(e)))?;
-                        METRICS.block.flush_count.inc();
+                        metrics.inc(|m| &m.flush_count);
                     }
                     CacheType::Unsafe => {
                         // This is a noop.
@@ -312,6 +554,34 @@ impl Request {
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(|e| ErrStatus::IoErr(IoErrStatus::Write(e)))
             }
+            RequestType::Discard => {
+                // All the segments are validated before touching the disk.
+                for segment in self.read_segments(disk, mem)? {
+                    let (offset, len) = segment.disk_range();
+                    // The discarded data is unspecified afterwards.
+                    disk.mark_dirty(offset, len);
+                    disk.file_mut()
+                        .discard(offset, len)
+                        .map_err(|e| ErrStatus::IoErr(IoErrStatus::Discard(e)))?;
+                }
+                metrics.inc(|m| &m.discard_count);
+                Ok(0)
+            }
+            RequestType::WriteZeroes => {
+                for segment in self.read_segments(disk, mem)? {
+                    let (offset, len) = segment.disk_range();
+                    disk.mark_dirty(offset, len);
+                    disk.file_mut()
+                        .write_zeroes(
+                            offset,
+                            len,
+                            segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+                        )
+                        .map_err(|e| ErrStatus::IoErr(IoErrStatus::WriteZeroes(e)))?;
+                }
+                metrics.inc(|m| &m.write_zeroes_count);
+                Ok(0)
+            }
             RequestType::Unsupported(op) => Err(ErrStatus::Unsupported(op)),
         }
     }
@@ -337,6 +607,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -363,6 +635,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -434,6 +714,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pending_request_status() {
+        let metrics = BlockMetrics::new("test");
+        let mut pending = PendingRequest {
+            request_type: RequestType::In,
+            data_len: 0x200,
+            status_addr: GuestAddress(0),
+            queue_index: 0,
+            desc_idx: 0,
+            start_us: 0,
+        };
+
+        let status = pending.status(Ok(0x200), &metrics);
+        assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(status.num_used_bytes(), 0x201);
+        let status = pending.status(Ok(0x100), &metrics);
+        assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_IOERR as u8);
+        assert_eq!(status.num_used_bytes(), 0x101);
+        let status = pending.status(Err(io::Error::from_raw_os_error(libc::EIO)), &metrics);
+        assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_IOERR as u8);
+
+        // The requests which don't go through the asynchronous engine fail instead of
+        // crashing the device.
+        pending.request_type = RequestType::GetDeviceID;
+        let status = pending.status(Ok(0), &metrics);
+        assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_IOERR as u8);
+        assert_eq!(status.num_used_bytes(), 1);
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_parse() {
@@ -494,6 +803,17 @@ mod tests {
             ));
         }
 
+        // Write only data for DISCARD and WRITE_ZEROES.
+        for request_type in &[VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let mut q = vq.create_queue();
+            m.write_obj::<u32>(*request_type, GuestAddress(0x1000))
+                .unwrap();
+            assert!(matches!(
+                Request::parse(&q.pop(m).unwrap(), m),
+                Err(Error::UnexpectedWriteOnlyDescriptor)
+            ));
+        }
+
         {
             let mut q = vq.create_queue();
             // Read only data for GetDeviceID.
@@ -603,6 +923,8 @@ mod tests {
             (u32, std::sync::Arc<fn() -> Self>),
             (u32, std::sync::Arc<fn() -> Self>),
             (u32, std::sync::Arc<fn() -> Self>),
+            (u32, std::sync::Arc<fn() -> Self>),
+            (u32, std::sync::Arc<fn() -> Self>),
             (
                 u32,
                 std::sync::Arc<Map<<u32 as Arbitrary>::Strategy, fn(u32) -> Self>>,
@@ -617,13 +939,15 @@ mod tests {
                 (1u32, std::sync::Arc::new(|| RequestType::Out {})),
                 (1u32, std::sync::Arc::new(|| RequestType::Flush {})),
                 (1u32, std::sync::Arc::new(|| RequestType::GetDeviceID {})),
+                (1u32, std::sync::Arc::new(|| RequestType::Discard {})),
+                (1u32, std::sync::Arc::new(|| RequestType::WriteZeroes {})),
                 (
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -642,6 +966,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -654,6 +980,8 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard => VIRTQ_DESC_F_NEXT,
+            RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -793,7 +1121,7 @@ mod tests {
 
                         return match request.request_type {
                             // Readonly buffer is writable.
-                            RequestType::Out => {
+                            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes => {
                                 data_desc_flags |= VIRTQ_DESC_F_WRITE;
                                 vq.dtable[DATA_DESCRIPTOR].flags.set(data_desc_flags);
                                 (Err(Error::UnexpectedWriteOnlyDescriptor), mem, q)
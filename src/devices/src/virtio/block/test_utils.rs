@@ -1,21 +1,27 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::virtio::{Block, CacheType, Queue};
+use crate::virtio::block::NUM_QUEUES;
+use crate::virtio::{Block, CacheType, FileEngineType, ImageFormat, Queue};
 use rate_limiter::RateLimiter;
 use utils::tempfile::TempFile;
 
 /// Create a default Block instance to be used in tests.
 pub fn default_block() -> Block {
+    default_block_with_engine(FileEngineType::Sync)
+}
+
+/// Create a default Block instance using the specified file engine to be used in tests.
+pub fn default_block_with_engine(file_engine_type: FileEngineType) -> Block {
     // Create backing file.
     let f = TempFile::new().unwrap();
     f.as_file().set_len(0x1000).unwrap();
 
-    default_block_with_path(f.as_path().to_str().unwrap().to_string())
+    default_block_with_path(f.as_path().to_str().unwrap().to_string(), file_engine_type)
 }
 
 /// Create a default Block instance using file at the specified path to be used in tests.
-pub fn default_block_with_path(path: String) -> Block {
+pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -> Block {
     // Rate limiting is enabled but with a high operation rate (10 million ops/s).
     let rate_limiter = RateLimiter::new(0, 0, 0, 100_000, 0, 10).unwrap();
 
@@ -25,9 +31,13 @@ pub fn default_block_with_path(path: String) -> Block {
         id,
         None,
         CacheType::Unsafe,
+        ImageFormat::Raw,
+        file_engine_type,
         path,
+        None,
         false,
         false,
+        NUM_QUEUES,
         rate_limiter,
     )
     .unwrap()
@@ -37,7 +47,7 @@ pub fn invoke_handler_for_queue_event(b: &mut Block) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     assert_eq!(b.interrupt_evt.read().unwrap(), 1);
 }
@@ -49,6 +49,12 @@ pub trait VirtioDevice: AsAny + Send {
     /// Returns the device queues event fds.
     fn queue_events(&self) -> &[EventFd];
 
+    /// Returns the number of queues the driver has to set up before activating the device.
+    /// The driver can leave the remaining queues unused.
+    fn num_required_queues(&self) -> usize {
+        self.queues().len()
+    }
+
     /// Returns the device interrupt eventfd.
     fn interrupt_evt(&self) -> &EventFd;
 
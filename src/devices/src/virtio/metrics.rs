@@ -0,0 +1,59 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Handles on the metrics of the virtio devices which are also broken down by device id.
+
+use std::sync::Arc;
+
+use logger::{IncMetric, PerDeviceMetrics, SharedIncMetric};
+
+/// Metrics type shared by all the devices of a kind.
+pub trait DeviceMetricsType: Default + Sized + 'static {
+    /// Metrics of all the devices of this kind.
+    fn global() -> &'static Self;
+    /// Metrics of the devices of this kind, by device id.
+    fn per_device() -> &'static PerDeviceMetrics<Self>;
+}
+
+/// Metrics of a device, accounted both in the global metrics of its kind and
+/// in the metrics of the device id.
+pub struct DeviceMetrics<M: DeviceMetricsType> {
+    device: Arc<M>,
+}
+
+impl<M: DeviceMetricsType> Clone for DeviceMetrics<M> {
+    fn clone(&self) -> Self {
+        DeviceMetrics {
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<M: DeviceMetricsType> DeviceMetrics<M> {
+    /// Creates the metrics handle of the `device_id` device.
+    pub fn new(device_id: &str) -> Self {
+        DeviceMetrics {
+            device: M::per_device().get(device_id),
+        }
+    }
+
+    /// Returns the metrics of this device only.
+    pub fn device(&self) -> &M {
+        &self.device
+    }
+
+    pub fn inc<F>(&self, metric: F)
+    where
+        F: Fn(&M) -> &SharedIncMetric,
+    {
+        self.add(metric, 1);
+    }
+
+    pub fn add<F>(&self, metric: F, value: usize)
+    where
+        F: Fn(&M) -> &SharedIncMetric,
+    {
+        metric(M::global()).add(value);
+        metric(&self.device).add(value);
+    }
+}
@@ -84,10 +84,13 @@ impl MmioTransport {
     }
 
     fn are_queues_valid(&self) -> bool {
-        self.locked_device()
+        let device = self.locked_device();
+        let num_required_queues = device.num_required_queues();
+        device
             .queues()
             .iter()
-            .all(|q| q.is_valid(&self.mem))
+            .enumerate()
+            .all(|(i, q)| (i >= num_required_queues && !q.ready) || q.is_valid(&self.mem))
     }
 
     fn with_queue<U, F>(&self, d: U, f: F) -> U
@@ -149,6 +152,17 @@ impl MmioTransport {
         }
     }
 
+    // The layout of the queues depends on the negotiated features, and has to be known before
+    // the queues are validated.
+    fn set_queues_layout(&mut self) {
+        let mut device = self.locked_device();
+        if device.acked_features() & (1 << VIRTIO_F_RING_PACKED) != 0 {
+            for queue in device.queues_mut() {
+                queue.enable_packed_ring();
+            }
+        }
+    }
+
     /// Update device status according to the state machine defined by VirtIO Spec 1.0.
     /// Please refer to VirtIO Spec 1.0, section 2.1.1 and 3.1.1.
     ///
@@ -173,6 +187,9 @@ impl MmioTransport {
             DRIVER_OK if self.device_status == (ACKNOWLEDGE | DRIVER | FEATURES_OK) => {
                 self.device_status = status;
                 let device_activated = self.locked_device().is_activated();
+                if !device_activated {
+                    self.set_queues_layout();
+                }
                 if !device_activated && self.are_queues_valid() {
                     self.locked_device()
                         .activate(self.mem.clone())
@@ -320,7 +337,11 @@ impl BusDevice for MmioTransport {
         }
     }
 
-    fn interrupt(&self, irq_mask: u32) -> std::io::Result<()> {
+    fn interrupt(&mut self, irq_mask: u32) -> std::io::Result<()> {
+        // The driver relies on the generation to detect device configuration changes.
+        if irq_mask & VIRTIO_MMIO_INT_CONFIG != 0 {
+            self.config_generation = self.config_generation.wrapping_add(1);
+        }
         self.interrupt_status
             .fetch_or(irq_mask as usize, Ordering::SeqCst);
         // interrupt_evt() is safe to unwrap because the inner interrupt_evt is initialized in the
@@ -346,6 +367,7 @@ pub(crate) mod tests {
         interrupt_status: Arc<AtomicUsize>,
         queue_evts: Vec<EventFd>,
         queues: Vec<Queue>,
+        num_required_queues: usize,
         device_activated: bool,
         config_bytes: [u8; 0xeff],
     }
@@ -362,6 +384,7 @@ pub(crate) mod tests {
                     EventFd::new(libc::EFD_NONBLOCK).unwrap(),
                 ],
                 queues: vec![Queue::new(16), Queue::new(32)],
+                num_required_queues: 2,
                 device_activated: false,
                 config_bytes: [0; 0xeff],
             }
@@ -416,6 +439,10 @@ pub(crate) mod tests {
             &self.queue_evts
         }
 
+        fn num_required_queues(&self) -> usize {
+            self.num_required_queues
+        }
+
         fn interrupt_evt(&self) -> &EventFd {
             &self.interrupt_evt
         }
@@ -782,6 +809,46 @@ pub(crate) mod tests {
         assert_eq!(read_le_u32(&buf[..]), 1);
     }
 
+    #[test]
+    fn test_packed_queues_layout() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut dummy = DummyDevice::new();
+        dummy.set_acked_features(1 << VIRTIO_F_RING_PACKED);
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(dummy)));
+
+        set_device_status(&mut d, device_status::ACKNOWLEDGE);
+        set_device_status(&mut d, device_status::ACKNOWLEDGE | device_status::DRIVER);
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK,
+        );
+
+        // The size of packed queues does not have to be a power of 2.
+        let mut buf = vec![0; 4];
+        for q in 0..2 {
+            d.queue_select = q;
+            write_le_u32(&mut buf[..], 12);
+            d.write(0x38, &buf[..]);
+            write_le_u32(&mut buf[..], 1);
+            d.write(0x44, &buf[..]);
+        }
+        assert!(!d.are_queues_valid());
+
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE
+                | device_status::DRIVER
+                | device_status::FEATURES_OK
+                | device_status::DRIVER_OK,
+        );
+        assert!(d.locked_device().is_activated());
+        assert!(d
+            .locked_device()
+            .queues()
+            .iter()
+            .all(|q| q.uses_packed_ring));
+    }
+
     fn activate_device(d: &mut MmioTransport) {
         set_device_status(d, device_status::ACKNOWLEDGE);
         set_device_status(d, device_status::ACKNOWLEDGE | device_status::DRIVER);
@@ -821,6 +888,46 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_unused_queues() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut dummy = DummyDevice::new();
+        dummy.num_required_queues = 1;
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(dummy)));
+        let mut buf = vec![0; 4];
+
+        set_device_status(&mut d, device_status::ACKNOWLEDGE);
+        set_device_status(&mut d, device_status::ACKNOWLEDGE | device_status::DRIVER);
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK,
+        );
+
+        // The second queue can be left unused.
+        d.queue_select = 0;
+        write_le_u32(&mut buf[..], 16);
+        d.write(0x38, &buf[..]);
+        write_le_u32(&mut buf[..], 1);
+        d.write(0x44, &buf[..]);
+        assert!(d.are_queues_valid());
+
+        // Once set up, it has to be valid though.
+        d.queue_select = 1;
+        write_le_u32(&mut buf[..], 3);
+        d.write(0x38, &buf[..]);
+        write_le_u32(&mut buf[..], 1);
+        d.write(0x44, &buf[..]);
+        assert!(!d.are_queues_valid());
+
+        // The first queue is always required.
+        d.queue_select = 0;
+        write_le_u32(&mut buf[..], 0);
+        d.write(0x44, &buf[..]);
+        d.queue_select = 1;
+        d.write(0x44, &buf[..]);
+        assert!(!d.are_queues_valid());
+    }
+
     #[test]
     fn test_bus_device_reset() {
         let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -845,6 +952,39 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_bus_device_interrupt() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+        let mut buf = vec![0; 4];
+
+        // Used ring notifications leave the configuration generation as is.
+        d.interrupt(VIRTIO_MMIO_INT_VRING).unwrap();
+        assert_eq!(
+            d.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        assert_eq!(d.locked_device().interrupt_evt().read().unwrap(), 1);
+        d.read(0xfc, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+
+        // Configuration change notifications bump it.
+        d.interrupt(VIRTIO_MMIO_INT_CONFIG).unwrap();
+        assert_eq!(
+            d.interrupt_status.load(Ordering::SeqCst),
+            (VIRTIO_MMIO_INT_VRING | VIRTIO_MMIO_INT_CONFIG) as usize
+        );
+        assert_eq!(d.locked_device().interrupt_evt().read().unwrap(), 1);
+        d.read(0xfc, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 1);
+
+        // The generation keeps increasing across resets.
+        write_le_u32(&mut buf[..], 0);
+        d.write(0x70, &buf[..]);
+        d.interrupt(VIRTIO_MMIO_INT_CONFIG).unwrap();
+        assert_eq!(d.config_generation, 2);
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
@@ -12,11 +12,14 @@ use std::io::Error as IOError;
 pub mod balloon;
 pub mod block;
 pub mod device;
+pub mod metrics;
 mod mmio;
 pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_user;
+pub mod vhost_user_block;
 pub mod vsock;
 
 pub use self::balloon::*;
@@ -26,6 +29,7 @@ pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_user_block::VhostUserBlock;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
@@ -0,0 +1,178 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filter of the frames sent by the guest with forged source addresses.
+
+use std::net::Ipv4Addr;
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::IPv4Packet;
+use utils::net::mac::MacAddr;
+
+// Length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+
+/// Only lets through the frames sent from the guest MAC address and, for IPv4 packets and ARP
+/// messages, from one of the allowed IPv4 addresses. The unspecified address is allowed as
+/// well, since the guest uses it while acquiring an address through DHCP or probing for
+/// conflicts. Frames of other protocols are only checked for their source MAC address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AntiSpoofingFilter {
+    guest_mac: MacAddr,
+    allowed_ipv4_addrs: Vec<Ipv4Addr>,
+}
+
+impl AntiSpoofingFilter {
+    pub fn new(guest_mac: MacAddr, allowed_ipv4_addrs: Vec<Ipv4Addr>) -> Self {
+        AntiSpoofingFilter {
+            guest_mac,
+            allowed_ipv4_addrs,
+        }
+    }
+
+    /// IPv4 addresses the guest can send from.
+    pub fn allowed_ipv4_addrs(&self) -> &[Ipv4Addr] {
+        &self.allowed_ipv4_addrs
+    }
+
+    fn ipv4_addr_allowed(&self, addr: Ipv4Addr) -> bool {
+        addr.is_unspecified() || self.allowed_ipv4_addrs.contains(&addr)
+    }
+
+    /// Says if the Ethernet `frame` can be sent. Malformed frames are not.
+    pub fn allows(&self, frame: &[u8]) -> bool {
+        let eth_frame = match EthernetFrame::from_bytes(frame) {
+            Ok(eth_frame) => eth_frame,
+            Err(_) => return false,
+        };
+        if eth_frame.src_mac() != self.guest_mac {
+            return false;
+        }
+
+        // The payload may be padded up to the minimum Ethernet frame length.
+        let payload = eth_frame.payload();
+        match eth_frame.ethertype() {
+            ETHERTYPE_IPV4 => {
+                if payload.len() < IPV4_MIN_HEADER_LEN {
+                    return false;
+                }
+                let total_len = IPv4Packet::from_bytes_unchecked(payload).total_len() as usize;
+                payload
+                    .get(..total_len)
+                    .and_then(|packet| IPv4Packet::from_bytes(packet, false).ok())
+                    .map_or(false, |packet| {
+                        self.ipv4_addr_allowed(packet.source_address())
+                    })
+            }
+            ETHERTYPE_ARP => {
+                if payload.len() < ETH_IPV4_FRAME_LEN {
+                    return false;
+                }
+                match EthIPv4ArpFrame::from_bytes(&payload[..ETH_IPV4_FRAME_LEN]) {
+                    Ok(arp_frame) => {
+                        arp_frame.sha() == self.guest_mac && self.ipv4_addr_allowed(arp_frame.spa())
+                    }
+                    Err(_) => false,
+                }
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dumbo::pdu::ipv4::PROTOCOL_UDP;
+
+    const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+    fn ipv4_frame(buf: &mut [u8], src_mac: MacAddr, src_addr: Ipv4Addr) -> usize {
+        let mut eth_frame = EthernetFrame::write_incomplete(
+            &mut buf[..],
+            MacAddr::from_bytes_unchecked(&[0xff; 6]),
+            src_mac,
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let packet_len = IPv4Packet::write_header(
+            eth_frame.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            src_addr,
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap()
+        .with_payload_len_unchecked(0, true)
+        .len();
+        eth_frame.with_payload_len_unchecked(packet_len).len()
+    }
+
+    fn arp_frame(buf: &mut [u8], src_mac: MacAddr, sha: MacAddr, spa: Ipv4Addr) -> usize {
+        let mut eth_frame = EthernetFrame::write_incomplete(
+            &mut buf[..],
+            MacAddr::from_bytes_unchecked(&[0xff; 6]),
+            src_mac,
+            ETHERTYPE_ARP,
+        )
+        .unwrap();
+        EthIPv4ArpFrame::write_reply(
+            &mut eth_frame.inner_mut().payload_mut()[..ETH_IPV4_FRAME_LEN],
+            sha,
+            spa,
+            sha,
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap();
+        eth_frame
+            .with_payload_len_unchecked(ETH_IPV4_FRAME_LEN)
+            .len()
+    }
+
+    #[test]
+    fn test_anti_spoofing_filter() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let other_mac = MacAddr::parse_str("12:34:56:78:9a:bd").unwrap();
+        let guest_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let other_addr = Ipv4Addr::new(10, 0, 0, 3);
+        let filter = AntiSpoofingFilter::new(guest_mac, vec![guest_addr]);
+        assert_eq!(filter.allowed_ipv4_addrs(), &[guest_addr]);
+        let mut buf = [0u8; 100];
+
+        let len = ipv4_frame(&mut buf, guest_mac, guest_addr);
+        assert!(filter.allows(&buf[..len]));
+        let len = ipv4_frame(&mut buf, guest_mac, Ipv4Addr::UNSPECIFIED);
+        assert!(filter.allows(&buf[..len]));
+        let len = ipv4_frame(&mut buf, guest_mac, other_addr);
+        assert!(!filter.allows(&buf[..len]));
+        let len = ipv4_frame(&mut buf, other_mac, guest_addr);
+        assert!(!filter.allows(&buf[..len]));
+        // Padded packet.
+        let len = ipv4_frame(&mut buf, guest_mac, guest_addr);
+        assert!(len < 60);
+        assert!(filter.allows(&buf[..60]));
+        // Truncated packet.
+        let len = ipv4_frame(&mut buf, guest_mac, guest_addr);
+        assert!(!filter.allows(&buf[..len - 1]));
+
+        let len = arp_frame(&mut buf, guest_mac, guest_mac, guest_addr);
+        assert!(filter.allows(&buf[..len]));
+        // Padded and truncated messages.
+        assert!(filter.allows(&buf[..60]));
+        assert!(!filter.allows(&buf[..len - 1]));
+        let len = arp_frame(&mut buf, guest_mac, other_mac, guest_addr);
+        assert!(!filter.allows(&buf[..len]));
+        let len = arp_frame(&mut buf, guest_mac, guest_mac, other_addr);
+        assert!(!filter.allows(&buf[..len]));
+
+        // Only the source MAC address of the other protocols is checked.
+        EthernetFrame::write_incomplete(&mut buf[..], other_mac, guest_mac, ETHERTYPE_IPV6)
+            .unwrap();
+        assert!(filter.allows(&buf[..60]));
+        EthernetFrame::write_incomplete(&mut buf[..], guest_mac, other_mac, ETHERTYPE_IPV6)
+            .unwrap();
+        assert!(!filter.allows(&buf[..60]));
+        assert!(!filter.allows(&buf[..10]));
+    }
+}
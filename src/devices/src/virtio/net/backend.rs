@@ -0,0 +1,58 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host side of the queue pairs of a network device.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::net::{Error, Result};
+
+/// Exchanges the frames of a queue pair with the host. The frames are preceded by their
+/// virtio-net header, and the file descriptor is readable when frames are pending.
+pub trait NetBackend: AsRawFd + Send {
+    /// Reads the next pending frame into `buf`, returning its length.
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Sends the frame found in `buf`.
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Lets the backend deliver frames to this queue pair, or not.
+    fn set_queue_enabled(&self, enabled: bool) -> Result<()>;
+
+    /// Largest MTU the backend can carry.
+    fn mtu(&self) -> Result<u16>;
+
+    /// Provides the tap device behind the backend, if any.
+    fn tap(&self) -> Option<&Tap> {
+        None
+    }
+
+    /// Provides the path of the socket the frames are exchanged over, if any.
+    fn socket_path(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl NetBackend for Tap {
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        Tap::set_queue_enabled(self, enabled).map_err(Error::TapSetQueue)
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Tap::mtu(self).map_err(Error::TapGetMtu)
+    }
+
+    fn tap(&self) -> Option<&Tap> {
+        Some(self)
+    }
+}
@@ -5,14 +5,24 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use crate::virtio::net::anti_spoofing::AntiSpoofingFilter;
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::dhcp::{DhcpLease, DhcpResponder};
+use crate::virtio::net::metrics::NetMetrics;
+use crate::virtio::net::pcap::PcapWriter;
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::unix_socket::UnixSocketBackend;
+use crate::virtio::net::vhost::{self, VhostNetBackend};
 use crate::virtio::net::Error;
 use crate::virtio::net::Result;
-use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX};
+use crate::virtio::net::{
+    MAX_BUFFER_SIZE, MIN_MTU, NUM_QUEUES, NUM_QUEUE_PAIRS, QUEUE_SIZE, RX_INDEX, TX_INDEX,
+};
 use crate::virtio::{
-    ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
+    ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_F_RING_PACKED,
+    VIRTIO_MMIO_INT_VRING,
 };
 use crate::{report_net_event_fail, Error as DeviceError};
 
@@ -23,8 +33,9 @@ use mmds::ns::MmdsNetworkStack;
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 #[cfg(not(test))]
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -32,10 +43,14 @@ use std::{cmp, mem, result};
 use utils::eventfd::EventFd;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK,
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
+    VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE, VIRTIO_NET_F_GUEST_CSUM,
     VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_MTU,
+    VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_ANNOUNCE, VIRTIO_NET_S_LINK_UP,
 };
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 enum FrontendError {
@@ -46,6 +61,17 @@ enum FrontendError {
     ReadOnlyDescriptor,
 }
 
+// Control queue commands are made of a class, a command and the command specific data. Only
+// the commands changing the number of queue pairs and acknowledging the announcements are
+// supported, which are much shorter.
+const CTRL_COMMAND_MAX_LEN: usize = 16;
+
+// Virtio features which are offered to the guest on top of the regular ones when the queues
+// are processed by the vhost-net driver, if the driver supports them.
+const VHOST_NET_EXTRA_FEATURES: u64 = (1u64 << VIRTIO_NET_F_MRG_RXBUF)
+    | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+    | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+
 pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -68,8 +94,21 @@ fn frame_bytes_from_buf_mut(buf: &mut [u8]) -> Result<&mut [u8]> {
     }
 }
 
+// Appends the frame found in `frame_buf` to the capture, if any. The capture is stopped when
+// the frame cannot be written.
+fn capture_frame(pcap: &mut Option<PcapWriter>, frame_buf: &[u8]) {
+    if let Some(writer) = pcap.as_mut() {
+        if let Ok(frame) = frame_bytes_from_buf(frame_buf) {
+            if let Err(e) = writer.write_frame(frame) {
+                error!("Failed to capture frame, stopping the capture: {:?}", e);
+                *pcap = None;
+            }
+        }
+    }
+}
+
 // This initializes to all 0 the VNET hdr part of a buf.
-fn init_vnet_hdr(buf: &mut [u8]) {
+pub(crate) fn init_vnet_hdr(buf: &mut [u8]) {
     // The buffer should be larger than vnet_hdr_len.
     // TODO: any better way to set all these bytes to 0? Or is this optimized by the compiler?
     for i in &mut buf[0..vnet_hdr_len()] {
@@ -78,24 +117,82 @@ fn init_vnet_hdr(buf: &mut [u8]) {
 }
 
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: [u8; MAC_ADDR_LEN],
+    // Only meaningful when VIRTIO_NET_F_STATUS is offered.
+    pub status: u16,
+    // Only meaningful when VIRTIO_NET_F_MQ is offered.
+    pub max_virtqueue_pairs: u16,
+    // Only meaningful when VIRTIO_NET_F_MTU is offered.
+    pub mtu: u16,
 }
 
 impl Default for ConfigSpace {
     fn default() -> ConfigSpace {
         ConfigSpace {
             guest_mac: [0; MAC_ADDR_LEN],
+            status: 0,
+            max_virtqueue_pairs: 0,
+            mtu: 0,
         }
     }
 }
 
 unsafe impl ByteValued for ConfigSpace {}
 
+/// A RX/TX virtqueue pair, backed by its own queue of the tap interface or by its own
+/// connection to a userspace switch.
+pub struct QueuePair {
+    pub(crate) backend: Box<dyn NetBackend>,
+
+    pub(crate) rx_deferred_frame: bool,
+
+    rx_bytes_read: usize,
+    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
+}
+
+impl QueuePair {
+    fn new(backend: Box<dyn NetBackend>) -> Self {
+        QueuePair {
+            backend,
+            rx_deferred_frame: false,
+            rx_bytes_read: 0,
+            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+        }
+    }
+}
+
+impl AsRawFd for QueuePair {
+    fn as_raw_fd(&self) -> RawFd {
+        self.backend.as_raw_fd()
+    }
+}
+
+// Index of the RX queue of a queue pair, in the Net device queues/queues_evts vector.
+fn rx_queue_index(pair: usize) -> usize {
+    pair * NUM_QUEUES + RX_INDEX
+}
+
+// Index of the TX queue of a queue pair, in the Net device queues/queues_evts vector.
+fn tx_queue_index(pair: usize) -> usize {
+    pair * NUM_QUEUES + TX_INDEX
+}
+
+// Offloads handled by the tap device, on behalf of the guest and of the host.
+const TAP_OFFLOAD_FEATURES: u64 = 1 << VIRTIO_NET_F_GUEST_CSUM
+    | 1 << VIRTIO_NET_F_CSUM
+    | 1 << VIRTIO_NET_F_GUEST_TSO4
+    | 1 << VIRTIO_NET_F_GUEST_UFO
+    | 1 << VIRTIO_NET_F_HOST_TSO4
+    | 1 << VIRTIO_NET_F_HOST_UFO;
+
 pub struct Net {
     pub(crate) id: String,
 
-    pub tap: Tap,
+    pub(crate) queue_pairs: Vec<QueuePair>,
+    // Number of queue pairs used by the driver, starting with the first one.
+    pub(crate) active_queue_pairs: usize,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -106,12 +203,8 @@ pub struct Net {
     pub(crate) rx_rate_limiter: RateLimiter,
     pub(crate) tx_rate_limiter: RateLimiter,
 
-    pub(crate) rx_deferred_frame: bool,
     rx_deferred_irqs: bool,
 
-    rx_bytes_read: usize,
-    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
-
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
 
@@ -126,41 +219,126 @@ pub struct Net {
 
     pub(crate) mmds_ns: Option<MmdsNetworkStack>,
 
+    // Set when the queues are processed by the vhost-net driver.
+    pub(crate) vhost: Option<VhostNetBackend>,
+    // Signaled by the vhost-net driver when it uses buffers from the queue found at the
+    // same index.
+    pub(crate) vhost_call_evts: Vec<EventFd>,
+
+    // Set while the frames going through the device are captured.
+    pub(crate) pcap: Option<PcapWriter>,
+
+    // Drops the frames sent with forged source addresses, when set.
+    pub(crate) anti_spoofing: Option<AntiSpoofingFilter>,
+
+    // Answers the DHCP requests of the guest, when set.
+    pub(crate) dhcp: Option<DhcpResponder>,
+
+    pub(crate) metrics: NetMetrics,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
+    /// Create a new virtio network device with the given TAP interface. Each of the
+    /// `num_queue_pairs` RX/TX queue pairs is served by its own queue of the interface.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
         guest_mac: Option<&MacAddr>,
+        num_queue_pairs: u16,
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
         allow_mmds_requests: bool,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
-
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let taps = if num_queue_pairs > NUM_QUEUE_PAIRS {
+            Tap::open_named_multi_queue(&tap_if_name, usize::from(num_queue_pairs))
+        } else {
+            Tap::open_named(&tap_if_name).map(|tap| vec![tap])
+        }
+        .map_err(Error::TapOpen)?;
+
+        // The offload flags and the vnet header size are shared by all the queues of the
+        // interface. Set offload flags to match the virtio features below.
+        taps[0]
+            .set_offload(
+                net_gen::TUN_F_CSUM
+                    | net_gen::TUN_F_UFO
+                    | net_gen::TUN_F_TSO4
+                    | net_gen::TUN_F_TSO6,
+            )
+            .map_err(Error::TapSetOffload)?;
 
         let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
+        taps[0]
+            .set_vnet_hdr_size(vnet_hdr_size)
             .map_err(Error::TapSetVnetHdrSize)?;
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
+        // Only the first queue pair is used until the driver enables the other ones.
+        for tap in taps.iter().skip(1) {
+            tap.set_queue_enabled(false).map_err(Error::TapSetQueue)?;
+        }
+
+        Self::new_with_backends(
+            id,
+            taps.into_iter()
+                .map(|tap| Box::new(tap) as Box<dyn NetBackend>)
+                .collect(),
+            TAP_OFFLOAD_FEATURES,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            allow_mmds_requests,
+        )
+    }
+
+    /// Create a new virtio network device exchanging its frames with the userspace switch
+    /// listening on the unix socket found at `socket_path`. The device has a single RX/TX
+    /// queue pair and offers no offloads, since the switch only handles complete frames.
+    pub fn new_with_socket(
+        id: String,
+        socket_path: String,
+        guest_mac: Option<&MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        allow_mmds_requests: bool,
+    ) -> Result<Self> {
+        let backend = UnixSocketBackend::connect(&socket_path).map_err(Error::SocketConnect)?;
+        Self::new_with_backends(
+            id,
+            vec![Box::new(backend)],
+            0,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            allow_mmds_requests,
+        )
+    }
+
+    // Creates a device with a queue pair for each of the `backends`, offering the
+    // `offload_features` on top of the ones common to all backends.
+    fn new_with_backends(
+        id: String,
+        backends: Vec<Box<dyn NetBackend>>,
+        offload_features: u64,
+        guest_mac: Option<&MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        allow_mmds_requests: bool,
+    ) -> Result<Self> {
+        let num_queue_pairs = backends.len() as u16;
+        let mut avail_features = offload_features
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_F_RING_PACKED
             | 1 << VIRTIO_F_VERSION_1;
 
         let mut config_space = ConfigSpace::default();
+        config_space.status = VIRTIO_NET_S_LINK_UP as u16;
         if let Some(mac) = guest_mac {
             config_space.guest_mac.copy_from_slice(mac.get_bytes());
             // When this feature isn't available, the driver generates a random MAC address.
@@ -168,31 +346,38 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
+        // The queue pairs are followed by the control queue.
+        let num_queues = usize::from(num_queue_pairs) * NUM_QUEUES + 1;
+        if num_queue_pairs > NUM_QUEUE_PAIRS {
+            // The driver enables the additional queue pairs through the control queue.
+            avail_features |= 1 << VIRTIO_NET_F_MQ;
+            config_space.max_virtqueue_pairs = num_queue_pairs;
+        }
+
         let mut queue_evts = Vec::new();
-        for _ in QUEUE_SIZES.iter() {
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
         }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         let mmds_ns = if allow_mmds_requests {
             Some(MmdsNetworkStack::new_with_defaults(None))
         } else {
             None
         };
+        let metrics = NetMetrics::new(&id);
         Ok(Net {
             id,
-            tap,
+            queue_pairs: backends.into_iter().map(QueuePair::new).collect(),
+            active_queue_pairs: 1,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
             rx_rate_limiter,
             tx_rate_limiter,
-            rx_deferred_frame: false,
             rx_deferred_irqs: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
             interrupt_status: Arc::new(AtomicUsize::new(0)),
@@ -202,6 +387,12 @@ impl Net {
             config_space,
             mmds_ns,
             guest_mac: guest_mac.copied(),
+            vhost: None,
+            vhost_call_evts: Vec::new(),
+            pcap: None,
+            anti_spoofing: None,
+            dhcp: None,
+            metrics,
 
             #[cfg(test)]
             mocks: Mocks::default(),
@@ -213,14 +404,263 @@ impl Net {
         &self.id
     }
 
+    /// Provides the metrics of this net device.
+    pub fn metrics(&self) -> &NetMetrics {
+        &self.metrics
+    }
+
     /// Provides the MAC of this net device.
     pub fn guest_mac(&self) -> Option<&MacAddr> {
         self.guest_mac.as_ref()
     }
 
-    /// Provides the host IFACE name of this net device.
+    /// Provides the host IFACE name of this net device, empty when the frames are exchanged
+    /// over a unix socket.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.queue_pairs[0]
+            .backend
+            .tap()
+            .map_or_else(String::new, |tap| tap.if_name_as_str().to_string())
+    }
+
+    /// Provides the path of the unix socket the frames of this net device are exchanged over,
+    /// if any.
+    pub fn socket_path(&self) -> Option<&str> {
+        self.queue_pairs[0].backend.socket_path()
+    }
+
+    /// Provides the number of RX/TX queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> u16 {
+        self.queue_pairs.len() as u16
+    }
+
+    /// Provides the number of RX/TX queue pairs enabled by the driver.
+    pub fn active_queue_pairs(&self) -> u16 {
+        self.active_queue_pairs as u16
+    }
+
+    // Index of the control queue, missing from the devices handled by the vhost-net driver and
+    // from the ones restored from older snapshots.
+    fn ctrl_queue_index(&self) -> Option<usize> {
+        let num_data_queues = self.queue_pairs.len() * NUM_QUEUES;
+        if num_data_queues >= self.queues.len() {
+            return None;
+        }
+        // Drivers which do not negotiate multiple queue pairs place the control queue right
+        // after the first one.
+        if self.acked_features & (1 << VIRTIO_NET_F_MQ) == 0 {
+            Some(NUM_QUEUES)
+        } else {
+            Some(num_data_queues)
+        }
+    }
+
+    /// Removes the control queue, along with the features relying on it. Only the first queue
+    /// pair can be used afterwards.
+    pub(crate) fn remove_ctrl_queue(&mut self) {
+        let num_queues = self.queue_pairs.len() * NUM_QUEUES;
+        self.queues.truncate(num_queues);
+        self.queue_evts.truncate(num_queues);
+        self.avail_features &=
+            !(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE);
+    }
+
+    /// Enables the first `num_pairs` queue pairs and disables the other ones. The queues of
+    /// the enabled pairs have to be set up by the driver.
+    pub fn set_active_queue_pairs(&mut self, num_pairs: u16) -> Result<()> {
+        let new_pairs = usize::from(num_pairs);
+        if new_pairs == 0
+            || new_pairs > self.queue_pairs.len()
+            || self.queues[..new_pairs * NUM_QUEUES]
+                .iter()
+                .any(|queue| !queue.ready)
+        {
+            return Err(Error::InvalidNumQueuePairs(num_pairs));
+        }
+
+        let old_pairs = self.active_queue_pairs;
+        for queue_pair in &self.queue_pairs[new_pairs..cmp::max(old_pairs, new_pairs)] {
+            queue_pair.backend.set_queue_enabled(false)?;
+        }
+        for queue_pair in &self.queue_pairs[old_pairs..cmp::max(old_pairs, new_pairs)] {
+            queue_pair.backend.set_queue_enabled(true)?;
+        }
+        self.active_queue_pairs = new_pairs;
+
+        Ok(())
+    }
+
+    /// Hands the queues over to the kernel vhost-net driver once the device is activated. The
+    /// frames then move between the guest memory and the tap device without going through the
+    /// VMM, so neither the MMDS nor the rate limiters apply to them.
+    pub fn enable_vhost_net(&mut self) -> Result<()> {
+        // The driver only exchanges frames with tap devices.
+        if self.queue_pairs[0].backend.tap().is_none() {
+            return Err(Error::VhostNetSocketBackend);
+        }
+        // Each vhost-net instance only handles the RX and TX queues of one pair.
+        if self.queue_pairs.len() != 1 {
+            return Err(Error::VhostNetMultiQueue);
+        }
+        let vhost = VhostNetBackend::new().map_err(Error::VhostNet)?;
+        // The frames carry the same header as with the userspace device model.
+        let required_features = 1u64 << VIRTIO_F_VERSION_1;
+        if vhost.features() & required_features != required_features {
+            return Err(Error::VhostNetMissingFeatures(
+                required_features & !vhost.features(),
+            ));
+        }
+
+        // The vhost-net driver only processes the data queues.
+        self.remove_ctrl_queue();
+        let mut vhost_call_evts = Vec::new();
+        for _ in 0..self.queues.len() {
+            vhost_call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+
+        // The ring features are handled by the vhost-net driver as well.
+        self.avail_features &= !(1 << VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_F_RING_PACKED);
+        self.avail_features |= vhost.features() & VHOST_NET_EXTRA_FEATURES;
+        self.vhost = Some(vhost);
+        self.vhost_call_evts = vhost_call_evts;
+        Ok(())
+    }
+
+    /// Says if the queues of this device are processed by the vhost-net driver.
+    pub fn vhost_net_enabled(&self) -> bool {
+        self.vhost.is_some()
+    }
+
+    // Shares the guest memory with the vhost-net driver and hands the queues over.
+    fn setup_vhost_net(&self, vhost: &VhostNetBackend, mem: &GuestMemoryMmap) -> vhost::Result<()> {
+        // The driver only knows about the features related to the queues, the offloads are
+        // handled by the tap device.
+        vhost.set_features(self.acked_features & vhost.features())?;
+        vhost.set_mem_table(mem)?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            vhost.set_vring(
+                mem,
+                index,
+                queue,
+                &self.queue_evts[index],
+                &self.vhost_call_evts[index],
+            )?;
+            // Both queues of a pair are backed by the same tap queue.
+            if let Some(tap) = self.queue_pairs[index / NUM_QUEUES].backend.tap() {
+                vhost.set_backend(index, tap)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_vhost_call_event(&mut self, queue_index: usize) {
+        if let Err(e) = self.vhost_call_evts[queue_index].read() {
+            error!("Failed to get vhost-net call event: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+        } else {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    /// Starts writing the frames received and sent by the device, including the ones exchanged
+    /// with the MMDS, to the pcap file found at `path`. Replaces any ongoing capture.
+    pub fn start_capture(&mut self, path: &str, snaplen: u32, max_size: u64) -> Result<()> {
+        if self.vhost.is_some() {
+            return Err(Error::CaptureVhostNet);
+        }
+        self.pcap = Some(PcapWriter::new(path, snaplen, max_size).map_err(Error::Capture)?);
+        Ok(())
+    }
+
+    /// Stops the ongoing capture, if any.
+    pub fn stop_capture(&mut self) {
+        self.pcap = None;
+    }
+
+    /// Says if the frames going through the device are captured.
+    pub fn capture_enabled(&self) -> bool {
+        self.pcap.is_some()
+    }
+
+    /// Drops the frames sent by the guest from another MAC address than the one of the device,
+    /// as well as the IPv4 packets and ARP messages sent from addresses which are not part of
+    /// `allowed_ipv4_addrs`.
+    pub fn enable_anti_spoofing(&mut self, allowed_ipv4_addrs: Vec<Ipv4Addr>) -> Result<()> {
+        let guest_mac = self.guest_mac.ok_or(Error::AntiSpoofingMacMissing)?;
+        self.anti_spoofing = Some(AntiSpoofingFilter::new(guest_mac, allowed_ipv4_addrs));
+        Ok(())
+    }
+
+    /// IPv4 addresses the guest can send from, when the anti-spoofing filter is enabled.
+    pub fn anti_spoofing_ipv4_addrs(&self) -> Option<&[Ipv4Addr]> {
+        self.anti_spoofing
+            .as_ref()
+            .map(AntiSpoofingFilter::allowed_ipv4_addrs)
+    }
+
+    /// Answers the DHCP requests sent by the guest with the given lease, instead of forwarding
+    /// them to the tap.
+    pub fn enable_dhcp(&mut self, lease: DhcpLease) {
+        self.dhcp = Some(DhcpResponder::new(lease));
+    }
+
+    /// Network configuration handed out to the guest, when the DHCP responder is enabled.
+    pub fn dhcp_lease(&self) -> Option<&DhcpLease> {
+        self.dhcp.as_ref().map(DhcpResponder::lease)
+    }
+
+    /// Advertises `mtu` to the driver, which has to fit the MTU of the tap interface, if any.
+    pub fn set_mtu(&mut self, mtu: u16) -> Result<()> {
+        let backend_mtu = self.queue_pairs[0].backend.mtu()?;
+        if mtu < MIN_MTU || mtu > backend_mtu {
+            return Err(Error::InvalidMtu(mtu));
+        }
+        self.config_space.mtu = mtu;
+        self.avail_features |= 1 << VIRTIO_NET_F_MTU;
+        Ok(())
+    }
+
+    /// Provides the MTU advertised to the driver, if any.
+    pub fn mtu(&self) -> Option<u16> {
+        if self.avail_features & (1 << VIRTIO_NET_F_MTU) != 0 {
+            Some(self.config_space.mtu)
+        } else {
+            None
+        }
+    }
+
+    /// Says if the link of the device is up.
+    pub fn link_up(&self) -> bool {
+        self.config_space.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Brings the link of the device up or down. The frames sent and received while the link is
+    /// down are dropped. It is up to the caller to notify the driver of the configuration change.
+    pub fn set_link_up(&mut self, link_up: bool) -> Result<()> {
+        if self.vhost.is_some() {
+            return Err(Error::LinkStatusVhostNet);
+        }
+        if link_up {
+            self.config_space.status |= VIRTIO_NET_S_LINK_UP as u16;
+        } else {
+            self.config_space.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+        Ok(())
+    }
+
+    /// Asks the driver to announce the guest on the network, e.g. by sending gratuitous ARP
+    /// messages, so that the neighbours learn where it is reachable. Does nothing unless the
+    /// driver negotiated the feature. Returns whether the configuration changed, in which case
+    /// it is up to the caller to notify the driver.
+    pub fn announce(&mut self) -> bool {
+        if self.acked_features & (1 << VIRTIO_NET_F_GUEST_ANNOUNCE) == 0 {
+            return false;
+        }
+        self.config_space.status |= VIRTIO_NET_S_ANNOUNCE as u16;
+        self.metrics.inc(|m| &m.announce_count);
+        true
     }
 
     /// Says if this device supports MMDS.
@@ -246,11 +686,23 @@ impl Net {
     }
 
     fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
+        // The driver may only want to be notified once more buffers are used.
+        if let DeviceState::Activated(ref mem) = self.device_state {
+            let mut needs_notification = false;
+            for queue in self.queues.iter_mut() {
+                needs_notification |= queue.needs_notification(mem);
+            }
+            if !needs_notification {
+                self.rx_deferred_irqs = false;
+                return Ok(());
+            }
+        }
+
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
         self.interrupt_evt.write(1).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
-            METRICS.net.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
             DeviceError::FailedSignalingUsedQueue(e)
         })?;
 
@@ -269,27 +721,28 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, pair: usize) -> bool {
+        let rx_bytes_read = self.queue_pairs[pair].rx_bytes_read as u64;
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
         if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
-            METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.inc(|m| &m.rx_rate_limiter_throttled);
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
         if !self
             .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
+            .consume(rx_bytes_read, TokenType::Bytes)
         {
             // revert the OPS consume()
             self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
-            METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.inc(|m| &m.rx_rate_limiter_throttled);
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
@@ -297,13 +750,13 @@ impl Net {
             self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
             self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+                .manual_replenish(rx_bytes_read, TokenType::Bytes);
         }
         success
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest.
+    fn do_write_frame_to_guest(&mut self, pair: usize) -> std::result::Result<(), FrontendError> {
         let mut result: std::result::Result<(), FrontendError> = Ok(());
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
@@ -311,14 +764,18 @@ impl Net {
             DeviceState::Inactive => unreachable!(),
         };
 
-        let queue = &mut self.queues[RX_INDEX];
-        let head_descriptor = queue.pop(mem).ok_or_else(|| {
-            METRICS.net.no_rx_avail_buffer.inc();
-            FrontendError::EmptyQueue
-        })?;
+        let queue = &mut self.queues[rx_queue_index(pair)];
+        let head_descriptor = match queue.pop_or_enable_notification(mem) {
+            Some(head_descriptor) => head_descriptor,
+            None => {
+                self.metrics.inc(|m| &m.no_rx_avail_buffer);
+                return Err(FrontendError::EmptyQueue);
+            }
+        };
         let head_index = head_descriptor.index;
 
-        let mut frame_slice = &self.rx_frame_buf[..self.rx_bytes_read];
+        let queue_pair = &self.queue_pairs[pair];
+        let mut frame_slice = &queue_pair.rx_frame_buf[..queue_pair.rx_bytes_read];
         let frame_len = frame_slice.len();
         let mut maybe_next_descriptor = Some(head_descriptor);
         while let Some(descriptor) = &maybe_next_descriptor {
@@ -334,16 +791,15 @@ impl Net {
             let len = std::cmp::min(frame_slice.len(), descriptor.len as usize);
             match mem.write_slice(&frame_slice[..len], descriptor.addr) {
                 Ok(()) => {
-                    METRICS.net.rx_count.inc();
+                    self.metrics.inc(|m| &m.rx_count);
                     frame_slice = &frame_slice[len..];
                 }
                 Err(e) => {
                     error!("Failed to write slice: {:?}", e);
-                    match e {
-                        GuestMemoryError::PartialBuffer { .. } => &METRICS.net.rx_partial_writes,
-                        _ => &METRICS.net.rx_fails,
-                    }
-                    .inc();
+                    self.metrics.inc(|m| match e {
+                        GuestMemoryError::PartialBuffer { .. } => &m.rx_partial_writes,
+                        _ => &m.rx_fails,
+                    });
                     result = Err(FrontendError::GuestMemory(e));
                     break;
                 }
@@ -353,7 +809,7 @@ impl Net {
         }
         if result.is_ok() && !frame_slice.is_empty() {
             warn!("Receiving buffer is too small to hold frame of current size");
-            METRICS.net.rx_fails.inc();
+            self.metrics.inc(|m| &m.rx_fails);
             result = Err(FrontendError::DescriptorChainTooSmall);
         }
 
@@ -366,18 +822,22 @@ impl Net {
         self.rx_deferred_irqs = true;
 
         if result.is_ok() {
-            METRICS.net.rx_bytes_count.add(frame_len);
-            METRICS.net.rx_packets_count.inc();
+            self.metrics.add(|m| &m.rx_bytes_count, frame_len);
+            self.metrics.inc(|m| &m.rx_packets_count);
+            capture_frame(
+                &mut self.pcap,
+                &self.queue_pairs[pair].rx_frame_buf[..frame_len],
+            );
         }
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest. In case of an
+    // error retries the operation if possible. Returns true if the operation was successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -392,27 +852,42 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to the DHCP responder, then to MMDS, and if neither accepts it,
+    // sends it on the host TAP or unix socket.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
-    // Returns whether MMDS consumed the frame.
+    // Returns whether the DHCP responder or MMDS consumed the frame.
     fn write_to_mmds_or_tap(
+        dhcp: Option<&mut DhcpResponder>,
         mmds_ns: Option<&mut MmdsNetworkStack>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
-        tap: &mut Tap,
+        backend: &mut dyn NetBackend,
         guest_mac: Option<MacAddr>,
+        metrics: &NetMetrics,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
             frame_bytes_from_buf(frame_buf).map_err(|e| {
                 error!("VNET header missing in the TX frame.");
-                METRICS.net.tx_malformed_frames.inc();
+                metrics.inc(|m| &m.tx_malformed_frames);
                 e
             })
         };
+        if let Some(dhcp) = dhcp {
+            if dhcp.detour_frame(checked_frame(frame_buf)?) {
+                metrics.inc(|m| &m.dhcp_requests_count);
+
+                // DHCP frames are not accounted by the rate limiter.
+                rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
+                rate_limiter.manual_replenish(1, TokenType::Ops);
+
+                return Ok(true);
+            }
+        }
         if let Some(ns) = mmds_ns {
             if ns.detour_frame(checked_frame(frame_buf)?) {
                 METRICS.mmds.rx_accepted.inc();
+                metrics.inc(|m| &m.mmds_requests_count);
 
                 // MMDS frames are not accounted by the rate limiter.
                 rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
@@ -429,51 +904,63 @@ impl Net {
         if let Some(mac) = guest_mac {
             let _ = EthernetFrame::from_bytes(checked_frame(frame_buf)?).map(|eth_frame| {
                 if mac != eth_frame.src_mac() {
-                    METRICS.net.tx_spoofed_mac_count.inc();
+                    metrics.inc(|m| &m.tx_spoofed_mac_count);
                 }
             });
         }
 
-        match tap.write(frame_buf) {
+        match backend.write_frame(frame_buf) {
             Ok(_) => {
-                METRICS.net.tx_bytes_count.add(frame_buf.len());
-                METRICS.net.tx_packets_count.inc();
-                METRICS.net.tx_count.inc();
+                metrics.add(|m| &m.tx_bytes_count, frame_buf.len());
+                metrics.inc(|m| &m.tx_packets_count);
+                metrics.inc(|m| &m.tx_count);
             }
             Err(e) => {
                 error!("Failed to write to tap: {:?}", e);
-                METRICS.net.tap_write_fails.inc();
+                metrics.inc(|m| &m.tap_write_fails);
             }
         };
         Ok(false)
     }
 
-    // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
-        if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
-            {
+    // We currently prioritize packets from the DHCP responder and the MMDS over regular network
+    // packets. These frames are delivered on the first queue pair.
+    fn read_from_mmds_or_tap(&mut self, pair: usize) -> Result<usize> {
+        let rx_frame_buf = &mut self.queue_pairs[pair].rx_frame_buf;
+        if let Some(dhcp) = self.dhcp.as_mut().filter(|_| pair == 0) {
+            if let Some(len) = dhcp.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
+                self.metrics.inc(|m| &m.dhcp_replies_count);
+                init_vnet_hdr(rx_frame_buf);
+                return Ok(vnet_hdr_len() + len.get());
+            }
+        }
+        if let Some(ns) = self.mmds_ns.as_mut().filter(|_| pair == 0) {
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                self.metrics.inc(|m| &m.mmds_replies_count);
+                init_vnet_hdr(rx_frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        self.read_tap(pair).map_err(Error::IO)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair) {
+                Ok(_) if !self.link_up() => {
+                    // Nothing reaches the guest while the cable is unplugged.
+                    self.metrics.inc(|m| &m.rx_link_down_frames_dropped);
+                }
                 Ok(count) => {
-                    self.rx_bytes_read = count;
-                    METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    self.queue_pairs[pair].rx_bytes_read = count;
+                    self.metrics.inc(|m| &m.rx_count);
+                    if !self.rate_limited_rx_single_frame(pair) {
+                        self.queue_pairs[pair].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -484,7 +971,7 @@ impl Net {
                         Some(err) if err == EAGAIN => (),
                         _ => {
                             error!("Failed to read tap: {:?}", e);
-                            METRICS.net.tap_read_fails.inc();
+                            self.metrics.inc(|m| &m.tap_read_fails);
                             return Err(DeviceError::FailedReadTap);
                         }
                     };
@@ -502,48 +989,49 @@ impl Net {
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair) {
+            self.queue_pairs[pair].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair);
         }
 
         self.signal_rx_used_queue()
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[pair].rx_deferred_frame {
+            self.handle_deferred_frame(pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
             DeviceState::Inactive => unreachable!(),
         };
 
-        // The MMDS network stack works like a state machine, based on synchronous calls, and
-        // without being added to any event loop. If any frame is accepted by the MMDS, we also
-        // trigger a process_rx() which checks if there are any new frames to be sent, starting
-        // with the MMDS network stack.
+        // The MMDS network stack and the DHCP responder work like state machines, based on
+        // synchronous calls, and without being added to any event loop. If any frame is accepted
+        // by one of them, we also trigger a process_rx() which checks if there are any new frames
+        // to be sent, starting with theirs.
         let mut process_rx_for_mmds = false;
         let mut raise_irq = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let link_up = self.link_up();
+        let tx_queue = &mut self.queues[tx_queue_index(pair)];
 
-        while let Some(head) = tx_queue.pop(mem) {
+        while let Some(head) = tx_queue.pop_or_enable_notification(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
             if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
-                METRICS.net.tx_rate_limiter_throttled.inc();
+                self.metrics.inc(|m| &m.tx_rate_limiter_throttled);
                 break;
             }
 
@@ -573,7 +1061,7 @@ impl Net {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
-                METRICS.net.tx_rate_limiter_throttled.inc();
+                self.metrics.inc(|m| &m.tx_rate_limiter_throttled);
                 break;
             }
 
@@ -591,32 +1079,46 @@ impl Net {
                 match read_result {
                     Ok(()) => {
                         read_count += limit - read_count;
-                        METRICS.net.tx_count.inc();
+                        self.metrics.inc(|m| &m.tx_count);
                     }
                     Err(e) => {
                         error!("Failed to read slice: {:?}", e);
-                        match e {
-                            GuestMemoryError::PartialBuffer { .. } => &METRICS.net.tx_partial_reads,
-                            _ => &METRICS.net.tx_fails,
-                        }
-                        .inc();
+                        self.metrics.inc(|m| match e {
+                            GuestMemoryError::PartialBuffer { .. } => &m.tx_partial_reads,
+                            _ => &m.tx_fails,
+                        });
                         read_count = 0;
                         break;
                     }
                 }
             }
 
-            let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
-                self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
-                &self.tx_frame_buf[..read_count],
-                &mut self.tap,
-                self.guest_mac,
-            )
-            .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
-                // MMDS consumed this frame/request, let's also try to process the response.
-                process_rx_for_mmds = true;
+            capture_frame(&mut self.pcap, &self.tx_frame_buf[..read_count]);
+            let frame_allowed = match self.anti_spoofing.as_ref() {
+                Some(filter) => frame_bytes_from_buf(&self.tx_frame_buf[..read_count])
+                    .map_or(false, |frame| filter.allows(frame)),
+                None => true,
+            };
+            if !link_up {
+                self.metrics.inc(|m| &m.tx_link_down_frames_dropped);
+            } else if frame_allowed {
+                let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
+                    self.dhcp.as_mut(),
+                    self.mmds_ns.as_mut(),
+                    &mut self.tx_rate_limiter,
+                    &self.tx_frame_buf[..read_count],
+                    self.queue_pairs[pair].backend.as_mut(),
+                    self.guest_mac,
+                    &self.metrics,
+                )
+                .unwrap_or(false);
+                if frame_consumed_by_mmds && !self.queue_pairs[0].rx_deferred_frame {
+                    // MMDS or the DHCP responder consumed this frame/request, let's also try to
+                    // process the response.
+                    process_rx_for_mmds = true;
+                }
+            } else {
+                self.metrics.inc(|m| &m.tx_spoofed_frames_dropped);
             }
 
             tx_queue
@@ -628,17 +1130,100 @@ impl Net {
         if raise_irq {
             self.signal_used_queue()?;
         } else {
-            METRICS.net.no_tx_avail_buffer.inc();
+            self.metrics.inc(|m| &m.no_tx_avail_buffer);
         }
 
         // An incoming frame for the MMDS may trigger the transmission of a new message.
         if process_rx_for_mmds {
-            self.process_rx()
+            self.process_rx(0)
         } else {
             Ok(())
         }
     }
 
+    // Executes the commands found on the control queue.
+    fn process_ctrl_queue(&mut self, ctrl_index: usize) -> result::Result<(), DeviceError> {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut raise_irq = false;
+        while let Some(head) = self.queues[ctrl_index].pop_or_enable_notification(&mem) {
+            let head_index = head.index;
+            let mut command = [0u8; CTRL_COMMAND_MAX_LEN];
+            let mut command_len = 0;
+            let mut ack_addr = None;
+
+            // The command is followed by a device writable ack byte.
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    ack_addr = Some(desc.addr);
+                    break;
+                }
+                let len = cmp::min(desc.len as usize, CTRL_COMMAND_MAX_LEN - command_len);
+                if let Err(e) =
+                    mem.read_slice(&mut command[command_len..command_len + len], desc.addr)
+                {
+                    error!("Failed to read control command: {:?}", e);
+                    break;
+                }
+                command_len += len;
+                next_desc = desc.next_descriptor();
+            }
+
+            let mut used_len = 0;
+            if let Some(ack_addr) = ack_addr {
+                let ack = match self.execute_ctrl_command(&command[..command_len]) {
+                    Ok(()) => VIRTIO_NET_OK,
+                    Err(e) => {
+                        error!("Failed to execute control command: {:?}", e);
+                        self.metrics.inc(|m| &m.event_fails);
+                        VIRTIO_NET_ERR
+                    }
+                };
+                match mem.write_obj(ack as u8, ack_addr) {
+                    Ok(()) => used_len = 1,
+                    Err(e) => error!("Failed to write control command ack: {:?}", e),
+                }
+            }
+
+            self.queues[ctrl_index]
+                .add_used(&mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+            raise_irq = true;
+        }
+
+        if raise_irq {
+            self.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    fn execute_ctrl_command(&mut self, command: &[u8]) -> Result<()> {
+        match command {
+            [class, cmd, data @ ..]
+                if u32::from(*class) == VIRTIO_NET_CTRL_MQ
+                    && u32::from(*cmd) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+                    && data.len() >= 2 =>
+            {
+                let num_pairs = u16::from_le_bytes([data[0], data[1]]);
+                self.set_active_queue_pairs(num_pairs)
+            }
+            [class, cmd, ..]
+                if u32::from(*class) == VIRTIO_NET_CTRL_ANNOUNCE
+                    && u32::from(*cmd) == VIRTIO_NET_CTRL_ANNOUNCE_ACK =>
+            {
+                self.config_space.status &= !(VIRTIO_NET_S_ANNOUNCE as u16);
+                Ok(())
+            }
+            [class, cmd, ..] => Err(Error::UnsupportedCtrlCommand(*class, *cmd)),
+            _ => Err(Error::UnsupportedCtrlCommand(0, 0)),
+        }
+    }
+
     /// Updates the parameters for the rate limiters
     pub fn patch_rate_limiters(
         &mut self,
@@ -652,112 +1237,152 @@ impl Net {
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+        let queue_pair = &mut self.queue_pairs[pair];
+        queue_pair.backend.read_frame(&mut queue_pair.rx_frame_buf)
+    }
+
+    /// Dispatches the event signaled on the queue found at `queue_index`.
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
+        if Some(queue_index) == self.ctrl_queue_index() {
+            if let Err(e) = self.queue_evts[queue_index].read() {
+                error!("Failed to get ctrl queue event: {:?}", e);
+                self.metrics.inc(|m| &m.event_fails);
+            } else {
+                self.process_ctrl_queue(queue_index)
+                    .unwrap_or_else(report_net_event_fail);
+            }
+            return;
+        }
+
+        let pair = queue_index / NUM_QUEUES;
+        if pair >= self.active_queue_pairs {
+            // The driver is not supposed to use the queues of the disabled pairs.
+            warn!("Net: queue event on disabled queue {}", queue_index);
+            let _ = self.queue_evts[queue_index].read();
+            return;
+        }
+        if queue_index % NUM_QUEUES == RX_INDEX {
+            self.process_rx_queue_event(pair);
+        } else {
+            self.process_tx_queue_event(pair);
+        }
     }
 
-    pub fn process_rx_queue_event(&mut self) {
-        METRICS.net.rx_queue_event_count.inc();
+    pub fn process_rx_queue_event(&mut self, pair: usize) {
+        self.metrics.inc(|m| &m.rx_queue_event_count);
 
-        if let Err(e) = self.queue_evts[RX_INDEX].read() {
+        if let Err(e) = self.queue_evts[rx_queue_index(pair)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", e);
-            METRICS.net.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
             if !self.rx_rate_limiter.is_blocked() {
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             } else {
-                METRICS.net.rx_rate_limiter_throttled.inc();
+                self.metrics.inc(|m| &m.rx_rate_limiter_throttled);
             }
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair: usize) {
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
             DeviceState::Inactive => unreachable!(),
         };
-        METRICS.net.rx_tap_event_count.inc();
+        self.metrics.inc(|m| &m.rx_tap_event_count);
 
         // While there are no available RX queue buffers and there's a deferred_frame
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
-            METRICS.net.no_rx_avail_buffer.inc();
+        if self.queues[rx_queue_index(pair)].is_empty(mem)
+            && self.queue_pairs[pair].rx_deferred_frame
+        {
+            self.metrics.inc(|m| &m.no_rx_avail_buffer);
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
         if self.rx_rate_limiter.is_blocked() {
-            METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.inc(|m| &m.rx_rate_limiter_throttled);
             return;
         }
 
-        if self.rx_deferred_frame
+        if self.queue_pairs[pair].rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
-        METRICS.net.tx_queue_event_count.inc();
-        if let Err(e) = self.queue_evts[TX_INDEX].read() {
+    pub fn process_tx_queue_event(&mut self, pair: usize) {
+        self.metrics.inc(|m| &m.tx_queue_event_count);
+        if let Err(e) = self.queue_evts[tx_queue_index(pair)].read() {
             error!("Failed to get tx queue event: {:?}", e);
-            METRICS.net.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
         } else if !self.tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair).unwrap_or_else(report_net_event_fail);
         } else {
-            METRICS.net.tx_rate_limiter_throttled.inc();
+            self.metrics.inc(|m| &m.tx_rate_limiter_throttled);
         }
     }
 
     pub fn process_rx_rate_limiter_event(&mut self) {
-        METRICS.net.rx_event_rate_limiter_count.inc();
+        self.metrics.inc(|m| &m.rx_event_rate_limiter_count);
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
         match self.rx_rate_limiter.event_handler() {
             Ok(_) => {
-                // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to receive the frames.
+                for pair in 0..self.active_queue_pairs {
+                    self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
+                }
             }
             Err(e) => {
                 error!("Failed to get rx rate-limiter event: {:?}", e);
-                METRICS.net.event_fails.inc();
+                self.metrics.inc(|m| &m.event_fails);
             }
         }
     }
 
     pub fn process_tx_rate_limiter_event(&mut self) {
-        METRICS.net.tx_rate_limiter_event_count.inc();
+        self.metrics.inc(|m| &m.tx_rate_limiter_event_count);
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
         match self.tx_rate_limiter.event_handler() {
             Ok(_) => {
-                // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to send the frames.
+                for pair in 0..self.active_queue_pairs {
+                    self.process_tx(pair).unwrap_or_else(report_net_event_fail);
+                }
             }
             Err(e) => {
                 error!("Failed to get tx rate-limiter event: {:?}", e);
-                METRICS.net.event_fails.inc();
+                self.metrics.inc(|m| &m.event_fails);
             }
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        // The vhost-net driver processes the queues on its own.
+        if self.vhost.is_some() {
+            return;
+        }
+        for pair in 0..self.active_queue_pairs {
+            let _ = self.resume_rx(pair);
+            let _ = self.process_tx(pair);
+        }
     }
 }
 
@@ -770,6 +1395,11 @@ impl VirtioDevice for Net {
         &self.queues
     }
 
+    fn num_required_queues(&self) -> usize {
+        // Only the first queue pair has to be set up by the driver.
+        NUM_QUEUES
+    }
+
     fn queues_mut(&mut self) -> &mut [Queue] {
         &mut self.queues
     }
@@ -803,7 +1433,7 @@ impl VirtioDevice for Net {
         let config_len = config_space_bytes.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
-            METRICS.net.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
@@ -817,11 +1447,12 @@ impl VirtioDevice for Net {
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
-        let config_space_bytes = self.config_space.as_mut_slice();
+        // Only the MAC address is writable.
+        let config_space_bytes = &mut self.config_space.as_mut_slice()[..MAC_ADDR_LEN];
         let config_len = config_space_bytes.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
-            METRICS.net.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
 
@@ -829,7 +1460,7 @@ impl VirtioDevice for Net {
         self.guest_mac = Some(MacAddr::from_bytes_unchecked(
             &self.config_space.guest_mac[..MAC_ADDR_LEN],
         ));
-        METRICS.net.mac_address_updates.inc();
+        self.metrics.inc(|m| &m.mac_address_updates);
     }
 
     fn is_activated(&self) -> bool {
@@ -840,6 +1471,16 @@ impl VirtioDevice for Net {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if let Some(vhost) = self.vhost.as_ref() {
+            if let Err(e) = self.setup_vhost_net(vhost, &mem) {
+                error!("Net: Cannot set up the vhost-net driver: {:?}", e);
+                return Err(super::super::ActivateError::BadActivate);
+            }
+        } else if self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0 {
+            for queue in self.queues.iter_mut() {
+                queue.enable_notif_suppression();
+            }
+        }
         if self.activate_evt.write(1).is_err() {
             error!("Net: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
@@ -857,25 +1498,33 @@ pub mod tests {
         frame_bytes_from_buf, frame_bytes_from_buf_mut, init_vnet_hdr, vnet_hdr_len,
     };
     use std::net::Ipv4Addr;
+    use std::os::unix::net::UnixDatagram;
     use std::sync::atomic::Ordering;
     use std::time::Duration;
     use std::{io, mem, thread};
 
     use crate::check_metric_after_block;
+    use crate::virtio::net::dhcp::tests::{
+        lease as dhcp_lease, write_request as write_dhcp_request,
+    };
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        check_used_queue_signal, default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent,
-        NetQueue, ReadTapMock, TapTrafficSimulator,
+        check_used_queue_signal, default_guest_mac, default_net, if_index, inject_tap_tx_frame,
+        set_mac, NetEvent, NetQueue, ReadTapMock, TapTrafficSimulator,
     };
     use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::queue::VIRTQ_DESC_F_NEXT;
+    use crate::virtio::test_utils::VirtQueue;
     use crate::virtio::{
         Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTIO_MMIO_INT_VRING,
         VIRTQ_DESC_F_WRITE,
     };
     use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+    use dumbo::pdu::dhcp::{DHCP_SERVER_PORT, MESSAGE_TYPE_DISCOVER, OPTION_MESSAGE_TYPE};
     use dumbo::pdu::ethernet::ETHERTYPE_ARP;
     use logger::{IncMetric, METRICS};
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
+    use utils::tempfile::TempFile;
     use virtio_gen::virtio_net::{
         virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
         VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
@@ -884,17 +1533,20 @@ pub mod tests {
     use vm_memory::{Address, GuestMemory};
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
+                    self.queue_pairs[pair].rx_frame_buf[..frame.len()].copy_from_slice(&frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => {
+                    let queue_pair = &mut self.queue_pairs[pair];
+                    queue_pair.backend.read_frame(&mut queue_pair.rx_frame_buf)
+                }
             }
         }
     }
@@ -950,6 +1602,12 @@ pub mod tests {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_F_RING_PACKED
             | 1 << VIRTIO_F_VERSION_1;
 
         assert_eq!(net.avail_features_by_page(0), features as u32);
@@ -965,6 +1623,252 @@ pub mod tests {
         assert_eq!(net.acked_features, features);
     }
 
+    #[test]
+    fn test_multi_queue() {
+        let mut net = Net::new_with_tap(
+            "mq-net".to_string(),
+            "mq-net-device".to_string(),
+            None,
+            2,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            false,
+        )
+        .unwrap();
+
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 2);
+        // Two queue pairs and the control queue.
+        assert_eq!(net.queues().len(), 5);
+        assert_eq!(net.queue_events().len(), 5);
+        assert_eq!(net.num_required_queues(), NUM_QUEUES);
+        // The control queue follows the first pair until the driver negotiates the feature.
+        assert_eq!(net.ctrl_queue_index(), Some(NUM_QUEUES));
+        net.ack_features_by_page(0, 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ);
+        assert_eq!(net.ctrl_queue_index(), Some(4));
+        assert_eq!(net.num_queue_pairs(), 2);
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert!(matches!(
+            net.enable_vhost_net(),
+            Err(Error::VhostNetMultiQueue)
+        ));
+
+        // The queues of the enabled pairs have to be set up first.
+        let set_pairs = [
+            VIRTIO_NET_CTRL_MQ as u8,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+            2,
+            0,
+        ];
+        assert!(net.execute_ctrl_command(&set_pairs).is_err());
+        for queue in net.queues_mut().iter_mut().take(4) {
+            queue.ready = true;
+        }
+        net.execute_ctrl_command(&set_pairs).unwrap();
+        assert_eq!(net.active_queue_pairs(), 2);
+        // The queue of the second pair of the tap is attached.
+        assert!(net.queue_pairs[1].backend.set_queue_enabled(true).is_err());
+
+        assert!(net.set_active_queue_pairs(0).is_err());
+        assert!(net.set_active_queue_pairs(3).is_err());
+        assert_eq!(net.active_queue_pairs(), 2);
+        net.set_active_queue_pairs(1).unwrap();
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert!(net.queue_pairs[1].backend.set_queue_enabled(false).is_err());
+
+        // Unknown and truncated commands are rejected.
+        assert!(net.execute_ctrl_command(&[0, 0, 1, 0]).is_err());
+        assert!(net.execute_ctrl_command(&set_pairs[..3]).is_err());
+        assert!(net.execute_ctrl_command(&[]).is_err());
+
+        // Single queue pair devices still have a control queue.
+        let mut net = default_net();
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(net.queues().len(), NUM_QUEUES + 1);
+        assert_eq!(net.ctrl_queue_index(), Some(NUM_QUEUES));
+
+        // Which can be removed.
+        net.remove_ctrl_queue();
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_GUEST_ANNOUNCE), 0);
+        assert_eq!(net.queues().len(), NUM_QUEUES);
+        assert_eq!(net.queue_events().len(), NUM_QUEUES);
+        assert_eq!(net.ctrl_queue_index(), None);
+    }
+
+    #[test]
+    fn test_ctrl_queue_without_mq() {
+        let mut net = Net::new_with_tap(
+            "mq-net-ctrl".to_string(),
+            "mq-net-ctrl".to_string(),
+            None,
+            2,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            false,
+        )
+        .unwrap();
+        // The driver only knows about the first pair, followed by the control queue.
+        net.ack_features_by_page(
+            0,
+            1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE,
+        );
+        assert_eq!(net.ctrl_queue_index(), Some(NUM_QUEUES));
+
+        let mem = crate::virtio::test_utils::default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[NUM_QUEUES] = vq.create_queue();
+        net.device_state = DeviceState::Activated(mem.clone());
+        assert!(net.announce());
+
+        // The announcement is acknowledged through the queue found at index 2.
+        let command_addr = GuestAddress(0x1000);
+        let ack_addr = GuestAddress(0x2000);
+        mem.write_slice(
+            &[
+                VIRTIO_NET_CTRL_ANNOUNCE as u8,
+                VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8,
+            ],
+            command_addr,
+        )
+        .unwrap();
+        mem.write_obj(0xffu8, ack_addr).unwrap();
+        vq.dtable[0].set(command_addr.0, 2, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(ack_addr.0, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        net.queue_evts[NUM_QUEUES].write(1).unwrap();
+        net.process_queue_event(NUM_QUEUES);
+
+        vq.check_used_elem(0, 0, 1);
+        assert_eq!(mem.read_obj::<u8>(ack_addr).unwrap(), VIRTIO_NET_OK as u8);
+        let mut status = [0u8; 2];
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+    }
+
+    #[test]
+    fn test_socket_backend() {
+        let tmp = TempFile::new().unwrap();
+        let socket_path = tmp.as_path().to_str().unwrap().to_string();
+        drop(tmp);
+        // Nobody listens on the socket yet.
+        assert!(matches!(
+            Net::new_with_socket(
+                "socket-net".to_string(),
+                socket_path.clone(),
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                false,
+            ),
+            Err(Error::SocketConnect(_))
+        ));
+
+        let switch = UnixDatagram::bind(&socket_path).unwrap();
+        let mut net = Net::new_with_socket(
+            "socket-net".to_string(),
+            socket_path.clone(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(net.socket_path(), Some(socket_path.as_str()));
+        assert_eq!(net.iface_name(), "");
+        assert_eq!(net.num_queue_pairs(), 1);
+        // The switch only handles complete frames.
+        assert_eq!(net.avail_features() & TAP_OFFLOAD_FEATURES, 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        // Only the guest MTU is bounded.
+        net.set_mtu(9000).unwrap();
+        assert!(matches!(
+            net.enable_vhost_net(),
+            Err(Error::VhostNetSocketBackend)
+        ));
+
+        // The frames reach the switch without their header.
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let (frame_buf, frame_len) = create_arp_request(
+            guest_mac,
+            Ipv4Addr::new(10, 1, 2, 3),
+            MacAddr::parse_str("22:22:22:22:22:22").unwrap(),
+            Ipv4Addr::new(10, 1, 1, 1),
+        );
+        assert!(!Net::write_to_mmds_or_tap(
+            None,
+            None,
+            &mut net.tx_rate_limiter,
+            &frame_buf[..frame_len],
+            net.queue_pairs[0].backend.as_mut(),
+            Some(guest_mac),
+            &net.metrics,
+        )
+        .unwrap());
+        let mut buf = [0u8; 1000];
+        let len = switch.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &frame_buf[vnet_hdr_len()..frame_len]);
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_announce() {
+        let mut net = default_net();
+        let mut status = [0u8; 2];
+
+        // Nothing happens until the driver negotiates the feature.
+        assert!(!net.announce());
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+
+        net.ack_features_by_page(0, 1 << VIRTIO_NET_F_GUEST_ANNOUNCE);
+        check_metric_after_block!(&METRICS.net.announce_count, 1, assert!(net.announce()));
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(
+            u16::from_le_bytes(status),
+            (VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE) as u16
+        );
+        // The caller notifies the driver through the transport.
+        assert_eq!(net.interrupt_status().load(Ordering::SeqCst), 0);
+
+        // The driver acknowledges the announcement through the control queue.
+        net.execute_ctrl_command(&[
+            VIRTIO_NET_CTRL_ANNOUNCE as u8,
+            VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8,
+        ])
+        .unwrap();
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+    }
+
+    #[test]
+    fn test_vhost_net() {
+        // Not every host exposes the vhost-net driver.
+        if !std::path::Path::new("/dev/vhost-net").exists() {
+            return;
+        }
+
+        let mut net = default_net();
+        assert!(!net.vhost_net_enabled());
+        net.enable_vhost_net().unwrap();
+        assert!(net.vhost_net_enabled());
+        assert_eq!(net.vhost_call_evts.len(), net.queues().len());
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MRG_RXBUF), 0);
+
+        // The used buffers signaled by the driver are forwarded to the guest.
+        net.vhost_call_evts[RX_INDEX].write(1).unwrap();
+        net.process_vhost_call_event(RX_INDEX);
+        assert_eq!(
+            net.interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+    }
+
     #[test]
     fn test_virtio_device_read_config() {
         let mut net = default_net();
@@ -978,10 +1882,30 @@ pub mod tests {
 
         // Invalid read.
         config_mac = [0u8; MAC_ADDR_LEN];
-        net.read_config(MAC_ADDR_LEN as u64 + 1, &mut config_mac);
+        net.read_config(mem::size_of::<ConfigSpace>() as u64 + 1, &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
     }
 
+    #[test]
+    fn test_mtu() {
+        let mut net = default_net();
+        assert_eq!(net.mtu(), None);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+
+        // The MTU has to fit the one of the tap interface.
+        assert!(net.set_mtu(MIN_MTU - 1).is_err());
+        assert!(net.set_mtu(1501).is_err());
+        assert_eq!(net.mtu(), None);
+
+        net.set_mtu(1450).unwrap();
+        assert_eq!(net.mtu(), Some(1450));
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+        // The MTU follows the maximum number of queue pairs in the config space.
+        let mut mtu = [0u8; 2];
+        net.read_config(10, &mut mtu);
+        assert_eq!(u16::from_le_bytes(mtu), 1450);
+    }
+
     #[test]
     fn test_virtio_device_rewrite_config() {
         let mut net = default_net();
@@ -1130,7 +2054,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1163,7 +2087,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         check_used_queue_signal(&th.net(), 1);
@@ -1202,7 +2126,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         check_used_queue_signal(&th.net(), 1);
@@ -1220,7 +2144,8 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1240,7 +2165,8 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1255,11 +2181,44 @@ pub mod tests {
         assert!(!tap_traffic_simulator.pop_rx_packet(&mut []));
     }
 
+    #[test]
+    fn test_tx_notif_suppression() {
+        let mut th = TestHelper::default();
+        th.net().acked_features = 1 << VIRTIO_RING_F_EVENT_IDX;
+        th.activate_net();
+        let desc_list = [(0, 100, 0)];
+
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.txq.used.idx.get(), 1);
+        // The driver is notified once the used_event index is crossed.
+        check_used_queue_signal(&th.net(), 1);
+        // And asked to notify the device of the next available chain.
+        assert_eq!(th.txq.used.event.get(), 1);
+
+        // The chains used before the new used_event index are not signaled.
+        th.txq.avail.event.set(2);
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.txq.used.idx.get(), 2);
+        check_used_queue_signal(&th.net(), 0);
+        assert_eq!(th.txq.used.event.get(), 2);
+
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.txq.used.idx.get(), 3);
+        check_used_queue_signal(&th.net(), 1);
+    }
+
     #[test]
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1281,7 +2240,8 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1309,7 +2269,8 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1349,7 +2310,8 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1377,7 +2339,8 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1409,6 +2372,147 @@ pub mod tests {
         assert_eq!(&buf[..600], &frame_2[..600]);
     }
 
+    #[test]
+    fn test_capture() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        let _tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
+
+        let capture_file = TempFile::new().unwrap();
+        let capture_path = capture_file.as_path().to_str().unwrap();
+        assert!(th.net().start_capture(capture_path, 0, 1024).is_err());
+        assert!(!th.net().capture_enabled());
+        th.net().start_capture(capture_path, 100, 4096).unwrap();
+        assert!(th.net().capture_enabled());
+
+        // Both the sent and the received frames are captured.
+        let desc_list = [(0, 300, 0)];
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 300);
+        th.add_desc_chain(NetQueue::Rx, 1000, &[(1, 500, VIRTQ_DESC_F_WRITE)]);
+        inject_tap_tx_frame(&th.net(), 200);
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        // The global header, then both frames truncated to the snaplen.
+        assert_eq!(
+            std::fs::metadata(capture_path).unwrap().len(),
+            24 + 2 * (16 + 100)
+        );
+
+        th.net().stop_capture();
+        assert!(!th.net().capture_enabled());
+    }
+
+    #[test]
+    fn test_anti_spoofing() {
+        let mut net = default_net();
+        net.guest_mac = None;
+        assert!(net.enable_anti_spoofing(vec![]).is_err());
+        assert!(net.anti_spoofing_ipv4_addrs().is_none());
+
+        let mut th = TestHelper::default();
+        th.activate_net();
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
+        let allowed_addrs = vec![Ipv4Addr::new(10, 0, 0, 2)];
+        th.net()
+            .enable_anti_spoofing(allowed_addrs.clone())
+            .unwrap();
+        assert_eq!(
+            th.net().anti_spoofing_ipv4_addrs(),
+            Some(allowed_addrs.as_slice())
+        );
+
+        // The frame is sent from the zero MAC address.
+        let desc_list = [(0, 100, 0)];
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        check_metric_after_block!(
+            METRICS.net.tx_spoofed_frames_dropped,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        th.txq.check_used_elem(0, 0, 0);
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut [0; 100]));
+
+        // Frames of protocols other than IPv4 and ARP sent from the guest MAC address go through.
+        let desc_list = [(1, 100, 0)];
+        th.add_desc_chain(NetQueue::Tx, 200, &desc_list);
+        let mut frame = th.write_tx_frame(&desc_list, 100);
+        let src_mac_offset = vnet_hdr_len() + 6;
+        frame[src_mac_offset..src_mac_offset + 6].copy_from_slice(default_guest_mac().get_bytes());
+        frame[src_mac_offset + 6..src_mac_offset + 8].copy_from_slice(&[0x86, 0xdd]);
+        th.mem
+            .write_slice(&frame, GuestAddress::new(th.txq.dtable[1].addr.get()))
+            .unwrap();
+        check_metric_after_block!(
+            METRICS.net.tx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        let mut buf = vec![0; 100];
+        assert!(tap_traffic_simulator.pop_rx_packet(&mut buf[vnet_hdr_len()..]));
+        assert_eq!(&buf[..], &frame[..]);
+    }
+
+    #[test]
+    fn test_link_status() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].backend.tap().unwrap()));
+
+        // The status follows the guest MAC address in the config space.
+        let mut status = [0u8; 2];
+        assert!(th.net().link_up());
+        th.net().read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        th.net().set_link_up(false).unwrap();
+        assert!(!th.net().link_up());
+        th.net().read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), 0);
+
+        // The frames sent while the link is down are dropped.
+        let desc_list = [(0, 100, 0)];
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        check_metric_after_block!(
+            METRICS.net.tx_link_down_frames_dropped,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        th.txq.check_used_elem(0, 0, 0);
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut [0; 100]));
+
+        // So are the received ones.
+        th.add_desc_chain(NetQueue::Rx, 1000, &[(1, 500, VIRTQ_DESC_F_WRITE)]);
+        inject_tap_tx_frame(&th.net(), 200);
+        check_metric_after_block!(
+            METRICS.net.rx_link_down_frames_dropped,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.rxq.used.idx.get(), 0);
+
+        // Frames flow again once the link is back up.
+        th.net().set_link_up(true).unwrap();
+        th.net().read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        inject_tap_tx_frame(&th.net(), 200);
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.rxq.used.idx.get(), 1);
+    }
+
     fn create_arp_request(
         src_mac: MacAddr,
         src_ip: Ipv4Addr,
@@ -1444,6 +2548,8 @@ pub mod tests {
     #[test]
     fn test_mmds_detour_and_injection() {
         let mut net = default_net();
+        // Use a dedicated interface id, the default one is shared by all the tests.
+        net.metrics = NetMetrics::new("test_mmds_detour_and_injection");
 
         let src_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
         let src_ip = Ipv4Addr::new(10, 1, 2, 3);
@@ -1458,11 +2564,13 @@ pub mod tests {
             &METRICS.mmds.rx_accepted,
             1,
             assert!(Net::write_to_mmds_or_tap(
+                None,
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.queue_pairs[0].backend.as_mut(),
                 Some(src_mac),
+                &net.metrics,
             )
             .unwrap())
         );
@@ -1471,7 +2579,50 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
+        );
+
+        // The detoured frames are accounted for the interface as well.
+        assert_eq!(net.metrics.device().mmds_requests_count.count(), 1);
+        assert_eq!(net.metrics.device().mmds_replies_count.count(), 1);
+    }
+
+    #[test]
+    fn test_dhcp_detour_and_injection() {
+        let mut net = default_net();
+        assert!(net.dhcp_lease().is_none());
+        net.enable_dhcp(dhcp_lease());
+        assert_eq!(net.dhcp_lease(), Some(&dhcp_lease()));
+
+        let mut frame_buf = [0u8; MAX_BUFFER_SIZE];
+        let frame_len = vnet_hdr_len()
+            + write_dhcp_request(
+                frame_bytes_from_buf_mut(&mut frame_buf).unwrap(),
+                DHCP_SERVER_PORT,
+                &[(OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_DISCOVER])],
+            );
+
+        // Validate the frame was consumed by the DHCP responder.
+        check_metric_after_block!(
+            &METRICS.net.dhcp_requests_count,
+            1,
+            assert!(Net::write_to_mmds_or_tap(
+                net.dhcp.as_mut(),
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiter,
+                &frame_buf[..frame_len],
+                net.queue_pairs[0].backend.as_mut(),
+                None,
+                &net.metrics,
+            )
+            .unwrap())
+        );
+
+        // Validate that the offer is sent to the guest.
+        check_metric_after_block!(
+            &METRICS.net.dhcp_replies_count,
+            1,
+            net.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
@@ -1492,11 +2643,13 @@ pub mod tests {
             &METRICS.net.tx_spoofed_mac_count,
             0,
             Net::write_to_mmds_or_tap(
+                None,
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.queue_pairs[0].backend.as_mut(),
                 Some(guest_mac),
+                &net.metrics,
             )
         );
 
@@ -1505,11 +2658,13 @@ pub mod tests {
             &METRICS.net.tx_spoofed_mac_count,
             1,
             Net::write_to_mmds_or_tap(
+                None,
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.queue_pairs[0].backend.as_mut(),
                 Some(not_guest_mac),
+                &net.metrics,
             )
         );
     }
@@ -1546,7 +2701,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().queue_pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1650,7 +2805,7 @@ pub mod tests {
             th.net().rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1661,7 +2816,7 @@ pub mod tests {
                 // assert that limiter is blocked
                 assert!(th.net().rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 check_used_queue_signal(&th.net(), 1);
                 // make sure the data is still queued for processing
@@ -1755,7 +2910,7 @@ pub mod tests {
             th.net().rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1770,7 +2925,7 @@ pub mod tests {
                 // assert that limiter is blocked
                 assert!(th.net().rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 check_used_queue_signal(&th.net(), 1);
                 // make sure the data is still queued for processing
@@ -0,0 +1,449 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! DHCP server handing out a single, statically configured, address to the guest.
+//!
+//! Like the MMDS network stack, the responder is driven synchronously by the device: the
+//! DHCP requests sent by the guest are detoured from the TX path, and the replies are written
+//! to the guest ahead of the frames read from the tap.
+
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
+
+use dumbo::pdu::dhcp::{
+    DhcpMessage, Error as DhcpMessageError, DHCP_CLIENT_PORT, DHCP_SERVER_PORT, FLAG_BROADCAST,
+    MESSAGE_TYPE_ACK, MESSAGE_TYPE_DISCOVER, MESSAGE_TYPE_NAK, MESSAGE_TYPE_OFFER,
+    MESSAGE_TYPE_REQUEST, OPTION_DNS_SERVERS, OPTION_INTERFACE_MTU, OPTION_LEASE_TIME,
+    OPTION_MESSAGE_TYPE, OPTION_REQUESTED_IP_ADDR, OPTION_ROUTER, OPTION_SERVER_ID,
+    OPTION_SUBNET_MASK, OP_BOOTREQUEST,
+};
+use dumbo::pdu::ethernet::{Error as EthernetFrameError, EthernetFrame, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_UDP};
+use dumbo::pdu::udp::{Error as UdpDatagramError, UdpDatagram, UDP_HEADER_SIZE};
+use logger::error;
+use utils::net::mac::MacAddr;
+
+/// Largest number of DNS servers, which have to fit in a single DHCP option.
+pub const MAX_DNS_SERVERS: usize = 63;
+
+// The Ethernet MAC address of the DHCP server.
+const SERVER_MAC_ADDR: &str = "06:01:23:45:67:02";
+// The address is statically assigned, so it never expires.
+const INFINITE_LEASE_TIME: u32 = 0xffff_ffff;
+// Length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+
+#[derive(Debug)]
+enum WriteReplyError {
+    Dhcp(DhcpMessageError),
+    Ethernet(EthernetFrameError),
+    IPv4Packet(IPv4PacketError),
+    Udp(UdpDatagramError),
+}
+
+/// Network configuration handed out to the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DhcpLease {
+    /// Address assigned to the guest.
+    pub ipv4_addr: Ipv4Addr,
+    /// Length of the network prefix of the guest subnet.
+    pub prefix_len: u8,
+    /// Default gateway, also used as the address of the DHCP server.
+    pub gateway: Ipv4Addr,
+    /// DNS servers, in order of preference.
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// MTU of the guest interface.
+    pub mtu: Option<u16>,
+}
+
+impl DhcpLease {
+    /// Subnet mask matching the prefix length.
+    pub fn subnet_mask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32u32.saturating_sub(u32::from(self.prefix_len)))
+                .unwrap_or(0),
+        )
+    }
+}
+
+struct PendingReply {
+    dst_mac: MacAddr,
+    message_type: u8,
+    xid: u32,
+    flags: u16,
+    chaddr: MacAddr,
+}
+
+/// Answers the DHCPDISCOVER and DHCPREQUEST messages sent by the guest with the lease
+/// configured for its interface.
+pub struct DhcpResponder {
+    mac_addr: MacAddr,
+    lease: DhcpLease,
+    pending_reply: Option<PendingReply>,
+}
+
+impl DhcpResponder {
+    pub fn new(lease: DhcpLease) -> Self {
+        DhcpResponder {
+            // The unwrap is safe if parse_str() is implemented properly.
+            mac_addr: MacAddr::parse_str(SERVER_MAC_ADDR).unwrap(),
+            lease,
+            pending_reply: None,
+        }
+    }
+
+    pub fn lease(&self) -> &DhcpLease {
+        &self.lease
+    }
+
+    /// Consumes the Ethernet frame `src` if it holds a UDP datagram heading to the DHCP server
+    /// port, in which case a reply may become pending.
+    pub fn detour_frame(&mut self, src: &[u8]) -> bool {
+        let eth = match EthernetFrame::from_bytes(src) {
+            Ok(eth) if eth.ethertype() == ETHERTYPE_IPV4 => eth,
+            _ => return false,
+        };
+
+        let payload = eth.payload();
+        if payload.len() < IPV4_MIN_HEADER_LEN {
+            return false;
+        }
+        // The payload may be padded up to the minimum Ethernet frame length.
+        let total_len = IPv4Packet::from_bytes_unchecked(payload).total_len() as usize;
+        let ip = match payload
+            .get(..total_len)
+            .and_then(|packet| IPv4Packet::from_bytes(packet, false).ok())
+        {
+            Some(ip) if ip.protocol() == PROTOCOL_UDP => ip,
+            _ => return false,
+        };
+        let udp = match UdpDatagram::from_bytes(ip.payload(), None) {
+            Ok(udp) if udp.destination_port() == DHCP_SERVER_PORT => udp,
+            _ => return false,
+        };
+
+        // The datagram is meant for the DHCP server, even if it turns out to be malformed.
+        if let Ok(message) = DhcpMessage::from_bytes(udp.payload()) {
+            if message.op() == OP_BOOTREQUEST {
+                self.handle_request(eth.src_mac(), &message);
+            }
+        }
+        true
+    }
+
+    fn handle_request(&mut self, src_mac: MacAddr, message: &DhcpMessage<&[u8]>) {
+        let message_type = match message.message_type() {
+            Some(MESSAGE_TYPE_DISCOVER) => MESSAGE_TYPE_OFFER,
+            Some(MESSAGE_TYPE_REQUEST) => {
+                // The guest accepted the offer of another server.
+                if let Some(server_id) = message.ipv4_addr_option(OPTION_SERVER_ID) {
+                    if server_id != self.lease.gateway {
+                        return;
+                    }
+                }
+                // Clients renewing their lease send their current address instead.
+                let requested_addr = message
+                    .ipv4_addr_option(OPTION_REQUESTED_IP_ADDR)
+                    .unwrap_or_else(|| message.ciaddr());
+                if requested_addr == self.lease.ipv4_addr {
+                    MESSAGE_TYPE_ACK
+                } else {
+                    MESSAGE_TYPE_NAK
+                }
+            }
+            // The other messages do not call for a reply from a server which never hands out
+            // another address.
+            _ => return,
+        };
+
+        self.pending_reply = Some(PendingReply {
+            // Negative acknowledgements are broadcast, like the replies to the clients which
+            // cannot receive unicast frames before being configured.
+            dst_mac: if message.flags() & FLAG_BROADCAST != 0 || message_type == MESSAGE_TYPE_NAK {
+                MacAddr::from_bytes_unchecked(&[0xff; 6])
+            } else {
+                src_mac
+            },
+            message_type,
+            xid: message.xid(),
+            flags: message.flags(),
+            chaddr: message.chaddr(),
+        });
+    }
+
+    /// Writes the pending reply to `buf`, if there is one, returning the length of the frame.
+    pub fn write_next_frame(&mut self, buf: &mut [u8]) -> Option<NonZeroUsize> {
+        let reply = self.pending_reply.take()?;
+        match self.write_reply(buf, &reply) {
+            Ok(len) => Some(len),
+            Err(e) => {
+                error!("Failed to write DHCP reply: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn write_reply(
+        &self,
+        buf: &mut [u8],
+        reply: &PendingReply,
+    ) -> Result<NonZeroUsize, WriteReplyError> {
+        let mut eth_unsized =
+            EthernetFrame::write_incomplete(buf, reply.dst_mac, self.mac_addr, ETHERTYPE_IPV4)
+                .map_err(WriteReplyError::Ethernet)?;
+        // The guest is not configured yet, so the reply is sent to the limited broadcast address.
+        let mut ip_unsized = IPv4Packet::write_header(
+            eth_unsized.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            self.lease.gateway,
+            Ipv4Addr::BROADCAST,
+        )
+        .map_err(WriteReplyError::IPv4Packet)?;
+
+        let ip_payload = ip_unsized.inner_mut().payload_mut();
+        if ip_payload.len() < UDP_HEADER_SIZE {
+            return Err(WriteReplyError::Udp(UdpDatagramError::DatagramTooShort));
+        }
+        let message_len = self
+            .write_message(&mut ip_payload[UDP_HEADER_SIZE..], reply)
+            .map_err(WriteReplyError::Dhcp)?;
+        let udp_len = UDP_HEADER_SIZE + message_len;
+        let mut udp = UdpDatagram::from_bytes_unchecked(&mut ip_payload[..udp_len]);
+        udp.set_source_port(DHCP_SERVER_PORT)
+            .set_destination_port(DHCP_CLIENT_PORT)
+            .set_len(udp_len as u16)
+            .set_checksum(0);
+        let checksum = udp.compute_checksum(self.lease.gateway, Ipv4Addr::BROADCAST);
+        udp.set_checksum(checksum);
+
+        let ip_len = ip_unsized.with_payload_len_unchecked(udp_len, true).len();
+        Ok(
+            // The unwrap() is safe because ip_len > 0.
+            NonZeroUsize::new(eth_unsized.with_payload_len_unchecked(ip_len).len()).unwrap(),
+        )
+    }
+
+    fn write_message(
+        &self,
+        buf: &mut [u8],
+        reply: &PendingReply,
+    ) -> Result<usize, DhcpMessageError> {
+        let message_type = [reply.message_type];
+        let server_id = self.lease.gateway.octets();
+        let lease_time = INFINITE_LEASE_TIME.to_be_bytes();
+        let subnet_mask = self.lease.subnet_mask().octets();
+        let dns_servers: Vec<u8> = self
+            .lease
+            .dns_servers
+            .iter()
+            .flat_map(|addr| addr.octets().to_vec())
+            .collect();
+        let mtu = self.lease.mtu.map(u16::to_be_bytes);
+
+        let mut options: Vec<(u8, &[u8])> = vec![
+            (OPTION_MESSAGE_TYPE, &message_type[..]),
+            (OPTION_SERVER_ID, &server_id[..]),
+        ];
+        let yiaddr = if reply.message_type == MESSAGE_TYPE_NAK {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            options.push((OPTION_LEASE_TIME, &lease_time[..]));
+            options.push((OPTION_SUBNET_MASK, &subnet_mask[..]));
+            options.push((OPTION_ROUTER, &server_id[..]));
+            if !dns_servers.is_empty() {
+                options.push((OPTION_DNS_SERVERS, &dns_servers[..]));
+            }
+            if let Some(mtu) = mtu.as_ref() {
+                options.push((OPTION_INTERFACE_MTU, &mtu[..]));
+            }
+            self.lease.ipv4_addr
+        };
+
+        DhcpMessage::write_reply(buf, reply.xid, reply.flags, yiaddr, reply.chaddr, &options)
+            .map(|message| message.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use dumbo::pdu::dhcp::OP_BOOTREPLY;
+
+    const CLIENT_MAC: &str = "12:34:56:78:9a:bc";
+
+    pub(crate) fn lease() -> DhcpLease {
+        DhcpLease {
+            ipv4_addr: Ipv4Addr::new(10, 0, 0, 2),
+            prefix_len: 24,
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+            dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            mtu: Some(1400),
+        }
+    }
+
+    // Writes a request sent by the client, reusing the reply format.
+    pub(crate) fn write_request(buf: &mut [u8], dst_port: u16, options: &[(u8, &[u8])]) -> usize {
+        let client_mac = MacAddr::parse_str(CLIENT_MAC).unwrap();
+        let mut eth_unsized = EthernetFrame::write_incomplete(
+            &mut buf[..],
+            MacAddr::from_bytes_unchecked(&[0xff; 6]),
+            client_mac,
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let mut ip_unsized = IPv4Packet::write_header(
+            eth_unsized.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+        )
+        .unwrap();
+        let ip_payload = ip_unsized.inner_mut().payload_mut();
+        let message_len = DhcpMessage::write_reply(
+            &mut ip_payload[UDP_HEADER_SIZE..],
+            0x1234,
+            0,
+            Ipv4Addr::UNSPECIFIED,
+            client_mac,
+            options,
+        )
+        .unwrap()
+        .len();
+        ip_payload[UDP_HEADER_SIZE] = OP_BOOTREQUEST;
+        let udp_len = UDP_HEADER_SIZE + message_len;
+        UdpDatagram::from_bytes_unchecked(&mut ip_payload[..udp_len])
+            .set_source_port(DHCP_CLIENT_PORT)
+            .set_destination_port(dst_port)
+            .set_len(udp_len as u16);
+        let ip_len = ip_unsized.with_payload_len_unchecked(udp_len, true).len();
+        eth_unsized.with_payload_len_unchecked(ip_len).len()
+    }
+
+    fn read_reply(buf: &[u8]) -> (EthernetFrame<&[u8]>, DhcpMessage<&[u8]>) {
+        let eth = EthernetFrame::from_bytes(buf).unwrap();
+        let ip = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(ip.source_address(), lease().gateway);
+        assert_eq!(ip.destination_address(), Ipv4Addr::BROADCAST);
+        let udp =
+            UdpDatagram::from_bytes(ip.payload(), Some((lease().gateway, Ipv4Addr::BROADCAST)))
+                .unwrap();
+        assert_eq!(udp.source_port(), DHCP_SERVER_PORT);
+        assert_eq!(udp.destination_port(), DHCP_CLIENT_PORT);
+        // The reply is not padded, so the message ends the frame.
+        let message = DhcpMessage::from_bytes(&buf[buf.len() - udp.payload().len()..]).unwrap();
+        assert_eq!(message.op(), OP_BOOTREPLY);
+        assert_eq!(message.xid(), 0x1234);
+        assert_eq!(message.chaddr(), MacAddr::parse_str(CLIENT_MAC).unwrap());
+        (eth, message)
+    }
+
+    #[test]
+    fn test_dhcp_responder() {
+        let mut responder = DhcpResponder::new(lease());
+        assert_eq!(responder.lease(), &lease());
+        assert_eq!(lease().subnet_mask(), Ipv4Addr::new(255, 255, 255, 0));
+        let mut buf = [0u8; 1000];
+        let mut reply_buf = [0u8; 1000];
+
+        // Datagrams heading to other ports go to the tap.
+        let len = write_request(
+            &mut buf,
+            53,
+            &[(OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_DISCOVER])],
+        );
+        assert!(!responder.detour_frame(&buf[..len]));
+        assert!(responder.write_next_frame(&mut reply_buf).is_none());
+
+        let len = write_request(
+            &mut buf,
+            DHCP_SERVER_PORT,
+            &[(OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_DISCOVER])],
+        );
+        assert!(responder.detour_frame(&buf[..len]));
+        let reply_len = responder.write_next_frame(&mut reply_buf).unwrap().get();
+        assert!(responder.write_next_frame(&mut reply_buf).is_none());
+        let (eth, message) = read_reply(&reply_buf[..reply_len]);
+        assert_eq!(eth.dst_mac(), MacAddr::parse_str(CLIENT_MAC).unwrap());
+        assert_eq!(message.message_type(), Some(MESSAGE_TYPE_OFFER));
+        assert_eq!(message.yiaddr(), lease().ipv4_addr);
+        assert_eq!(
+            message.ipv4_addr_option(OPTION_SERVER_ID),
+            Some(lease().gateway)
+        );
+        assert_eq!(
+            message.ipv4_addr_option(OPTION_ROUTER),
+            Some(lease().gateway)
+        );
+        assert_eq!(
+            message.ipv4_addr_option(OPTION_SUBNET_MASK),
+            Some(lease().subnet_mask())
+        );
+        assert_eq!(
+            message.option(OPTION_DNS_SERVERS),
+            Some(&[8u8, 8, 8, 8, 8, 8, 4, 4][..])
+        );
+        assert_eq!(
+            message.option(OPTION_INTERFACE_MTU),
+            Some(&1400u16.to_be_bytes()[..])
+        );
+        assert_eq!(message.option(OPTION_LEASE_TIME), Some(&[0xffu8; 4][..]));
+
+        let server_id = lease().gateway.octets();
+        let requested_addr = lease().ipv4_addr.octets();
+        let len = write_request(
+            &mut buf,
+            DHCP_SERVER_PORT,
+            &[
+                (OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_REQUEST]),
+                (OPTION_SERVER_ID, &server_id),
+                (OPTION_REQUESTED_IP_ADDR, &requested_addr),
+            ],
+        );
+        assert!(responder.detour_frame(&buf[..len]));
+        let reply_len = responder.write_next_frame(&mut reply_buf).unwrap().get();
+        let (_, message) = read_reply(&reply_buf[..reply_len]);
+        assert_eq!(message.message_type(), Some(MESSAGE_TYPE_ACK));
+        assert_eq!(message.yiaddr(), lease().ipv4_addr);
+
+        // The guest asks for another address.
+        let other_addr = Ipv4Addr::new(10, 0, 0, 3).octets();
+        let len = write_request(
+            &mut buf,
+            DHCP_SERVER_PORT,
+            &[
+                (OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_REQUEST]),
+                (OPTION_REQUESTED_IP_ADDR, &other_addr),
+            ],
+        );
+        assert!(responder.detour_frame(&buf[..len]));
+        let reply_len = responder.write_next_frame(&mut reply_buf).unwrap().get();
+        let (eth, message) = read_reply(&reply_buf[..reply_len]);
+        assert_eq!(eth.dst_mac(), MacAddr::from_bytes_unchecked(&[0xff; 6]));
+        assert_eq!(message.message_type(), Some(MESSAGE_TYPE_NAK));
+        assert_eq!(message.yiaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(message.option(OPTION_SUBNET_MASK), None);
+
+        // The guest accepted the offer of another server.
+        let other_server_id = Ipv4Addr::new(10, 0, 0, 254).octets();
+        let len = write_request(
+            &mut buf,
+            DHCP_SERVER_PORT,
+            &[
+                (OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_REQUEST]),
+                (OPTION_SERVER_ID, &other_server_id),
+                (OPTION_REQUESTED_IP_ADDR, &requested_addr),
+            ],
+        );
+        assert!(responder.detour_frame(&buf[..len]));
+        assert!(responder.write_next_frame(&mut reply_buf).is_none());
+
+        // Messages without a type are consumed without a reply.
+        let len = write_request(&mut buf, DHCP_SERVER_PORT, &[]);
+        assert!(responder.detour_frame(&buf[..len]));
+        assert!(responder.write_next_frame(&mut reply_buf).is_none());
+        // Truncated packets go to the tap.
+        assert!(!responder.detour_frame(&buf[..len - 100]));
+    }
+}
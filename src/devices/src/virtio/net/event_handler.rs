@@ -4,19 +4,28 @@
 use std::os::unix::io::AsRawFd;
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
-use logger::{debug, error, warn, IncMetric, METRICS};
+use logger::{debug, error, warn};
 use utils::epoll::EventSet;
 
 use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::VirtioDevice;
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", e);
+        if self.vhost.is_some() {
+            // The queue events are consumed by the vhost-net driver, which also reads from
+            // the tap, so only its calls need handling here.
+            for call_evt in self.vhost_call_evts.iter() {
+                if let Err(e) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                    error!("Failed to register vhost-net call event: {}", e);
+                }
+            }
+            return;
         }
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", e);
+        for queue_evt in self.queue_evts.iter() {
+            if let Err(e) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", e);
+            }
         }
         if let Err(e) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
             error!("Failed to register rx queue event: {}", e);
@@ -24,11 +33,13 @@ impl Net {
         if let Err(e) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
             error!("Failed to register tx queue event: {}", e);
         }
-        if let Err(e) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", e);
+        for queue_pair in self.queue_pairs.iter() {
+            if let Err(e) = ops.add(Events::new(
+                queue_pair,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to register tap event: {}", e);
+            }
         }
     }
 
@@ -67,25 +78,36 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
             let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
             let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let tap_pair = self
+                .queue_pairs
+                .iter()
+                .position(|queue_pair| queue_pair.as_raw_fd() == source);
+            let queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
+            let call_index = self
+                .vhost_call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source);
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
                 _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
                 _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
-                _ => {
-                    warn!("Net: Spurious event received: {:?}", source);
-                    METRICS.net.event_fails.inc();
-                }
+                _ => match (tap_pair, queue_index, call_index) {
+                    (_, _, Some(call_index)) => self.process_vhost_call_event(call_index),
+                    (Some(pair), _, None) => self.process_tap_rx_event(pair),
+                    (None, Some(queue_index), None) => self.process_queue_event(queue_index),
+                    (None, None, None) => {
+                        warn!("Net: Spurious event received: {:?}", source);
+                        self.metrics.inc(|m| &m.event_fails);
+                    }
+                },
             }
         } else {
             warn!(
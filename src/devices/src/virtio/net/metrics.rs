@@ -0,0 +1,46 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use logger::{NetDeviceMetrics, PerDeviceMetrics, METRICS};
+
+use crate::virtio::metrics::{DeviceMetrics, DeviceMetricsType};
+
+impl DeviceMetricsType for NetDeviceMetrics {
+    fn global() -> &'static Self {
+        &METRICS.net
+    }
+
+    fn per_device() -> &'static PerDeviceMetrics<Self> {
+        &METRICS.net_interfaces
+    }
+}
+
+/// Metrics of a network device, accounted both in the global net metrics and
+/// in the metrics of the interface the device belongs to.
+pub type NetMetrics = DeviceMetrics<NetDeviceMetrics>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use logger::IncMetric;
+
+    #[test]
+    fn test_net_metrics() {
+        let metrics = NetMetrics::new("test_net_metrics");
+        let global_count = METRICS.net.rx_bytes_count.count();
+
+        metrics.inc(|m| &m.rx_bytes_count);
+        metrics.add(|m| &m.rx_bytes_count, 2);
+        assert_eq!(metrics.device().rx_bytes_count.count(), 3);
+        assert!(METRICS.net.rx_bytes_count.count() >= global_count + 3);
+        // Handles of the same interface share the counters.
+        assert_eq!(
+            NetMetrics::new("test_net_metrics")
+                .device()
+                .rx_bytes_count
+                .count(),
+            3
+        );
+    }
+}
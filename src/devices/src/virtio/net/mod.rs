@@ -11,16 +11,33 @@ pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 pub const RX_INDEX: usize = 0;
 // The index of the tx queue from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
+// Default number of RX/TX queue pairs.
+pub const NUM_QUEUE_PAIRS: u16 = 1;
+// Maximum number of RX/TX queue pairs.
+pub const MAX_NUM_QUEUE_PAIRS: u16 = 16;
+// Smallest MTU IPv4 hosts have to support.
+pub const MIN_MTU: u16 = 68;
 
+mod anti_spoofing;
+mod backend;
 pub mod device;
+mod dhcp;
 pub mod event_handler;
+pub mod metrics;
+mod pcap;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+mod unix_socket;
+mod vhost;
 
 pub use self::device::Net;
+pub use self::dhcp::{DhcpLease, MAX_DNS_SERVERS as MAX_DHCP_DNS_SERVERS};
 pub use self::event_handler::*;
+pub use self::metrics::NetMetrics;
+pub use pcap::MAX_SNAPLEN as MAX_CAPTURE_SNAPLEN;
 pub use tap::Error as TapError;
+pub use vhost::Error as VhostNetError;
 
 #[derive(Debug)]
 pub enum Error {
@@ -32,12 +49,40 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Attaching or detaching a tap queue failed.
+    TapSetQueue(TapError),
+    /// Getting the MTU of the tap interface failed.
+    TapGetMtu(TapError),
     /// EventFd error.
     EventFd(io::Error),
     /// IO error.
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// The driver requested an invalid number of queue pairs.
+    InvalidNumQueuePairs(u16),
+    /// The control queue command is not supported.
+    UnsupportedCtrlCommand(u8, u8),
+    /// Setting up the vhost-net driver failed.
+    VhostNet(VhostNetError),
+    /// The vhost-net driver lacks some of the required virtio features.
+    VhostNetMissingFeatures(u64),
+    /// The vhost-net driver only serves a single queue pair.
+    VhostNetMultiQueue,
+    /// Creating or writing to the capture file failed.
+    Capture(io::Error),
+    /// The frames handled by the vhost-net driver cannot be captured.
+    CaptureVhostNet,
+    /// The anti-spoofing filter needs the guest MAC address.
+    AntiSpoofingMacMissing,
+    /// The link of the interfaces handled by the vhost-net driver cannot be brought down.
+    LinkStatusVhostNet,
+    /// The MTU is below the minimum or above the MTU of the tap interface.
+    InvalidMtu(u16),
+    /// Connecting to the unix socket of the userspace switch failed.
+    SocketConnect(io::Error),
+    /// The vhost-net driver cannot exchange frames over a unix socket.
+    VhostNetSocketBackend,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,133 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capture of the frames of a network device in the pcap format.
+//!
+//! The file starts with the global header, followed by a record header and the (possibly
+//! truncated) bytes of each frame. All the fields are written in the host byte order, which
+//! readers detect from the magic number.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use utils::time::{get_time_us, ClockType};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const GLOBAL_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+
+/// Largest number of bytes captured from each frame.
+pub const MAX_SNAPLEN: u32 = 65535;
+
+/// Writes the frames going through a network device to a pcap file.
+pub struct PcapWriter {
+    file: File,
+    snaplen: u32,
+    max_size: u64,
+    size: u64,
+}
+
+impl PcapWriter {
+    /// Creates the capture file found at `path`, replacing any existing one. At most `snaplen`
+    /// bytes are captured from each frame, and frames stop being captured once the file would
+    /// grow past `max_size` bytes.
+    pub fn new<P: AsRef<Path>>(path: P, snaplen: u32, max_size: u64) -> io::Result<PcapWriter> {
+        if snaplen == 0 || snaplen > MAX_SNAPLEN || max_size < GLOBAL_HEADER_LEN {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut header = Vec::with_capacity(GLOBAL_HEADER_LEN as usize);
+        header.extend_from_slice(&PCAP_MAGIC.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_ne_bytes());
+        // Timestamps are in UTC and their accuracy is not known.
+        header.extend_from_slice(&0i32.to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&snaplen.to_ne_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+        file.write_all(&header)?;
+
+        Ok(PcapWriter {
+            file,
+            snaplen,
+            max_size,
+            size: GLOBAL_HEADER_LEN,
+        })
+    }
+
+    /// Appends the Ethernet `frame` to the capture, unless the file is full.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let captured_len = std::cmp::min(frame.len(), self.snaplen as usize);
+        let record_len = RECORD_HEADER_LEN + captured_len as u64;
+        if self.size + record_len > self.max_size {
+            return Ok(());
+        }
+
+        let timestamp_us = get_time_us(ClockType::Real);
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&((timestamp_us / 1_000_000) as u32).to_ne_bytes());
+        record.extend_from_slice(&((timestamp_us % 1_000_000) as u32).to_ne_bytes());
+        record.extend_from_slice(&(captured_len as u32).to_ne_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_ne_bytes());
+        record.extend_from_slice(&frame[..captured_len]);
+        self.file.write_all(&record)?;
+
+        self.size += record_len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_ne_bytes(buf)
+    }
+
+    #[test]
+    fn test_pcap_writer() {
+        let tmp = TempFile::new().unwrap();
+        assert!(PcapWriter::new(tmp.as_path(), 0, 1024).is_err());
+        assert!(PcapWriter::new(tmp.as_path(), MAX_SNAPLEN + 1, 1024).is_err());
+        assert!(PcapWriter::new(tmp.as_path(), MAX_SNAPLEN, GLOBAL_HEADER_LEN - 1).is_err());
+
+        // Room for the header and two records holding at most 4 bytes.
+        let mut pcap = PcapWriter::new(tmp.as_path(), 4, 64).unwrap();
+        pcap.write_frame(&[1, 2]).unwrap();
+        pcap.write_frame(&[3, 4, 5, 6, 7, 8]).unwrap();
+        // The file is full.
+        pcap.write_frame(&[9]).unwrap();
+
+        let bytes = fs::read(tmp.as_path()).unwrap();
+        assert_eq!(bytes.len(), 62);
+        assert_eq!(read_u32(&bytes, 0), PCAP_MAGIC);
+        assert_eq!(read_u32(&bytes, 16), 4);
+        assert_eq!(read_u32(&bytes, 20), LINKTYPE_ETHERNET);
+
+        // Captured and original lengths, followed by the frame bytes.
+        assert_eq!(read_u32(&bytes, 32), 2);
+        assert_eq!(read_u32(&bytes, 36), 2);
+        assert_eq!(&bytes[40..42], &[1, 2]);
+        assert_eq!(read_u32(&bytes, 50), 4);
+        assert_eq!(read_u32(&bytes, 54), 6);
+        assert_eq!(&bytes[58..62], &[3, 4, 5, 6]);
+    }
+}
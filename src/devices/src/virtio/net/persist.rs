@@ -4,6 +4,7 @@
 //! Defines the structures needed for saving/restoring net devices.
 
 use std::io;
+use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
@@ -11,12 +12,14 @@ use mmds::{ns::MmdsNetworkStack, persist::MmdsNetworkStackState};
 use rate_limiter::{persist::RateLimiterState, RateLimiter};
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_net::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE};
 use vm_memory::GuestMemoryMmap;
 
-use super::device::{ConfigSpace, Net};
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::Net;
+use super::dhcp::DhcpLease;
+use super::{NUM_QUEUES, NUM_QUEUE_PAIRS, QUEUE_SIZE};
 
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
@@ -25,6 +28,63 @@ use crate::virtio::{DeviceState, TYPE_NET};
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
+    #[version(start = 3, ser_fn = "net_config_space_mtu_ser")]
+    mtu: Option<u16>,
+}
+
+impl NetConfigSpaceState {
+    fn net_config_space_mtu_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The driver would read a zero MTU from the config space once reset.
+        if target_version < 3 && self.mtu.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the MTU feature.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DhcpLeaseState {
+    ipv4_addr: u32,
+    prefix_len: u8,
+    gateway: u32,
+    dns_servers: Vec<u32>,
+    mtu: Option<u16>,
+}
+
+impl From<&DhcpLease> for DhcpLeaseState {
+    fn from(lease: &DhcpLease) -> Self {
+        DhcpLeaseState {
+            ipv4_addr: u32::from(lease.ipv4_addr),
+            prefix_len: lease.prefix_len,
+            gateway: u32::from(lease.gateway),
+            dns_servers: lease
+                .dns_servers
+                .iter()
+                .map(|addr| u32::from(*addr))
+                .collect(),
+            mtu: lease.mtu,
+        }
+    }
+}
+
+impl From<&DhcpLeaseState> for DhcpLease {
+    fn from(state: &DhcpLeaseState) -> Self {
+        DhcpLease {
+            ipv4_addr: Ipv4Addr::from(state.ipv4_addr),
+            prefix_len: state.prefix_len,
+            gateway: Ipv4Addr::from(state.gateway),
+            dns_servers: state
+                .dns_servers
+                .iter()
+                .map(|addr| Ipv4Addr::from(*addr))
+                .collect(),
+            mtu: state.mtu,
+        }
+    }
 }
 
 #[derive(Clone, Versionize)]
@@ -36,9 +96,107 @@ pub struct NetState {
     tx_rate_limiter_state: RateLimiterState,
     mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
+    #[version(
+        start = 3,
+        ser_fn = "net_num_queue_pairs_ser",
+        default_fn = "default_num_queue_pairs"
+    )]
+    num_queue_pairs: u16,
+    #[version(start = 3, default_fn = "default_num_queue_pairs")]
+    active_queue_pairs: u16,
+    #[version(start = 3, ser_fn = "net_anti_spoofing_ser")]
+    anti_spoofing_ipv4_addrs: Option<Vec<u32>>,
+    #[version(start = 3, ser_fn = "net_dhcp_ser")]
+    dhcp_lease: Option<DhcpLeaseState>,
+    #[version(
+        start = 3,
+        ser_fn = "net_link_status_ser",
+        default_fn = "default_link_up"
+    )]
+    link_up: bool,
+    #[version(start = 3, ser_fn = "net_socket_backend_ser")]
+    socket_path: Option<String>,
     virtio_state: VirtioDeviceState,
 }
 
+impl NetState {
+    fn net_num_queue_pairs_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The queue pairs cannot be merged once the driver started using them.
+        if target_version < 3 && self.num_queue_pairs != NUM_QUEUE_PAIRS {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement multiple queue pairs.".to_owned(),
+            ));
+        }
+
+        // Older versions only create the control queue along with multiple queue pairs. It can
+        // be left out as long as the driver does not use it.
+        if target_version < 3 && self.virtio_state.queues.len() > NUM_QUEUES {
+            if self.virtio_state.acked_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+                return Err(VersionizeError::Semantic(
+                    "Target version does not implement the control queue.".to_owned(),
+                ));
+            }
+            self.virtio_state.queues.truncate(NUM_QUEUES);
+            self.virtio_state.avail_features &=
+                !(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE);
+        }
+
+        Ok(())
+    }
+
+    fn net_anti_spoofing_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring the device without its filter would let the guest forge addresses.
+        if target_version < 3 && self.anti_spoofing_ipv4_addrs.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the anti-spoofing filter.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn net_dhcp_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The guest would not be able to renew its lease once restored.
+        if target_version < 3 && self.dhcp_lease.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the DHCP responder.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn net_link_status_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The link would silently come back up once restored.
+        if target_version < 3 && !self.link_up {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the link status.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn net_socket_backend_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The device would be restored with a tap interface instead.
+        if target_version < 3 && self.socket_path.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the unix socket backend.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_link_up(_source_version: u16) -> bool {
+        true
+    }
+
+    fn default_num_queue_pairs(_source_version: u16) -> u16 {
+        NUM_QUEUE_PAIRS
+    }
+}
+
 pub struct NetConstructorArgs {
     pub mem: GuestMemoryMmap,
 }
@@ -64,7 +222,16 @@ impl Persist<'_> for Net {
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac: self.config_space.guest_mac,
+                mtu: self.mtu(),
             },
+            num_queue_pairs: self.num_queue_pairs(),
+            active_queue_pairs: self.active_queue_pairs(),
+            anti_spoofing_ipv4_addrs: self
+                .anti_spoofing_ipv4_addrs()
+                .map(|addrs| addrs.iter().map(|addr| u32::from(*addr)).collect()),
+            dhcp_lease: self.dhcp_lease().map(DhcpLeaseState::from),
+            link_up: self.link_up(),
+            socket_path: self.socket_path().map(String::from),
             virtio_state: VirtioDeviceState::from_device(self),
         }
     }
@@ -78,14 +245,26 @@ impl Persist<'_> for Net {
             .map_err(Error::CreateRateLimiter)?;
         let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)
             .map_err(Error::CreateRateLimiter)?;
-        let mut net = Net::new_with_tap(
-            state.id.clone(),
-            state.tap_if_name.clone(),
-            None,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            state.mmds_ns.is_some(),
-        )
+        let mut net = match state.socket_path.as_ref() {
+            // The switch of the new host has to listen on the same path.
+            Some(socket_path) => Net::new_with_socket(
+                state.id.clone(),
+                socket_path.clone(),
+                None,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                state.mmds_ns.is_some(),
+            ),
+            None => Net::new_with_tap(
+                state.id.clone(),
+                state.tap_if_name.clone(),
+                None,
+                state.num_queue_pairs,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                state.mmds_ns.is_some(),
+            ),
+        }
         .map_err(Error::CreateNet)?;
 
         // Safe to unwrap because MmdsNetworkStack::restore() cannot fail.
@@ -94,16 +273,19 @@ impl Persist<'_> for Net {
             .as_ref()
             .map(|mmds_state| MmdsNetworkStack::restore((), &mmds_state).unwrap());
 
+        if state.virtio_state.queues.len() < net.queues.len() {
+            // Snapshots taken by older versions lack the control queue.
+            net.remove_ctrl_queue();
+        }
+        let num_queues = net.queues.len();
         net.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_NET, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(&constructor_args.mem, TYPE_NET, num_queues, QUEUE_SIZE)
             .map_err(Error::VirtioState)?;
         net.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
-        net.avail_features = state.virtio_state.avail_features;
+        net.avail_features = state.virtio_state.all_avail_features();
         net.acked_features = state.virtio_state.acked_features;
-        net.config_space = ConfigSpace {
-            guest_mac: state.config_space.guest_mac,
-        };
+        net.config_space.guest_mac = state.config_space.guest_mac;
 
         net.guest_mac = Some(MacAddr::from_bytes_unchecked(
             &state.config_space.guest_mac[..MAC_ADDR_LEN],
@@ -112,6 +294,24 @@ impl Persist<'_> for Net {
         if state.virtio_state.activated {
             net.device_state = DeviceState::Activated(constructor_args.mem);
         }
+        if let Some(addrs) = state.anti_spoofing_ipv4_addrs.as_ref() {
+            net.enable_anti_spoofing(addrs.iter().map(|addr| Ipv4Addr::from(*addr)).collect())
+                .map_err(Error::CreateNet)?;
+        }
+        if let Some(lease_state) = state.dhcp_lease.as_ref() {
+            net.enable_dhcp(DhcpLease::from(lease_state));
+        }
+        if let Some(mtu) = state.config_space.mtu {
+            // The tap interface of the new host has to accommodate the guest.
+            net.set_mtu(mtu).map_err(Error::CreateNet)?;
+        }
+        if !state.link_up {
+            net.set_link_up(false).map_err(Error::CreateNet)?;
+        }
+        if state.active_queue_pairs != NUM_QUEUE_PAIRS {
+            net.set_active_queue_pairs(state.active_queue_pairs)
+                .map_err(Error::CreateNet)?;
+        }
 
         Ok(net)
     }
@@ -121,9 +321,14 @@ impl Persist<'_> for Net {
 mod tests {
     use super::*;
     use crate::virtio::device::VirtioDevice;
+    use crate::virtio::VIRTIO_F_RING_PACKED;
 
+    use crate::virtio::net::dhcp::tests::lease as dhcp_lease;
     use crate::virtio::net::test_utils::{default_guest_memory, default_net};
+    use std::os::unix::net::UnixDatagram;
     use std::sync::atomic::Ordering;
+    use utils::tempfile::TempFile;
+    use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
 
     #[test]
     fn test_persistence() {
@@ -134,7 +339,7 @@ mod tests {
         let id;
         let tap_if_name;
         let allow_mmds_requests;
-        let virtio_state;
+        let mut virtio_state;
 
         // Create and save the net device.
         {
@@ -149,6 +354,12 @@ mod tests {
             tap_if_name = net.iface_name();
             allow_mmds_requests = net.mmds_ns.is_some();
             virtio_state = VirtioDeviceState::from_device(&net);
+            // The control queue is left out of the older snapshots.
+            virtio_state.avail_features &= !(1 << VIRTIO_NET_F_CTRL_VQ
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
+                | 1 << VIRTIO_RING_F_INDIRECT_DESC
+                | 1 << VIRTIO_RING_F_EVENT_IDX
+                | 1 << VIRTIO_F_RING_PACKED);
         }
 
         // Deserialize and restore the net device.
@@ -168,6 +379,7 @@ mod tests {
                 virtio_state.interrupt_status
             );
             assert_eq!(restored_net.is_activated(), virtio_state.activated);
+            assert_eq!(restored_net.queues().len(), NUM_QUEUES);
 
             // Test that net specific fields are the same.
             assert_eq!(&restored_net.id, &id);
@@ -177,4 +389,265 @@ mod tests {
             assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
         }
     }
+
+    #[test]
+    fn test_num_queue_pairs_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+
+        let (state, queues, avail_features) = {
+            let net = Net::new_with_tap(
+                "mq-net".to_string(),
+                "mq-net-persist".to_string(),
+                None,
+                2,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                false,
+            )
+            .unwrap();
+
+            // Older versions only know about single queue pair devices.
+            assert!(<Net as Persist>::save(&net)
+                .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+                .is_err());
+
+            <Net as Persist>::save(&net)
+                .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+                .unwrap();
+            (
+                NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+                net.queues().to_vec(),
+                net.avail_features(),
+            )
+        };
+        assert_eq!(state.num_queue_pairs, 2);
+        assert_eq!(state.active_queue_pairs, 1);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.num_queue_pairs(), 2);
+        assert_eq!(restored_net.active_queue_pairs(), 1);
+        // Two queue pairs and the control queue.
+        assert_eq!(restored_net.queue_events().len(), 5);
+        assert_eq!(restored_net.queues(), queues.as_slice());
+        assert_eq!(restored_net.avail_features(), avail_features);
+    }
+
+    #[test]
+    fn test_anti_spoofing_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+        let allowed_ipv4_addrs = vec![Ipv4Addr::new(10, 0, 0, 2)];
+
+        let mut net = default_net();
+        net.enable_anti_spoofing(allowed_ipv4_addrs.clone())
+            .unwrap();
+
+        // Older versions would restore the device without the filter.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            restored_net.anti_spoofing_ipv4_addrs(),
+            Some(allowed_ipv4_addrs.as_slice())
+        );
+    }
+
+    #[test]
+    fn test_dhcp_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+
+        let mut net = default_net();
+        net.enable_dhcp(dhcp_lease());
+
+        // Older versions would restore the device without the DHCP responder.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.dhcp_lease(), Some(&dhcp_lease()));
+    }
+    #[test]
+    fn test_link_status_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+
+        let mut net = default_net();
+        net.set_link_up(false).unwrap();
+
+        // Older versions would restore the device with its link up.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert!(!restored_net.link_up());
+    }
+    #[test]
+    fn test_ctrl_queue_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+
+        let mut net = default_net();
+        net.ack_features_by_page(
+            0,
+            1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE,
+        );
+        net.device_state = DeviceState::Activated(default_guest_memory());
+
+        // Older versions would restore the device without the control queue used by the driver.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.queues().len(), NUM_QUEUES + 1);
+    }
+    #[test]
+    fn test_mtu_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3)
+            .set_type_version(NetConfigSpaceState::type_id(), 3);
+
+        let mut net = default_net();
+        net.set_mtu(1450).unwrap();
+
+        // Older versions would not advertise the MTU once the device is reset.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.mtu(), Some(1450));
+    }
+
+    #[test]
+    fn test_socket_backend_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(NetState::type_id(), 3);
+
+        let tmp = TempFile::new().unwrap();
+        let socket_path = tmp.as_path().to_str().unwrap().to_string();
+        drop(tmp);
+        let _switch = UnixDatagram::bind(&socket_path).unwrap();
+        let net = Net::new_with_socket(
+            "socket-net".to_string(),
+            socket_path.clone(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            false,
+        )
+        .unwrap();
+
+        // Older versions would look for a tap interface instead.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.socket_path(), Some(socket_path.as_str()));
+        assert_eq!(restored_net.iface_name(), "");
+        assert_eq!(restored_net.avail_features(), net.avail_features());
+        std::fs::remove_file(&socket_path).unwrap();
+    }
 }
@@ -28,6 +28,8 @@ pub enum Error {
     IoctlError(IoError),
     /// Couldn't open /dev/net/tun.
     OpenTun(IoError),
+    /// Couldn't create the socket used to query the interface.
+    CreateSocket(IoError),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -36,6 +38,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -105,6 +108,28 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Self::open(if_name, 0)
+    }
+
+    /// Create the queues of a multi-queue TUN/TAP device given the interface name.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_named_multi_queue(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        let first_queue = Self::open(if_name, net_gen::IFF_MULTI_QUEUE)?;
+        // The kernel picks the interface name when opening the first queue, if a template
+        // (or no name at all) was given.
+        let if_name = first_queue.if_name_as_str().to_string();
+
+        let mut taps = vec![first_queue];
+        for _ in 1..num_queues {
+            taps.push(Self::open(&if_name, net_gen::IFF_MULTI_QUEUE)?);
+        }
+        Ok(taps)
+    }
+
+    fn open(if_name: &str, extra_flags: c_uint) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -121,9 +146,10 @@ impl Tap {
         // We just checked that the fd is valid.
         let tuntap = unsafe { File::from_raw_fd(fd) };
 
+        let flags = net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | extra_flags;
         let ifreq = IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .flags(flags as i16)
             .execute(&tuntap, TUNSETIFF())?;
 
         // Safe since only the name is accessed, and it's cloned out.
@@ -163,6 +189,41 @@ impl Tap {
 
         Ok(())
     }
+
+    /// Attach the queue to its multi-queue tap interface, or detach it. The interface does not
+    /// send frames to detached queues.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
+
+    /// Get the MTU of the tap interface.
+    pub fn mtu(&self) -> Result<u16> {
+        // The interface settings are only reachable through a socket.
+        // This is safe since we check the return value.
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::CreateSocket(IoError::last_os_error()));
+        }
+        // This is safe; nothing else will use or hold onto the raw socket fd.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        let ifreq = IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .execute(&socket, c_ulong::from(net_gen::sockios::SIOCGIFMTU))?;
+
+        // Safe since the kernel just filled in the MTU.
+        let mtu = unsafe { *ifreq.ifr_ifru.ifru_mtu.as_ref() };
+        Ok(mtu as u16)
+    }
 }
 
 impl Read for Tap {
@@ -237,6 +298,25 @@ pub mod tests {
         Tap::open_named("exclusivetap").unwrap_err();
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let taps = Tap::open_named_multi_queue("mqtap%d", 3).unwrap();
+        assert_eq!(taps.len(), 3);
+        assert!(taps[0].if_name_as_str().starts_with("mqtap"));
+        assert!(taps
+            .iter()
+            .all(|tap| tap.if_name_as_str() == taps[0].if_name_as_str()));
+
+        // Queues can be detached and attached back, but only once.
+        taps[2].set_queue_enabled(false).unwrap();
+        taps[2].set_queue_enabled(false).unwrap_err();
+        taps[2].set_queue_enabled(true).unwrap();
+
+        // Single queue interfaces have no queues to detach.
+        let tap = Tap::open_named("").unwrap();
+        tap.set_queue_enabled(false).unwrap_err();
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
@@ -252,6 +332,18 @@ pub mod tests {
         assert!(faulty_tap.set_offload(0).is_err());
     }
 
+    #[test]
+    fn test_mtu() {
+        let tap = Tap::open_named("").unwrap();
+        assert_eq!(tap.mtu().unwrap(), 1500);
+
+        let faulty_tap = Tap {
+            tap_file: unsafe { File::from_raw_fd(-2) },
+            if_name: [0x01; 16],
+        };
+        assert!(faulty_tap.mtu().is_err());
+    }
+
     #[test]
     fn test_raw_fd() {
         let tap = Tap::open_named("").unwrap();
@@ -12,6 +12,7 @@ use std::{mem, result};
 #[cfg(test)]
 use crate::virtio::net::device::vnet_hdr_len;
 use crate::virtio::net::tap::{Error, IfReqBuilder, Tap};
+use crate::virtio::net::NUM_QUEUE_PAIRS;
 use crate::virtio::test_utils::VirtQueue;
 use crate::virtio::{Net, Queue, QueueError};
 
@@ -36,12 +37,13 @@ pub fn default_net() -> Net {
         format!("net-device{}", next_tap),
         tap_dev_name,
         Some(&guest_mac),
+        NUM_QUEUE_PAIRS,
         RateLimiter::default(),
         RateLimiter::default(),
         true,
     )
     .unwrap();
-    enable(&net.tap);
+    enable(net.queue_pairs[0].backend.tap().unwrap());
 
     net
 }
@@ -241,7 +243,8 @@ pub fn check_used_queue_signal(net: &Net, count: u64) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator =
+        TapTrafficSimulator::new(if_index(net.queue_pairs[0].backend.tap().unwrap()));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -366,10 +369,10 @@ pub mod test {
 
         pub fn simulate_event(&mut self, event: NetEvent) {
             match event {
-                NetEvent::RxQueue => self.net().process_rx_queue_event(),
+                NetEvent::RxQueue => self.net().process_rx_queue_event(0),
                 NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(),
-                NetEvent::Tap => self.net().process_tap_rx_event(),
-                NetEvent::TxQueue => self.net().process_tx_queue_event(),
+                NetEvent::Tap => self.net().process_tap_rx_event(0),
+                NetEvent::TxQueue => self.net().process_tx_queue_event(0),
                 NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(),
             };
         }
@@ -430,7 +433,7 @@ pub mod test {
                 self.event_manager.run_with_timeout(100).unwrap()
             );
             // Check that the frame has been deferred.
-            assert!(self.net().rx_deferred_frame);
+            assert!(self.net().queue_pairs[0].rx_deferred_frame);
             // Check that the descriptor chain has been discarded.
             assert_eq!(self.rxq.used.idx.get(), used_idx + 1);
             check_used_queue_signal(&self.net(), 1);
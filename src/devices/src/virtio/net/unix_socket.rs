@@ -0,0 +1,339 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Backend exchanging the frames of a network device with a userspace switch, over a unix
+//! socket. It needs no privileges on the host, unlike tap devices.
+//!
+//! Each message carries a single Ethernet frame, without its virtio-net header. The socket is
+//! of the `SOCK_SEQPACKET` type when the switch listens on such a socket, and of the
+//! `SOCK_DGRAM` one otherwise.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::device::{init_vnet_hdr, vnet_hdr_len};
+use crate::virtio::net::Result;
+
+/// Connection to a userspace switch, backing a single queue pair.
+#[derive(Debug)]
+pub struct UnixSocketBackend {
+    socket: File,
+    path: String,
+}
+
+// Builds the address of the socket found at `path`.
+fn socket_addr(path: &str) -> io::Result<libc::sockaddr_un> {
+    // This is safe since the address is plain old data.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // The path has to be null terminated.
+    let path = Path::new(path).as_os_str().as_bytes();
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+impl UnixSocketBackend {
+    /// Connects to the switch listening on the unix socket found at `path`.
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let addr = socket_addr(path)?;
+        let socket = match Self::connect_with_type(&addr, libc::SOCK_SEQPACKET) {
+            // The switch socket is of another type.
+            Err(e) if e.raw_os_error() == Some(libc::EPROTOTYPE) => {
+                Self::connect_with_type(&addr, libc::SOCK_DGRAM)?
+            }
+            result => result?,
+        };
+
+        Ok(UnixSocketBackend {
+            socket,
+            path: path.to_string(),
+        })
+    }
+
+    fn connect_with_type(addr: &libc::sockaddr_un, socket_type: c_int) -> io::Result<File> {
+        // This is safe since we check the return value.
+        let fd = unsafe { libc::socket(libc::AF_UNIX, socket_type | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // This is safe; nothing else will use or hold onto the raw socket fd.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        if socket_type == libc::SOCK_DGRAM {
+            // The switch can only reply to datagrams sent from an address, so let the kernel
+            // pick an abstract one.
+            // This is safe since the address is plain old data, of the given length.
+            let ret = unsafe {
+                let mut local_addr: libc::sockaddr_un = mem::zeroed();
+                local_addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+                libc::bind(
+                    fd,
+                    &local_addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sa_family_t>() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // This is safe since the address outlives the call, and we check the return value.
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The frames are read until the socket runs dry.
+        // This is safe since we check the return value.
+        let ret = unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+impl NetBackend for UnixSocketBackend {
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() < vnet_hdr_len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // The frames are complete, so the header is left blank.
+        init_vnet_hdr(buf);
+        let frame_buf = &mut buf[vnet_hdr_len()..];
+        // This is safe since the kernel writes at most `frame_buf.len()` bytes, and we check
+        // the return value.
+        let ret = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                frame_buf.as_mut_ptr() as *mut libc::c_void,
+                frame_buf.len(),
+                0,
+            )
+        };
+        match ret {
+            ret if ret < 0 => Err(io::Error::last_os_error()),
+            // Nothing is read once the switch closed its end of a connected socket.
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            len => Ok(vnet_hdr_len() + len as usize),
+        }
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() < vnet_hdr_len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let frame = &buf[vnet_hdr_len()..];
+        // This is safe since the kernel reads at most `frame.len()` bytes, and we check the
+        // return value. Frames sent once the switch is gone fail instead of raising SIGPIPE.
+        let ret = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buf.len())
+    }
+
+    fn set_queue_enabled(&self, _enabled: bool) -> Result<()> {
+        // The socket backs a single queue pair, which is always enabled.
+        Ok(())
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        // The frames are only bound by the size of the device buffers.
+        Ok(u16::MAX)
+    }
+
+    fn socket_path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+}
+
+impl AsRawFd for UnixSocketBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+
+    use utils::tempfile::TempFile;
+
+    fn socket_path() -> String {
+        let tmp = TempFile::new().unwrap();
+        let path = tmp.as_path().to_str().unwrap().to_string();
+        // Only the name is needed, the switch creates the socket.
+        drop(tmp);
+        path
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        assert!(UnixSocketBackend::connect("").is_err());
+        assert!(UnixSocketBackend::connect(&"a".repeat(200)).is_err());
+        // Nobody listens there.
+        assert!(UnixSocketBackend::connect(&socket_path()).is_err());
+    }
+
+    #[test]
+    fn test_seqpacket_backend() {
+        // The standard library only provides stream listeners, so the switch end is created
+        // the same way as the backend one.
+        let path = socket_path();
+        let addr = socket_addr(&path).unwrap();
+        // This is safe since we check the return values.
+        let listener = unsafe {
+            let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+            assert!(fd >= 0);
+            assert_eq!(
+                libc::bind(
+                    fd,
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                ),
+                0
+            );
+            assert_eq!(libc::listen(fd, 1), 0);
+            File::from_raw_fd(fd)
+        };
+
+        let mut backend = UnixSocketBackend::connect(&path).unwrap();
+        assert_eq!(backend.socket_path(), Some(path.as_str()));
+        assert!(backend.tap().is_none());
+        assert!(backend.set_queue_enabled(false).is_ok());
+        assert_eq!(backend.mtu().unwrap(), u16::MAX);
+
+        // This is safe since we check the return value.
+        let switch = unsafe {
+            let fd = libc::accept(
+                listener.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+            assert!(fd >= 0);
+            File::from_raw_fd(fd)
+        };
+        let mut buf = [0xffu8; 100];
+        // Nothing is pending yet.
+        assert_eq!(
+            backend.read_frame(&mut buf).unwrap_err().raw_os_error(),
+            Some(libc::EAGAIN)
+        );
+
+        // The header is stripped from the sent frames.
+        let mut frame = vec![0u8; vnet_hdr_len()];
+        frame.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(backend.write_frame(&frame).unwrap(), frame.len());
+        let mut switch_buf = [0u8; 100];
+        let len = unsafe {
+            libc::recv(
+                switch.as_raw_fd(),
+                switch_buf.as_mut_ptr() as *mut libc::c_void,
+                switch_buf.len(),
+                0,
+            )
+        };
+        assert_eq!(&switch_buf[..len as usize], &[1, 2, 3]);
+
+        // And a blank header is added to the received ones.
+        let len = unsafe {
+            libc::send(
+                switch.as_raw_fd(),
+                [4u8, 5].as_ptr() as *const libc::c_void,
+                2,
+                0,
+            )
+        };
+        assert_eq!(len, 2);
+        assert_eq!(backend.read_frame(&mut buf).unwrap(), vnet_hdr_len() + 2);
+        assert!(buf[..vnet_hdr_len()].iter().all(|b| *b == 0));
+        assert_eq!(&buf[vnet_hdr_len()..vnet_hdr_len() + 2], &[4, 5]);
+
+        // The switch went away.
+        drop(switch);
+        assert_eq!(
+            backend.read_frame(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(backend.write_frame(&frame).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dgram_backend() {
+        let path = socket_path();
+        let switch = UnixDatagram::bind(&path).unwrap();
+        // Stream sockets are not supported.
+        let stream_path = socket_path();
+        let _stream_listener = UnixListener::bind(&stream_path).unwrap();
+        assert!(UnixSocketBackend::connect(&stream_path).is_err());
+
+        let mut backend = UnixSocketBackend::connect(&path).unwrap();
+        let mut frame = vec![0u8; vnet_hdr_len()];
+        frame.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(backend.write_frame(&frame).unwrap(), frame.len());
+        assert!(backend.write_frame(&frame[..1]).is_err());
+
+        // The switch replies to the abstract address the frame came from, which the standard
+        // library cannot handle.
+        // This is safe since the buffers outlive the calls, and we check the return values.
+        unsafe {
+            let mut switch_buf = [0u8; 100];
+            let mut backend_addr: libc::sockaddr_un = mem::zeroed();
+            let mut backend_addr_len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+            let len = libc::recvfrom(
+                switch.as_raw_fd(),
+                switch_buf.as_mut_ptr() as *mut libc::c_void,
+                switch_buf.len(),
+                0,
+                &mut backend_addr as *mut _ as *mut libc::sockaddr,
+                &mut backend_addr_len,
+            );
+            assert_eq!(&switch_buf[..len as usize], &[1, 2, 3]);
+            let len = libc::sendto(
+                switch.as_raw_fd(),
+                [4u8, 5].as_ptr() as *const libc::c_void,
+                2,
+                0,
+                &backend_addr as *const _ as *const libc::sockaddr,
+                backend_addr_len,
+            );
+            assert_eq!(len, 2);
+        }
+
+        let mut buf = [0xffu8; 100];
+        assert_eq!(backend.read_frame(&mut buf).unwrap(), vnet_hdr_len() + 2);
+        assert_eq!(&buf[vnet_hdr_len()..vnet_hdr_len() + 2], &[4, 5]);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&stream_path).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Handle on the kernel vhost-net driver.
+//!
+//! vhost-net moves the frames between the RX/TX virtqueues and the tap device from within the
+//! host kernel. The kernel accesses the guest memory through the VMM mappings, described by a
+//! memory table. It gets notified of the new available buffers through the queue eventfds
+//! the driver writes to (kick) and signals the used buffers on separate eventfds (call).
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::c_uint;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use utils::eventfd::EventFd;
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
+use vm_memory::{
+    GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress,
+};
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::Queue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+// Default limit of the vhost kernel module on the number of memory table entries.
+const MAX_MEMORY_REGIONS: usize = 64;
+
+const VHOST: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, MemoryTableHeader);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VringFile);
+
+// The layouts below follow the Linux UAPI:
+// https://elixir.bootlin.com/linux/v4.14/source/include/uapi/linux/vhost.h
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MemoryTableHeader {
+    num_regions: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+// Only the header is part of the ioctl size, the kernel copies `num_regions` entries after it.
+#[repr(C)]
+struct MemoryTable {
+    header: MemoryTableHeader,
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VringAddr {
+    index: u32,
+    flags: u32,
+    descriptor: u64,
+    used: u64,
+    available: u64,
+    log: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VringFile {
+    index: u32,
+    fd: i32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to open the vhost-net device.
+    Open(io::Error),
+    /// A vhost ioctl failed.
+    Ioctl(io::Error),
+    /// The guest memory has more regions than the kernel allows.
+    TooManyMemoryRegions(usize),
+    /// The guest address of a virtqueue is not mapped.
+    InvalidVringAddress(u64),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Turns the return value of a vhost ioctl into a `Result`.
+fn check_ioctl(ret: i32) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::Ioctl(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Instance of the vhost-net driver, serving the queues of a single network device.
+pub struct VhostNetBackend {
+    file: File,
+    features: u64,
+}
+
+impl VhostNetBackend {
+    /// Opens a new instance of the vhost-net driver, owned by the current process.
+    pub fn new() -> Result<VhostNetBackend> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::Open)?;
+
+        // Safe because we know the file is a vhost device and we check the return value.
+        check_ioctl(unsafe { ioctl(&file, VHOST_SET_OWNER()) })?;
+
+        let mut features = 0u64;
+        // Safe because the kernel writes exactly a u64 and we check the return value.
+        check_ioctl(unsafe { ioctl_with_mut_ref(&file, VHOST_GET_FEATURES(), &mut features) })?;
+
+        Ok(VhostNetBackend { file, features })
+    }
+
+    /// Virtio features supported by the driver.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Sets the virtio features acked by the guest driver.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // Safe because the kernel only reads a u64 and we check the return value.
+        check_ioctl(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })
+    }
+
+    /// Describes the mappings of the guest memory to the driver.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        if mem.num_regions() > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(mem.num_regions()));
+        }
+
+        let mut table = MemoryTable {
+            header: MemoryTableHeader {
+                num_regions: mem.num_regions() as u32,
+                padding: 0,
+            },
+            regions: [MemoryRegion::default(); MAX_MEMORY_REGIONS],
+        };
+        mem.with_regions_mut(|index, region| {
+            table.regions[index] = MemoryRegion {
+                guest_phys_addr: region.start_addr().0,
+                memory_size: region.len(),
+                // Can't fail because the address is the start of the region.
+                userspace_addr: region.get_host_address(MemoryRegionAddress(0)).unwrap() as u64,
+                flags_padding: 0,
+            };
+            Ok::<(), Error>(())
+        })?;
+
+        // Safe because the kernel only reads the `num_regions` entries following the header,
+        // which are all part of the table, and we check the return value.
+        check_ioctl(unsafe { ioctl_with_ref(&self.file, VHOST_SET_MEM_TABLE(), &table) })
+    }
+
+    /// Hands the `index` virtqueue over to the driver. The driver reads the guest
+    /// notifications from `kick_evt` and signals used buffers on `call_evt`.
+    pub fn set_vring(
+        &self,
+        mem: &GuestMemoryMmap,
+        index: usize,
+        queue: &Queue,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        let index = index as u32;
+        let host_address = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(|_| Error::InvalidVringAddress(addr.0))
+        };
+        let vring_addr = VringAddr {
+            index,
+            flags: 0,
+            descriptor: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            available: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+        let vring_num = VringState {
+            index,
+            num: u32::from(queue.actual_size()),
+        };
+        let vring_base = VringState {
+            index,
+            num: u32::from(queue.next_avail.0),
+        };
+        let vring_file = |evt: &EventFd| VringFile {
+            index,
+            fd: evt.as_raw_fd(),
+        };
+
+        // Safe because the kernel only reads the structures passed in and we check the return
+        // values.
+        unsafe {
+            check_ioctl(ioctl_with_ref(
+                &self.file,
+                VHOST_SET_VRING_NUM(),
+                &vring_num,
+            ))?;
+            check_ioctl(ioctl_with_ref(
+                &self.file,
+                VHOST_SET_VRING_ADDR(),
+                &vring_addr,
+            ))?;
+            check_ioctl(ioctl_with_ref(
+                &self.file,
+                VHOST_SET_VRING_BASE(),
+                &vring_base,
+            ))?;
+            check_ioctl(ioctl_with_ref(
+                &self.file,
+                VHOST_SET_VRING_KICK(),
+                &vring_file(kick_evt),
+            ))?;
+            check_ioctl(ioctl_with_ref(
+                &self.file,
+                VHOST_SET_VRING_CALL(),
+                &vring_file(call_evt),
+            ))
+        }
+    }
+
+    /// Starts moving the frames of the `index` virtqueue from or to `tap`.
+    pub fn set_backend(&self, index: usize, tap: &Tap) -> Result<()> {
+        let vring_file = VringFile {
+            index: index as u32,
+            fd: tap.as_raw_fd(),
+        };
+        // Safe because the kernel only reads the structure and we check the return value.
+        check_ioctl(unsafe { ioctl_with_ref(&self.file, VHOST_NET_SET_BACKEND(), &vring_file) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem;
+    use std::path::Path;
+
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
+
+    #[test]
+    fn test_ioctl_layouts() {
+        assert_eq!(mem::size_of::<MemoryTableHeader>(), 8);
+        assert_eq!(mem::size_of::<MemoryRegion>(), 32);
+        assert_eq!(mem::size_of::<VringState>(), 8);
+        assert_eq!(mem::size_of::<VringAddr>(), 40);
+        assert_eq!(mem::size_of::<VringFile>(), 8);
+
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+
+    #[test]
+    fn test_backend() {
+        // Not every host exposes the vhost-net driver.
+        if !Path::new(VHOST_NET_PATH).exists() {
+            return;
+        }
+
+        let backend = VhostNetBackend::new().unwrap();
+        assert_ne!(backend.features(), 0);
+        backend.set_features(0).unwrap();
+
+        let mem = default_mem();
+        backend.set_mem_table(&mem).unwrap();
+
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let call_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        backend
+            .set_vring(&mem, 0, &queue, &kick_evt, &call_evt)
+            .unwrap();
+
+        // Rings have to be in guest memory.
+        queue.used_ring = GuestAddress(mem.last_addr().0 + 1);
+        assert!(matches!(
+            backend.set_vring(&mem, 0, &queue, &kick_evt, &call_evt),
+            Err(Error::InvalidVringAddress(_))
+        ));
+    }
+}
@@ -7,19 +7,31 @@ use super::device::*;
 use super::queue::*;
 use crate::virtio::MmioTransport;
 use snapshot::Persist;
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
 use vm_memory::{address::Address, GuestAddress, GuestMemoryMmap};
 
 use std::num::Wrapping;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 
+// Ring features older versions do not implement.
+const NEW_RING_FEATURES: u64 =
+    1 << VIRTIO_RING_F_INDIRECT_DESC | 1 << VIRTIO_RING_F_EVENT_IDX | 1 << VIRTIO_F_RING_PACKED;
+
 #[derive(Debug)]
 pub enum Error {
     InvalidInput,
 }
 
+#[derive(Clone, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct PackedChainState {
+    id: u16,
+    num_descs: u16,
+}
+
 #[derive(Clone, Debug, PartialEq, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct QueueState {
@@ -43,6 +55,47 @@ pub struct QueueState {
 
     next_avail: Wrapping<u16>,
     next_used: Wrapping<u16>,
+
+    #[version(start = 3)]
+    num_added: Wrapping<u16>,
+    #[version(start = 3, ser_fn = "queue_notif_suppression_ser")]
+    uses_notif_suppression: bool,
+    #[version(start = 3, ser_fn = "queue_packed_ring_ser")]
+    uses_packed_ring: bool,
+    #[version(start = 3, default_fn = "default_wrap_counter")]
+    avail_wrap_counter: bool,
+    #[version(start = 3, default_fn = "default_wrap_counter")]
+    used_wrap_counter: bool,
+    #[version(start = 3)]
+    packed_chains: Vec<PackedChainState>,
+}
+
+impl QueueState {
+    fn queue_notif_suppression_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The driver would wait for notifications only sent through the avail_event field.
+        if target_version < 3 && self.uses_notif_suppression {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement notification suppression.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn queue_packed_ring_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The driver would find its descriptors in a ring of another layout.
+        if target_version < 3 && self.uses_packed_ring {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement packed queues.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_wrap_counter(_source_version: u16) -> bool {
+        true
+    }
 }
 
 impl Persist<'_> for Queue {
@@ -60,6 +113,19 @@ impl Persist<'_> for Queue {
             used_ring: self.used_ring.0,
             next_avail: self.next_avail,
             next_used: self.next_used,
+            num_added: self.num_added,
+            uses_notif_suppression: self.uses_notif_suppression,
+            uses_packed_ring: self.uses_packed_ring,
+            avail_wrap_counter: self.avail_wrap_counter,
+            used_wrap_counter: self.used_wrap_counter,
+            packed_chains: self
+                .packed_chains
+                .iter()
+                .map(|chain| PackedChainState {
+                    id: chain.id,
+                    num_descs: chain.num_descs,
+                })
+                .collect(),
         }
     }
 
@@ -76,6 +142,19 @@ impl Persist<'_> for Queue {
             used_ring: GuestAddress::new(state.used_ring),
             next_avail: state.next_avail,
             next_used: state.next_used,
+            num_added: state.num_added,
+            uses_notif_suppression: state.uses_notif_suppression,
+            uses_packed_ring: state.uses_packed_ring,
+            avail_wrap_counter: state.avail_wrap_counter,
+            used_wrap_counter: state.used_wrap_counter,
+            packed_chains: state
+                .packed_chains
+                .iter()
+                .map(|chain| PackedChain {
+                    id: chain.id,
+                    num_descs: chain.num_descs,
+                })
+                .collect(),
         })
     }
 }
@@ -85,25 +164,47 @@ impl Persist<'_> for Queue {
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VirtioDeviceState {
     pub device_type: u32,
+    /// The features offered to the driver, apart from the ring features.
     pub avail_features: u64,
     pub acked_features: u64,
     pub queues: Vec<QueueState>,
     pub interrupt_status: usize,
     pub activated: bool,
+    /// The ring features offered to the driver. They are kept apart so that they are hidden
+    /// from the driver when the state is saved for an older version.
+    #[version(start = 2, ser_fn = "ring_features_ser")]
+    pub ring_features: u64,
 }
 
 impl VirtioDeviceState {
+    fn ring_features_ser(&mut self, _target_version: u16) -> VersionizeResult<()> {
+        // The driver would keep using the features the older versions do not implement.
+        if self.acked_features & NEW_RING_FEATURES != 0 {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the negotiated ring features.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn from_device(device: &dyn VirtioDevice) -> Self {
         VirtioDeviceState {
             device_type: device.device_type(),
-            avail_features: device.avail_features(),
+            avail_features: device.avail_features() & !NEW_RING_FEATURES,
             acked_features: device.acked_features(),
             queues: device.queues().iter().map(Persist::save).collect(),
             interrupt_status: device.interrupt_status().load(Ordering::Relaxed),
             activated: device.is_activated(),
+            ring_features: device.avail_features() & NEW_RING_FEATURES,
         }
     }
 
+    /// Returns all the features offered to the driver.
+    pub fn all_avail_features(&self) -> u64 {
+        self.avail_features | self.ring_features
+    }
+
     /// Does sanity checking on the `self` state against expected values
     /// and builds queues from state.
     pub fn build_queues_checked(
@@ -118,7 +219,7 @@ impl VirtioDeviceState {
         // - acked features is a subset of available ones,
         // - right number of queues,
         if self.device_type != expected_device_type
-            || (self.acked_features & !self.avail_features) != 0
+            || (self.acked_features & !self.all_avail_features()) != 0
             || self.queues.len() != expected_num_queues
         {
             return Err(Error::InvalidInput);
@@ -141,8 +242,9 @@ impl VirtioDeviceState {
             // Snapshot can happen at any time, including during device configuration/activation
             // when fields are only partially configured.
             //
-            // Only if the device was activated, check `q.is_valid()`.
-            if self.activated && !q.is_valid(mem) {
+            // Only if the device was activated, check `q.is_valid()`. Queues left unused by
+            // the driver are not checked.
+            if self.activated && q.ready && !q.is_valid(mem) {
                 return Err(Error::InvalidInput);
             }
         }
@@ -200,7 +302,7 @@ impl Persist<'_> for MmioTransport {
 mod tests {
     use super::*;
     use crate::virtio::mmio::tests::DummyDevice;
-    use crate::virtio::{net, Block, Net, Vsock, VsockUnixBackend};
+    use crate::virtio::{net, Block, FileEngineType, Net, Vsock, VsockPortAcl, VsockUnixBackend};
 
     use crate::virtio::block::test_utils::default_block_with_path;
     use crate::virtio::test_utils::default_mem;
@@ -218,6 +320,12 @@ mod tests {
                 used_ring: 0,
                 next_avail: Wrapping(0),
                 next_used: Wrapping(0),
+                num_added: Wrapping(0),
+                uses_notif_suppression: false,
+                uses_packed_ring: false,
+                avail_wrap_counter: true,
+                used_wrap_counter: true,
+                packed_chains: vec![],
             }
         }
     }
@@ -231,6 +339,7 @@ mod tests {
                 queues: vec![],
                 interrupt_status: 0,
                 activated: false,
+                ring_features: 0,
             }
         }
     }
@@ -315,6 +424,120 @@ mod tests {
         assert_eq!(restored_queue, queue);
     }
 
+    #[test]
+    fn test_queue_notif_suppression_persistence() {
+        let mut queue = Queue::new(128);
+        queue.enable_notif_suppression();
+        queue.num_added = Wrapping(3);
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(QueueState::type_id(), 3);
+
+        // Older versions would not update the avail_event field the driver relies on.
+        assert!(queue
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        queue
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_queue = Queue::restore(
+            (),
+            &QueueState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_queue, queue);
+    }
+
+    #[test]
+    fn test_queue_packed_ring_persistence() {
+        let mut queue = Queue::new(128);
+        queue.enable_packed_ring();
+        queue.next_avail = Wrapping(5);
+        queue.avail_wrap_counter = false;
+        queue.packed_chains.push(PackedChain {
+            id: 3,
+            num_descs: 2,
+        });
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(QueueState::type_id(), 3);
+
+        // Older versions only know about split queues.
+        assert!(queue
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        queue
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_queue = Queue::restore(
+            (),
+            &QueueState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_queue, queue);
+    }
+
+    #[test]
+    fn test_ring_features_persistence() {
+        let (_, _, block) = default_block();
+        let mut state = VirtioDeviceState::from_device(&*block.lock().unwrap());
+        assert_eq!(state.ring_features, NEW_RING_FEATURES);
+        assert_eq!(state.avail_features & NEW_RING_FEATURES, 0);
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(VirtioDeviceState::type_id(), 2);
+
+        // The ring features are hidden from the driver of older versions...
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_state =
+            VirtioDeviceState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(restored_state.all_avail_features() & NEW_RING_FEATURES, 0);
+        assert_eq!(restored_state.acked_features & NEW_RING_FEATURES, 0);
+
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_state =
+            VirtioDeviceState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap();
+        assert_eq!(restored_state, state);
+
+        // ... unless it already acked them.
+        state.acked_features = 1 << VIRTIO_RING_F_EVENT_IDX;
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+        state.acked_features = 1 << VIRTIO_F_RING_PACKED;
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .is_ok());
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+    }
+
     #[test]
     fn test_virtio_device_state_versionize() {
         let dummy = DummyDevice::new();
@@ -375,7 +598,10 @@ mod tests {
         // Create backing file.
         let f = TempFile::new().unwrap();
         f.as_file().set_len(0x1000).unwrap();
-        let block = default_block_with_path(f.as_path().to_str().unwrap().to_string());
+        let block = default_block_with_path(
+            f.as_path().to_str().unwrap().to_string(),
+            FileEngineType::Sync,
+        );
         let block = Arc::new(Mutex::new(block));
         let mmio_transport = MmioTransport::new(mem.clone(), block.clone());
 
@@ -402,7 +628,9 @@ mod tests {
         // Remove the file so the path can be used by the socket.
         temp_uds_path.remove().unwrap();
         let uds_path = String::from(temp_uds_path.as_path().to_str().unwrap());
-        let backend = VsockUnixBackend::new(guest_cid, uds_path).unwrap();
+        let backend =
+            VsockUnixBackend::new(guest_cid, uds_path, vec![], vec![], VsockPortAcl::default())
+                .unwrap();
         let vsock = Vsock::new(guest_cid, backend).unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
         let mmio_transport = MmioTransport::new(mem.clone(), vsock.clone());
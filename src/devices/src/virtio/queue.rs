@@ -16,6 +16,22 @@ use vm_memory::{
 
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+pub(super) const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+// Flags the driver and the device flip to make the descriptors of a packed ring available and
+// used, along with their wrap counters.
+const VIRTQ_PACKED_DESC_F_AVAIL: u16 = 0x80;
+const VIRTQ_PACKED_DESC_F_USED: u16 = 0x8000;
+
+// Values of the flags of the packed ring event suppression structures.
+const VIRTQ_PACKED_EVENT_FLAG_DISABLE: u16 = 0x1;
+const VIRTQ_PACKED_EVENT_FLAG_DESC: u16 = 0x2;
+
+// Bit of the off_wrap field of the event suppression structures holding the wrap counter.
+const VIRTQ_PACKED_EVENT_F_WRAP_CTR: u16 = 15;
+
+/// Feature bit negotiating the packed layout of the queues.
+pub(super) const VIRTIO_F_RING_PACKED: u32 = 34;
 
 // GuestMemoryMmap::read_obj_from_addr() will be used to fetch the descriptor,
 // which has an explicit constraint that the entire descriptor doesn't
@@ -31,6 +47,8 @@ pub enum QueueError {
     DescIndexOutOfBounds(u16),
     /// Attempted an invalid write into the used ring.
     UsedRing(GuestMemoryError),
+    /// Buffer id not popped from the packed ring.
+    UnknownBufferId(u16),
 }
 
 impl fmt::Display for QueueError {
@@ -44,6 +62,7 @@ impl fmt::Display for QueueError {
                 "Failed to write value into the virtio queue used ring: {}",
                 e
             ),
+            UnknownBufferId(val) => write!(f, "Unknown buffer id: {}", val),
         }
     }
 }
@@ -60,16 +79,44 @@ struct Descriptor {
 
 unsafe impl ByteValued for Descriptor {}
 
+/// A descriptor of a packed ring, with C representation.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+unsafe impl ByteValued for PackedDescriptor {}
+
+// How the descriptors of a chain are laid out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DescLayout {
+    // Descriptors of a split queue, or of one of its indirect tables, linked through their
+    // `next` field.
+    Split,
+    // Descriptors of a packed ring, following each other around the ring.
+    Packed,
+    // Descriptors of an indirect table of a packed queue, all following each other.
+    PackedIndirect,
+}
+
 /// A virtio descriptor chain.
 pub struct DescriptorChain<'a> {
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
+    layout: DescLayout,
 
     /// Reference to guest memory
     pub mem: &'a GuestMemoryMmap,
 
-    /// Index into the descriptor table
+    /// Index into the descriptor table. The head of a chain of indirect descriptors keeps the
+    /// index of the descriptor referring to the indirect table, and the head of a chain popped
+    /// from a packed queue holds the buffer id instead, which is what gets handed back through
+    /// the used ring.
     pub index: u16,
 
     /// Guest physical address of device specific data
@@ -92,6 +139,22 @@ impl<'a> DescriptorChain<'a> {
         desc_table: GuestAddress,
         queue_size: u16,
         index: u16,
+    ) -> Option<DescriptorChain> {
+        DescriptorChain::checked_new_with_layout(
+            mem,
+            desc_table,
+            queue_size,
+            index,
+            DescLayout::Split,
+        )
+    }
+
+    fn checked_new_with_layout(
+        mem: &GuestMemoryMmap,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        index: u16,
+        layout: DescLayout,
     ) -> Option<DescriptorChain> {
         if index >= queue_size {
             return None;
@@ -101,7 +164,23 @@ impl<'a> DescriptorChain<'a> {
         mem.checked_offset(desc_head, 16)?;
 
         // These reads can't fail unless Guest memory is hopelessly broken.
-        let desc = match mem.read_obj::<Descriptor>(desc_head) {
+        let desc = match layout {
+            DescLayout::Split => mem
+                .read_obj::<Descriptor>(desc_head)
+                .map(|desc| (desc.addr, desc.len, desc.flags, desc.next)),
+            DescLayout::Packed => mem
+                .read_obj::<PackedDescriptor>(desc_head)
+                .map(|desc| (desc.addr, desc.len, desc.flags, (index + 1) % queue_size)),
+            // The whole table makes up the chain, whatever the flags of its descriptors.
+            DescLayout::PackedIndirect => mem.read_obj::<PackedDescriptor>(desc_head).map(|desc| {
+                let mut flags = desc.flags & !VIRTQ_DESC_F_NEXT;
+                if index + 1 < queue_size {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                }
+                (desc.addr, desc.len, flags, index + 1)
+            }),
+        };
+        let (addr, len, flags, next) = match desc {
             Ok(ret) => ret,
             Err(_) => {
                 // TODO log address
@@ -114,11 +193,12 @@ impl<'a> DescriptorChain<'a> {
             desc_table,
             queue_size,
             ttl: queue_size,
+            layout,
             index,
-            addr: GuestAddress(desc.addr),
-            len: desc.len,
-            flags: desc.flags,
-            next: desc.next,
+            addr: GuestAddress(addr),
+            len,
+            flags,
+            next,
         };
 
         if chain.is_valid() {
@@ -137,6 +217,11 @@ impl<'a> DescriptorChain<'a> {
         self.flags & VIRTQ_DESC_F_NEXT != 0 && self.ttl > 1
     }
 
+    /// Gets if this descriptor refers to a table of indirect descriptors, instead of a buffer.
+    pub fn is_indirect(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_INDIRECT != 0
+    }
+
     /// If the driver designated this as a write only descriptor.
     ///
     /// If this is false, this descriptor is read only.
@@ -151,16 +236,67 @@ impl<'a> DescriptorChain<'a> {
     /// the head of the next _available_ descriptor chain.
     pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
         if self.has_next() {
-            DescriptorChain::checked_new(self.mem, self.desc_table, self.queue_size, self.next).map(
-                |mut c| {
-                    c.ttl = self.ttl - 1;
-                    c
-                },
+            DescriptorChain::checked_new_with_layout(
+                self.mem,
+                self.desc_table,
+                self.queue_size,
+                self.next,
+                self.layout,
             )
+            // Indirect descriptors can only be found at the head of a chain.
+            .filter(|c| !c.is_indirect())
+            .map(|mut c| {
+                c.ttl = self.ttl - 1;
+                c
+            })
         } else {
             None
         }
     }
+
+    // Walks the table of indirect descriptors this descriptor refers to, instead of the
+    // descriptor table of the queue.
+    fn into_indirect(self) -> Option<DescriptorChain<'a>> {
+        // The indirect table ends the chain, holds at least one descriptor, and cannot refer
+        // to another indirect table.
+        if self.flags & VIRTQ_DESC_F_NEXT != 0
+            || self.len == 0
+            || self.len % 16 != 0
+            || self.len / 16 > u32::from(u16::MAX)
+        {
+            return None;
+        }
+        let table_size = (self.len / 16) as u16;
+        let layout = match self.layout {
+            DescLayout::Split => DescLayout::Split,
+            _ => DescLayout::PackedIndirect,
+        };
+
+        // The ttl of the chain is the size of the table, so cycles are caught as well.
+        DescriptorChain::checked_new_with_layout(self.mem, self.addr, table_size, 0, layout)
+            .filter(|c| !c.is_indirect())
+            .map(|mut c| {
+                c.index = self.index;
+                c
+            })
+    }
+}
+
+/// Buffer popped from a packed queue, taking `num_descs` slots of the ring until it is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PackedChain {
+    pub(crate) id: u16,
+    pub(crate) num_descs: u16,
+}
+
+// Moves `idx` by `count` slots forward in a packed ring of `size` descriptors, flipping the
+// wrap counter when going around the ring.
+fn advance_packed_idx(idx: &mut Wrapping<u16>, wrap_counter: &mut bool, count: u16, size: u16) {
+    *idx += Wrapping(count);
+    if idx.0 >= size {
+        *idx -= Wrapping(size);
+        *wrap_counter = !*wrap_counter;
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -186,6 +322,27 @@ pub struct Queue {
 
     pub(crate) next_avail: Wrapping<u16>,
     pub(crate) next_used: Wrapping<u16>,
+
+    /// Number of descriptor chains added to the used ring since the driver was last notified
+    pub(crate) num_added: Wrapping<u16>,
+
+    /// Indicates if the used_event and avail_event fields of the rings are used to suppress
+    /// notifications, once `VIRTIO_RING_F_EVENT_IDX` is negotiated
+    pub(crate) uses_notif_suppression: bool,
+
+    /// Indicates if the queue is laid out as a packed ring, once `VIRTIO_F_RING_PACKED` is
+    /// negotiated. The descriptor table is then the ring itself, and the available and used
+    /// rings are the driver and device event suppression structures. `next_avail` and
+    /// `next_used` are then positions in the ring.
+    pub(crate) uses_packed_ring: bool,
+
+    /// Wrap counters of the packed ring, flipped each time `next_avail` and `next_used` go
+    /// around the ring
+    pub(crate) avail_wrap_counter: bool,
+    pub(crate) used_wrap_counter: bool,
+
+    /// Buffers popped from the packed ring and not used yet, in the order they were popped
+    pub(crate) packed_chains: Vec<PackedChain>,
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -201,9 +358,28 @@ impl Queue {
             used_ring: GuestAddress(0),
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
+            num_added: Wrapping(0),
+            uses_notif_suppression: false,
+            uses_packed_ring: false,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            packed_chains: Vec::new(),
         }
     }
 
+    /// Lays the queue out as a packed ring. Only valid once the driver acked
+    /// `VIRTIO_F_RING_PACKED`, and before the queue is validated.
+    pub fn enable_packed_ring(&mut self) {
+        self.uses_packed_ring = true;
+    }
+
+    /// Lets the device and the driver suppress the notifications they do not need, through the
+    /// used_event and avail_event fields of the rings. Only valid once the driver acked
+    /// `VIRTIO_RING_F_EVENT_IDX`.
+    pub fn enable_notif_suppression(&mut self) {
+        self.uses_notif_suppression = true;
+    }
+
     pub fn get_max_size(&self) -> u16 {
         self.max_size
     }
@@ -219,13 +395,19 @@ impl Queue {
         let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
         let avail_ring = self.avail_ring;
-        let avail_ring_size = 6 + 2 * queue_size;
         let used_ring = self.used_ring;
-        let used_ring_size = 6 + 8 * queue_size;
+        // The event suppression structures of packed rings are both made of two 16-bit fields.
+        let (avail_ring_size, avail_ring_align, used_ring_size) = if self.uses_packed_ring {
+            (4, 0x3, 4)
+        } else {
+            (6 + 2 * queue_size, 0x1, 6 + 8 * queue_size)
+        };
         if !self.ready {
             error!("attempt to use virtio queue that is not marked ready");
             false
-        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
+        } else if self.size > self.max_size
+            || self.size == 0
+            || (!self.uses_packed_ring && (self.size & (self.size - 1)) != 0)
         {
             error!("virtio queue with invalid size: {}", self.size);
             false
@@ -262,13 +444,13 @@ impl Queue {
         } else if desc_table.raw_value() & 0xf != 0 {
             error!("virtio queue descriptor table breaks alignment constraints");
             false
-        } else if avail_ring.raw_value() & 0x1 != 0 {
+        } else if avail_ring.raw_value() & avail_ring_align != 0 {
             error!("virtio queue available ring breaks alignment constraints");
             false
         } else if used_ring.raw_value() & 0x3 != 0 {
             error!("virtio queue used ring breaks alignment constraints");
             false
-        } else if self.len(mem) > self.max_size {
+        } else if !self.uses_packed_ring && self.len(mem) > self.max_size {
             error!(
                 "virtio queue number of available descriptors {} is greater than queue max size {}",
                 self.len(mem),
@@ -282,16 +464,59 @@ impl Queue {
 
     /// Returns the number of yet-to-be-popped descriptor chains in the avail ring.
     pub fn len(&self, mem: &GuestMemoryMmap) -> u16 {
+        if self.uses_packed_ring {
+            return self.packed_len(mem);
+        }
         (self.avail_idx(mem) - self.next_avail).0
     }
 
     /// Checks if the driver has made any descriptor chains available in the avail ring.
     pub fn is_empty(&self, mem: &GuestMemoryMmap) -> bool {
+        if self.uses_packed_ring {
+            return !self.is_packed_desc_avail(mem, self.next_avail.0, self.avail_wrap_counter);
+        }
         self.len(mem) == 0
     }
 
+    // Reads the descriptor found at `pos` in the packed ring.
+    fn packed_desc(&self, mem: &GuestMemoryMmap, pos: u16) -> PackedDescriptor {
+        // `self.is_valid()` already performed all the bound checks on the descriptor ring, so
+        // it's safe to unwrap guest memory reads and to use unchecked offsets.
+        mem.read_obj(self.desc_table.unchecked_add(u64::from(pos) * 16))
+            .unwrap()
+    }
+
+    // Says if the driver made the descriptor found at `pos` in the packed ring available, for
+    // the lap of the ring matching `wrap_counter`.
+    fn is_packed_desc_avail(&self, mem: &GuestMemoryMmap, pos: u16, wrap_counter: bool) -> bool {
+        let flags = self.packed_desc(mem, pos).flags;
+        (flags & VIRTQ_PACKED_DESC_F_AVAIL != 0) == wrap_counter
+            && (flags & VIRTQ_PACKED_DESC_F_USED != 0) != wrap_counter
+    }
+
+    // Counts the chains available in the packed ring, by walking their descriptors.
+    fn packed_len(&self, mem: &GuestMemoryMmap) -> u16 {
+        let size = self.actual_size();
+        let mut pos = self.next_avail;
+        let mut wrap_counter = self.avail_wrap_counter;
+        let mut num_chains = 0;
+        for _ in 0..size {
+            if !self.is_packed_desc_avail(mem, pos.0, wrap_counter) {
+                break;
+            }
+            if self.packed_desc(mem, pos.0).flags & VIRTQ_DESC_F_NEXT == 0 {
+                num_chains += 1;
+            }
+            advance_packed_idx(&mut pos, &mut wrap_counter, 1, size);
+        }
+        num_chains
+    }
+
     /// Pop the first available descriptor chain from the avail ring.
     pub fn pop<'a, 'b>(&'a mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        if self.uses_packed_ring {
+            return self.pop_packed(mem);
+        }
         if self.len(mem) == 0 {
             return None;
         }
@@ -331,17 +556,191 @@ impl Queue {
             .read_obj(self.avail_ring.unchecked_add(u64::from(index_offset)))
             .unwrap();
 
-        DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index).map(
-            |dc| {
+        DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index)
+            .and_then(|dc| {
+                if dc.is_indirect() {
+                    dc.into_indirect()
+                } else {
+                    Some(dc)
+                }
+            })
+            .map(|dc| {
                 self.next_avail += Wrapping(1);
                 dc
-            },
-        )
+            })
+    }
+
+    // Pops the first available descriptor chain from the packed ring.
+    fn pop_packed<'a, 'b>(&'a mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        if self.is_empty(mem) {
+            return None;
+        }
+
+        // This fence ensures all subsequent reads see the updated driver writes.
+        fence(Ordering::Acquire);
+
+        // The descriptors of the chain follow each other in the ring, and the buffer id is
+        // found in the last one. The chain cannot be longer than the ring.
+        let size = self.actual_size();
+        let mut last = self.next_avail.0;
+        let mut num_descs = 1;
+        while self.packed_desc(mem, last).flags & VIRTQ_DESC_F_NEXT != 0 {
+            if num_descs == size {
+                return None;
+            }
+            last = (last + 1) % size;
+            num_descs += 1;
+        }
+        let id = self.packed_desc(mem, last).id;
+        // The used buffers are only told apart by their ids, so the driver cannot make a buffer
+        // available again before it is used.
+        if self.packed_chains.iter().any(|chain| chain.id == id) {
+            error!("attempted to pop a buffer already in use: {}", id);
+            return None;
+        }
+
+        let mut head = DescriptorChain::checked_new_with_layout(
+            mem,
+            self.desc_table,
+            size,
+            self.next_avail.0,
+            DescLayout::Packed,
+        )?;
+        head.ttl = num_descs;
+        head.index = id;
+        if head.is_indirect() {
+            head = head.into_indirect()?;
+        }
+
+        advance_packed_idx(
+            &mut self.next_avail,
+            &mut self.avail_wrap_counter,
+            num_descs,
+            size,
+        );
+        self.packed_chains.push(PackedChain { id, num_descs });
+        Some(head)
+    }
+
+    /// Pop the first available descriptor chain from the avail ring. When the ring is empty,
+    /// asks the driver to notify the device once more chains are made available, as long as
+    /// notifications are suppressed.
+    pub fn pop_or_enable_notification<'a, 'b>(
+        &'a mut self,
+        mem: &'b GuestMemoryMmap,
+    ) -> Option<DescriptorChain<'b>> {
+        if self.uses_notif_suppression && self.try_enable_notification(mem) {
+            return None;
+        }
+        self.pop(mem)
+    }
+
+    /// Asks the driver to notify the device once the next descriptor chain is made available.
+    /// Returns false when chains are still available, in which case the driver may not send
+    /// a notification for them and the caller should process them first.
+    pub fn try_enable_notification(&mut self, mem: &GuestMemoryMmap) -> bool {
+        // The driver notifies the device for every chain otherwise.
+        if !self.uses_notif_suppression {
+            return true;
+        }
+        if !self.is_empty(mem) {
+            return false;
+        }
+
+        // `self.is_valid()` already performed all the bound checks on the virtq rings, so it's
+        // safe to unwrap guest memory writes and to use unchecked offsets.
+        if self.uses_packed_ring {
+            // The device event suppression structure points the driver to the next descriptor.
+            let off_wrap = self.next_avail.0
+                | (self.avail_wrap_counter as u16) << VIRTQ_PACKED_EVENT_F_WRAP_CTR;
+            mem.write_obj(off_wrap, self.used_ring).unwrap();
+            mem.write_obj(
+                VIRTQ_PACKED_EVENT_FLAG_DESC,
+                self.used_ring.unchecked_add(2),
+            )
+            .unwrap();
+        } else {
+            // The avail_event field follows the ring of the `struct virtq_used`.
+            let avail_event_addr = self
+                .used_ring
+                .unchecked_add(u64::from(4 + 8 * self.actual_size()));
+            mem.write_obj(self.next_avail.0, avail_event_addr).unwrap();
+        }
+
+        // This fence ensures the driver sees avail_event before the index is read again.
+        fence(Ordering::SeqCst);
+
+        // The driver may have made a chain available before seeing the new avail_event.
+        self.is_empty(mem)
+    }
+
+    /// Says if the driver has to be notified of the chains added to the used ring since the
+    /// last call. When notifications are suppressed, that is the case if one of them crossed
+    /// the used_event index set by the driver, similarly to `vring_need_event()` in Linux.
+    pub fn needs_notification(&mut self, mem: &GuestMemoryMmap) -> bool {
+        let num_added = std::mem::replace(&mut self.num_added, Wrapping(0));
+        if self.uses_packed_ring {
+            return self.packed_needs_notification(mem, num_added);
+        }
+        if !self.uses_notif_suppression {
+            return true;
+        }
+
+        // This fence ensures the used ring updates are visible before used_event is read.
+        fence(Ordering::SeqCst);
+
+        // `self.is_valid()` already performed all the bound checks on the virtq rings, so it's
+        // safe to unwrap guest memory reads and to use unchecked offsets. The used_event
+        // field follows the ring of the `struct virtq_avail`.
+        let used_event_addr = self
+            .avail_ring
+            .unchecked_add(u64::from(4 + 2 * self.actual_size()));
+        let used_event = Wrapping(mem.read_obj::<u16>(used_event_addr).unwrap());
+
+        self.next_used - used_event - Wrapping(1) < num_added
+    }
+
+    // Reads the driver event suppression structure of the packed ring, which may disable the
+    // notifications, or only enable them once a given descriptor is used.
+    fn packed_needs_notification(&self, mem: &GuestMemoryMmap, num_added: Wrapping<u16>) -> bool {
+        // This fence ensures the used descriptors are visible before the structure is read.
+        fence(Ordering::SeqCst);
+
+        // `self.is_valid()` already performed all the bound checks on the virtq rings, so it's
+        // safe to unwrap guest memory reads and to use unchecked offsets.
+        let off_wrap: u16 = mem.read_obj(self.avail_ring).unwrap();
+        let flags: u16 = mem.read_obj(self.avail_ring.unchecked_add(2)).unwrap();
+        match flags {
+            VIRTQ_PACKED_EVENT_FLAG_DISABLE => false,
+            VIRTQ_PACKED_EVENT_FLAG_DESC if self.uses_notif_suppression => {
+                let mut used_event = Wrapping(off_wrap & !(1 << VIRTQ_PACKED_EVENT_F_WRAP_CTR));
+                // The descriptor may belong to the previous lap of the ring.
+                if (off_wrap >> VIRTQ_PACKED_EVENT_F_WRAP_CTR != 0) != self.used_wrap_counter {
+                    used_event -= Wrapping(self.actual_size());
+                }
+                self.next_used - used_event - Wrapping(1) < num_added
+            }
+            _ => true,
+        }
     }
 
     /// Undo the effects of the last `self.pop()` call.
     /// The caller can use this, if it was unable to consume the last popped descriptor chain.
     pub fn undo_pop(&mut self) {
+        if self.uses_packed_ring {
+            if let Some(chain) = self.packed_chains.pop() {
+                let size = self.actual_size();
+                // Moving back is the same as going around the ring, minus the chain.
+                advance_packed_idx(
+                    &mut self.next_avail,
+                    &mut self.avail_wrap_counter,
+                    size - chain.num_descs,
+                    size,
+                );
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+            return;
+        }
         self.next_avail -= Wrapping(1);
     }
 
@@ -352,6 +751,9 @@ impl Queue {
         desc_index: u16,
         len: u32,
     ) -> Result<(), QueueError> {
+        if self.uses_packed_ring {
+            return self.add_used_packed(mem, desc_index, len);
+        }
         if desc_index >= self.actual_size() {
             error!(
                 "attempted to add out of bounds descriptor to used ring: {}",
@@ -372,6 +774,7 @@ impl Queue {
             .map_err(QueueError::UsedRing)?;
 
         self.next_used += Wrapping(1);
+        self.num_added += Wrapping(1);
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
@@ -381,6 +784,55 @@ impl Queue {
             .map_err(QueueError::UsedRing)
     }
 
+    // Writes the used descriptor of the buffer `id` in the packed ring, over the slots of
+    // the buffers used before it.
+    fn add_used_packed(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        id: u16,
+        len: u32,
+    ) -> Result<(), QueueError> {
+        let chain = match self.packed_chains.iter().position(|chain| chain.id == id) {
+            Some(pos) => self.packed_chains.remove(pos),
+            None => {
+                error!("attempted to add unknown buffer to used ring: {}", id);
+                return Err(QueueError::UnknownBufferId(id));
+            }
+        };
+
+        let desc_addr = self
+            .desc_table
+            .unchecked_add(u64::from(self.next_used.0) * 16);
+        mem.write_obj(len, desc_addr.unchecked_add(8))
+            .map_err(QueueError::UsedRing)?;
+        mem.write_obj(id, desc_addr.unchecked_add(12))
+            .map_err(QueueError::UsedRing)?;
+
+        let mut flags = if self.used_wrap_counter {
+            VIRTQ_PACKED_DESC_F_AVAIL | VIRTQ_PACKED_DESC_F_USED
+        } else {
+            0
+        };
+        if len > 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+
+        let size = self.actual_size();
+        advance_packed_idx(
+            &mut self.next_used,
+            &mut self.used_wrap_counter,
+            chain.num_descs,
+            size,
+        );
+        self.num_added += Wrapping(chain.num_descs);
+
+        // This fence ensures the id and length are visible before the flags update is.
+        fence(Ordering::Release);
+
+        mem.write_obj(flags, desc_addr.unchecked_add(14))
+            .map_err(QueueError::UsedRing)
+    }
+
     /// Fetch the available ring index (`virtq_avail->idx`) from guest memory.
     /// This is written by the driver, to indicate the next slot that will be filled in the avail
     /// ring.
@@ -401,7 +853,7 @@ pub(crate) mod tests {
     pub use super::*;
 
     use crate::virtio::test_utils::VirtQueue;
-    use crate::virtio::QueueError::{DescIndexOutOfBounds, UsedRing};
+    use crate::virtio::QueueError::{DescIndexOutOfBounds, UnknownBufferId, UsedRing};
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
     #[test]
@@ -591,6 +1043,95 @@ pub(crate) mod tests {
         assert!(d.next_descriptor().is_none());
     }
 
+    #[test]
+    fn test_indirect_descriptors() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // The head of the chain refers to a table of 4 indirect descriptors, of which the chain
+        // walks (0, 2, 1).
+        let table = vq.set_indirect_table(3, GuestAddress(0x8000), 4);
+        table[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        table[2].set(0x2000, 0x200, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1);
+        table[1].set(0x3000, 0x300, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(3);
+        vq.avail.idx.set(1);
+
+        let head = q.pop(m).unwrap();
+        // The head keeps the index of the descriptor referring to the table.
+        assert_eq!(head.index, 3);
+        assert_eq!(head.addr, GuestAddress(0x1000));
+        assert_eq!(head.len, 0x100);
+        assert!(!head.is_indirect());
+        assert!(!head.is_write_only());
+        let d = head.next_descriptor().unwrap();
+        assert_eq!(d.addr, GuestAddress(0x2000));
+        assert!(d.is_write_only());
+        let d = d.next_descriptor().unwrap();
+        assert_eq!(d.addr, GuestAddress(0x3000));
+        assert_eq!(d.len, 0x300);
+        assert!(!d.has_next());
+        assert!(d.next_descriptor().is_none());
+
+        // Descriptors looping in the table are only walked as many times as the table holds.
+        table[1].set(0x3000, 0x300, VIRTQ_DESC_F_NEXT, 0);
+        q.undo_pop();
+        let mut d = q.pop(m).unwrap();
+        let mut count = 1;
+        while let Some(next) = d.next_descriptor() {
+            d = next;
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        table[1].set(0x3000, 0x300, VIRTQ_DESC_F_WRITE, 0);
+
+        // Indirect tables cannot be nested, neither at their head...
+        q.undo_pop();
+        table[0].flags.set(VIRTQ_DESC_F_INDIRECT);
+        assert!(q.pop(m).is_none());
+        // ... nor further down the chain.
+        table[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        table[2]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_INDIRECT);
+        let head = q.pop(m).unwrap();
+        assert!(head.next_descriptor().is_none());
+        table[2].flags.set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+        // Indirect descriptors also cannot be found further down a direct chain.
+        q.undo_pop();
+        vq.dtable[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 3);
+        vq.avail.ring[0].set(0);
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 0);
+        assert!(head.next_descriptor().is_none());
+
+        // The descriptor referring to the table cannot be chained.
+        q.undo_pop();
+        vq.avail.ring[0].set(3);
+        vq.dtable[3]
+            .flags
+            .set(VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT);
+        assert!(q.pop(m).is_none());
+        vq.dtable[3].flags.set(VIRTQ_DESC_F_INDIRECT);
+
+        // The table has to hold whole descriptors, and at least one of them.
+        vq.dtable[3].len.set(0);
+        assert!(q.pop(m).is_none());
+        vq.dtable[3].len.set(0x18);
+        assert!(q.pop(m).is_none());
+        // And it has to be in guest memory.
+        vq.dtable[3].set(0xfff0, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+        assert!(q.pop(m).is_none());
+
+        vq.dtable[3].set(0x8000, 0x10, VIRTQ_DESC_F_INDIRECT, 0);
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 3);
+        // The next descriptor is out of the table, so the chain ends there.
+        assert!(head.next_descriptor().is_none());
+    }
+
     #[test]
     fn test_add_used() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -635,6 +1176,341 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_notif_suppression() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.ready = true;
+
+        // Without suppression, the driver is notified of every used chain.
+        vq.dtable[0].set(0x1000, 0x1000, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        assert!(q.pop_or_enable_notification(m).is_some());
+        q.add_used(m, 0, 0x1000).unwrap();
+        assert!(q.needs_notification(m));
+        assert!(q.try_enable_notification(m));
+
+        q.enable_notif_suppression();
+        // The driver wants to be notified once the second chain is used.
+        vq.avail.event.set(1);
+        vq.avail.ring[1].set(0);
+        vq.avail.idx.set(2);
+        assert!(!q.try_enable_notification(m));
+        assert!(q.pop_or_enable_notification(m).is_some());
+        q.add_used(m, 0, 0x1000).unwrap();
+        // The second chain crossed used_event.
+        assert!(q.needs_notification(m));
+        // Nothing was added since the last call.
+        assert!(!q.needs_notification(m));
+
+        // The ring is empty, so the driver is asked to notify the next chain.
+        assert!(q.pop_or_enable_notification(m).is_none());
+        assert_eq!(vq.used.event.get(), 2);
+
+        // A chain made available after the last pop is still processed.
+        vq.avail.ring[2].set(0);
+        vq.avail.idx.set(3);
+        assert!(q.pop_or_enable_notification(m).is_some());
+        q.add_used(m, 0, 0x1000).unwrap();
+        // The driver only wants to be notified once the fifth chain is used.
+        vq.avail.event.set(4);
+        assert!(!q.needs_notification(m));
+        vq.avail.ring[3].set(0);
+        vq.avail.ring[4].set(0);
+        vq.avail.idx.set(5);
+        assert!(q.pop(m).is_some());
+        q.add_used(m, 0, 0x1000).unwrap();
+        assert!(!q.needs_notification(m));
+        assert!(q.pop(m).is_some());
+        q.add_used(m, 0, 0x1000).unwrap();
+        assert!(q.needs_notification(m));
+    }
+
+    // Writes a descriptor at `pos` in the packed ring of `q`, made available by the driver
+    // for the lap of the ring matching `wrap_counter`.
+    fn set_packed_desc(
+        m: &GuestMemoryMmap,
+        q: &Queue,
+        pos: u16,
+        (addr, len, id): (u64, u32, u16),
+        flags: u16,
+        wrap_counter: bool,
+    ) {
+        let flags = flags
+            | if wrap_counter {
+                VIRTQ_PACKED_DESC_F_AVAIL
+            } else {
+                VIRTQ_PACKED_DESC_F_USED
+            };
+        let desc = PackedDescriptor {
+            addr,
+            len,
+            id,
+            flags,
+        };
+        m.write_obj(desc, q.desc_table.unchecked_add(u64::from(pos) * 16))
+            .unwrap();
+    }
+
+    fn packed_desc(m: &GuestMemoryMmap, q: &Queue, pos: u16) -> PackedDescriptor {
+        m.read_obj(q.desc_table.unchecked_add(u64::from(pos) * 16))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_packed_queue_processing() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.enable_packed_ring();
+
+        // The size of a packed ring does not have to be a power of 2.
+        q.size = 3;
+        assert!(q.is_valid(m));
+        q.avail_ring = vq.avail_start().unchecked_add(2);
+        assert!(!q.is_valid(m));
+        q.avail_ring = vq.avail_start();
+        q.size = 4;
+
+        // Two chains are made available: (0, 1) with the buffer id 7, and (2) with the id 3.
+        assert!(q.is_empty(m));
+        set_packed_desc(m, &q, 0, (0x1000, 0x100, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 1, (0x2000, 0x200, 7), 0, true);
+        set_packed_desc(m, &q, 2, (0x3000, 0x300, 3), VIRTQ_DESC_F_WRITE, true);
+        assert_eq!(q.len(m), 2);
+        assert!(!q.is_empty(m));
+
+        // The buffer id is found in the last descriptor of the chain.
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 7);
+        assert_eq!(head.addr, GuestAddress(0x1000));
+        assert_eq!(head.len, 0x100);
+        let d = head.next_descriptor().unwrap();
+        assert_eq!(d.addr, GuestAddress(0x2000));
+        assert!(d.next_descriptor().is_none());
+
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 3);
+        assert!(head.is_write_only());
+        assert!(head.next_descriptor().is_none());
+        assert!(q.is_empty(m));
+        assert!(q.pop(m).is_none());
+
+        // Undoing the last pop lets us walk the last chain again.
+        q.undo_pop();
+        assert_eq!(q.len(m), 1);
+        assert_eq!(q.pop(m).unwrap().index, 3);
+
+        // Only the popped buffers can be used.
+        match q.add_used(m, 5, 0) {
+            Err(UnknownBufferId(5)) => (),
+            _ => unreachable!(),
+        }
+
+        // The buffers are used out of order, each one over as many slots as it was popped from.
+        q.add_used(m, 3, 0x100).unwrap();
+        let desc = packed_desc(m, &q, 0);
+        assert_eq!((desc.id, desc.len), (3, 0x100));
+        assert_eq!(
+            desc.flags,
+            VIRTQ_PACKED_DESC_F_AVAIL | VIRTQ_PACKED_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+        q.add_used(m, 7, 0).unwrap();
+        let desc = packed_desc(m, &q, 1);
+        assert_eq!((desc.id, desc.len), (7, 0));
+        assert_eq!(
+            desc.flags,
+            VIRTQ_PACKED_DESC_F_AVAIL | VIRTQ_PACKED_DESC_F_USED
+        );
+        assert_eq!(q.next_used, Wrapping(3));
+        assert!(q.packed_chains.is_empty());
+        match q.add_used(m, 7, 0) {
+            Err(UnknownBufferId(7)) => (),
+            _ => unreachable!(),
+        }
+
+        // The next chain wraps around the ring, flipping the wrap counters.
+        set_packed_desc(m, &q, 3, (0x4000, 0x400, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 0, (0x5000, 0x500, 9), 0, false);
+        assert_eq!(q.len(m), 1);
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 9);
+        assert_eq!(head.next_descriptor().unwrap().addr, GuestAddress(0x5000));
+        assert_eq!(q.next_avail, Wrapping(1));
+        assert!(!q.avail_wrap_counter);
+        // The descriptors used during the previous lap are not available anymore.
+        assert!(q.is_empty(m));
+
+        q.undo_pop();
+        assert_eq!(q.next_avail, Wrapping(3));
+        assert!(q.avail_wrap_counter);
+        assert_eq!(q.pop(m).unwrap().index, 9);
+
+        q.add_used(m, 9, 0x10).unwrap();
+        assert_eq!(packed_desc(m, &q, 3).id, 9);
+        assert_eq!(q.next_used, Wrapping(1));
+        assert!(!q.used_wrap_counter);
+
+        // The used descriptors of the second lap have both flags cleared.
+        set_packed_desc(m, &q, 1, (0x6000, 0x600, 1), 0, false);
+        assert_eq!(q.pop(m).unwrap().index, 1);
+        q.add_used(m, 1, 0x20).unwrap();
+        assert_eq!(packed_desc(m, &q, 1).flags, VIRTQ_DESC_F_WRITE);
+
+        // Chains cannot be longer than the ring.
+        for pos in 0..4 {
+            set_packed_desc(m, &q, pos, (0x1000, 0x100, 0), VIRTQ_DESC_F_NEXT, false);
+        }
+        assert!(q.pop(m).is_none());
+    }
+
+    #[test]
+    fn test_packed_out_of_order_completion() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.enable_packed_ring();
+        q.size = 8;
+
+        // Three chains are made available: (0, 1, 2) with the buffer id 4, (3, 4) with the id 2,
+        // and (5, 6) reusing the id 4 before the first buffer is used.
+        set_packed_desc(m, &q, 0, (0x1000, 0x100, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 1, (0x2000, 0x200, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 2, (0x3000, 0x300, 4), VIRTQ_DESC_F_WRITE, true);
+        set_packed_desc(m, &q, 3, (0x4000, 0x400, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 4, (0x5000, 0x500, 2), VIRTQ_DESC_F_WRITE, true);
+        set_packed_desc(m, &q, 5, (0x6000, 0x600, 0), VIRTQ_DESC_F_NEXT, true);
+        set_packed_desc(m, &q, 6, (0x7000, 0x700, 4), VIRTQ_DESC_F_WRITE, true);
+
+        assert_eq!(q.pop(m).unwrap().index, 4);
+        assert_eq!(q.pop(m).unwrap().index, 2);
+        // The buffer id 4 is still in use, so the last chain is rejected.
+        assert!(q.pop(m).is_none());
+        assert_eq!(q.next_avail, Wrapping(5));
+        assert_eq!(q.packed_chains.len(), 2);
+
+        // The last popped buffer is used first, over the slots of the first chain.
+        q.add_used(m, 2, 0x500).unwrap();
+        let desc = packed_desc(m, &q, 0);
+        assert_eq!((desc.id, desc.len), (2, 0x500));
+        assert_eq!(q.next_used, Wrapping(2));
+        q.add_used(m, 4, 0x300).unwrap();
+        let desc = packed_desc(m, &q, 2);
+        assert_eq!((desc.id, desc.len), (4, 0x300));
+        assert_eq!(q.next_used, Wrapping(5));
+        assert_eq!(q.num_added, Wrapping(5));
+        assert!(q.packed_chains.is_empty());
+
+        // Once used, the buffer id can be made available again.
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 4);
+        assert_eq!(head.addr, GuestAddress(0x6000));
+        q.add_used(m, 4, 0x700).unwrap();
+        let desc = packed_desc(m, &q, 5);
+        assert_eq!((desc.id, desc.len), (4, 0x700));
+        assert_eq!(q.next_used, Wrapping(7));
+    }
+
+    #[test]
+    fn test_packed_indirect_descriptors() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.enable_packed_ring();
+
+        // The descriptors of a packed indirect table all make up the chain, in order.
+        let table = GuestAddress(0x8000);
+        for i in 0..3u64 {
+            let desc = PackedDescriptor {
+                addr: 0x1000 * (i + 1),
+                len: 0x100,
+                id: 0,
+                flags: VIRTQ_DESC_F_WRITE,
+            };
+            m.write_obj(desc, table.unchecked_add(i * 16)).unwrap();
+        }
+        set_packed_desc(m, &q, 0, (0x8000, 0x30, 5), VIRTQ_DESC_F_INDIRECT, true);
+
+        let head = q.pop(m).unwrap();
+        assert_eq!(head.index, 5);
+        assert_eq!(head.addr, GuestAddress(0x1000));
+        assert!(head.has_next());
+        let d = head.next_descriptor().unwrap();
+        assert_eq!(d.addr, GuestAddress(0x2000));
+        let d = d.next_descriptor().unwrap();
+        assert_eq!(d.addr, GuestAddress(0x3000));
+        assert!(d.is_write_only());
+        assert!(!d.has_next());
+        assert!(d.next_descriptor().is_none());
+
+        // The indirect table takes a single slot of the ring.
+        q.add_used(m, 5, 0x300).unwrap();
+        assert_eq!(q.next_used, Wrapping(1));
+    }
+
+    #[test]
+    fn test_packed_notif_suppression() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+        q.enable_packed_ring();
+        q.size = 4;
+        let off_wrap_addr = q.avail_ring;
+        let flags_addr = q.avail_ring.unchecked_add(2);
+
+        // The driver may disable the notifications altogether.
+        set_packed_desc(m, &q, 0, (0x1000, 0x100, 0), 0, true);
+        assert!(q.pop_or_enable_notification(m).is_some());
+        q.add_used(m, 0, 0).unwrap();
+        assert!(q.needs_notification(m));
+        m.write_obj(VIRTQ_PACKED_EVENT_FLAG_DISABLE, flags_addr)
+            .unwrap();
+        assert!(!q.needs_notification(m));
+        // Asking for a given descriptor is only honored once suppression is negotiated.
+        m.write_obj(VIRTQ_PACKED_EVENT_FLAG_DESC, flags_addr)
+            .unwrap();
+        assert!(q.needs_notification(m));
+        assert!(q.try_enable_notification(m));
+
+        q.enable_notif_suppression();
+        // The ring is empty, so the driver is asked to notify the next descriptor.
+        assert!(q.pop_or_enable_notification(m).is_none());
+        let off_wrap: u16 = m.read_obj(q.used_ring).unwrap();
+        assert_eq!(off_wrap, 1 | 1 << VIRTQ_PACKED_EVENT_F_WRAP_CTR);
+        let flags: u16 = m.read_obj(q.used_ring.unchecked_add(2)).unwrap();
+        assert_eq!(flags, VIRTQ_PACKED_EVENT_FLAG_DESC);
+
+        // The driver wants to be notified once the descriptor at 2 is used.
+        for pos in 1..4 {
+            set_packed_desc(m, &q, pos, (0x1000, 0x100, pos), 0, true);
+        }
+        assert!(!q.try_enable_notification(m));
+        m.write_obj(2u16 | 1 << VIRTQ_PACKED_EVENT_F_WRAP_CTR, off_wrap_addr)
+            .unwrap();
+        for _ in 1..4 {
+            assert!(q.pop(m).is_some());
+        }
+        q.add_used(m, 1, 0).unwrap();
+        assert!(!q.needs_notification(m));
+        q.add_used(m, 2, 0).unwrap();
+        assert!(q.needs_notification(m));
+
+        // The descriptor may belong to the previous lap, once the used index wrapped around.
+        m.write_obj(3u16 | 1 << VIRTQ_PACKED_EVENT_F_WRAP_CTR, off_wrap_addr)
+            .unwrap();
+        q.add_used(m, 3, 0).unwrap();
+        assert_eq!(q.next_used, Wrapping(0));
+        assert!(q.needs_notification(m));
+        // Or to the current one.
+        m.write_obj(1u16, off_wrap_addr).unwrap();
+        set_packed_desc(m, &q, 0, (0x1000, 0x100, 0), 0, false);
+        assert!(q.pop(m).is_some());
+        q.add_used(m, 0, 0).unwrap();
+        assert!(!q.needs_notification(m));
+    }
+
     #[test]
     fn test_queue_error_display() {
         let err = UsedRing(GuestMemoryError::InvalidGuestAddress(GuestAddress(0)));
@@ -642,5 +1518,8 @@ pub(crate) mod tests {
 
         let err = DescIndexOutOfBounds(1);
         let _ = format!("{}{:?}", err, err);
+
+        let err = UnknownBufferId(1);
+        let _ = format!("{}{:?}", err, err);
     }
 }
@@ -5,7 +5,7 @@ use std::marker::PhantomData;
 use std::mem;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::virtio::{Queue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use crate::virtio::{Queue, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
 
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
 
@@ -320,6 +320,28 @@ impl<'a> VirtQueue<'a> {
         self.used.end()
     }
 
+    // Makes the descriptor at `index` refer to a table of `len` indirect descriptors, placed at
+    // `start`, and returns the descriptors of the table.
+    pub fn set_indirect_table(
+        &self,
+        index: usize,
+        start: GuestAddress,
+        len: u16,
+    ) -> Vec<VirtqDesc<'a>> {
+        let mem = self.dtable[index].addr.mem;
+        let mut table = Vec::with_capacity(len as usize);
+
+        let mut end = start;
+        for _ in 0..len {
+            let d = VirtqDesc::new(end, mem);
+            end = d.end();
+            table.push(d);
+        }
+
+        self.dtable[index].set(start.0, u32::from(len) * 16, VIRTQ_DESC_F_INDIRECT, 0);
+        table
+    }
+
     pub fn check_used_elem(&self, used_index: u16, expected_id: u16, expected_len: u32) {
         let used_elem = self.used.ring[used_index as usize].get();
         assert_eq!(used_elem.id, expected_id as u32);
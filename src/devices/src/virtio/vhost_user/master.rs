@@ -0,0 +1,355 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use utils::eventfd::EventFd;
+use vm_memory::{
+    ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress,
+};
+
+use super::message::*;
+use super::{Error, Result, VHOST_USER_PROTOCOL_F_REPLY_ACK};
+use crate::virtio::Queue;
+
+/// Connection to a vhost-user backend.
+pub struct Master {
+    sock: UnixStream,
+    // Whether the backend acknowledges the requests which don't have a reply.
+    reply_ack: bool,
+}
+
+impl Master {
+    /// Connects to the backend listening on `socket_path`.
+    pub fn connect<P: AsRef<Path>>(socket_path: P) -> Result<Master> {
+        let sock = UnixStream::connect(socket_path).map_err(Error::Connect)?;
+        Ok(Master {
+            sock,
+            reply_ack: false,
+        })
+    }
+
+    // Sends a request which doesn't have a reply, waiting for the backend acknowledgement if
+    // it was negotiated.
+    fn send_request(&mut self, request: u32, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        let flags = if self.reply_ack {
+            VHOST_USER_NEED_REPLY_MASK
+        } else {
+            0
+        };
+        let hdr = Header::new(request, flags, body.len());
+        send_message(&self.sock, &hdr, body, fds).map_err(Error::Socket)?;
+
+        if self.reply_ack {
+            let status: u64 = self.recv_reply(request)?;
+            if status != 0 {
+                return Err(Error::RequestFailed(request));
+            }
+        }
+        Ok(())
+    }
+
+    // Sends a request and returns the body of its reply.
+    fn send_request_with_reply<T: ByteValued>(&mut self, request: u32, body: &[u8]) -> Result<T> {
+        let hdr = Header::new(request, 0, body.len());
+        send_message(&self.sock, &hdr, body, &[]).map_err(Error::Socket)?;
+        self.recv_reply(request)
+    }
+
+    fn recv_reply<T: ByteValued>(&mut self, request: u32) -> Result<T> {
+        let (hdr, body, files) = recv_message(&self.sock).map_err(Error::Socket)?;
+        if hdr.request != request
+            || !hdr.is_reply()
+            || body.len() != mem::size_of::<T>()
+            || !files.is_empty()
+        {
+            return Err(Error::InvalidReply(request));
+        }
+        let mut reply = T::default();
+        reply.as_mut_slice().copy_from_slice(&body);
+        Ok(reply)
+    }
+
+    /// Claims the backend for this connection.
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send_request(VHOST_USER_SET_OWNER, &[], &[])
+    }
+
+    /// Gets the virtio features offered by the backend.
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.send_request_with_reply(VHOST_USER_GET_FEATURES, &[])
+    }
+
+    /// Sets the virtio features acknowledged by the driver.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(VHOST_USER_SET_FEATURES, features.as_slice(), &[])
+    }
+
+    /// Gets the protocol features offered by the backend.
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.send_request_with_reply(VHOST_USER_GET_PROTOCOL_FEATURES, &[])
+    }
+
+    /// Sets the protocol features used on this connection.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(VHOST_USER_SET_PROTOCOL_FEATURES, features.as_slice(), &[])?;
+        self.reply_ack = features & (1u64 << VHOST_USER_PROTOCOL_F_REPLY_ACK) != 0;
+        Ok(())
+    }
+
+    /// Gets the maximum number of queues supported by the backend.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.send_request_with_reply(VHOST_USER_GET_QUEUE_NUM, &[])
+    }
+
+    /// Reads `size` bytes found at `offset` in the device configuration space.
+    pub fn get_config(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        let config_hdr = ConfigHeader {
+            offset,
+            size,
+            flags: 0,
+        };
+        let mut body = config_hdr.as_slice().to_vec();
+        body.resize(body.len() + size as usize, 0);
+        let hdr = Header::new(VHOST_USER_GET_CONFIG, 0, body.len());
+        send_message(&self.sock, &hdr, &body, &[]).map_err(Error::Socket)?;
+
+        let (hdr, body, _) = recv_message(&self.sock).map_err(Error::Socket)?;
+        let config_hdr_len = mem::size_of::<ConfigHeader>();
+        if hdr.request != VHOST_USER_GET_CONFIG || !hdr.is_reply() || body.len() < config_hdr_len {
+            return Err(Error::InvalidReply(VHOST_USER_GET_CONFIG));
+        }
+        let mut reply_hdr = ConfigHeader::default();
+        reply_hdr
+            .as_mut_slice()
+            .copy_from_slice(&body[..config_hdr_len]);
+        // Backends signal failures with an empty configuration space.
+        if reply_hdr.size == 0 {
+            return Err(Error::RequestFailed(VHOST_USER_GET_CONFIG));
+        }
+        if reply_hdr.offset != offset
+            || reply_hdr.size != size
+            || body.len() != config_hdr_len + size as usize
+        {
+            return Err(Error::InvalidReply(VHOST_USER_GET_CONFIG));
+        }
+        Ok(body[config_hdr_len..].to_vec())
+    }
+
+    /// Shares the guest memory with the backend. Every region has to be backed by a file.
+    pub fn set_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        if mem.num_regions() > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(mem.num_regions()));
+        }
+
+        let mut body = MemoryTableHeader {
+            num_regions: mem.num_regions() as u32,
+            padding: 0,
+        }
+        .as_slice()
+        .to_vec();
+        let mut fds = Vec::new();
+        mem.with_regions_mut(|_, region| {
+            let guest_phys_addr = region.start_addr().0;
+            let file_offset = region
+                .file_offset()
+                .ok_or(Error::UnsharedMemoryRegion(guest_phys_addr))?;
+            // Can't fail because the address is the start of the region.
+            let userspace_addr = region.get_host_address(MemoryRegionAddress(0)).unwrap() as u64;
+            let entry = MemoryRegion {
+                guest_phys_addr,
+                memory_size: region.len(),
+                userspace_addr,
+                mmap_offset: file_offset.start(),
+            };
+            body.extend_from_slice(entry.as_slice());
+            fds.push(file_offset.file().as_raw_fd());
+            Ok(())
+        })?;
+
+        self.send_request(VHOST_USER_SET_MEM_TABLE, &body, &fds)
+    }
+
+    /// Hands the `index` virtqueue over to the backend and enables it. The backend reads
+    /// the guest notifications from `kick_evt` and signals used buffers on `call_evt`.
+    pub fn set_vring(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        index: usize,
+        queue: &Queue,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        let index = index as u32;
+        let host_address = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(|_| Error::InvalidVringAddress(addr.0))
+        };
+        let vring_addr = VringAddr {
+            index,
+            flags: 0,
+            descriptor: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            available: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+
+        let vring_state = |num: u16| VringState {
+            index,
+            num: u32::from(num),
+        };
+        self.send_request(
+            VHOST_USER_SET_VRING_NUM,
+            vring_state(queue.actual_size()).as_slice(),
+            &[],
+        )?;
+        self.send_request(VHOST_USER_SET_VRING_ADDR, vring_addr.as_slice(), &[])?;
+        self.send_request(
+            VHOST_USER_SET_VRING_BASE,
+            vring_state(queue.next_avail.0).as_slice(),
+            &[],
+        )?;
+        // The index of the ring is carried by the lower byte of the body.
+        self.send_request(
+            VHOST_USER_SET_VRING_CALL,
+            u64::from(index).as_slice(),
+            &[call_evt.as_raw_fd()],
+        )?;
+        self.send_request(
+            VHOST_USER_SET_VRING_KICK,
+            u64::from(index).as_slice(),
+            &[kick_evt.as_raw_fd()],
+        )?;
+        // Rings start disabled when protocol features are negotiated.
+        self.send_request(
+            VHOST_USER_SET_VRING_ENABLE,
+            VringState { index, num: 1 }.as_slice(),
+            &[],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::virtio::test_utils::default_mem;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::test_utils::{shared_mem, TestBackend};
+    use crate::virtio::vhost_user::{VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG};
+
+    #[test]
+    fn test_connect() {
+        assert!(matches!(
+            Master::connect("/invalid/vhost/user/socket"),
+            Err(Error::Connect(_))
+        ));
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let backend = TestBackend::new(vec![0xab; 8], 0x1000);
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+
+        master.set_owner().unwrap();
+        let features = master.get_features().unwrap();
+        assert_ne!(features & (1u64 << VHOST_USER_F_PROTOCOL_FEATURES), 0);
+        master.set_features(features).unwrap();
+
+        let protocol_features = master.get_protocol_features().unwrap();
+        assert_ne!(
+            protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_CONFIG),
+            0
+        );
+        assert_ne!(
+            protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_REPLY_ACK),
+            0
+        );
+        master.set_protocol_features(protocol_features).unwrap();
+        assert!(master.reply_ack);
+
+        assert_eq!(master.get_queue_num().unwrap(), 4);
+        assert_eq!(master.get_config(0, 8).unwrap(), vec![0xab; 8]);
+        // The backend fails reads past the end of the configuration space.
+        assert!(matches!(
+            master.get_config(4, 8),
+            Err(Error::RequestFailed(VHOST_USER_GET_CONFIG))
+        ));
+
+        let state = backend.state();
+        assert!(state.owner);
+        assert_eq!(state.acked_features, features);
+        assert_eq!(state.acked_protocol_features, protocol_features);
+    }
+
+    #[test]
+    fn test_set_mem_table() {
+        let backend = TestBackend::new(vec![0; 8], 0x1000);
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+
+        // Anonymous memory cannot be shared.
+        assert!(matches!(
+            master.set_mem_table(&default_mem()),
+            Err(Error::UnsharedMemoryRegion(0))
+        ));
+
+        let mem = shared_mem();
+        master.set_mem_table(&mem).unwrap();
+        let state = backend.state();
+        let region = mem.find_region(GuestAddress(0)).unwrap();
+        assert_eq!(state.regions.len(), 1);
+        assert_eq!(state.regions[0].guest_phys_addr, 0);
+        assert_eq!(state.regions[0].memory_size, region.len());
+        assert_eq!(
+            state.regions[0].userspace_addr,
+            region.get_host_address(MemoryRegionAddress(0)).unwrap() as u64
+        );
+    }
+
+    #[test]
+    fn test_set_vring() {
+        let backend = TestBackend::new(vec![0; 8], 0x1000);
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+        let mem = shared_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let call_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        master.set_protocol_features(0).unwrap();
+        master
+            .set_vring(&mem, 1, &queue, &kick_evt, &call_evt)
+            .unwrap();
+        let state = backend.state();
+        let vring = &state.vrings[1];
+        assert_eq!(vring.num, 16);
+        assert_eq!(
+            vring.desc_addr,
+            mem.get_host_address(vq.dtable_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring.avail_addr,
+            mem.get_host_address(vq.avail_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring.used_addr,
+            mem.get_host_address(vq.used_start()).unwrap() as u64
+        );
+        assert_eq!(vring.base, 0);
+        assert!(vring.kick.is_some());
+        assert!(vring.call.is_some());
+        assert!(vring.enabled);
+        drop(state);
+
+        // Rings have to be in guest memory.
+        queue.used_ring = GuestAddress(mem.last_addr().0 + 1);
+        assert!(matches!(
+            master.set_vring(&mem, 1, &queue, &kick_evt, &call_evt),
+            Err(Error::InvalidVringAddress(_))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wire format of the vhost-user messages and helpers for exchanging them, along with file
+//! descriptors, over a unix socket.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use vm_memory::ByteValued;
+
+// Requests sent by the master.
+pub const VHOST_USER_GET_FEATURES: u32 = 1;
+pub const VHOST_USER_SET_FEATURES: u32 = 2;
+pub const VHOST_USER_SET_OWNER: u32 = 3;
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub const VHOST_USER_SET_VRING_NUM: u32 = 8;
+pub const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+pub const VHOST_USER_SET_VRING_BASE: u32 = 10;
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+pub const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+pub const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+pub const VHOST_USER_GET_CONFIG: u32 = 24;
+
+// Message header flags.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+pub const VHOST_USER_VERSION_MASK: u32 = 0x3;
+pub const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+pub const VHOST_USER_NEED_REPLY_MASK: u32 = 0x1 << 3;
+
+/// Maximum number of memory regions in a `VHOST_USER_SET_MEM_TABLE` request.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+/// Maximum size of a message body. The largest messages are the configuration space ones.
+pub const MAX_MSG_SIZE: usize = 0x1000;
+
+/// Header of every vhost-user message.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Header {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+// Safe because Header only contains plain data.
+unsafe impl ByteValued for Header {}
+
+impl Header {
+    pub fn new(request: u32, flags: u32, size: usize) -> Self {
+        Header {
+            request,
+            flags: flags | VHOST_USER_VERSION,
+            size: size as u32,
+        }
+    }
+
+    pub fn is_reply(&self) -> bool {
+        self.flags & VHOST_USER_REPLY_MASK != 0
+    }
+
+    pub fn needs_reply(&self) -> bool {
+        self.flags & VHOST_USER_NEED_REPLY_MASK != 0
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.flags & VHOST_USER_VERSION_MASK == VHOST_USER_VERSION
+            && self.size as usize <= MAX_MSG_SIZE
+    }
+}
+
+/// Body of the vring requests which carry a single number.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+// Safe because VringState only contains plain data.
+unsafe impl ByteValued for VringState {}
+
+/// Body of the `VHOST_USER_SET_VRING_ADDR` request. The addresses are virtual addresses in the
+/// master address space.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub descriptor: u64,
+    pub used: u64,
+    pub available: u64,
+    pub log: u64,
+}
+
+// Safe because VringAddr only contains plain data.
+unsafe impl ByteValued for VringAddr {}
+
+/// Memory region entry of the `VHOST_USER_SET_MEM_TABLE` request.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+// Safe because MemoryRegion only contains plain data.
+unsafe impl ByteValued for MemoryRegion {}
+
+/// Header of the `VHOST_USER_SET_MEM_TABLE` request, followed by `num_regions` entries.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryTableHeader {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+// Safe because MemoryTableHeader only contains plain data.
+unsafe impl ByteValued for MemoryTableHeader {}
+
+/// Header of the configuration space requests, followed by `size` bytes of configuration space.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConfigHeader {
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+// Safe because ConfigHeader only contains plain data.
+unsafe impl ByteValued for ConfigHeader {}
+
+// Big enough for the control message carrying `MAX_MEMORY_REGIONS` file descriptors, and
+// aligned for `cmsghdr`.
+const CMSG_BUFFER_LEN: usize = 16;
+
+/// Sends the message made of `hdr` and `body` on `sock`, along with the `fds` file descriptors.
+pub fn send_message(sock: &UnixStream, hdr: &Header, body: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_MEMORY_REGIONS {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut iovecs = [
+        libc::iovec {
+            iov_base: hdr.as_slice().as_ptr() as *mut libc::c_void,
+            iov_len: mem::size_of::<Header>(),
+        },
+        libc::iovec {
+            iov_base: body.as_ptr() as *mut libc::c_void,
+            iov_len: body.len(),
+        },
+    ];
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+
+    // Safe because msghdr only contains plain data and pointers, which can be null.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iovecs.as_mut_ptr();
+    msg.msg_iovlen = iovecs.len() as _;
+
+    if !fds.is_empty() {
+        let fds_len = mem::size_of_val(fds) as u32;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        // Safe because CMSG_SPACE only does arithmetic.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+        // Safe because the control buffer is big enough for one control message holding
+        // `fds`, as checked above, and it's properly aligned.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    // Safe because the message points to valid buffers and we check the return value.
+    let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret as usize != mem::size_of::<Header>() + body.len() {
+        return Err(io::Error::from(io::ErrorKind::WriteZero));
+    }
+    Ok(())
+}
+
+/// Receives a message from `sock`, returning its header, its body and the file descriptors
+/// that came with it.
+pub fn recv_message(sock: &UnixStream) -> io::Result<(Header, Vec<u8>, Vec<File>)> {
+    let mut hdr = Header::default();
+    let mut iovec = libc::iovec {
+        iov_base: hdr.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+        iov_len: mem::size_of::<Header>(),
+    };
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+
+    // Safe because msghdr only contains plain data and pointers, which can be null.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iovec;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buffer) as _;
+
+    // Safe because the message points to valid buffers and we check the return value.
+    let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    // Take ownership of the received file descriptors first, so that they get closed if
+    // the message turns out to be invalid.
+    let mut files = Vec::new();
+    // Safe because the kernel filled in the control buffer and it only holds file descriptor
+    // control messages, which we don't read past.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    files.push(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+    }
+
+    let mut reader = sock;
+    if (ret as usize) < mem::size_of::<Header>() {
+        reader.read_exact(&mut hdr.as_mut_slice()[ret as usize..])?;
+    }
+    if !hdr.is_valid() {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let mut body = vec![0u8; hdr.size as usize];
+    reader.read_exact(&mut body)?;
+    Ok((hdr, body, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom, Write};
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_header() {
+        let hdr = Header::new(VHOST_USER_GET_FEATURES, 0, 8);
+        assert_eq!(hdr.flags, VHOST_USER_VERSION);
+        assert!(hdr.is_valid());
+        assert!(!hdr.is_reply());
+        assert!(!hdr.needs_reply());
+
+        let hdr = Header::new(
+            VHOST_USER_SET_FEATURES,
+            VHOST_USER_REPLY_MASK | VHOST_USER_NEED_REPLY_MASK,
+            8,
+        );
+        assert!(hdr.is_reply());
+        assert!(hdr.needs_reply());
+
+        // Unknown versions and oversized bodies are rejected.
+        let mut hdr = Header::new(VHOST_USER_GET_CONFIG, 0, MAX_MSG_SIZE + 1);
+        assert!(!hdr.is_valid());
+        hdr.size = 0;
+        hdr.flags = 0x2;
+        assert!(!hdr.is_valid());
+    }
+
+    #[test]
+    fn test_send_recv_message() {
+        let (master, slave) = UnixStream::pair().unwrap();
+
+        // Messages without file descriptors.
+        let hdr = Header::new(VHOST_USER_SET_FEATURES, 0, 8);
+        send_message(&master, &hdr, 0xabu64.as_slice(), &[]).unwrap();
+        let (recv_hdr, body, files) = recv_message(&slave).unwrap();
+        assert_eq!(recv_hdr, hdr);
+        assert_eq!(body, 0xabu64.as_slice());
+        assert!(files.is_empty());
+
+        // Messages with file descriptors.
+        let f1 = TempFile::new().unwrap();
+        let f2 = TempFile::new().unwrap();
+        f2.as_file().write_all(&[0xcd; 8]).unwrap();
+        let hdr = Header::new(VHOST_USER_SET_VRING_KICK, 0, 0);
+        send_message(
+            &master,
+            &hdr,
+            &[],
+            &[f1.as_file().as_raw_fd(), f2.as_file().as_raw_fd()],
+        )
+        .unwrap();
+        let (recv_hdr, body, mut files) = recv_message(&slave).unwrap();
+        assert_eq!(recv_hdr, hdr);
+        assert!(body.is_empty());
+        assert_eq!(files.len(), 2);
+        // The received file descriptors refer to the same files.
+        let mut buf = [0u8; 8];
+        files[1].seek(SeekFrom::Start(0)).unwrap();
+        files[1].read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xcd; 8]);
+
+        // Too many file descriptors.
+        let fds = vec![f1.as_file().as_raw_fd(); MAX_MEMORY_REGIONS + 1];
+        assert!(send_message(&master, &hdr, &[], &fds).is_err());
+
+        // Invalid headers.
+        let hdr = Header {
+            request: VHOST_USER_GET_FEATURES,
+            flags: 0,
+            size: 0,
+        };
+        send_message(&master, &hdr, &[], &[]).unwrap();
+        assert_eq!(
+            recv_message(&slave).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Closed connections.
+        drop(master);
+        assert_eq!(
+            recv_message(&slave).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
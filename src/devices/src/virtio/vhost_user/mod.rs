@@ -0,0 +1,47 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Master side of the vhost-user protocol.
+//!
+//! vhost-user devices hand their virtqueues over to a backend running in a separate process.
+//! The backend is set up through messages sent on a unix socket: the guest memory is shared
+//! with it through file descriptors, then each virtqueue is described by its guest memory
+//! location, along with the eventfds used for guest notifications (kick) and used buffer
+//! notifications (call).
+
+mod master;
+pub mod message;
+pub mod test_utils;
+
+pub use self::master::Master;
+
+use std::io;
+
+/// Virtio feature bit signaling that the backend supports protocol features negotiation.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+// Protocol feature bits.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the backend socket.
+    Connect(io::Error),
+    /// The guest memory has more regions than the protocol allows.
+    TooManyMemoryRegions(usize),
+    /// The guest memory region at the given address is not backed by a file, so it cannot be
+    /// shared with the backend.
+    UnsharedMemoryRegion(u64),
+    /// The guest address of a virtqueue is not mapped.
+    InvalidVringAddress(u64),
+    /// The backend replied with a message not matching the request.
+    InvalidReply(u32),
+    /// The backend failed to execute the request.
+    RequestFailed(u32),
+    /// Failed to exchange a message with the backend.
+    Socket(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
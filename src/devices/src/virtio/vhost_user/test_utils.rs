@@ -0,0 +1,336 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal vhost-user block backend, standing in for an external storage daemon in tests.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem;
+use std::num::Wrapping;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use utils::tempfile::TempFile;
+use virtio_gen::virtio_blk::*;
+use vm_memory::{ByteValued, Bytes, FileOffset, GuestAddress, GuestMemoryMmap};
+
+use super::message::*;
+use super::{
+    VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_MQ,
+    VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+use crate::virtio::block::SECTOR_SHIFT;
+use crate::virtio::{DescriptorChain, Queue};
+
+/// Number of virtqueues supported by the test backend.
+pub const NUM_VRINGS: usize = 4;
+/// Virtio features offered by the test backend.
+pub const BACKEND_FEATURES: u64 = (1u64 << VIRTIO_F_VERSION_1)
+    | (1u64 << VIRTIO_BLK_F_FLUSH)
+    | (1u64 << VIRTIO_BLK_F_MQ)
+    | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES);
+/// Protocol features offered by the test backend.
+pub const BACKEND_PROTOCOL_FEATURES: u64 = (1u64 << VHOST_USER_PROTOCOL_F_MQ)
+    | (1u64 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+    | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG);
+
+/// Creates a guest memory backed by a memfd, which can be shared with a backend.
+pub fn shared_mem() -> GuestMemoryMmap {
+    let size = 0x10000;
+    // Safe because the name is a valid C string and we check the return value.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            b"guest_mem\0".as_ptr(),
+            libc::MFD_CLOEXEC,
+        )
+    };
+    assert!(fd >= 0);
+    // Safe because we own the file descriptor.
+    let file = unsafe { File::from_raw_fd(fd as i32) };
+    file.set_len(size as u64).unwrap();
+
+    GuestMemoryMmap::from_ranges_with_files_guarded(
+        &[(GuestAddress(0), size, Some(FileOffset::new(file, 0)))],
+        false,
+    )
+    .unwrap()
+}
+
+/// Virtqueue state, as described by the master.
+#[derive(Default)]
+pub struct Vring {
+    pub num: u32,
+    pub desc_addr: u64,
+    pub avail_addr: u64,
+    pub used_addr: u64,
+    pub base: u16,
+    pub kick: Option<File>,
+    pub call: Option<File>,
+    pub enabled: bool,
+}
+
+/// State of the test backend, as set up by the master.
+#[derive(Default)]
+pub struct BackendState {
+    pub owner: bool,
+    pub acked_features: u64,
+    pub acked_protocol_features: u64,
+    pub regions: Vec<MemoryRegion>,
+    pub mem: Option<GuestMemoryMmap>,
+    pub vrings: Vec<Vring>,
+    pub disk: Vec<u8>,
+}
+
+impl BackendState {
+    // Translates a master virtual address to a guest physical address.
+    fn guest_addr(&self, addr: u64) -> GuestAddress {
+        let region = self
+            .regions
+            .iter()
+            .find(|r| addr >= r.userspace_addr && addr - r.userspace_addr < r.memory_size)
+            .expect("Unmapped vring address");
+        GuestAddress(region.guest_phys_addr + addr - region.userspace_addr)
+    }
+
+    // Executes the virtio block request found at `head` and returns the number of bytes
+    // written to guest memory.
+    fn execute_request(mem: &GuestMemoryMmap, disk: &mut [u8], head: &DescriptorChain) -> u32 {
+        let request_type: u32 = mem.read_obj(head.addr).unwrap();
+        let sector: u64 = mem.read_obj(GuestAddress(head.addr.0 + 8)).unwrap();
+        let mut offset = (sector << SECTOR_SHIFT) as usize;
+        let mut status = VIRTIO_BLK_S_OK;
+        let mut used_len = 0;
+
+        let mut desc = head.next_descriptor().unwrap();
+        while desc.has_next() {
+            let len = desc.len as usize;
+            if offset + len > disk.len() {
+                status = VIRTIO_BLK_S_IOERR;
+            } else {
+                match request_type {
+                    VIRTIO_BLK_T_IN => {
+                        mem.write_slice(&disk[offset..offset + len], desc.addr)
+                            .unwrap();
+                        used_len += desc.len;
+                    }
+                    VIRTIO_BLK_T_OUT => mem
+                        .read_slice(&mut disk[offset..offset + len], desc.addr)
+                        .unwrap(),
+                    _ => status = VIRTIO_BLK_S_UNSUPP,
+                }
+            }
+            offset += len;
+            desc = desc.next_descriptor().unwrap();
+        }
+
+        mem.write_obj(status as u8, desc.addr).unwrap();
+        used_len + 1
+    }
+}
+
+/// vhost-user block backend serving a connection on a separate thread. The disk lives in
+/// memory and the virtqueues are only processed on demand.
+pub struct TestBackend {
+    socket_path: PathBuf,
+    state: Arc<Mutex<BackendState>>,
+}
+
+impl TestBackend {
+    /// Creates a backend exposing `config` as configuration space, for a disk of `disk_size`
+    /// bytes.
+    pub fn new(config: Vec<u8>, disk_size: usize) -> Self {
+        let mut tmp_file = TempFile::new().unwrap();
+        let socket_path = tmp_file.as_path().to_path_buf();
+        tmp_file.remove().unwrap();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let state = Arc::new(Mutex::new(BackendState {
+            vrings: (0..NUM_VRINGS).map(|_| Vring::default()).collect(),
+            disk: vec![0; disk_size],
+            ..Default::default()
+        }));
+        let thread_state = state.clone();
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            // The connection is served until the master hangs up.
+            while let Ok((hdr, body, files)) = recv_message(&sock) {
+                Self::handle_message(&sock, &thread_state, &config, hdr, &body, files);
+            }
+        });
+
+        TestBackend { socket_path, state }
+    }
+
+    /// Path of the socket the backend listens on.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Locks the backend state.
+    pub fn state(&self) -> MutexGuard<BackendState> {
+        self.state.lock().unwrap()
+    }
+
+    fn reply(sock: &UnixStream, request: u32, body: &[u8]) {
+        let hdr = Header::new(request, VHOST_USER_REPLY_MASK, body.len());
+        send_message(sock, &hdr, body, &[]).unwrap();
+    }
+
+    fn read_body<T: ByteValued>(body: &[u8]) -> T {
+        let mut value = T::default();
+        value
+            .as_mut_slice()
+            .copy_from_slice(&body[..mem::size_of::<T>()]);
+        value
+    }
+
+    fn handle_message(
+        sock: &UnixStream,
+        state: &Mutex<BackendState>,
+        config: &[u8],
+        hdr: Header,
+        body: &[u8],
+        mut files: Vec<File>,
+    ) {
+        let mut state = state.lock().unwrap();
+        match hdr.request {
+            VHOST_USER_GET_FEATURES => {
+                return Self::reply(sock, hdr.request, BACKEND_FEATURES.as_slice());
+            }
+            VHOST_USER_SET_FEATURES => state.acked_features = Self::read_body(body),
+            VHOST_USER_SET_OWNER => state.owner = true,
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                return Self::reply(sock, hdr.request, BACKEND_PROTOCOL_FEATURES.as_slice());
+            }
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                state.acked_protocol_features = Self::read_body(body)
+            }
+            VHOST_USER_GET_QUEUE_NUM => {
+                return Self::reply(sock, hdr.request, (NUM_VRINGS as u64).as_slice());
+            }
+            VHOST_USER_GET_CONFIG => {
+                let mut config_hdr: ConfigHeader = Self::read_body(body);
+                let start = config_hdr.offset as usize;
+                let end = start + config_hdr.size as usize;
+                let mut reply = Vec::new();
+                if end <= config.len() {
+                    reply.extend_from_slice(config_hdr.as_slice());
+                    reply.extend_from_slice(&config[start..end]);
+                } else {
+                    // Failures are signaled with an empty configuration space.
+                    config_hdr.size = 0;
+                    reply.extend_from_slice(config_hdr.as_slice());
+                }
+                return Self::reply(sock, hdr.request, &reply);
+            }
+            VHOST_USER_SET_MEM_TABLE => {
+                let table_hdr: MemoryTableHeader = Self::read_body(body);
+                let entries = &body[mem::size_of::<MemoryTableHeader>()..];
+                let regions: Vec<MemoryRegion> = (0..table_hdr.num_regions as usize)
+                    .map(|i| Self::read_body(&entries[i * mem::size_of::<MemoryRegion>()..]))
+                    .collect();
+                let ranges: Vec<_> = regions
+                    .iter()
+                    .zip(files.drain(..))
+                    .map(|(region, file)| {
+                        (
+                            GuestAddress(region.guest_phys_addr),
+                            region.memory_size as usize,
+                            Some(FileOffset::new(file, region.mmap_offset)),
+                        )
+                    })
+                    .collect();
+                state.mem = Some(GuestMemoryMmap::from_ranges_with_files(&ranges, false).unwrap());
+                state.regions = regions;
+            }
+            VHOST_USER_SET_VRING_NUM => {
+                let vring_state: VringState = Self::read_body(body);
+                state.vrings[vring_state.index as usize].num = vring_state.num;
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let vring_addr: VringAddr = Self::read_body(body);
+                let vring = &mut state.vrings[vring_addr.index as usize];
+                vring.desc_addr = vring_addr.descriptor;
+                vring.avail_addr = vring_addr.available;
+                vring.used_addr = vring_addr.used;
+            }
+            VHOST_USER_SET_VRING_BASE => {
+                let vring_state: VringState = Self::read_body(body);
+                state.vrings[vring_state.index as usize].base = vring_state.num as u16;
+            }
+            VHOST_USER_SET_VRING_KICK => {
+                let index = Self::read_body::<u64>(body) as u8 as usize;
+                state.vrings[index].kick = files.pop();
+            }
+            VHOST_USER_SET_VRING_CALL => {
+                let index = Self::read_body::<u64>(body) as u8 as usize;
+                state.vrings[index].call = files.pop();
+            }
+            VHOST_USER_SET_VRING_ENABLE => {
+                let vring_state: VringState = Self::read_body(body);
+                state.vrings[vring_state.index as usize].enabled = vring_state.num != 0;
+            }
+            request => panic!("Unexpected vhost-user request: {}", request),
+        }
+
+        if hdr.needs_reply() {
+            Self::reply(sock, hdr.request, 0u64.as_slice());
+        }
+    }
+
+    /// Consumes the guest notification of the `index` virtqueue, executes the pending requests
+    /// and signals the used buffers to the master. Returns the number of executed requests.
+    pub fn process_queue(&self, index: usize) -> usize {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let mut vring = mem::take(&mut state.vrings[index]);
+
+        let mut kick_count = [0u8; 8];
+        vring
+            .kick
+            .as_ref()
+            .unwrap()
+            .read_exact(&mut kick_count)
+            .unwrap();
+
+        let mut queue = Queue::new(vring.num as u16);
+        queue.size = vring.num as u16;
+        queue.ready = true;
+        queue.desc_table = state.guest_addr(vring.desc_addr);
+        queue.avail_ring = state.guest_addr(vring.avail_addr);
+        queue.used_ring = state.guest_addr(vring.used_addr);
+        // Requests are executed synchronously, so the used ring catches up right away.
+        queue.next_avail = Wrapping(vring.base);
+        queue.next_used = Wrapping(vring.base);
+
+        let mem = state.mem.as_ref().unwrap();
+        let mut count = 0;
+        while let Some(head) = queue.pop(mem) {
+            let used_len = BackendState::execute_request(mem, &mut state.disk, &head);
+            queue.add_used(mem, head.index, used_len).unwrap();
+            count += 1;
+        }
+        vring.base = queue.next_avail.0;
+
+        if count > 0 {
+            vring
+                .call
+                .as_ref()
+                .unwrap()
+                .write_all(&1u64.to_ne_bytes())
+                .unwrap();
+        }
+        state.vrings[index] = vring;
+        count
+    }
+}
+
+impl Drop for TestBackend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
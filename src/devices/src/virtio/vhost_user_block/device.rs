@@ -0,0 +1,496 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use logger::error;
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::*;
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
+use vm_memory::GuestMemoryMmap;
+
+use super::{Error, Result};
+use crate::virtio::block::{BlockMetrics, CONFIG_SPACE_SIZE, MAX_NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::vhost_user::{
+    self, Master, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK,
+    VIRTIO_MMIO_INT_VRING,
+};
+use crate::Error as DeviceError;
+
+// Offset of the `num_queues` field in the virtio block configuration space.
+const NUM_QUEUES_CONFIG_OFFSET: usize = 34;
+
+// Virtio features which are passed through to the guest, if the backend offers them.
+const SUPPORTED_FEATURES: u64 = (1u64 << VIRTIO_F_VERSION_1)
+    | (1u64 << VIRTIO_BLK_F_SIZE_MAX)
+    | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+    | (1u64 << VIRTIO_BLK_F_RO)
+    | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
+    | (1u64 << VIRTIO_BLK_F_FLUSH)
+    | (1u64 << VIRTIO_BLK_F_TOPOLOGY)
+    | (1u64 << VIRTIO_BLK_F_DISCARD)
+    | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES)
+    | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+    | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+
+// Protocol features used when the backend offers them.
+const SUPPORTED_PROTOCOL_FEATURES: u64 = (1u64 << VHOST_USER_PROTOCOL_F_MQ)
+    | (1u64 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+    | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG);
+
+/// Virtio block device whose request queues are processed by an external vhost-user backend.
+pub struct VhostUserBlock {
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    config_space: Vec<u8>,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) interrupt_status: Arc<AtomicUsize>,
+    pub(crate) interrupt_evt: EventFd,
+    pub(crate) queue_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    pub(crate) socket_path: String,
+    pub(crate) master: Master,
+    // Signaled by the backend when it uses buffers from the queue found at the same index.
+    pub(crate) call_evts: Vec<EventFd>,
+    pub(crate) metrics: BlockMetrics,
+}
+
+impl VhostUserBlock {
+    /// Connects to the vhost-user backend listening on `socket_path` and creates a block
+    /// device with `num_queues` request queues on top of it.
+    pub fn new(id: String, socket_path: String, num_queues: u16) -> Result<VhostUserBlock> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::UnsupportedNumQueues(num_queues));
+        }
+
+        let mut master = Master::connect(&socket_path).map_err(Error::VhostUser)?;
+        master.set_owner().map_err(Error::VhostUser)?;
+
+        let backend_features = master.get_features().map_err(Error::VhostUser)?;
+        if backend_features & (1u64 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            return Err(Error::MissingProtocolFeatures);
+        }
+        let protocol_features =
+            master.get_protocol_features().map_err(Error::VhostUser)? & SUPPORTED_PROTOCOL_FEATURES;
+        // The configuration space, holding the disk geometry, comes from the backend.
+        if protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            return Err(Error::MissingConfigSupport);
+        }
+        master
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUser)?;
+
+        let mut avail_features = backend_features & SUPPORTED_FEATURES;
+        if num_queues > 1 {
+            if backend_features & (1u64 << VIRTIO_BLK_F_MQ) == 0
+                || protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_MQ) == 0
+                || master.get_queue_num().map_err(Error::VhostUser)? < u64::from(num_queues)
+            {
+                return Err(Error::UnsupportedNumQueues(num_queues));
+            }
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let mut config_space = master
+            .get_config(0, CONFIG_SPACE_SIZE as u32)
+            .map_err(Error::VhostUser)?;
+        // The guest only gets to see the queues it was configured with.
+        config_space[NUM_QUEUES_CONFIG_OFFSET..NUM_QUEUES_CONFIG_OFFSET + 2]
+            .copy_from_slice(&num_queues.to_le_bytes());
+
+        let mut queue_evts = Vec::new();
+        let mut call_evts = Vec::new();
+        let mut queues = Vec::new();
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+            call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+            queues.push(Queue::new(QUEUE_SIZE));
+        }
+
+        Ok(VhostUserBlock {
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queues,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queue_evts,
+            device_state: DeviceState::Inactive,
+            metrics: BlockMetrics::new(&id),
+            id,
+            socket_path,
+            master,
+            call_evts,
+        })
+    }
+
+    /// Provides the ID of this block device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the path of the socket the backend listens on.
+    pub fn socket_path(&self) -> &String {
+        &self.socket_path
+    }
+
+    /// Provides the number of request queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
+    pub(crate) fn process_call_event(&mut self, queue_index: usize) {
+        if let Err(e) = self.call_evts[queue_index].read() {
+            error!("Failed to get vhost-user call event: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+        } else {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    pub(crate) fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })?;
+        Ok(())
+    }
+
+    // Shares the guest memory with the backend and hands the queues set up by the driver over.
+    fn setup_backend(&mut self, mem: &GuestMemoryMmap) -> vhost_user::Result<()> {
+        self.master
+            .set_features(self.acked_features | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES))?;
+        self.master.set_mem_table(mem)?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            // The driver can leave some of the queues unused.
+            if !queue.ready {
+                continue;
+            }
+            self.master.set_vring(
+                mem,
+                index,
+                queue,
+                &self.queue_evts[index],
+                &self.call_evts[index],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn num_required_queues(&self) -> usize {
+        // Drivers set up at most one request queue per vCPU.
+        1
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            self.metrics.inc(|m| &m.cfg_fails);
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The only writable field, the cache mode of VIRTIO_BLK_F_CONFIG_WCE, is never offered
+        // to the driver, so the configuration space of the backend cannot change.
+        error!(
+            "vhost-user block: Unsupported config space write at offset {:#x}, len {}",
+            offset,
+            data.len()
+        );
+        self.metrics.inc(|m| &m.cfg_fails);
+    }
+
+    fn is_activated(&self) -> bool {
+        match self.device_state {
+            DeviceState::Inactive => false,
+            DeviceState::Activated(_) => true,
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if let Err(e) = self.setup_backend(&mem) {
+            error!("vhost-user block: Cannot set up the backend: {:?}", e);
+            return Err(ActivateError::BadActivate);
+        }
+        if self.activate_evt.write(1).is_err() {
+            error!("vhost-user block: Cannot write to activate_evt");
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use logger::{IncMetric, METRICS};
+
+    use crate::check_metric_after_block;
+    use crate::virtio::queue::tests::*;
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
+    use crate::virtio::vhost_user::test_utils::{
+        shared_mem, TestBackend, BACKEND_FEATURES, NUM_VRINGS,
+    };
+    use vm_memory::{Address, Bytes, GuestAddress};
+
+    // Configuration space of a 0x1000 bytes disk.
+    pub fn test_config() -> Vec<u8> {
+        let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+        config[..8].copy_from_slice(&8u64.to_le_bytes());
+        config
+    }
+
+    pub fn default_vhost_user_block(backend: &TestBackend) -> VhostUserBlock {
+        VhostUserBlock::new(
+            "test".to_string(),
+            backend.socket_path().to_str().unwrap().to_string(),
+            1,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let mut block = default_vhost_user_block(&backend);
+
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        assert_eq!(block.id(), "test");
+        assert_eq!(block.socket_path(), backend.socket_path().to_str().unwrap());
+        assert_eq!(block.num_queues(), 1);
+        assert_eq!(block.queue_events().len(), 1);
+        assert_eq!(block.num_required_queues(), 1);
+        // Only the features the device knows about are passed through.
+        assert_eq!(
+            block.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH)
+        );
+
+        // The configuration space comes from the backend.
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 8);
+        let mut num_queues = [0u8; 2];
+        block.read_config(NUM_QUEUES_CONFIG_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 1);
+        // The driver cannot change it.
+        check_metric_after_block!(
+            &METRICS.block.cfg_fails,
+            1,
+            block.write_config(0, &[0xff; 8])
+        );
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 8);
+
+        let state = backend.state();
+        assert!(state.owner);
+        assert_ne!(
+            state.acked_protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_CONFIG),
+            0
+        );
+        // Nothing is handed over before activation.
+        assert!(state.mem.is_none());
+    }
+
+    #[test]
+    fn test_new_errors() {
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let socket_path = backend.socket_path().to_str().unwrap().to_string();
+
+        assert!(matches!(
+            VhostUserBlock::new("test".to_string(), socket_path.clone(), 0),
+            Err(Error::UnsupportedNumQueues(0))
+        ));
+        // The backend only supports a few queues.
+        assert!(matches!(
+            VhostUserBlock::new("test".to_string(), socket_path, NUM_VRINGS as u16 + 1),
+            Err(Error::UnsupportedNumQueues(_))
+        ));
+        assert!(matches!(
+            VhostUserBlock::new("test".to_string(), "/invalid/socket".to_string(), 1),
+            Err(Error::VhostUser(vhost_user::Error::Connect(_)))
+        ));
+
+        // Backends have to expose their configuration space.
+        let backend = TestBackend::new(vec![0u8; 8], 0x1000);
+        assert!(matches!(
+            VhostUserBlock::new(
+                "test".to_string(),
+                backend.socket_path().to_str().unwrap().to_string(),
+                1
+            ),
+            Err(Error::VhostUser(vhost_user::Error::RequestFailed(_)))
+        ));
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let block = VhostUserBlock::new(
+            "test".to_string(),
+            backend.socket_path().to_str().unwrap().to_string(),
+            NUM_VRINGS as u16,
+        )
+        .unwrap();
+
+        assert_eq!(block.queues().len(), NUM_VRINGS);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        let mut num_queues = [0u8; 2];
+        block.read_config(NUM_QUEUES_CONFIG_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), NUM_VRINGS as u16);
+    }
+
+    #[test]
+    fn test_activate() {
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let mut block = default_vhost_user_block(&backend);
+
+        // The guest memory has to be shared with the backend.
+        assert!(block.activate(default_mem()).is_err());
+        assert!(!block.is_activated());
+
+        let mem = shared_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.set_acked_features(block.avail_features());
+        block.activate(mem).unwrap();
+        assert!(block.is_activated());
+        assert_eq!(block.activate_evt.read().unwrap(), 1);
+
+        let state = backend.state();
+        assert_eq!(
+            state.acked_features,
+            BACKEND_FEATURES & !(1u64 << VIRTIO_BLK_F_MQ)
+        );
+        assert_eq!(state.regions.len(), 1);
+        assert!(state.vrings[0].enabled);
+        assert_eq!(state.vrings[0].num, 16);
+        // Unused queues are not handed over.
+        assert!(!state.vrings[1].enabled);
+    }
+
+    #[test]
+    fn test_io() {
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let mut block = default_vhost_user_block(&backend);
+        let mem = shared_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.activate(mem.clone()).unwrap();
+
+        // Write a sector, then read it back.
+        let request_addr = GuestAddress(0x1000);
+        let data_addr = GuestAddress(0x2000);
+        let status_addr = GuestAddress(0x3000);
+        vq.dtable[0].set(request_addr.0, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(data_addr.0, 0x200, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(status_addr.0, 1, VIRTQ_DESC_F_WRITE, 0);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_addr)
+            .unwrap();
+        mem.write_obj::<u64>(1, request_addr.unchecked_add(8))
+            .unwrap();
+        mem.write_slice(&[0xab; 0x200], data_addr).unwrap();
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        // The guest kicks the backend directly.
+        block.queue_evts[0].write(1).unwrap();
+        assert_eq!(backend.process_queue(0), 1);
+        vq.check_used_elem(0, 0, 1);
+        assert_eq!(mem.read_obj::<u8>(status_addr).unwrap(), 0);
+        assert_eq!(backend.state().disk[0x200..0x400], [0xab; 0x200][..]);
+
+        // The backend calls are forwarded to the guest as interrupts.
+        block.process_call_event(0);
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(
+            block.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+
+        vq.dtable[1].set(
+            data_addr.0,
+            0x200,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            2,
+        );
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_addr).unwrap();
+        mem.write_slice(&[0u8; 0x200], data_addr).unwrap();
+        vq.avail.ring[1].set(0);
+        vq.avail.idx.set(2);
+        block.queue_evts[0].write(1).unwrap();
+        assert_eq!(backend.process_queue(0), 1);
+        vq.check_used_elem(1, 0, 0x201);
+        let mut buf = [0u8; 0x200];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf[..], [0xab; 0x200][..]);
+    }
+}
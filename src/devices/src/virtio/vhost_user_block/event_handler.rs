@@ -0,0 +1,125 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn};
+use utils::epoll::EventSet;
+
+use crate::virtio::vhost_user_block::device::VhostUserBlock;
+use crate::virtio::VirtioDevice;
+
+impl VhostUserBlock {
+    // The queue events are consumed by the backend, so only its calls need handling here.
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        for call_evt in self.call_evts.iter() {
+            if let Err(e) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                error!("Failed to register vhost-user call event: {}", e);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(e) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", e);
+        }
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        debug!("vhost-user block: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume vhost-user block activate event: {:?}", e);
+        }
+        self.register_runtime_events(ops);
+        if let Err(e) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", e);
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostUserBlock {
+    // Handle an event for the activation or the backend calls.
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let supported_events = EventSet::IN;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "vhost-user block: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let activate_fd = self.activate_evt.as_raw_fd();
+
+            match source {
+                _ if activate_fd == source => self.process_activate_event(ops),
+                _ => match self
+                    .call_evts
+                    .iter()
+                    .position(|call_evt| call_evt.as_raw_fd() == source)
+                {
+                    Some(queue_index) => self.process_call_event(queue_index),
+                    None => warn!("vhost-user block: Spurious event received: {:?}", source),
+                },
+            }
+        } else {
+            warn!(
+                "vhost-user block: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::test_utils::{shared_mem, TestBackend};
+    use crate::virtio::vhost_user_block::device::tests::{default_vhost_user_block, test_config};
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let backend = TestBackend::new(test_config(), 0x1000);
+        let mut block = default_vhost_user_block(&backend);
+        let mem = shared_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+
+        let block = Arc::new(Mutex::new(block));
+        let _id = event_manager.add_subscriber(block.clone());
+
+        // A call from the backend is not handled before the activation.
+        block.lock().unwrap().call_evts[0].write(1).unwrap();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+
+        block.lock().unwrap().activate(mem).unwrap();
+        // Process the activate event.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+
+        // The pending call is turned into a guest interrupt.
+        event_manager
+            .run_with_timeout(100)
+            .expect("Metrics event timeout or error.");
+        assert_eq!(block.lock().unwrap().interrupt_evt().read().unwrap(), 1);
+    }
+}
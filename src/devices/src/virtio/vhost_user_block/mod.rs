@@ -0,0 +1,28 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod device;
+pub mod event_handler;
+
+pub use self::device::VhostUserBlock;
+pub use self::event_handler::*;
+
+use std::io;
+
+use crate::virtio::vhost_user::Error as VhostUserError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// EventFd error.
+    EventFd(io::Error),
+    /// The backend doesn't support the configuration space requests.
+    MissingConfigSupport,
+    /// The backend doesn't support the protocol features negotiation.
+    MissingProtocolFeatures,
+    /// The backend doesn't support the number of queues.
+    UnsupportedNumQueues(u16),
+    /// Failed to set up the backend.
+    VhostUser(VhostUserError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -576,6 +576,11 @@ where
         self.state
     }
 
+    /// Get the underlying connected stream.
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
     /// Send some raw, untracked, data straight to the underlying connected stream.
     /// Returns: number of bytes written, or the error describing the write failure.
     ///
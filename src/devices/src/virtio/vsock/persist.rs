@@ -7,6 +7,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use super::*;
+use logger::warn;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -43,6 +44,70 @@ pub enum VsockBackendState {
 pub struct VsockUdsState {
     /// The path for the UDS socket.
     pub(crate) path: String,
+    /// The guest ports whose connections are forwarded to host TCP sockets.
+    #[version(start = 3, ser_fn = "tcp_ports_ser")]
+    pub(crate) tcp_forwards: Vec<VsockTcpPortState>,
+    /// The host TCP ports on which connections to guest ports are accepted.
+    #[version(start = 3)]
+    pub(crate) tcp_listeners: Vec<VsockTcpPortState>,
+    /// The host ports the guest can connect to, any of them when missing.
+    #[version(start = 3, ser_fn = "port_acl_ser")]
+    pub(crate) guest_to_host_ports: Option<Vec<u32>>,
+    /// The guest ports the host can connect to, any of them when missing.
+    #[version(start = 3)]
+    pub(crate) host_to_guest_ports: Option<Vec<u32>>,
+}
+
+impl VsockUdsState {
+    fn tcp_ports_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && !(self.tcp_forwards.is_empty() && self.tcp_listeners.is_empty()) {
+            warn!(
+                "Target version does not implement vsock TCP ports. \
+                Their connections will go through Unix sockets instead."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn port_acl_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring without the access control list would allow connections to any port.
+        if target_version < 3
+            && (self.guest_to_host_ports.is_some() || self.host_to_guest_ports.is_some())
+        {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement vsock port access control lists.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The serializable mapping of a guest port to a host TCP port.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpPortState {
+    vsock_port: u32,
+    tcp_port: u16,
+}
+
+impl From<&VsockTcpPort> for VsockTcpPortState {
+    fn from(tcp_port: &VsockTcpPort) -> Self {
+        VsockTcpPortState {
+            vsock_port: tcp_port.vsock_port,
+            tcp_port: tcp_port.tcp_port,
+        }
+    }
+}
+
+impl From<&VsockTcpPortState> for VsockTcpPort {
+    fn from(state: &VsockTcpPortState) -> Self {
+        VsockTcpPort {
+            vsock_port: state.vsock_port,
+            tcp_port: state.tcp_port,
+        }
+    }
 }
 
 /// A helper structure that holds the constructor arguments for VsockUnixBackend
@@ -65,6 +130,10 @@ impl Persist<'_> for VsockUnixBackend {
     fn save(&self) -> Self::State {
         VsockBackendState::Uds(VsockUdsState {
             path: self.host_sock_path.clone(),
+            tcp_forwards: self.tcp_forwards.iter().map(Into::into).collect(),
+            tcp_listeners: self.tcp_listeners.iter().map(Into::into).collect(),
+            guest_to_host_ports: self.port_acl.guest_to_host.clone(),
+            host_to_guest_ports: self.port_acl.host_to_guest.clone(),
         })
     }
 
@@ -76,6 +145,12 @@ impl Persist<'_> for VsockUnixBackend {
             VsockBackendState::Uds(uds_state) => Ok(VsockUnixBackend::new(
                 constructor_args.cid,
                 uds_state.path.clone(),
+                uds_state.tcp_forwards.iter().map(Into::into).collect(),
+                uds_state.tcp_listeners.iter().map(Into::into).collect(),
+                VsockPortAcl {
+                    guest_to_host: uds_state.guest_to_host_ports.clone(),
+                    host_to_guest: uds_state.host_to_guest_ports.clone(),
+                },
             )?),
         }
     }
@@ -113,7 +188,7 @@ where
         let mut vsock = Self::with_queues(state.cid, constructor_args.backend, queues)?;
 
         vsock.acked_features = state.virtio_state.acked_features;
-        vsock.avail_features = state.virtio_state.avail_features;
+        vsock.avail_features = state.virtio_state.all_avail_features();
         vsock.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         vsock.device_state = if state.virtio_state.activated {
             DeviceState::Activated(constructor_args.mem)
@@ -141,6 +216,13 @@ pub(crate) mod tests {
         fn save(&self) -> Self::State {
             VsockBackendState::Uds(VsockUdsState {
                 path: "test".to_owned(),
+                tcp_forwards: vec![VsockTcpPortState {
+                    vsock_port: 52,
+                    tcp_port: 8080,
+                }],
+                tcp_listeners: vec![],
+                guest_to_host_ports: None,
+                host_to_guest_ports: None,
             })
         }
 
@@ -154,6 +236,70 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_persist_tcp_ports() {
+        let state = TestBackend::new().save();
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(VsockUdsState::type_id(), 3);
+
+        // Older versions drop the TCP ports.
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        match VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap() {
+            VsockBackendState::Uds(uds_state) => assert!(uds_state.tcp_forwards.is_empty()),
+        }
+
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        match VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap() {
+            VsockBackendState::Uds(uds_state) => {
+                assert_eq!(
+                    uds_state.tcp_forwards,
+                    vec![VsockTcpPortState {
+                        vsock_port: 52,
+                        tcp_port: 8080,
+                    }]
+                );
+                assert!(uds_state.tcp_listeners.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_persist_port_acl() {
+        let mut state = match TestBackend::new().save() {
+            VsockBackendState::Uds(uds_state) => uds_state,
+        };
+        state.host_to_guest_ports = Some(vec![1024]);
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .new_version()
+            .new_version()
+            .set_type_version(VsockUdsState::type_id(), 3);
+
+        // Older versions can't enforce the access control list.
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .is_err());
+
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 4)
+            .unwrap();
+        let restored_state =
+            VsockUdsState::deserialize(&mut mem.as_slice(), &version_map, 4).unwrap();
+        assert_eq!(restored_state.guest_to_host_ports, None);
+        assert_eq!(restored_state.host_to_guest_ports, Some(vec![1024]));
+    }
+
     #[test]
     fn test_persist_uds_backend() {
         let ctx = TestContext::new();
@@ -7,12 +7,20 @@
 /// `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
 /// handling vsock connection states.
 /// Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
+/// Some ports can also be mapped to TCP ports of the host loopback interface, in which case
+/// their connections go through host-side TCP sockets instead, and the ports connections can be
+/// established to can be restricted in either direction.
 mod muxer;
 mod muxer_killq;
 mod muxer_rxq;
+mod muxer_stream;
 
 pub use muxer::VsockMuxer as VsockUnixBackend;
 
+use serde::{Deserialize, Serialize};
+
+use muxer_stream::MuxerStream;
+
 mod defs {
     /// Maximum number of established connections that we can handle.
     pub const MAX_CONNECTIONS: usize = 1023;
@@ -40,9 +48,56 @@ pub enum Error {
     UnixConnect(std::io::Error),
     /// Error reading from host-side Unix socket.
     UnixRead(std::io::Error),
+    /// Error accepting a new connection from a host-side TCP socket.
+    TcpAccept(std::io::Error),
+    /// Error binding to a host-side TCP socket.
+    TcpBind(std::io::Error),
+    /// Error connecting to a host-side TCP socket.
+    TcpConnect(std::io::Error),
+    /// The port access control list doesn't allow connections to this port.
+    PortDenied(u32),
     /// Muxer connection limit reached.
     TooManyConnections,
 }
 
 type Result<T> = std::result::Result<T, Error>;
-type MuxerConnection = super::csm::VsockConnection<std::os::unix::net::UnixStream>;
+type MuxerConnection = super::csm::VsockConnection<MuxerStream>;
+
+/// Maps a vsock port of the guest to a TCP port of the host loopback interface.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockTcpPort {
+    /// The guest vsock port.
+    pub vsock_port: u32,
+    /// The TCP port, on 127.0.0.1.
+    pub tcp_port: u16,
+}
+
+/// Restricts the vsock ports connections can be established to. A direction without a list
+/// allows connections to any port.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockPortAcl {
+    /// The host ports the guest can connect to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_to_host: Option<Vec<u32>>,
+    /// The guest ports the host can connect to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_to_guest: Option<Vec<u32>>,
+}
+
+impl VsockPortAcl {
+    /// Check if the guest can connect to the host port `port`.
+    pub fn allows_guest_to_host(&self, port: u32) -> bool {
+        Self::allows(&self.guest_to_host, port)
+    }
+
+    /// Check if the host can connect to the guest port `port`.
+    pub fn allows_host_to_guest(&self, port: u32) -> bool {
+        Self::allows(&self.host_to_guest, port)
+    }
+
+    fn allows(ports: &Option<Vec<u32>>, port: u32) -> bool {
+        ports.as_ref().map_or(true, |ports| ports.contains(&port))
+    }
+}
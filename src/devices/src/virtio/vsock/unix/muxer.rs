@@ -19,7 +19,7 @@
 /// 2. Event dispatcher
 ///    There are three event categories that the vsock backend is interested it:
 ///    1. A new host-initiated connection is ready to be accepted from the listening host Unix
-///       socket;
+///       socket, or from one of the listening host TCP sockets mapped to guest ports;
 ///    2. Data is available for reading from a newly-accepted host-initiated connection (i.e.
 ///       the host is ready to issue a vsock connection request, informing us of the
 ///       destination port to which it wants to connect);
@@ -30,8 +30,12 @@
 ///    other pollable FDs are then registered under this nested epoll FD.
 ///    To route all these events to their handlers, the muxer uses another `HashMap` object,
 ///    mapping `RawFd`s to `EpollListener`s.
+///
+/// Connection requests for ports missing from the `VsockPortAcl` of the muxer are refused: the
+/// guest gets an RST packet back, and the host-side stream is closed.
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 
@@ -48,8 +52,8 @@ use super::super::{
 use super::defs;
 use super::muxer_killq::MuxerKillQ;
 use super::muxer_rxq::MuxerRxQ;
-use super::MuxerConnection;
 use super::{Error, Result};
+use super::{MuxerConnection, MuxerStream, VsockPortAcl, VsockTcpPort};
 
 /// A unique identifier of a `MuxerConnection` object. Connections are stored in a hash map,
 /// keyed by a `ConnMapKey` object.
@@ -76,6 +80,12 @@ enum EpollListener {
     Connection { key: ConnMapKey, evset: EventSet },
     /// A listener interested in new host-initiated connections.
     HostSock,
+    /// A listener interested in new host-initiated connections to `peer_port`, accepted from
+    /// a host TCP socket.
+    HostTcpSock {
+        listener: TcpListener,
+        peer_port: u32,
+    },
     /// A listener interested in reading host "connect <port>" commands from a freshly
     /// connected host socket.
     LocalStream(UnixStream),
@@ -110,6 +120,13 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// The guest ports whose connection requests are forwarded to host TCP sockets, instead
+    /// of Unix sockets.
+    pub(crate) tcp_forwards: Vec<VsockTcpPort>,
+    /// The host TCP ports on which connections to guest ports are accepted.
+    pub(crate) tcp_listeners: Vec<VsockTcpPort>,
+    /// The ports connections can be established to.
+    pub(crate) port_acl: VsockPortAcl,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -307,7 +324,30 @@ impl VsockBackend for VsockMuxer {}
 
 impl VsockMuxer {
     /// Muxer constructor.
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+    ///
+    /// The connection requests for the guest ports found in `tcp_forwards` go to
+    /// 127.0.0.1:`tcp_port`, and the connections accepted on 127.0.0.1:`tcp_port` for the
+    /// ports found in `tcp_listeners` go to the guest port. Connections can only be
+    /// established to the ports allowed by `port_acl`.
+    pub fn new(
+        cid: u64,
+        host_sock_path: String,
+        tcp_forwards: Vec<VsockTcpPort>,
+        tcp_listeners: Vec<VsockTcpPort>,
+        port_acl: VsockPortAcl,
+    ) -> Result<Self> {
+        // Open/bind on the host TCP sockets first, so that we don't leave the host Unix socket
+        // behind when one of them is already in use.
+        let tcp_socks = tcp_listeners
+            .iter()
+            .map(|tcp_port| {
+                TcpListener::bind((Ipv4Addr::LOCALHOST, tcp_port.tcp_port))
+                    .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+                    .map(|sock| (sock, tcp_port.vsock_port))
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(Error::TcpBind)?;
+
         // Open/bind on the host Unix socket, so we can accept host-initiated
         // connections.
         let host_sock = UnixListener::bind(&host_sock_path)
@@ -325,10 +365,24 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            tcp_forwards,
+            tcp_listeners,
+            port_acl,
         };
 
         // Listen on the host initiated socket, for incoming connections.
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+
+        // And on the TCP sockets mapped to guest ports.
+        for (listener, peer_port) in tcp_socks {
+            muxer.add_listener(
+                listener.as_raw_fd(),
+                EpollListener::HostTcpSock {
+                    listener,
+                    peer_port,
+                },
+            )?;
+        }
         Ok(muxer)
     }
 
@@ -383,11 +437,51 @@ impl VsockMuxer {
                     });
             }
 
+            // A new host-initiated connection is ready to be accepted from a TCP socket. The
+            // destination port is the one mapped to this socket, so there is no "connect"
+            // command to wait for.
+            Some(EpollListener::HostTcpSock {
+                listener,
+                peer_port,
+            }) => {
+                let peer_port = *peer_port;
+                let accepted = listener.accept();
+                if self.conn_map.len() == defs::MAX_CONNECTIONS {
+                    // The accepted connection is discarded right away.
+                    warn!("vsock: connection limit reached; refusing new host connection");
+                    return;
+                }
+                accepted
+                    .and_then(|(stream, _)| stream.set_nonblocking(true).map(|_| stream))
+                    .map_err(Error::TcpAccept)
+                    .and_then(|stream| self.check_host_to_guest(peer_port).map(|_| stream))
+                    .and_then(|stream| {
+                        let local_port = self.allocate_local_port();
+                        self.add_connection(
+                            ConnMapKey {
+                                local_port,
+                                peer_port,
+                            },
+                            MuxerConnection::new_local_init(
+                                MuxerStream::Tcp(stream),
+                                uapi::VSOCK_HOST_CID,
+                                self.cid,
+                                local_port,
+                                peer_port,
+                            ),
+                        )
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!("vsock: unable to accept local TCP connection: {:?}", err);
+                    });
+            }
+
             // Data is ready to be read from a host-initiated connection. That would be the
             // "connect" command that we're expecting.
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
                     Self::read_local_stream_port(&mut stream)
+                        .and_then(|peer_port| self.check_host_to_guest(peer_port))
                         .map(|peer_port| (self.allocate_local_port(), peer_port))
                         .and_then(|(local_port, peer_port)| {
                             self.add_connection(
@@ -396,7 +490,7 @@ impl VsockMuxer {
                                     peer_port,
                                 },
                                 MuxerConnection::new_local_init(
-                                    stream,
+                                    MuxerStream::Unix(stream),
                                     uapi::VSOCK_HOST_CID,
                                     self.cid,
                                     local_port,
@@ -463,6 +557,17 @@ impl VsockMuxer {
             .map_err(|_| Error::InvalidPortRequest)
     }
 
+    /// Check if the host can connect to the guest port `peer_port`. The host-side stream of a
+    /// denied connection is simply dropped, since the guest doesn't know about it yet.
+    fn check_host_to_guest(&self, peer_port: u32) -> Result<u32> {
+        if self.port_acl.allows_host_to_guest(peer_port) {
+            Ok(peer_port)
+        } else {
+            METRICS.vsock.conns_denied.inc();
+            Err(Error::PortDenied(peer_port))
+        }
+    }
+
     /// Add a new connection to the active connection pool.
     fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
         // We might need to make room for this new connection, so let's sweep the kill queue
@@ -535,6 +640,7 @@ impl VsockMuxer {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => EventSet::IN,
             EpollListener::HostSock => EventSet::IN,
+            EpollListener::HostTcpSock { .. } => EventSet::IN,
         };
 
         self.epoll
@@ -588,15 +694,36 @@ impl VsockMuxer {
     /// Handle a new connection request comming from our peer (the guest vsock driver).
     ///
     /// This will attempt to connect to a host-side Unix socket, expected to be listening at
-    /// the file system path corresponing to the destination port. If successful, a new
-    /// connection object will be created and added to the connection pool. On failure, a new
-    /// RST packet will be scheduled for delivery to the guest.
+    /// the file system path corresponing to the destination port, or to the host-side TCP
+    /// socket the destination port is mapped to. If successful, a new connection object will
+    /// be created and added to the connection pool. On failure, or if the destination port is
+    /// not allowed, a new RST packet will be scheduled for delivery to the guest.
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
-        let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
+        if !self.port_acl.allows_guest_to_host(pkt.dst_port()) {
+            METRICS.vsock.conns_denied.inc();
+            self.enq_rst(pkt.dst_port(), pkt.src_port());
+            return;
+        }
+
+        let stream = match self
+            .tcp_forwards
+            .iter()
+            .find(|tcp_port| tcp_port.vsock_port == pkt.dst_port())
+        {
+            Some(tcp_port) => TcpStream::connect((Ipv4Addr::LOCALHOST, tcp_port.tcp_port))
+                .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+                .map(MuxerStream::Tcp)
+                .map_err(Error::TcpConnect),
+            None => {
+                let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
+                UnixStream::connect(port_path)
+                    .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+                    .map(MuxerStream::Unix)
+                    .map_err(Error::UnixConnect)
+            }
+        };
 
-        UnixStream::connect(port_path)
-            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
-            .map_err(Error::UnixConnect)
+        stream
             .and_then(|stream| {
                 self.add_connection(
                     ConnMapKey {
@@ -635,8 +762,12 @@ impl VsockMuxer {
             mut_fn(conn);
 
             // If this is a host-initiated connection that has just become established, we'll have
-            // to send an ack message to the host end.
-            if prev_state == ConnState::LocalInit && conn.state() == ConnState::Established {
+            // to send an ack message to the host end. TCP host ends don't know about vsock,
+            // so they don't expect one.
+            if prev_state == ConnState::LocalInit
+                && conn.state() == ConnState::Established
+                && conn.stream().is_unix()
+            {
                 let msg = format!("OK {}\n", key.local_port);
                 match conn.send_bytes_raw(msg.as_bytes()) {
                     Ok(written) if written == msg.len() => (),
@@ -769,7 +900,7 @@ impl VsockMuxer {
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Read, Write};
+    use std::io::{ErrorKind, Read, Write};
     use std::ops::Drop;
     use std::os::unix::net::{UnixListener, UnixStream};
     use std::path::{Path, PathBuf};
@@ -809,6 +940,14 @@ mod tests {
 
     impl MuxerTestContext {
         fn new(name: &str) -> Self {
+            Self::new_with_tcp_ports(name, vec![], vec![])
+        }
+
+        fn new_with_tcp_ports(
+            name: &str,
+            tcp_forwards: Vec<VsockTcpPort>,
+            tcp_listeners: Vec<VsockTcpPort>,
+        ) -> Self {
             let vsock_test_ctx = VsockTestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
             let pkt = VsockPacket::from_rx_virtq_head(
@@ -818,7 +957,14 @@ mod tests {
             )
             .unwrap();
 
-            let muxer = VsockMuxer::new(PEER_CID, get_file(name)).unwrap();
+            let muxer = VsockMuxer::new(
+                PEER_CID,
+                get_file(name),
+                tcp_forwards,
+                tcp_listeners,
+                VsockPortAcl::default(),
+            )
+            .unwrap();
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
                 pkt,
@@ -1156,6 +1302,204 @@ mod tests {
         assert_eq!(&buf, &data);
     }
 
+    // Finds a TCP port nobody listens on.
+    fn free_tcp_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn test_tcp_peer_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_port = VsockTcpPort {
+            vsock_port: LOCAL_PORT,
+            tcp_port: listener.local_addr().unwrap().port(),
+        };
+        let mut ctx =
+            MuxerTestContext::new_with_tcp_ports("tcp_peer_connection", vec![tcp_port], vec![]);
+
+        // The connection request for the mapped port goes to the TCP socket.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let (mut stream, _) = listener.accept().unwrap();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        let mut buf = vec![];
+        ctx.pkt
+            .write_from_offset_to(
+                &ctx._vsock_test_ctx.mem,
+                0,
+                &mut buf,
+                ctx.pkt.len() as usize,
+            )
+            .unwrap();
+        assert_eq!(&buf, &data);
+
+        // The connection is refused when nobody listens on the TCP port anymore.
+        drop(listener);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT + 1, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT + 1);
+
+        // The other ports still go to the Unix sockets.
+        let mut listener = ctx.create_local_listener(LOCAL_PORT + 1);
+        ctx.init_pkt(LOCAL_PORT + 1, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+    }
+
+    #[test]
+    fn test_tcp_local_connection() {
+        let peer_port = 1025;
+        let tcp_port = VsockTcpPort {
+            vsock_port: peer_port,
+            tcp_port: free_tcp_port(),
+        };
+        let mut ctx =
+            MuxerTestContext::new_with_tcp_ports("tcp_local_connection", vec![], vec![tcp_port]);
+
+        // No "connect" command is needed, the destination port is mapped to the TCP socket.
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, tcp_port.tcp_port)).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        ctx.notify_muxer();
+        let local_port = ctx.muxer.local_port_last;
+        let key = ConnMapKey {
+            local_port,
+            peer_port,
+        };
+        assert!(ctx.muxer.conn_map.contains_key(&key));
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+
+        // The TCP host end is not sent the connection ack.
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+        let mut buf = vec![0u8; 32];
+        assert_eq!(
+            stream.read(&mut buf[..]).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(local_port, peer_port, &data);
+        ctx.send();
+        stream.set_nonblocking(false).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), &data);
+    }
+
+    #[test]
+    fn test_tcp_bind_error() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_port = VsockTcpPort {
+            vsock_port: 1025,
+            tcp_port: listener.local_addr().unwrap().port(),
+        };
+        let host_sock_path = get_file("tcp_bind_error");
+        match VsockMuxer::new(
+            PEER_CID,
+            host_sock_path.clone(),
+            vec![],
+            vec![tcp_port],
+            VsockPortAcl::default(),
+        ) {
+            Err(Error::TcpBind(_)) => (),
+            _ => unreachable!(),
+        }
+        // The host Unix socket was not left behind.
+        assert!(!Path::new(&host_sock_path).exists());
+    }
+
+    #[test]
+    fn test_port_acl() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("port_acl");
+        ctx.muxer.port_acl = VsockPortAcl {
+            guest_to_host: Some(vec![LOCAL_PORT]),
+            host_to_guest: Some(vec![PEER_PORT]),
+        };
+        let conns_denied = METRICS.vsock.conns_denied.count();
+
+        // The guest is refused the ports missing from the list, even if a host socket listens
+        // on them.
+        let _listener = ctx.create_local_listener(LOCAL_PORT + 1);
+        ctx.init_pkt(LOCAL_PORT + 1, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT + 1);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert_eq!(METRICS.vsock.conns_denied.count(), conns_denied + 1);
+
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+
+        // The host stream is closed when asking for a port missing from the list.
+        let mut stream = UnixStream::connect(ctx.muxer.host_sock_path.clone()).unwrap();
+        ctx.notify_muxer();
+        stream
+            .write_all(format!("CONNECT {}\n", PEER_PORT + 1).as_bytes())
+            .unwrap();
+        ctx.notify_muxer();
+        assert_eq!(ctx.count_epoll_listeners(), (0, 1));
+        assert!(!ctx.muxer.has_pending_rx());
+        let mut buf = [0u8; 32];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(METRICS.vsock.conns_denied.count(), conns_denied + 2);
+
+        ctx.local_connect(PEER_PORT);
+    }
+
     #[test]
     fn test_local_close() {
         let peer_port = 1025;
@@ -0,0 +1,64 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// `MuxerStream` is the host-side stream of a muxer connection. Connections go through the
+/// Unix sockets found next to the muxer's host socket, unless their port is mapped to a TCP
+/// port of the host loopback interface.
+use std::io::{Read, Result, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// A connected host-side stream.
+pub enum MuxerStream {
+    /// A stream accepted from, or connected to, a host Unix socket.
+    Unix(UnixStream),
+    /// A stream accepted from, or connected to, a host TCP socket.
+    Tcp(TcpStream),
+}
+
+impl MuxerStream {
+    /// Check if the host end of the stream knows about vsock, i.e. if it can be sent the
+    /// connection acks of the muxer.
+    pub fn is_unix(&self) -> bool {
+        match self {
+            MuxerStream::Unix(_) => true,
+            MuxerStream::Tcp(_) => false,
+        }
+    }
+}
+
+impl Read for MuxerStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            MuxerStream::Unix(stream) => stream.read(buf),
+            MuxerStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MuxerStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            MuxerStream::Unix(stream) => stream.write(buf),
+            MuxerStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            MuxerStream::Unix(stream) => stream.flush(),
+            MuxerStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for MuxerStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MuxerStream::Unix(stream) => stream.as_raw_fd(),
+            MuxerStream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
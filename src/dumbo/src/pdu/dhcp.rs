@@ -0,0 +1,393 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing and writing DHCPv4 messages, which are carried by UDP datagrams
+//! exchanged between the ports `DHCP_CLIENT_PORT` and `DHCP_SERVER_PORT`.
+//!
+//! Only messages sent over Ethernet are handled. Details of the message format and of the
+//! options can be found at [1] [2].
+//!
+//! [1]: https://tools.ietf.org/html/rfc2131
+//! [2]: https://tools.ietf.org/html/rfc2132
+use std::convert::From;
+use std::net::Ipv4Addr;
+use std::result::Result;
+
+use super::bytes::{InnerBytes, NetworkBytes, NetworkBytesMut};
+
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+/// UDP port of the DHCP servers.
+pub const DHCP_SERVER_PORT: u16 = 67;
+/// UDP port of the DHCP clients.
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Operation of the messages sent by clients.
+pub const OP_BOOTREQUEST: u8 = 1;
+/// Operation of the messages sent by servers.
+pub const OP_BOOTREPLY: u8 = 2;
+
+/// Set by the clients which cannot receive unicast datagrams before being configured.
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+/// Subnet mask option.
+pub const OPTION_SUBNET_MASK: u8 = 1;
+/// Router option.
+pub const OPTION_ROUTER: u8 = 3;
+/// Domain name server option.
+pub const OPTION_DNS_SERVERS: u8 = 6;
+/// Interface MTU option.
+pub const OPTION_INTERFACE_MTU: u8 = 26;
+/// Requested IP address option.
+pub const OPTION_REQUESTED_IP_ADDR: u8 = 50;
+/// IP address lease time option.
+pub const OPTION_LEASE_TIME: u8 = 51;
+/// DHCP message type option.
+pub const OPTION_MESSAGE_TYPE: u8 = 53;
+/// Server identifier option.
+pub const OPTION_SERVER_ID: u8 = 54;
+
+/// DHCPDISCOVER message type.
+pub const MESSAGE_TYPE_DISCOVER: u8 = 1;
+/// DHCPOFFER message type.
+pub const MESSAGE_TYPE_OFFER: u8 = 2;
+/// DHCPREQUEST message type.
+pub const MESSAGE_TYPE_REQUEST: u8 = 3;
+/// DHCPDECLINE message type.
+pub const MESSAGE_TYPE_DECLINE: u8 = 4;
+/// DHCPACK message type.
+pub const MESSAGE_TYPE_ACK: u8 = 5;
+/// DHCPNAK message type.
+pub const MESSAGE_TYPE_NAK: u8 = 6;
+/// DHCPRELEASE message type.
+pub const MESSAGE_TYPE_RELEASE: u8 = 7;
+/// DHCPINFORM message type.
+pub const MESSAGE_TYPE_INFORM: u8 = 8;
+
+/// Hardware type of Ethernet.
+pub const HTYPE_ETHERNET: u8 = 1;
+
+const OP_OFFSET: usize = 0;
+const HTYPE_OFFSET: usize = 1;
+const HLEN_OFFSET: usize = 2;
+const XID_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 10;
+const CIADDR_OFFSET: usize = 12;
+const YIADDR_OFFSET: usize = 16;
+const CHADDR_OFFSET: usize = 28;
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+
+const MAGIC_COOKIE: u32 = 0x6382_5363;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_END: u8 = 255;
+
+// Some clients drop the messages shorter than the minimum BOOTP message length.
+const MIN_MESSAGE_LEN: usize = 300;
+
+/// Represents errors which may occur while parsing or writing a message.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Invalid hardware address length.
+    HLen,
+    /// Invalid hardware type.
+    HType,
+    /// The magic cookie preceding the options is missing.
+    MagicCookie,
+    /// The value of an option does not fit in 255 bytes.
+    OptionTooLong,
+    /// The provided slice is shorter than the message.
+    SliceTooShort,
+}
+
+/// Interprets the inner bytes as a DHCPv4 message.
+pub struct DhcpMessage<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> DhcpMessage<'a, T> {
+    /// Interprets `bytes` as a DHCP message without any validity checks.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        DhcpMessage {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Attempts to interpret `bytes` as a DHCP message sent over Ethernet.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        if bytes.len() < OPTIONS_OFFSET {
+            return Err(Error::SliceTooShort);
+        }
+
+        let message = DhcpMessage::from_bytes_unchecked(bytes);
+
+        if message.htype() != HTYPE_ETHERNET {
+            return Err(Error::HType);
+        }
+
+        if message.hlen() != MAC_ADDR_LEN as u8 {
+            return Err(Error::HLen);
+        }
+
+        if message.bytes.ntohl_unchecked(MAGIC_COOKIE_OFFSET) != MAGIC_COOKIE {
+            return Err(Error::MagicCookie);
+        }
+
+        Ok(message)
+    }
+
+    /// Returns the operation of the message.
+    #[inline]
+    pub fn op(&self) -> u8 {
+        self.bytes[OP_OFFSET]
+    }
+
+    /// Returns the hardware address type.
+    #[inline]
+    pub fn htype(&self) -> u8 {
+        self.bytes[HTYPE_OFFSET]
+    }
+
+    /// Returns the hardware address length.
+    #[inline]
+    pub fn hlen(&self) -> u8 {
+        self.bytes[HLEN_OFFSET]
+    }
+
+    /// Returns the transaction ID chosen by the client.
+    #[inline]
+    pub fn xid(&self) -> u32 {
+        self.bytes.ntohl_unchecked(XID_OFFSET)
+    }
+
+    /// Returns the flags of the message.
+    #[inline]
+    pub fn flags(&self) -> u16 {
+        self.bytes.ntohs_unchecked(FLAGS_OFFSET)
+    }
+
+    /// Returns the IP address of the client, if it is already configured.
+    #[inline]
+    pub fn ciaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(CIADDR_OFFSET))
+    }
+
+    /// Returns the IP address offered or assigned to the client.
+    #[inline]
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(YIADDR_OFFSET))
+    }
+
+    /// Returns the MAC address of the client.
+    #[inline]
+    pub fn chaddr(&self) -> MacAddr {
+        MacAddr::from_bytes_unchecked(&self.bytes[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN])
+    }
+
+    /// Returns the length of the message.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the value of the first option with the given `code`, if the message has one.
+    /// The options following a malformed one are ignored.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        let mut offset = OPTIONS_OFFSET;
+        while offset < self.bytes.len() {
+            match self.bytes[offset] {
+                OPTION_PAD => offset += 1,
+                OPTION_END => break,
+                option_code => {
+                    let value_offset = offset + 2;
+                    let value_len = usize::from(*self.bytes.get(offset + 1)?);
+                    let value = self.bytes.get(value_offset..value_offset + value_len)?;
+                    if option_code == code {
+                        return Some(value);
+                    }
+                    offset = value_offset + value_len;
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the DHCP message type, which is missing from plain BOOTP messages.
+    #[inline]
+    pub fn message_type(&self) -> Option<u8> {
+        match self.option(OPTION_MESSAGE_TYPE) {
+            Some([message_type]) => Some(*message_type),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an option holding an IPv4 address.
+    #[inline]
+    pub fn ipv4_addr_option(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code) {
+            Some([a, b, c, d]) => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, T: NetworkBytesMut> DhcpMessage<'a, T> {
+    /// Attempts to write to `buf` a reply to the transaction `xid` of the client found at
+    /// `chaddr`, followed by the given (code, value) `options`.
+    ///
+    /// The buffer is shrunk to the length of the message, which is padded up to the minimum
+    /// BOOTP message length.
+    pub fn write_reply(
+        buf: T,
+        xid: u32,
+        flags: u16,
+        yiaddr: Ipv4Addr,
+        chaddr: MacAddr,
+        options: &[(u8, &[u8])],
+    ) -> Result<Self, Error> {
+        let mut len = OPTIONS_OFFSET;
+        for (_, value) in options {
+            if value.len() > usize::from(u8::MAX) {
+                return Err(Error::OptionTooLong);
+            }
+            len += 2 + value.len();
+        }
+        // The end option.
+        len = std::cmp::max(len + 1, MIN_MESSAGE_LEN);
+        if buf.len() < len {
+            return Err(Error::SliceTooShort);
+        }
+
+        let mut message = DhcpMessage::from_bytes_unchecked(buf);
+        message.bytes.shrink_unchecked(len);
+        // The fields which are not set below are all zero.
+        for byte in message.bytes.iter_mut() {
+            *byte = 0;
+        }
+
+        message.bytes[OP_OFFSET] = OP_BOOTREPLY;
+        message.bytes[HTYPE_OFFSET] = HTYPE_ETHERNET;
+        message.bytes[HLEN_OFFSET] = MAC_ADDR_LEN as u8;
+        message.bytes.htonl_unchecked(XID_OFFSET, xid);
+        message.bytes.htons_unchecked(FLAGS_OFFSET, flags);
+        message
+            .bytes
+            .htonl_unchecked(YIADDR_OFFSET, u32::from(yiaddr));
+        message.bytes[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN]
+            .copy_from_slice(chaddr.get_bytes());
+        message
+            .bytes
+            .htonl_unchecked(MAGIC_COOKIE_OFFSET, MAGIC_COOKIE);
+
+        let mut offset = OPTIONS_OFFSET;
+        for (code, value) in options {
+            message.bytes[offset] = *code;
+            message.bytes[offset + 1] = value.len() as u8;
+            message.bytes[offset + 2..offset + 2 + value.len()].copy_from_slice(value);
+            offset += 2 + value.len();
+        }
+        message.bytes[offset] = OPTION_END;
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    impl<'a, T: NetworkBytes> fmt::Debug for DhcpMessage<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(DHCP message)")
+        }
+    }
+
+    #[test]
+    fn test_dhcp_message() {
+        let mut buf = [0u8; 400];
+        let chaddr = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let yiaddr = Ipv4Addr::new(10, 0, 0, 2);
+        let server_id = Ipv4Addr::new(10, 0, 0, 1);
+
+        assert_eq!(
+            DhcpMessage::write_reply(&mut buf[..299], 1, 0, yiaddr, chaddr, &[]).unwrap_err(),
+            Error::SliceTooShort
+        );
+        assert_eq!(
+            DhcpMessage::write_reply(&mut buf[..], 1, 0, yiaddr, chaddr, &[(1, &[0; 256])])
+                .unwrap_err(),
+            Error::OptionTooLong
+        );
+
+        let options: &[(u8, &[u8])] = &[
+            (OPTION_MESSAGE_TYPE, &[MESSAGE_TYPE_OFFER]),
+            (OPTION_SERVER_ID, &server_id.octets()),
+            (OPTION_LEASE_TIME, &[0, 0, 0]),
+        ];
+        let len = DhcpMessage::write_reply(
+            &mut buf[..],
+            0x1234_5678,
+            FLAG_BROADCAST,
+            yiaddr,
+            chaddr,
+            options,
+        )
+        .unwrap()
+        .len();
+        assert_eq!(len, MIN_MESSAGE_LEN);
+
+        let message = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(message.op(), OP_BOOTREPLY);
+        assert_eq!(message.xid(), 0x1234_5678);
+        assert_eq!(message.flags(), FLAG_BROADCAST);
+        assert_eq!(message.ciaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(message.yiaddr(), yiaddr);
+        assert_eq!(message.chaddr(), chaddr);
+        assert_eq!(message.message_type(), Some(MESSAGE_TYPE_OFFER));
+        assert_eq!(message.ipv4_addr_option(OPTION_SERVER_ID), Some(server_id));
+        // The option has an unexpected length.
+        assert_eq!(message.ipv4_addr_option(OPTION_LEASE_TIME), None);
+        assert_eq!(message.option(OPTION_LEASE_TIME), Some(&[0u8, 0, 0][..]));
+        assert_eq!(message.option(OPTION_ROUTER), None);
+
+        // The options past the end option are ignored.
+        buf[OPTIONS_OFFSET + 3] = OPTION_END;
+        let message = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(message.message_type(), Some(MESSAGE_TYPE_OFFER));
+        assert_eq!(message.option(OPTION_SERVER_ID), None);
+
+        // Truncated option.
+        let message = DhcpMessage::from_bytes(&buf[..OPTIONS_OFFSET + 2]).unwrap();
+        assert_eq!(message.message_type(), None);
+
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..OPTIONS_OFFSET - 1]).unwrap_err(),
+            Error::SliceTooShort
+        );
+        buf[MAGIC_COOKIE_OFFSET] = 0;
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..len]).unwrap_err(),
+            Error::MagicCookie
+        );
+        buf[HLEN_OFFSET] = 0;
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..len]).unwrap_err(),
+            Error::HLen
+        );
+        buf[HTYPE_OFFSET] = 0;
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..len]).unwrap_err(),
+            Error::HType
+        );
+    }
+}
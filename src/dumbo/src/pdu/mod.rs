@@ -14,6 +14,7 @@ use crate::pdu::ipv4::{PROTOCOL_TCP, PROTOCOL_UDP};
 
 pub mod arp;
 pub mod bytes;
+pub mod dhcp;
 pub mod ethernet;
 pub mod ipv4;
 pub mod tcp;
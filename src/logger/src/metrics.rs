@@ -24,12 +24,34 @@
 //!    "queue_event_count": 0,
 //!    "read_count": 0,
 //!    "write_count": 0
+//!  },
+//!  "block_drives": {
+//!    "rootfs": {
+//!      "activate_fails": 0,
+//!      "read_count": 0,
+//!      "read_latency_us": {
+//!        "count": 0,
+//!        "sum_us": 0,
+//!        "le_10": 0,
+//!        ...
+//!        "le_inf": 0
+//!      }
+//!    }
+//!  },
+//!  "net_interfaces": {
+//!    "eth0": {
+//!      "rx_bytes_count": 0,
+//!      "tx_bytes_count": 0,
+//!      ...
+//!    }
 //!  }
 //! }
 //! ```
 //! The example above means that inside the structure representing all the metrics there is a field
 //! named `block` which is in turn a serializable child structure collecting metrics for
-//! the block device such as `activate_fails`, `cfg_fails`, etc.
+//! the block device such as `activate_fails`, `cfg_fails`, etc. The `block_drives` field holds
+//! the same metrics, broken down by drive id. Likewise, `net_interfaces` breaks down the `net`
+//! metrics by network interface id.
 //!
 //! # Limitations
 //! Metrics are only written to buffers.
@@ -60,17 +82,17 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::Write;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-#[cfg(target_arch = "aarch64")]
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 
 #[cfg(target_arch = "aarch64")]
 use crate::warn;
 use lazy_static::lazy_static;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 #[cfg(target_arch = "aarch64")]
 use vm_superio::rtc_pl031::RTCEvents;
@@ -286,6 +308,56 @@ impl Serialize for SharedStoreMetric {
     }
 }
 
+/// Upper bounds (inclusive, in microseconds) of the `LatencyHistogram` buckets.
+/// Values above the last bound are accounted in an extra, unbounded bucket.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000,
+];
+
+/// Histogram of latencies expressed in microseconds.
+///
+/// Every bucket is a `SharedIncMetric`, so, just like the counters, the histogram only reports
+/// the samples recorded since the previous flush. The buckets are not cumulative: `le_<bound>`
+/// counts the samples greater than the previous bound and lower or equal to `<bound>`.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [SharedIncMetric; LATENCY_BUCKETS_US.len() + 1],
+    count: SharedIncMetric,
+    sum_us: SharedIncMetric,
+}
+
+impl LatencyHistogram {
+    /// Records a sample of `value_us` microseconds.
+    pub fn record(&self, value_us: u64) {
+        let index = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| value_us <= bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS_US.len());
+        self.buckets[index].inc();
+        self.count.inc();
+        self.sum_us.add(value_us as usize);
+    }
+
+    /// Returns the total number of samples recorded.
+    pub fn count(&self) -> usize {
+        self.count.count()
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.buckets.len() + 2))?;
+        map.serialize_entry("count", &self.count)?;
+        map.serialize_entry("sum_us", &self.sum_us)?;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(self.buckets.iter()) {
+            map.serialize_entry(&format!("le_{}", bound), bucket)?;
+        }
+        map.serialize_entry("le_inf", &self.buckets[LATENCY_BUCKETS_US.len()])?;
+        map.end()
+    }
+}
+
 /// Reporter object which computes the process wall time and
 /// process CPU time and populates the metric with the results.
 pub struct ProcessTimeReporter {
@@ -457,6 +529,8 @@ pub struct BlockDeviceMetrics {
     pub queue_event_count: SharedIncMetric,
     /// Number of events ratelimiter-related.
     pub rate_limiter_event_count: SharedIncMetric,
+    /// Number of completion events triggered by the asynchronous I/O engine.
+    pub async_completion_event_count: SharedIncMetric,
     /// Number of update operation triggered on this block device.
     pub update_count: SharedIncMetric,
     /// Number of failures while doing update on this block device.
@@ -469,8 +543,18 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedIncMetric,
     /// Number of successful write operations.
     pub write_count: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Latency of the read requests, from parsing until they are marked as used.
+    pub read_latency_us: LatencyHistogram,
+    /// Latency of the write requests, from parsing until they are marked as used.
+    pub write_latency_us: LatencyHistogram,
+    /// Latency of the flush requests, from parsing until they are marked as used.
+    pub flush_latency_us: LatencyHistogram,
 }
 
 /// Metrics specific to the i8042 device.
@@ -587,6 +671,53 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of frames with a spoofed source address dropped by the anti-spoofing filter.
+    pub tx_spoofed_frames_dropped: SharedIncMetric,
+    /// Number of frames sent by the guest to the DHCP responder.
+    pub dhcp_requests_count: SharedIncMetric,
+    /// Number of frames sent by the DHCP responder to the guest.
+    pub dhcp_replies_count: SharedIncMetric,
+    /// Number of frames received while the link was down, which were dropped.
+    pub rx_link_down_frames_dropped: SharedIncMetric,
+    /// Number of frames sent by the guest while the link was down, which were dropped.
+    pub tx_link_down_frames_dropped: SharedIncMetric,
+    /// Number of times the guest was asked to announce itself on the network.
+    pub announce_count: SharedIncMetric,
+    /// Number of frames sent by the guest which were detoured to MMDS.
+    pub mmds_requests_count: SharedIncMetric,
+    /// Number of frames sent by MMDS to the guest.
+    pub mmds_replies_count: SharedIncMetric,
+}
+
+/// Device metrics, one instance per device id.
+///
+/// The entries are created the first time a device asks for its metrics and are
+/// serialized as a map indexed by the device id.
+#[derive(Default)]
+pub struct PerDeviceMetrics<M>(RwLock<BTreeMap<String, Arc<M>>>);
+
+impl<M: Default> PerDeviceMetrics<M> {
+    /// Returns the metrics of the `device_id` device, creating them if needed.
+    pub fn get(&self, device_id: &str) -> Arc<M> {
+        if let Some(metrics) = extract_guard(self.0.read()).get(device_id) {
+            return metrics.clone();
+        }
+        extract_guard(self.0.write())
+            .entry(device_id.to_string())
+            .or_insert_with(|| Arc::new(M::default()))
+            .clone()
+    }
+}
+
+impl<M: Serialize> Serialize for PerDeviceMetrics<M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let devices = extract_guard(self.0.read());
+        let mut map = serializer.serialize_map(Some(devices.len()))?;
+        for (device_id, metrics) in devices.iter() {
+            map.serialize_entry(device_id, metrics.as_ref())?;
+        }
+        map.end()
+    }
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -752,6 +883,8 @@ pub struct VsockDeviceMetrics {
     pub conns_killed: SharedIncMetric,
     /// Number of removed connections.
     pub conns_removed: SharedIncMetric,
+    /// Number of connection requests refused by the port access control list.
+    pub conns_denied: SharedIncMetric,
     /// How many times the killq has been resynced.
     pub killq_resync: SharedIncMetric,
     /// How many flush fails have been seen.
@@ -784,6 +917,8 @@ pub struct FirecrackerMetrics {
     pub balloon: BalloonDeviceMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Block device metrics, per drive.
+    pub block_drives: PerDeviceMetrics<BlockDeviceMetrics>,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics related to the i8042 device.
@@ -796,6 +931,8 @@ pub struct FirecrackerMetrics {
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
     pub net: NetDeviceMetrics,
+    /// Network device metrics, per interface.
+    pub net_interfaces: PerDeviceMetrics<NetDeviceMetrics>,
     /// Metrics related to API PATCH requests.
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
@@ -889,6 +1026,64 @@ mod tests {
         assert_eq!(1, m1.fetch());
     }
 
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(0);
+        histogram.record(10);
+        histogram.record(11);
+        histogram.record(1_000_001);
+        assert_eq!(histogram.count(), 4);
+
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["count"], 4);
+        assert_eq!(json["sum_us"], 1_000_022);
+        assert_eq!(json["le_10"], 2);
+        assert_eq!(json["le_25"], 1);
+        assert_eq!(json["le_1000000"], 0);
+        assert_eq!(json["le_inf"], 1);
+
+        // Only the samples recorded since the last flush are reported.
+        histogram.record(30);
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["count"], 1);
+        assert_eq!(json["le_10"], 0);
+        assert_eq!(json["le_50"], 1);
+    }
+
+    #[test]
+    fn test_block_drives_metrics() {
+        let drives = PerDeviceMetrics::<BlockDeviceMetrics>::default();
+        let root = drives.get("root");
+        root.read_count.inc();
+        // The same instance is handed out for the same drive.
+        drives.get("root").read_count.inc();
+        drives.get("scratch").write_count.add(3);
+        assert_eq!(root.read_count.count(), 2);
+
+        let json = serde_json::to_value(&drives).unwrap();
+        assert_eq!(json["root"]["read_count"], 2);
+        assert_eq!(json["root"]["write_count"], 0);
+        assert_eq!(json["scratch"]["write_count"], 3);
+        assert!(json["root"]["read_latency_us"].is_object());
+    }
+
+    #[test]
+    fn test_net_interfaces_metrics() {
+        let ifaces = PerDeviceMetrics::<NetDeviceMetrics>::default();
+        let eth0 = ifaces.get("eth0");
+        eth0.rx_bytes_count.add(10);
+        // The same instance is handed out for the same interface.
+        ifaces.get("eth0").rx_bytes_count.add(5);
+        ifaces.get("eth1").tx_packets_count.inc();
+        assert_eq!(eth0.rx_bytes_count.count(), 15);
+
+        let json = serde_json::to_value(&ifaces).unwrap();
+        assert_eq!(json["eth0"]["rx_bytes_count"], 15);
+        assert_eq!(json["eth0"]["tx_packets_count"], 0);
+        assert_eq!(json["eth1"]["tx_packets_count"], 1);
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
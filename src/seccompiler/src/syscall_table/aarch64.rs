@@ -103,6 +103,9 @@ pub(crate) fn make_syscall_table(map: &mut HashMap<String, i64>) {
     map.insert("ioprio_set".to_string(), 30);
     map.insert("io_setup".to_string(), 0);
     map.insert("io_submit".to_string(), 2);
+    map.insert("io_uring_enter".to_string(), 426);
+    map.insert("io_uring_register".to_string(), 427);
+    map.insert("io_uring_setup".to_string(), 425);
     map.insert("kcmp".to_string(), 272);
     map.insert("kexec_load".to_string(), 104);
     map.insert("keyctl".to_string(), 219);
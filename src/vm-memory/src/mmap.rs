@@ -57,6 +57,9 @@ pub struct GuestRegionMmap {
     guest_base: GuestAddress,
     // handles dirty page tracking
     dirty_bitmap: Option<Bitmap>,
+    // Guarded mappings are built from raw pointers, so the mapping itself doesn't know
+    // about the file backing it.
+    file_offset: Option<FileOffset>,
 }
 
 impl GuestRegionMmap {
@@ -69,9 +72,25 @@ impl GuestRegionMmap {
             mapping,
             guest_base,
             dirty_bitmap: None,
+            file_offset: None,
         })
     }
 
+    /// Create a new memory-mapped memory region for the guest's physical memory, backed by
+    /// the file referred to by `file_offset`.
+    ///
+    /// Useful when `mapping` was built from a raw pointer and doesn't keep track of its backing
+    /// file, which then needs to be shared with other processes (e.g. vhost-user backends).
+    pub fn with_file_offset(
+        mapping: MmapRegion,
+        guest_base: GuestAddress,
+        file_offset: Option<FileOffset>,
+    ) -> result::Result<Self, Error> {
+        let mut region = Self::new(mapping, guest_base)?;
+        region.file_offset = file_offset;
+        Ok(region)
+    }
+
     /// Provide the region with a dedicated bitmap to handle dirty page tracking.
     pub fn enable_dirty_page_tracking(&mut self) {
         let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
@@ -336,7 +355,9 @@ impl GuestMemoryRegion for GuestRegionMmap {
     }
 
     fn file_offset(&self) -> Option<&FileOffset> {
-        self.mapping.file_offset()
+        self.mapping
+            .file_offset()
+            .or_else(|| self.file_offset.as_ref())
     }
 
     unsafe fn as_slice(&self) -> Option<&[u8]> {
@@ -451,11 +472,13 @@ impl GuestMemoryMmap {
                 .map(|x| {
                     let guest_base = x.borrow().0;
                     let size = x.borrow().1;
+                    let file_offset = x.borrow().2.clone();
 
-                    build_fn(x.borrow().2.clone(), size)
+                    build_fn(file_offset.clone(), size)
                         .map_err(Error::MmapRegion)
                         .and_then(|r| {
-                            let mut mmap = GuestRegionMmap::new(r, guest_base)?;
+                            let mut mmap =
+                                GuestRegionMmap::with_file_offset(r, guest_base, file_offset)?;
                             if track_dirty_pages {
                                 mmap.enable_dirty_page_tracking();
                             }
@@ -1812,4 +1835,42 @@ mod tests {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_regions_guarded_from_file() {
+        let region_size = 0x10000;
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(2 * region_size as u64).unwrap();
+        let file = Arc::new(file);
+        let regions = vec![
+            (
+                GuestAddress(0x0),
+                region_size,
+                Some(FileOffset::from_arc(file.clone(), 0)),
+            ),
+            (
+                GuestAddress(0x20000),
+                region_size,
+                Some(FileOffset::from_arc(file, region_size as u64)),
+            ),
+        ];
+
+        let guest_memory =
+            GuestMemoryMmap::from_ranges_with_files_guarded(&regions, false).unwrap();
+        // The regions remember their backing file, even if the guarded mappings don't.
+        let region = guest_memory.find_region(GuestAddress(0x20000)).unwrap();
+        assert!(region.mapping.file_offset().is_none());
+        assert_eq!(region.file_offset().unwrap().start(), region_size as u64);
+        validate_guard_region(region);
+
+        // Writes go through to the file, which is shared.
+        guest_memory
+            .write_obj(0xabu8, GuestAddress(0x20000 + 0x10))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        let file = region.file_offset().unwrap().file();
+        std::os::unix::fs::FileExt::read_exact_at(file, &mut buf, region_size as u64 + 0x10)
+            .unwrap();
+        assert_eq!(buf[0], 0xab);
+    }
 }
@@ -5,8 +5,9 @@
 
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::{Arc, Mutex};
 
 #[cfg(target_arch = "aarch64")]
@@ -31,7 +32,9 @@ use cpuid::common::is_same_model;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::RTCDevice;
 use devices::legacy::Serial;
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostUserBlock, VirtioDevice, Vsock, VsockUnixBackend,
+};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kernel::cmdline::Cmdline as KernelCmdline;
 #[cfg(target_arch = "aarch64")]
@@ -42,7 +45,7 @@ use snapshot::Persist;
 use utils::eventfd::EventFd;
 use utils::terminal::Terminal;
 use utils::time::TimestampUs;
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
 #[cfg(target_arch = "aarch64")]
 use vm_superio::RTC;
 
@@ -53,6 +56,8 @@ pub enum StartMicrovmError {
     AttachBlockDevice(io::Error),
     /// This error is thrown by the minimal boot loader implementation.
     ConfigureSystem(arch::Error),
+    /// Cannot create the file backing the guest memory.
+    CreateGuestMemoryFile(io::Error),
     /// Internal errors are due to resource exhaustion.
     CreateNetDevice(devices::virtio::net::Error),
     /// Failed to create a `RateLimiter` object.
@@ -103,6 +108,11 @@ impl Display for StartMicrovmError {
                 write!(f, "Unable to attach block device to Vmm. Error: {}", err)
             }
             ConfigureSystem(e) => write!(f, "System configuration error: {:?}", e),
+            CreateGuestMemoryFile(err) => write!(
+                f,
+                "Cannot create the file backing the guest memory: {}",
+                err
+            ),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
             CreateNetDevice(err) => {
                 let mut err_msg = format!("{:?}", err);
@@ -314,6 +324,8 @@ pub fn build_microvm_for_boot(
             .mem_size_mib
             .ok_or(MissingMemSizeConfig)?,
         track_dirty_pages,
+        // vhost-user backends access the guest memory directly.
+        !vm_resources.vhost_user_block.list.is_empty(),
     )?;
     let vcpu_config = vm_resources.vcpu_config();
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
@@ -350,6 +362,12 @@ pub fn build_microvm_for_boot(
         vm_resources.block.list.iter(),
         event_manager,
     )?;
+    attach_vhost_user_block_devices(
+        &mut vmm,
+        &mut boot_cmdline,
+        vm_resources.vhost_user_block.list.iter(),
+        event_manager,
+    )?;
     attach_net_devices(
         &mut vmm,
         &mut boot_cmdline,
@@ -522,18 +540,56 @@ pub fn build_microvm_from_snapshot(
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+/// Creates GuestMemory of `mem_size_mib` MiB in size. If `shared` is set, the memory is
+/// backed by a memfd which can be handed over to other processes.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    shared: bool,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = arch::arch_memory_regions(mem_size);
 
-    GuestMemoryMmap::from_ranges_guarded(&arch_mem_regions, track_dirty_pages)
+    if !shared {
+        return GuestMemoryMmap::from_ranges_guarded(&arch_mem_regions, track_dirty_pages)
+            .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
+
+    let memfd = create_memfd(mem_size).map_err(StartMicrovmError::CreateGuestMemoryFile)?;
+    let memfd = Arc::new(memfd);
+    // The regions are laid out back to back in the memfd.
+    let mut offset = 0;
+    let regions: Vec<_> = arch_mem_regions
+        .iter()
+        .map(|&(guest_addr, size)| {
+            let file_offset = FileOffset::from_arc(memfd.clone(), offset);
+            offset += size as u64;
+            (guest_addr, size, Some(file_offset))
+        })
+        .collect();
+    GuestMemoryMmap::from_ranges_with_files_guarded(&regions, track_dirty_pages)
         .map_err(StartMicrovmError::GuestMemoryMmap)
 }
 
+// Creates an anonymous file of `size` bytes.
+fn create_memfd(size: usize) -> io::Result<File> {
+    // Safe because the name is a valid C string and we check the return value.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            b"guest_mem\0".as_ptr(),
+            libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we checked that the file descriptor is valid and nobody else owns it.
+    let file = unsafe { File::from_raw_fd(fd as RawFd) };
+    file.set_len(size as u64)?;
+    Ok(file)
+}
+
 fn load_kernel(
     boot_config: &BootConfig,
     guest_memory: &GuestMemoryMmap,
@@ -856,6 +912,20 @@ fn attach_block_devices<'a>(
     Ok(())
 }
 
+fn attach_vhost_user_block_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut KernelCmdline,
+    blocks: impl Iterator<Item = &'a Arc<Mutex<VhostUserBlock>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for block in blocks {
+        let id = block.lock().expect("Poisoned lock").id().clone();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, block.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
 fn attach_net_devices<'a>(
     vmm: &mut Vmm,
     cmdline: &mut KernelCmdline,
@@ -911,11 +981,16 @@ pub mod tests {
     use super::*;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType};
+    use crate::vmm_config::drive::{
+        BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType, ImageFormat,
+        VhostUserBlockBuilder, VhostUserBlockDeviceConfig,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
     use arch::DeviceType;
+    use devices::virtio::block::CONFIG_SPACE_SIZE;
+    use devices::virtio::vhost_user::test_utils::TestBackend;
     use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK};
     use kernel::cmdline::Cmdline;
     use utils::tempfile::TempFile;
@@ -968,7 +1043,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1027,6 +1102,10 @@ pub mod tests {
                 partuuid: custom_block_cfg.partuuid.clone(),
                 is_read_only: custom_block_cfg.is_read_only,
                 cache_type: custom_block_cfg.cache_type,
+                image_format: ImageFormat::Raw,
+                io_engine: FileEngineType::Sync,
+                overlay_path_on_host: None,
+                num_queues: 1,
                 rate_limiter: None,
             };
             block_dev_configs.insert(block_device_config).unwrap();
@@ -1157,21 +1236,37 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, false).unwrap();
             assert!(!guest_memory.is_dirty_tracking_enabled());
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, false).unwrap();
             assert!(guest_memory.is_dirty_tracking_enabled());
         }
+
+        // Case 3: create guest memory backed by a memfd
+        {
+            use vm_memory::{GuestMemory, GuestMemoryRegion};
+
+            let guest_memory = create_guest_memory(mem_size, false, true).unwrap();
+            let mut expected_offset = 0;
+            guest_memory
+                .with_regions_mut(|_, region| {
+                    // The regions don't overlap in the file.
+                    assert_eq!(region.file_offset().unwrap().start(), expected_offset);
+                    expected_offset += region.len();
+                    Ok::<(), ()>(())
+                })
+                .unwrap();
+        }
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1184,6 +1279,37 @@ pub mod tests {
         assert_eq!(vcpu_vec.len(), vcpu_count as usize);
     }
 
+    #[test]
+    fn test_attach_vhost_user_block_devices() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+
+        let backend = TestBackend::new(vec![0u8; CONFIG_SPACE_SIZE], 0x1000);
+        let mut block_devs = VhostUserBlockBuilder::new();
+        block_devs
+            .insert(VhostUserBlockDeviceConfig {
+                drive_id: String::from("vhost"),
+                socket: backend.socket_path().to_str().unwrap().to_string(),
+                num_queues: 1,
+            })
+            .unwrap();
+
+        attach_vhost_user_block_devices(
+            &mut vmm,
+            &mut cmdline,
+            block_devs.list.iter(),
+            &mut event_manager,
+        )
+        .unwrap();
+        assert!(vmm
+            .mmio_device_manager
+            .get_device(DeviceType::Virtio(TYPE_BLOCK), "vhost")
+            .is_some());
+        // Never the root device.
+        assert!(!cmdline.as_str().contains("root="));
+    }
+
     #[test]
     fn test_attach_net_devices() {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
@@ -1192,10 +1318,16 @@ pub mod tests {
         let network_interface = NetworkInterfaceConfig {
             iface_id: String::from("netif"),
             host_dev_name: String::from("hostname"),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
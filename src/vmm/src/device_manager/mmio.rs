@@ -16,8 +16,8 @@ use arch::DeviceType;
 use devices::legacy::RTCDevice;
 use devices::pseudo::BootTimer;
 use devices::virtio::{
-    Balloon, Block, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
-    TYPE_VSOCK,
+    Balloon, Block, MmioTransport, Net, VhostUserBlock, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK,
+    TYPE_NET, TYPE_VSOCK, VIRTIO_MMIO_INT_CONFIG,
 };
 use devices::BusDevice;
 use kernel::cmdline as kernel_cmdline;
@@ -386,6 +386,45 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    /// Notifies the driver of the virtio device with the given type and id that the device
+    /// configuration changed.
+    pub fn notify_config_change(&self, virtio_type: u32, id: &str) -> Result<()> {
+        let busdev = self
+            .get_device(DeviceType::Virtio(virtio_type), id)
+            .ok_or(Error::DeviceNotFound)?;
+        busdev
+            .lock()
+            .expect("Poisoned lock")
+            .interrupt(VIRTIO_MMIO_INT_CONFIG)
+            .map_err(|e| Error::InternalDeviceError(e.to_string()))
+    }
+
+    /// Specifies whether any of the registered devices is served by a vhost-user backend or by
+    /// the vhost-net driver.
+    pub fn has_vhost_devices(&self) -> bool {
+        self.for_each_device(|devtype, _, _, bus_dev| {
+            if let DeviceType::Virtio(virtio_type) = *devtype {
+                let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                // Virtio devices are guaranteed MmioTransport.
+                let mmio_dev = bus_dev.as_any().downcast_ref::<MmioTransport>().unwrap();
+                let virtio = mmio_dev.locked_device();
+                let is_vhost = match virtio_type {
+                    TYPE_BLOCK => virtio.as_any().is::<VhostUserBlock>(),
+                    TYPE_NET => virtio
+                        .as_any()
+                        .downcast_ref::<Net>()
+                        .map_or(false, Net::vhost_net_enabled),
+                    _ => false,
+                };
+                if is_vhost {
+                    return Err(());
+                }
+            }
+            Ok(())
+        })
+        .is_err()
+    }
+
     /// Artificially kick devices as if they had external events.
     pub fn kick_devices(&self) {
         info!("Artificially kick devices.");
@@ -408,14 +447,18 @@ impl MMIODeviceManager {
                         }
                     }
                     TYPE_BLOCK => {
-                        let block = virtio.as_mut_any().downcast_mut::<Block>().unwrap();
-                        // If device is activated, kick the block queue(s) to make up for any
-                        // pending or in-flight epoll events we may have not captured in snapshot.
-                        // No need to kick Ratelimiters because they are restored 'unblocked' so
-                        // any inflight `timer_fd` events can be safely discarded.
-                        if block.is_activated() {
-                            info!("kick block {}.", id);
-                            block.process_virtio_queues();
+                        // The queues of the vhost-user block devices are processed by their
+                        // backends, so only the in-process ones are kicked.
+                        if let Some(block) = virtio.as_mut_any().downcast_mut::<Block>() {
+                            // If device is activated, kick the block queue(s) to make up for any
+                            // pending or in-flight epoll events we may have not captured in
+                            // snapshot. No need to kick Ratelimiters because they are restored
+                            // 'unblocked' so any inflight `timer_fd` events can be safely
+                            // discarded.
+                            if block.is_activated() {
+                                info!("kick block {}.", id);
+                                block.process_virtio_queues();
+                            }
                         }
                     }
                     TYPE_NET => {
@@ -460,6 +503,8 @@ impl DeviceInfoForFDT for MMIODeviceInfo {
 mod tests {
     use super::*;
     use crate::builder;
+    use devices::virtio::block::CONFIG_SPACE_SIZE;
+    use devices::virtio::vhost_user::test_utils::TestBackend;
     use devices::virtio::{ActivateResult, Queue, VirtioDevice};
     use std::sync::atomic::AtomicUsize;
     use std::sync::Arc;
@@ -585,6 +630,43 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_has_vhost_devices() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        device_manager
+            .register_virtio_test_device(vm.fd(), guest_mem.clone(), dummy, &mut cmdline, "dummy")
+            .unwrap();
+        assert!(!device_manager.has_vhost_devices());
+
+        let backend = TestBackend::new(vec![0u8; CONFIG_SPACE_SIZE], 0x1000);
+        let block = VhostUserBlock::new(
+            "vhost".to_string(),
+            backend.socket_path().to_str().unwrap().to_string(),
+            1,
+        )
+        .unwrap();
+        device_manager
+            .register_virtio_test_device(
+                vm.fd(),
+                guest_mem,
+                Arc::new(Mutex::new(block)),
+                &mut cmdline,
+                "vhost",
+            )
+            .unwrap();
+        assert!(device_manager.has_vhost_devices());
+    }
+
     #[test]
     fn test_register_too_many_devices() {
         let start_addr1 = GuestAddress(0x0);
@@ -713,6 +795,9 @@ mod tests {
             .get_device(DeviceType::Virtio(type_id), &id)
             .is_none());
 
+        assert!(device_manager.notify_config_change(type_id, "foo").is_ok());
+        assert!(device_manager.notify_config_change(type_id, id).is_err());
+
         #[cfg(target_arch = "x86_64")]
         {
             let dummy2 = Arc::new(Mutex::new(DummyDevice::new()));
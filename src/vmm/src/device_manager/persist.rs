@@ -186,6 +186,15 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 .downcast_ref::<MmioTransport>()
                 .expect("Unexpected BusDevice type");
 
+            // The in flight block requests must complete, and their interrupts be raised,
+            // before the transport and the queues are saved.
+            if let Some(block) = mmio_transport
+                .locked_device()
+                .as_mut_any()
+                .downcast_mut::<Block>()
+            {
+                block.drain_async_requests();
+            }
             let transport_state = mmio_transport.save();
 
             let mut locked_device = mmio_transport.locked_device();
@@ -355,14 +364,18 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 constructor_args.event_manager,
             )?;
         }
+        let mut announcing_net_ids = Vec::new();
         for net_state in &state.net_devices {
-            let device = Arc::new(Mutex::new(
-                Net::restore(
-                    NetConstructorArgs { mem: mem.clone() },
-                    &net_state.device_state,
-                )
-                .map_err(Error::Net)?,
-            ));
+            let mut net = Net::restore(
+                NetConstructorArgs { mem: mem.clone() },
+                &net_state.device_state,
+            )
+            .map_err(Error::Net)?;
+            // The guest may now be reachable through different switch ports.
+            if net.is_activated() && net.announce() {
+                announcing_net_ids.push(&net_state.device_id);
+            }
+            let device = Arc::new(Mutex::new(net));
 
             restore_helper(
                 device.clone(),
@@ -400,6 +413,12 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        for id in announcing_net_ids {
+            dev_manager
+                .notify_config_change(TYPE_NET, id)
+                .map_err(Error::DeviceManager)?;
+        }
+
         Ok(dev_manager)
     }
 }
@@ -410,7 +429,7 @@ mod tests {
     use crate::builder::tests::*;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
-    use crate::vmm_config::vsock::VsockDeviceConfig;
+    use crate::vmm_config::vsock::{VsockDeviceConfig, VsockPortAcl};
     use devices::virtio::block::CacheType;
     use utils::tempfile::TempFile;
 
@@ -570,10 +589,16 @@ mod tests {
             let network_interface = NetworkInterfaceConfig {
                 iface_id: String::from("netif"),
                 host_dev_name: String::from("hostname"),
+                host_socket_path: None,
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: true,
+                num_queue_pairs: 1,
+                vhost_net: false,
+                anti_spoofing: None,
+                dhcp: None,
+                mtu: None,
             };
             insert_net_device(
                 &mut vmm,
@@ -587,6 +612,9 @@ mod tests {
                 vsock_id: vsock_dev_id.to_string(),
                 guest_cid: 3,
                 uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                tcp_forwards: vec![],
+                tcp_listeners: vec![],
+                port_acl: VsockPortAcl::default(),
             };
             insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
 
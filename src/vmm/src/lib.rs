@@ -46,6 +46,7 @@ use crate::device_manager::mmio::MMIODeviceManager;
 use crate::memory_snapshot::SnapshotMemory;
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::net::NetworkInterfaceStats;
 use crate::vstate::vcpu::VcpuState;
 use crate::vstate::{
     vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse},
@@ -516,6 +517,46 @@ impl Vmm {
                     .update_disk_image(path_on_host)
                     .map_err(|e| e.to_string())
             })
+            .and_then(|()| {
+                self.mmio_device_manager
+                    .notify_config_change(TYPE_BLOCK, drive_id)
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Grows the host file backing the emulated block device with id `drive_id` to
+    /// `size_bytes` and notifies the guest of the new capacity.
+    pub fn update_block_device_size(&mut self, drive_id: &str, size_bytes: u64) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block.resize(size_bytes).map_err(|e| e.to_string())
+            })
+            .and_then(|()| {
+                self.mmio_device_manager
+                    .notify_config_change(TYPE_BLOCK, drive_id)
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Retrieves the chunks of the block device with id `drive_id` modified since they were
+    /// last cleared.
+    pub fn block_dirty_bitmap(&mut self, drive_id: &str) -> Result<Vec<u64>> {
+        let mut bitmap = Vec::new();
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                bitmap = block.dirty_chunks();
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)?;
+        Ok(bitmap)
+    }
+
+    /// Clears the chunks of the block device with id `drive_id` set in `bitmap`.
+    pub fn clear_block_dirty_bitmap(&mut self, drive_id: &str, bitmap: &[u64]) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block.clear_dirty_chunks(bitmap).map_err(|e| e.to_string())
+            })
             .map_err(Error::DeviceManager)
     }
 
@@ -545,12 +586,67 @@ impl Vmm {
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                if net.vhost_net_enabled() {
+                    return Err("vhost-net interfaces cannot be rate limited.".to_string());
+                }
                 net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
                 Ok(())
             })
             .map_err(Error::DeviceManager)
     }
 
+    /// Brings the link of the net device with id `net_id` up or down and notifies the guest.
+    pub fn update_net_link_status(&mut self, net_id: &str, link_up: bool) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_link_up(link_up).map_err(|e| format!("{:?}", e))
+            })
+            .and_then(|()| {
+                self.mmio_device_manager
+                    .notify_config_change(TYPE_NET, net_id)
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Returns the counters of the net device with id `net_id`.
+    pub fn net_interface_stats(&mut self, net_id: &str) -> Result<NetworkInterfaceStats> {
+        let mut stats = NetworkInterfaceStats::default();
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                stats = NetworkInterfaceStats::from(net.metrics().device());
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)?;
+        Ok(stats)
+    }
+
+    /// Starts capturing the frames of the net device with id `net_id` in the pcap file found
+    /// at `path`.
+    pub fn start_net_capture(
+        &mut self,
+        net_id: &str,
+        path: &str,
+        snaplen: u32,
+        max_size: u64,
+    ) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.start_capture(path, snaplen, max_size)
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Stops capturing the frames of the net device with id `net_id`.
+    pub fn stop_net_capture(&mut self, net_id: &str) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.stop_capture();
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Returns a reference to the balloon device if present.
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
@@ -639,7 +735,7 @@ impl Vmm {
                     .update_size(amount_mib)?;
             }
 
-            let locked_dev = busdev.lock().expect("Poisoned lock");
+            let mut locked_dev = busdev.lock().expect("Poisoned lock");
             locked_dev
                 .interrupt(devices::virtio::VIRTIO_MMIO_INT_CONFIG)
                 .map_err(BalloonError::InterruptError)
@@ -128,6 +128,8 @@ pub enum CreateSnapshotError {
     #[cfg(target_arch = "x86_64")]
     /// Number of devices exceeds the maximum supported devices for the snapshot data version.
     TooManyDevices(usize),
+    /// The state of the vhost backends cannot be saved.
+    VhostDevices,
 }
 
 impl Display for CreateSnapshotError {
@@ -162,6 +164,10 @@ impl Display for CreateSnapshotError {
                  for the snapshot data version requested is {}.",
                 val, FC_V0_23_MAX_DEVICES
             ),
+            VhostDevices => write!(
+                f,
+                "Cannot snapshot microVMs with devices served by vhost backends."
+            ),
         }
     }
 }
@@ -222,6 +228,11 @@ pub fn create_snapshot(
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, &vmm)?;
 
+    // The backends own part of the devices state, which can't be captured.
+    if vmm.mmio_device_manager.has_vhost_devices() {
+        return Err(CreateSnapshotError::VhostDevices);
+    }
+
     let microvm_state = vmm
         .save_state()
         .map_err(CreateSnapshotError::MicrovmState)?;
@@ -534,10 +545,16 @@ mod tests {
         let network_interface = NetworkInterfaceConfig {
             iface_id: String::from("netif"),
             host_dev_name: String::from("hostname"),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         };
         insert_net_device(
             &mut vmm,
@@ -683,6 +700,9 @@ mod tests {
             let err = TooManyDevices(0);
             let _ = format!("{}{:?}", err, err);
         }
+
+        let err = VhostDevices;
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
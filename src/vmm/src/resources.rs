@@ -66,6 +66,8 @@ pub struct VmmConfig {
     mmds_config: Option<MmdsConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
+    #[serde(rename = "vhost-user-drives", default)]
+    vhost_user_block_devices: Vec<VhostUserBlockDeviceConfig>,
     #[serde(rename = "vsock")]
     vsock_device: Option<VsockDeviceConfig>,
 }
@@ -80,6 +82,8 @@ pub struct VmResources {
     boot_config: Option<BootConfig>,
     /// The block devices.
     pub block: BlockBuilder,
+    /// The block devices served by vhost-user backends.
+    pub vhost_user_block: VhostUserBlockBuilder,
     /// The vsock device.
     pub vsock: VsockBuilder,
     /// The balloon device.
@@ -126,6 +130,12 @@ impl VmResources {
                 .map_err(Error::BlockDevice)?;
         }
 
+        for drive_config in vmm_config.vhost_user_block_devices.into_iter() {
+            resources
+                .set_vhost_user_block_device(drive_config)
+                .map_err(Error::BlockDevice)?;
+        }
+
         for net_config in vmm_config.net_devices.into_iter() {
             resources
                 .build_net_device(net_config)
@@ -276,7 +286,23 @@ impl VmResources {
         &mut self,
         block_device_config: BlockDeviceConfig,
     ) -> Result<DriveError> {
-        self.block.insert(block_device_config)
+        let drive_id = block_device_config.drive_id.clone();
+        self.block.insert(block_device_config)?;
+        // The drive might have been served by a vhost-user backend so far.
+        self.vhost_user_block.remove(&drive_id);
+        Ok(())
+    }
+
+    /// Inserts a block device served by a vhost-user backend, to be attached when the VM starts.
+    // If the drive_id does not exist, a new device is added to the list.
+    pub fn set_vhost_user_block_device(
+        &mut self,
+        config: VhostUserBlockDeviceConfig,
+    ) -> Result<DriveError> {
+        let drive_id = config.drive_id.clone();
+        self.vhost_user_block.insert(config)?;
+        self.block.remove(&drive_id);
+        Ok(())
     }
 
     /// Builds a network device to be attached when the VM starts.
@@ -335,6 +361,7 @@ impl From<&VmResources> for VmmConfig {
         VmmConfig {
             balloon_device: resources.balloon.get_config().ok(),
             block_devices: resources.block.configs(),
+            vhost_user_block_devices: resources.vhost_user_block.configs(),
             boot_source,
             logger: None,
             machine_config: Some(resources.vm_config.clone()),
@@ -360,6 +387,8 @@ mod tests {
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
     use crate::vstate::vcpu::VcpuConfig;
+    use devices::virtio::block::CONFIG_SPACE_SIZE;
+    use devices::virtio::vhost_user::test_utils::TestBackend;
     use logger::{LevelFilter, LOGGER};
     use utils::net::mac::MacAddr;
     use utils::tempfile::TempFile;
@@ -374,10 +403,16 @@ mod tests {
                 .to_str()
                 .unwrap()
                 .to_string(),
+            host_socket_path: None,
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         }
     }
 
@@ -397,6 +432,10 @@ mod tests {
                 is_root_device: false,
                 partuuid: Some("0eaa91a0-01".to_string()),
                 cache_type: CacheType::Unsafe,
+                image_format: ImageFormat::Raw,
+                io_engine: FileEngineType::Sync,
+                overlay_path_on_host: None,
+                num_queues: 1,
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
             },
@@ -428,6 +467,7 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
+            vhost_user_block: Default::default(),
             vsock: Default::default(),
             balloon: Default::default(),
             net_builder: default_net_builder(),
@@ -849,6 +889,7 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
+            vhost_user_block: Default::default(),
             vsock: Default::default(),
             balloon: BalloonBuilder::new(),
             net_builder: default_net_builder(),
@@ -880,6 +921,7 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
+            vhost_user_block: Default::default(),
             vsock: Default::default(),
             balloon: BalloonBuilder::new(),
             net_builder: default_net_builder(),
@@ -954,6 +996,43 @@ mod tests {
         assert_eq!(vm_resources.block.list.len(), 2);
     }
 
+    #[test]
+    fn test_set_vhost_user_block_device() {
+        let mut vm_resources = default_vm_resources();
+        let backend = TestBackend::new(vec![0u8; CONFIG_SPACE_SIZE], 0x1000);
+        let config = VhostUserBlockDeviceConfig {
+            drive_id: "block1".to_string(),
+            socket: backend.socket_path().to_str().unwrap().to_string(),
+            num_queues: 1,
+        };
+
+        // The drive is now served by the backend.
+        vm_resources.set_vhost_user_block_device(config).unwrap();
+        assert_eq!(vm_resources.vhost_user_block.list.len(), 1);
+        assert!(vm_resources.block.list.is_empty());
+        assert_eq!(
+            VmmConfig::from(&vm_resources).vhost_user_block_devices[0].drive_id,
+            "block1"
+        );
+
+        // And back.
+        let (block_device_cfg, _file) = default_block_cfg();
+        vm_resources.set_block_device(block_device_cfg).unwrap();
+        assert_eq!(vm_resources.block.list.len(), 1);
+        assert!(vm_resources.vhost_user_block.list.is_empty());
+
+        // Unreachable backends are reported.
+        let config = VhostUserBlockDeviceConfig {
+            drive_id: "block1".to_string(),
+            socket: "/invalid/socket".to_string(),
+            num_queues: 1,
+        };
+        match vm_resources.set_vhost_user_block_device(config) {
+            Err(DriveError::CreateVhostUserBlockDevice(_)) => (),
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     fn test_set_vsock_device() {
         let mut vm_resources = default_vm_resources();
@@ -19,20 +19,25 @@ use crate::vmm_config::balloon::{
     BalloonUpdateStatsConfig,
 };
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
-use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, DriveError};
+use crate::vmm_config::drive::{
+    BlockDeviceConfig, BlockDeviceUpdateConfig, BlockDirtyBitmap, DriveError,
+    VhostUserBlockDeviceConfig,
+};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+    NetworkInterfaceCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceError,
+    NetworkInterfaceStats, NetworkInterfaceUpdateConfig,
 };
 use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::{builder::StartMicrovmError, EventManager};
 use crate::{ExitCode, FC_EXIT_CODE_BAD_CONFIGURATION};
+use devices::virtio::block::DIRTY_CHUNK_SIZE;
 use logger::{info, update_metric_with_elapsed_time, METRICS};
 use seccompiler::BpfThreadMap;
 #[cfg(test)]
@@ -45,6 +50,9 @@ use tests::{
 /// bits of information (ids, paths, etc.).
 #[derive(PartialEq)]
 pub enum VmmAction {
+    /// Clear the chunks of a block device set in the given bitmap, once they are backed up. This
+    /// action can only be called after the microVM has booted.
+    ClearBlockDirtyBitmap(String, BlockDirtyBitmap),
     /// Configure the boot source of the microVM using as input the `ConfigureBootSource`. This
     /// action can only be called before the microVM has booted.
     ConfigureBootSource(BootSourceConfig),
@@ -61,8 +69,14 @@ pub enum VmmAction {
     GetBalloonConfig,
     /// Get the ballon device latest statistics.
     GetBalloonStats,
+    /// Get the chunks of a block device modified since they were last cleared. This action can
+    /// only be called after the microVM has booted.
+    GetBlockDirtyBitmap(String),
     /// Get complete microVM configuration in JSON format.
     GetFullVmConfig,
+    /// Get the counters of a network interface. This action can only be called after the
+    /// microVM has booted.
+    GetNetworkInterfaceStats(String),
     /// Get the machine configuration of the microVM.
     GetVmMachineConfig,
     /// Get microVM instance information.
@@ -76,6 +90,10 @@ pub enum VmmAction {
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
     /// booted.
     InsertNetworkDevice(NetworkInterfaceConfig),
+    /// Add a new block device served by a vhost-user backend or update one that already exists
+    /// using the `VhostUserBlockDeviceConfig` as input. This action can only be called before the
+    /// microVM has booted.
+    InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig),
     /// Load the microVM state using as input the `LoadSnapshotParams`. This action can only be
     /// called before the microVM has booted. If this action is successful, the loaded microVM will
     /// be in `Paused` state. Should change this state to `Resumed` for the microVM to run.
@@ -112,6 +130,8 @@ pub enum VmmAction {
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
+    /// Start or stop capturing the frames of a network interface, after microVM start.
+    UpdateNetworkCapture(NetworkInterfaceCaptureConfig),
 }
 
 /// Wrapper for all errors associated with VMM actions.
@@ -123,8 +143,8 @@ pub enum VmmActionError {
     BootSource(BootSourceConfigError),
     /// The action `CreateSnapshot` failed.
     CreateSnapshot(CreateSnapshotError),
-    /// One of the actions `InsertBlockDevice` or `UpdateBlockDevicePath`
-    /// failed because of bad user input.
+    /// One of the actions `InsertBlockDevice`, `InsertVhostUserBlockDevice`,
+    /// `UpdateBlockDevicePath`, `GetBlockDirtyBitmap` or `ClearBlockDirtyBitmap` failed.
     DriveConfig(DriveError),
     /// Internal Vmm error.
     InternalVmm(VmmError),
@@ -140,7 +160,8 @@ pub enum VmmActionError {
     Metrics(MetricsConfigError),
     /// The action `SetMmdsConfiguration` failed because of bad user input.
     MmdsConfig(MmdsConfigError),
-    /// The action `InsertNetworkDevice` failed because of bad user input.
+    /// One of the actions `InsertNetworkDevice`, `UpdateNetworkInterface`,
+    /// `UpdateNetworkCapture` or `GetNetworkInterfaceStats` failed.
     NetworkConfig(NetworkInterfaceError),
     /// The requested operation is not supported.
     NotSupported(String),
@@ -202,6 +223,8 @@ pub enum VmmData {
     BalloonConfig(BalloonDeviceConfig),
     /// The latest balloon device statistics.
     BalloonStats(BalloonStats),
+    /// The chunks of a block device modified since the previous request.
+    BlockDirtyBitmap(BlockDirtyBitmap),
     /// No data is sent on the channel.
     Empty,
     /// The complete microVM configuration in JSON format.
@@ -210,6 +233,8 @@ pub enum VmmData {
     MachineConfiguration(VmConfig),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
+    /// The counters of a network interface.
+    NetworkInterfaceStats(NetworkInterfaceStats),
 }
 
 /// Shorthand result type for external VMM commands.
@@ -321,6 +346,7 @@ impl<'a> PrebootApiController<'a> {
             GetVmInstanceInfo => Ok(VmmData::InstanceInformation(self.instance_info.clone())),
             InsertBlockDevice(config) => self.insert_block_device(config),
             InsertNetworkDevice(config) => self.insert_net_device(config),
+            InsertVhostUserBlockDevice(config) => self.insert_vhost_user_block_device(config),
             LoadSnapshot(config) => self.load_snapshot(&config),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
@@ -328,15 +354,19 @@ impl<'a> PrebootApiController<'a> {
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
             StartMicroVm => self.start_microvm(),
             // Operations not allowed pre-boot.
-            CreateSnapshot(_)
+            ClearBlockDirtyBitmap(_, _)
+            | CreateSnapshot(_)
             | FlushMetrics
             | Pause
             | Resume
             | GetBalloonStats
+            | GetBlockDirtyBitmap(_)
+            | GetNetworkInterfaceStats(_)
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
-            | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            | UpdateNetworkInterface(_)
+            | UpdateNetworkCapture(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
         }
@@ -358,6 +388,14 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::DriveConfig)
     }
 
+    fn insert_vhost_user_block_device(&mut self, cfg: VhostUserBlockDeviceConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_vhost_user_block_device(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::DriveConfig)
+    }
+
     fn insert_net_device(&mut self, cfg: NetworkInterfaceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -482,6 +520,9 @@ impl RuntimeApiController {
         use self::VmmAction::*;
         match request {
             // Supported operations allowed post-boot.
+            ClearBlockDirtyBitmap(drive_id, bitmap) => {
+                self.clear_block_dirty_bitmap(&drive_id, bitmap)
+            }
             CreateSnapshot(snapshot_create_cfg) => self.create_snapshot(&snapshot_create_cfg),
             FlushMetrics => self.flush_metrics(),
             GetBalloonConfig => self
@@ -498,7 +539,21 @@ impl RuntimeApiController {
                 .latest_balloon_stats()
                 .map(VmmData::BalloonStats)
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
+            GetBlockDirtyBitmap(drive_id) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .block_dirty_bitmap(&drive_id)
+                .map(|bitmap| VmmData::BlockDirtyBitmap(bitmap.into()))
+                .map_err(|e| VmmActionError::DriveConfig(DriveError::DirtyBitmap(e))),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
+            GetNetworkInterfaceStats(iface_id) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .net_interface_stats(&iface_id)
+                .map(VmmData::NetworkInterfaceStats)
+                .map_err(|e| VmmActionError::NetworkConfig(NetworkInterfaceError::Stats(e))),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
@@ -524,7 +579,8 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateNetworkInterface(netif_update) => self.update_net_device(netif_update),
+            UpdateNetworkCapture(capture_cfg) => self.update_net_capture(capture_cfg),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
@@ -532,6 +588,7 @@ impl RuntimeApiController {
             | ConfigureMetrics(_)
             | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
+            | InsertVhostUserBlockDevice(_)
             | LoadSnapshot(_)
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
@@ -648,6 +705,7 @@ impl RuntimeApiController {
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device,
     ///    update the disk image on the device and its virtio configuration
+    ///  - size of the block device, grow the disk image and notify the guest
     ///  - rate limiter configuration.
     fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> ActionResult {
         let mut vmm = self.vmm.lock().expect("Poisoned lock");
@@ -657,6 +715,12 @@ impl RuntimeApiController {
                 .map_err(DriveError::DeviceUpdate)
                 .map_err(VmmActionError::DriveConfig)?;
         }
+        if let Some(size_bytes) = new_cfg.size_bytes {
+            vmm.update_block_device_size(&new_cfg.drive_id, size_bytes)
+                .map(|()| VmmData::Empty)
+                .map_err(DriveError::DeviceUpdate)
+                .map_err(VmmActionError::DriveConfig)?;
+        }
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
                 &new_cfg.drive_id,
@@ -670,21 +734,62 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
-    /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+    /// Clears the dirty chunks of a block device set in `bitmap`.
+    fn clear_block_dirty_bitmap(
+        &mut self,
+        drive_id: &str,
+        bitmap: BlockDirtyBitmap,
+    ) -> ActionResult {
+        if bitmap.chunk_size != DIRTY_CHUNK_SIZE {
+            return Err(VmmActionError::DriveConfig(
+                DriveError::InvalidDirtyChunkSize(bitmap.chunk_size),
+            ));
+        }
         self.vmm
             .lock()
             .expect("Poisoned lock")
-            .update_net_rate_limiters(
-                &new_cfg.iface_id,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
-            )
+            .clear_block_dirty_bitmap(drive_id, &bitmap.bitmap)
             .map(|()| VmmData::Empty)
-            .map_err(NetworkInterfaceError::DeviceUpdate)
-            .map_err(VmmActionError::NetworkConfig)
+            .map_err(|e| VmmActionError::DriveConfig(DriveError::DirtyBitmap(e)))
+    }
+
+    /// Updates emulated net device properties:
+    ///  - RX and TX rate limiter configurations
+    ///  - link status, bring the link up or down and notify the guest.
+    fn update_net_device(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        vmm.update_net_rate_limiters(
+            &new_cfg.iface_id,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+        )
+        .map_err(NetworkInterfaceError::DeviceUpdate)
+        .map_err(VmmActionError::NetworkConfig)?;
+        if let Some(link_up) = new_cfg.link_up {
+            vmm.update_net_link_status(&new_cfg.iface_id, link_up)
+                .map_err(NetworkInterfaceError::DeviceUpdate)
+                .map_err(VmmActionError::NetworkConfig)?;
+        }
+        Ok(VmmData::Empty)
+    }
+
+    /// Starts or stops capturing the frames of a net device as described in `cfg`.
+    fn update_net_capture(&mut self, cfg: NetworkInterfaceCaptureConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        match cfg.path_on_host {
+            Some(ref path) => {
+                let max_size = cfg.max_size_bytes.ok_or(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::CaptureMaxSizeMissing,
+                ))?;
+                vmm.start_net_capture(&cfg.iface_id, path, cfg.snaplen(), max_size)
+            }
+            None => vmm.stop_net_capture(&cfg.iface_id),
+        }
+        .map(|()| VmmData::Empty)
+        .map_err(NetworkInterfaceError::Capture)
+        .map_err(VmmActionError::NetworkConfig)
     }
 }
 
@@ -692,9 +797,9 @@ impl RuntimeApiController {
 mod tests {
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::CacheType;
+    use crate::vmm_config::drive::{CacheType, FileEngineType, ImageFormat};
     use crate::vmm_config::logger::LoggerLevel;
-    use crate::vmm_config::vsock::VsockBuilder;
+    use crate::vmm_config::vsock::{VsockBuilder, VsockPortAcl};
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
     use devices::virtio::VsockError;
     use seccompiler::BpfThreadMap;
@@ -737,6 +842,7 @@ mod tests {
         balloon_set: bool,
         boot_cfg_set: bool,
         block_set: bool,
+        vhost_user_block_set: bool,
         vsock_set: bool,
         net_set: bool,
         mmds_set: bool,
@@ -806,6 +912,17 @@ mod tests {
             Ok(())
         }
 
+        pub fn set_vhost_user_block_device(
+            &mut self,
+            _: VhostUserBlockDeviceConfig,
+        ) -> Result<(), DriveError> {
+            if self.force_errors {
+                return Err(DriveError::InvalidNumQueues(0));
+            }
+            self.vhost_user_block_set = true;
+            Ok(())
+        }
+
         pub fn build_net_device(
             &mut self,
             _: NetworkInterfaceConfig,
@@ -847,6 +964,8 @@ mod tests {
     pub struct MockVmm {
         pub balloon_config_called: bool,
         pub latest_balloon_stats_called: bool,
+        pub block_dirty_bitmap_called: bool,
+        pub clear_block_dirty_bitmap_called: bool,
         pub pause_called: bool,
         pub resume_called: bool,
         #[cfg(target_arch = "x86_64")]
@@ -854,7 +973,12 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub update_block_device_size_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_net_link_status_called: bool,
+        pub net_interface_stats_called: bool,
+        pub start_net_capture_called: bool,
+        pub stop_net_capture_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -929,6 +1053,36 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_block_device_size(&mut self, _: &str, _: u64) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_block_device_size_called = true;
+            Ok(())
+        }
+
+        pub fn block_dirty_bitmap(&mut self, _: &str) -> Result<Vec<u64>, VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.block_dirty_bitmap_called = true;
+            Ok(vec![0b101])
+        }
+
+        pub fn clear_block_dirty_bitmap(&mut self, _: &str, _: &[u64]) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.clear_block_dirty_bitmap_called = true;
+            Ok(())
+        }
+
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
@@ -955,6 +1109,55 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_link_status(&mut self, _: &str, _: bool) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_link_status_called = true;
+            Ok(())
+        }
+
+        pub fn net_interface_stats(&mut self, _: &str) -> Result<NetworkInterfaceStats, VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.net_interface_stats_called = true;
+            Ok(NetworkInterfaceStats {
+                rx_packets: 1,
+                ..Default::default()
+            })
+        }
+
+        pub fn start_net_capture(
+            &mut self,
+            _: &str,
+            _: &str,
+            _: u32,
+            _: u64,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.start_net_capture_called = true;
+            Ok(())
+        }
+
+        pub fn stop_net_capture(&mut self, _: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.stop_net_capture_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1108,6 +1311,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: 1,
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
@@ -1122,6 +1329,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: 1,
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
@@ -1132,15 +1343,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_insert_vhost_user_block_dev() {
+        let req = VmmAction::InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig {
+            drive_id: String::new(),
+            socket: String::new(),
+            num_queues: 1,
+        });
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.vhost_user_block_set)
+        });
+
+        let req = VmmAction::InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig {
+            drive_id: String::new(),
+            socket: String::new(),
+            num_queues: 1,
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::InvalidNumQueues(0)),
+        );
+    }
+
     #[test]
     fn test_preboot_insert_net_dev() {
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1150,10 +1390,16 @@ mod tests {
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         });
         check_preboot_request_err(
             req,
@@ -1169,6 +1415,9 @@ mod tests {
             vsock_id: String::new(),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp_forwards: vec![],
+            tcp_listeners: vec![],
+            port_acl: VsockPortAcl::default(),
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1179,6 +1428,9 @@ mod tests {
             vsock_id: String::new(),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp_forwards: vec![],
+            tcp_listeners: vec![],
+            port_acl: VsockPortAcl::default(),
         });
         check_preboot_request_err(
             req,
@@ -1258,6 +1510,18 @@ mod tests {
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::GetBlockDirtyBitmap(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::ClearBlockDirtyBitmap(String::new(), BlockDirtyBitmap::from(vec![])),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::GetNetworkInterfaceStats(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1277,6 +1541,16 @@ mod tests {
                 iface_id: String::new(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                link_up: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateNetworkCapture(NetworkInterfaceCaptureConfig {
+                iface_id: String::new(),
+                path_on_host: None,
+                max_size_bytes: None,
+                snaplen: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1501,22 +1775,139 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_get_block_dirty_bitmap() {
+        let req = VmmAction::GetBlockDirtyBitmap(String::from("root"));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::BlockDirtyBitmap(BlockDirtyBitmap::from(vec![
+                    0b101
+                ])))
+            );
+            assert!(vmm.block_dirty_bitmap_called);
+        });
+
+        let req = VmmAction::GetBlockDirtyBitmap(String::from("root"));
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DirtyBitmap(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_clear_block_dirty_bitmap() {
+        let req = VmmAction::ClearBlockDirtyBitmap(
+            String::from("root"),
+            BlockDirtyBitmap::from(vec![0b101]),
+        );
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.clear_block_dirty_bitmap_called);
+        });
+
+        // The bitmap must use the chunk size of the device.
+        let req = VmmAction::ClearBlockDirtyBitmap(
+            String::from("root"),
+            BlockDirtyBitmap {
+                chunk_size: DIRTY_CHUNK_SIZE / 2,
+                bitmap: vec![0b101],
+            },
+        );
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::DriveConfig(
+                    DriveError::InvalidDirtyChunkSize(DIRTY_CHUNK_SIZE / 2)
+                ))
+            );
+            assert!(!vmm.clear_block_dirty_bitmap_called);
+        });
+
+        let req = VmmAction::ClearBlockDirtyBitmap(
+            String::from("root"),
+            BlockDirtyBitmap::from(vec![0b101]),
+        );
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DirtyBitmap(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_block_device_size() {
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            size_bytes: Some(0x10_0000),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_block_device_size_called);
+            assert!(!vmm.update_block_device_path_called);
+        });
+
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            size_bytes: Some(0x10_0000),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_up: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_rate_limiters_called);
+            assert!(!vmm.update_net_link_status_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            link_up: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_link_status() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            link_up: Some(false),
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
-            assert!(vmm.update_net_rate_limiters_called)
+            assert!(vmm.update_net_link_status_called)
         });
 
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_up: Some(true),
         });
         check_runtime_request_err(
             req,
@@ -1526,6 +1917,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_get_net_interface_stats() {
+        let req = VmmAction::GetNetworkInterfaceStats(String::from("eth0"));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::NetworkInterfaceStats(NetworkInterfaceStats {
+                    rx_packets: 1,
+                    ..Default::default()
+                }))
+            );
+            assert!(vmm.net_interface_stats_called);
+        });
+
+        let req = VmmAction::GetNetworkInterfaceStats(String::from("eth0"));
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::Stats(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_capture() {
+        let start_cfg = NetworkInterfaceCaptureConfig {
+            iface_id: String::from("eth0"),
+            path_on_host: Some(String::from("eth0.pcap")),
+            max_size_bytes: Some(0x10_0000),
+            snaplen: None,
+        };
+        let stop_cfg = NetworkInterfaceCaptureConfig {
+            iface_id: String::from("eth0"),
+            path_on_host: None,
+            max_size_bytes: None,
+            snaplen: None,
+        };
+        check_runtime_request(
+            VmmAction::UpdateNetworkCapture(start_cfg.clone()),
+            |result, vmm| {
+                assert_eq!(result, Ok(VmmData::Empty));
+                assert!(vmm.start_net_capture_called)
+            },
+        );
+        check_runtime_request(
+            VmmAction::UpdateNetworkCapture(stop_cfg.clone()),
+            |result, vmm| {
+                assert_eq!(result, Ok(VmmData::Empty));
+                assert!(vmm.stop_net_capture_called)
+            },
+        );
+
+        check_runtime_request_err(
+            VmmAction::UpdateNetworkCapture(stop_cfg),
+            VmmActionError::NetworkConfig(NetworkInterfaceError::Capture(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+        let mut cfg = start_cfg;
+        cfg.max_size_bytes = None;
+        check_runtime_request(VmmAction::UpdateNetworkCapture(cfg), |result, vmm| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::CaptureMaxSizeMissing
+                ))
+            );
+            assert!(!vmm.start_net_capture_called)
+        });
+    }
+
     #[test]
     fn test_runtime_disallowed() {
         check_runtime_request_err(
@@ -1553,20 +2015,38 @@ mod tests {
                 is_root_device: false,
                 partuuid: None,
                 cache_type: CacheType::Unsafe,
+                image_format: ImageFormat::Raw,
+                io_engine: FileEngineType::Sync,
+                overlay_path_on_host: None,
+                num_queues: 1,
                 is_read_only: false,
                 drive_id: String::new(),
                 rate_limiter: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig {
+                drive_id: String::new(),
+                socket: String::new(),
+                num_queues: 1,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
         check_runtime_request_err(
             VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
                 iface_id: String::new(),
                 host_dev_name: String::new(),
+                host_socket_path: None,
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: false,
+                num_queue_pairs: 1,
+                vhost_net: false,
+                anti_spoofing: None,
+                dhcp: None,
+                mtu: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1575,6 +2055,9 @@ mod tests {
                 vsock_id: String::new(),
                 guest_cid: 0,
                 uds_path: String::new(),
+                tcp_forwards: vec![],
+                tcp_listeners: vec![],
+                port_acl: VsockPortAcl::default(),
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1587,6 +2070,9 @@ mod tests {
                 vsock_id: String::new(),
                 guest_cid: 0,
                 uds_path: String::new(),
+                tcp_forwards: vec![],
+                tcp_listeners: vec![],
+                port_acl: VsockPortAcl::default(),
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1644,19 +2130,36 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: 1,
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
+        let req = VmmAction::InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig {
+            drive_id: String::new(),
+            socket: String::new(),
+            num_queues: 1,
+        });
+        verify_load_snap_disallowed_after_boot_resources(req, "InsertVhostUserBlockDevice");
+
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -1667,6 +2170,9 @@ mod tests {
             vsock_id: String::new(),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp_forwards: vec![],
+            tcp_listeners: vec![],
+            port_acl: VsockPortAcl::default(),
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetVsockDevice");
 
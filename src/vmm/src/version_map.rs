@@ -8,7 +8,10 @@ use std::collections::HashMap;
 use crate::device_manager::persist::DeviceStates;
 #[cfg(target_arch = "x86_64")]
 use crate::vstate::vcpu::VcpuState;
-use devices::virtio::block::persist::BlockState;
+use devices::virtio::block::persist::{BlockState, CacheTypeState};
+use devices::virtio::net::persist::{NetConfigSpaceState, NetState};
+use devices::virtio::persist::{QueueState, VirtioDeviceState};
+use devices::virtio::vsock::persist::VsockUdsState;
 
 use lazy_static::lazy_static;
 use versionize::VersionMap;
@@ -29,6 +32,15 @@ lazy_static! {
         #[cfg(target_arch = "x86_64")]
         version_map.set_type_version(VcpuState::type_id(), 2);
 
+        // v0.26 state change mappings.
+        version_map.new_version().set_type_version(BlockState::type_id(), 3);
+        version_map.set_type_version(CacheTypeState::type_id(), 2);
+        version_map.set_type_version(NetState::type_id(), 3);
+        version_map.set_type_version(NetConfigSpaceState::type_id(), 3);
+        version_map.set_type_version(QueueState::type_id(), 3);
+        version_map.set_type_version(VirtioDeviceState::type_id(), 2);
+        version_map.set_type_version(VsockUdsState::type_id(), 3);
+
         version_map
     };
 
@@ -41,6 +53,7 @@ lazy_static! {
 
         mapping.insert(String::from("0.24.0"), 2);
         mapping.insert(String::from("0.25.0"), 3);
+        mapping.insert(String::from("0.26.0"), 4);
 
         mapping
     };
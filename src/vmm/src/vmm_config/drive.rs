@@ -11,9 +11,11 @@ use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
-use devices::virtio::Block;
+use devices::virtio::block::{DIRTY_CHUNK_SIZE, MAX_NUM_QUEUES, NUM_QUEUES};
+use devices::virtio::vhost_user_block::Error as VhostUserBlockError;
+use devices::virtio::{Block, VhostUserBlock};
 
-pub use devices::virtio::CacheType;
+pub use devices::virtio::{CacheType, FileEngineType, ImageFormat};
 
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
@@ -30,14 +32,28 @@ pub enum DriveError {
     CreateBlockDevice(io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Unable to set up the vhost-user block device with its backend.
+    CreateVhostUserBlockDevice(VhostUserBlockError),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// Cannot retrieve or clear the dirty bitmap of the drive.
+    DirtyBitmap(VmmError),
     /// The block device path is invalid.
     InvalidBlockDevicePath,
+    /// The dirty bitmap uses another chunk size than the drive.
+    InvalidDirtyChunkSize(u64),
+    /// The number of queues is out of range.
+    InvalidNumQueues(u16),
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
+    /// Overlay drives cannot be read only.
+    ReadOnlyOverlay,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The I/O engine does not support the image format.
+    UnsupportedIoEngine(FileEngineType, ImageFormat),
+    /// The I/O engine does not support overlay drives.
+    UnsupportedOverlayIoEngine(FileEngineType),
 }
 
 impl Display for DriveError {
@@ -52,14 +68,39 @@ impl Display for DriveError {
             ),
             BlockDeviceUpdateFailed(e) => write!(f, "The update operation failed: {}", e),
             CreateRateLimiter(e) => write!(f, "Cannot create RateLimiter: {}", e),
+            CreateVhostUserBlockDevice(e) => {
+                write!(f, "Cannot set up the vhost-user block device: {:?}", e)
+            }
             DeviceUpdate(e) => write!(f, "Error during drive update (patch): {}", e),
+            DirtyBitmap(e) => write!(f, "Cannot access the drive dirty bitmap: {}", e),
             InvalidBlockDevicePath => write!(f, "Invalid block device path!"),
+            InvalidDirtyChunkSize(chunk_size) => write!(
+                f,
+                "Invalid dirty bitmap chunk size: {}. It must be {}.",
+                chunk_size, DIRTY_CHUNK_SIZE
+            ),
+            InvalidNumQueues(num_queues) => write!(
+                f,
+                "Invalid number of queues: {}. It must be between 1 and {}.",
+                num_queues, MAX_NUM_QUEUES
+            ),
             OpenBlockDevice(e) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 e
             ),
+            ReadOnlyOverlay => write!(f, "Overlay drives cannot be read only."),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            UnsupportedIoEngine(io_engine, image_format) => write!(
+                f,
+                "The {:?} I/O engine does not support {:?} images.",
+                io_engine, image_format
+            ),
+            UnsupportedOverlayIoEngine(io_engine) => write!(
+                f,
+                "The {:?} I/O engine does not support overlay drives.",
+                io_engine
+            ),
         }
     }
 }
@@ -86,10 +127,27 @@ pub struct BlockDeviceConfig {
     /// the guest driver.
     #[serde(default = "CacheType::default")]
     pub cache_type: CacheType,
+    /// Format of the disk image found at `path_on_host`.
+    #[serde(default = "ImageFormat::default")]
+    pub image_format: ImageFormat,
+    /// Engine used for executing the I/O requests of the drive.
+    #[serde(default = "FileEngineType::default")]
+    pub io_engine: FileEngineType,
+    /// Path of the copy-on-write overlay of the drive. If set, the image found at
+    /// `path_on_host` is opened read-only and the guest writes land in the overlay,
+    /// which is created if missing.
+    pub overlay_path_on_host: Option<String>,
+    /// Number of request queues exposed to the guest.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
 }
 
+fn default_num_queues() -> u16 {
+    NUM_QUEUES
+}
+
 impl From<&Block> for BlockDeviceConfig {
     fn from(block: &Block) -> Self {
         let rl: RateLimiterConfig = block.rate_limiter().into();
@@ -100,6 +158,10 @@ impl From<&Block> for BlockDeviceConfig {
             partuuid: block.partuuid().cloned(),
             is_read_only: block.is_read_only(),
             cache_type: block.cache_type(),
+            image_format: block.image_format(),
+            io_engine: block.file_engine_type(),
+            overlay_path_on_host: block.overlay_path().cloned(),
+            num_queues: block.num_queues(),
             rate_limiter: rl.into_option(),
         }
     }
@@ -114,10 +176,32 @@ pub struct BlockDeviceUpdateConfig {
     pub drive_id: String,
     /// New block file path on the host. Only provided data will be updated.
     pub path_on_host: Option<String>,
+    /// New size of the block device, in bytes. The backing file is grown to this size and
+    /// the guest is notified of the new capacity.
+    pub size_bytes: Option<u64>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
 }
 
+/// Chunks of a block device modified since they were last cleared.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDirtyBitmap {
+    /// Size of the chunks, in bytes.
+    pub chunk_size: u64,
+    /// Bit `i % 64` of the word `i / 64` is set if chunk `i` was modified.
+    pub bitmap: Vec<u64>,
+}
+
+impl From<Vec<u64>> for BlockDirtyBitmap {
+    fn from(bitmap: Vec<u64>) -> Self {
+        BlockDirtyBitmap {
+            chunk_size: DIRTY_CHUNK_SIZE,
+            bitmap,
+        }
+    }
+}
+
 /// Wrapper for the collection that holds all the Block Devices
 #[derive(Default)]
 pub struct BlockBuilder {
@@ -193,6 +277,13 @@ impl BlockBuilder {
         Ok(())
     }
 
+    /// Removes the block device with the specified `drive_id`, if any.
+    pub fn remove(&mut self, drive_id: &str) {
+        if let Some(index) = self.get_index_of_drive_id(drive_id) {
+            self.list.remove(index);
+        }
+    }
+
     /// Creates a Block device from a BlockDeviceConfig.
     pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
         // check if the path exists
@@ -201,6 +292,33 @@ impl BlockBuilder {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        // The image format translation is only implemented on top of the synchronous engine.
+        if block_device_config.io_engine == FileEngineType::Async
+            && block_device_config.image_format != ImageFormat::Raw
+        {
+            return Err(DriveError::UnsupportedIoEngine(
+                block_device_config.io_engine,
+                block_device_config.image_format,
+            ));
+        }
+
+        if block_device_config.num_queues == 0 || block_device_config.num_queues > MAX_NUM_QUEUES {
+            return Err(DriveError::InvalidNumQueues(block_device_config.num_queues));
+        }
+
+        if block_device_config.overlay_path_on_host.is_some() {
+            // The guest writes land in the overlay.
+            if block_device_config.is_read_only {
+                return Err(DriveError::ReadOnlyOverlay);
+            }
+            // So does the copy-on-write logic.
+            if block_device_config.io_engine == FileEngineType::Async {
+                return Err(DriveError::UnsupportedOverlayIoEngine(
+                    block_device_config.io_engine,
+                ));
+            }
+        }
+
         let rate_limiter = block_device_config
             .rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -212,9 +330,13 @@ impl BlockBuilder {
             block_device_config.drive_id,
             block_device_config.partuuid,
             block_device_config.cache_type,
+            block_device_config.image_format,
+            block_device_config.io_engine,
             block_device_config.path_on_host,
+            block_device_config.overlay_path_on_host,
             block_device_config.is_read_only,
             block_device_config.is_root_device,
+            block_device_config.num_queues,
             rate_limiter.unwrap_or_default(),
         )
         .map_err(DriveError::CreateBlockDevice)
@@ -230,10 +352,86 @@ impl BlockBuilder {
     }
 }
 
+/// Use this structure to set up a block device whose requests are served by an external
+/// vhost-user backend, before booting the kernel.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostUserBlockDeviceConfig {
+    /// Unique identifier of the drive.
+    pub drive_id: String,
+    /// Path of the Unix domain socket the vhost-user backend listens on.
+    pub socket: String,
+    /// Number of request queues exposed to the guest.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+}
+
+impl From<&VhostUserBlock> for VhostUserBlockDeviceConfig {
+    fn from(block: &VhostUserBlock) -> Self {
+        VhostUserBlockDeviceConfig {
+            drive_id: block.id().clone(),
+            socket: block.socket_path().clone(),
+            num_queues: block.num_queues(),
+        }
+    }
+}
+
+/// Wrapper for the collection that holds all the vhost-user block devices.
+#[derive(Default)]
+pub struct VhostUserBlockBuilder {
+    /// The list of vhost-user block devices.
+    pub list: Vec<Arc<Mutex<VhostUserBlock>>>,
+}
+
+impl VhostUserBlockBuilder {
+    /// Creates an empty vhost-user block devices builder.
+    pub fn new() -> Self {
+        Self { list: Vec::new() }
+    }
+
+    /// Connects to the backend described by `config` and inserts the resulting device in
+    /// the list. If a device with the same id already exists, it will overwrite it.
+    pub fn insert(&mut self, config: VhostUserBlockDeviceConfig) -> Result<()> {
+        if config.num_queues == 0 || config.num_queues > MAX_NUM_QUEUES {
+            return Err(DriveError::InvalidNumQueues(config.num_queues));
+        }
+
+        let position = self
+            .list
+            .iter()
+            .position(|b| b.lock().expect("Poisoned lock").id() == &config.drive_id);
+        let block_dev = Arc::new(Mutex::new(
+            VhostUserBlock::new(config.drive_id, config.socket, config.num_queues)
+                .map_err(DriveError::CreateVhostUserBlockDevice)?,
+        ));
+        match position {
+            Some(index) => self.list[index] = block_dev,
+            None => self.list.push(block_dev),
+        }
+        Ok(())
+    }
+
+    /// Removes the device with the specified `drive_id`, if any.
+    pub fn remove(&mut self, drive_id: &str) {
+        self.list
+            .retain(|b| b.lock().expect("Poisoned lock").id() != drive_id);
+    }
+
+    /// Returns a vec with the structures used to configure the devices.
+    pub fn configs(&self) -> Vec<VhostUserBlockDeviceConfig> {
+        self.list
+            .iter()
+            .map(|block| VhostUserBlockDeviceConfig::from(block.lock().unwrap().deref()))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use devices::virtio::block::CONFIG_SPACE_SIZE;
+    use devices::virtio::vhost_user::test_utils::TestBackend;
     use utils::tempfile::TempFile;
 
     impl PartialEq for DriveError {
@@ -251,6 +449,10 @@ mod tests {
                 is_root_device: self.is_root_device,
                 partuuid: self.partuuid.clone(),
                 cache_type: self.cache_type,
+                image_format: self.image_format,
+                io_engine: self.io_engine,
+                overlay_path_on_host: self.overlay_path_on_host.clone(),
+                num_queues: self.num_queues,
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
@@ -274,6 +476,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Writeback,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
@@ -304,6 +510,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -331,6 +541,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -343,6 +557,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
@@ -366,6 +584,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -378,6 +600,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
@@ -390,6 +616,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
@@ -427,6 +657,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -439,6 +673,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
@@ -451,6 +689,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
@@ -489,6 +731,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -501,6 +747,10 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
@@ -560,6 +810,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -572,6 +826,10 @@ mod tests {
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
@@ -593,6 +851,10 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
@@ -605,4 +867,176 @@ mod tests {
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
     }
+
+    #[test]
+    fn test_add_qcow2_block_device() {
+        // An empty file is not a valid qcow2 image.
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Qcow2,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(dummy_block_device) {
+            Err(DriveError::CreateBlockDevice(_)) => (),
+            _ => panic!("Expected a CreateBlockDevice error."),
+        }
+        assert_eq!(block_devs.list.len(), 0);
+    }
+
+    #[test]
+    fn test_add_async_block_device() {
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(0x1000).unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Writeback,
+            image_format: ImageFormat::Qcow2,
+            io_engine: FileEngineType::Async,
+            overlay_path_on_host: None,
+            num_queues: NUM_QUEUES,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+        };
+
+        // The Async engine only supports raw images.
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::UnsupportedIoEngine(FileEngineType::Async, ImageFormat::Qcow2)
+        );
+        assert_eq!(block_devs.list.len(), 0);
+
+        dummy_block_device.image_format = ImageFormat::Raw;
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        assert_eq!(block_devs.list.len(), 1);
+    }
+
+    #[test]
+    fn test_add_overlay_block_device() {
+        let base_file = TempFile::new().unwrap();
+        base_file.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: base_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Async,
+            overlay_path_on_host: Some(overlay_file.as_path().to_str().unwrap().to_string()),
+            num_queues: NUM_QUEUES,
+            is_read_only: true,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::ReadOnlyOverlay
+        );
+        dummy_block_device.is_read_only = false;
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::UnsupportedOverlayIoEngine(FileEngineType::Async)
+        );
+        assert_eq!(block_devs.list.len(), 0);
+
+        dummy_block_device.io_engine = FileEngineType::Sync;
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        assert_eq!(block_devs.list.len(), 1);
+        // The base image is exposed as a writable drive.
+        let configs = block_devs.configs();
+        assert_eq!(configs.first().unwrap(), &dummy_block_device);
+    }
+
+    #[test]
+    fn test_add_multi_queue_block_device() {
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(0x1000).unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            image_format: ImageFormat::Raw,
+            io_engine: FileEngineType::Sync,
+            overlay_path_on_host: None,
+            num_queues: 0,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::InvalidNumQueues(0)
+        );
+        dummy_block_device.num_queues = MAX_NUM_QUEUES + 1;
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::InvalidNumQueues(MAX_NUM_QUEUES + 1)
+        );
+        assert_eq!(block_devs.list.len(), 0);
+
+        dummy_block_device.num_queues = 4;
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        assert_eq!(block_devs.list.len(), 1);
+        let configs = block_devs.configs();
+        assert_eq!(configs.first().unwrap(), &dummy_block_device);
+    }
+
+    #[test]
+    fn test_add_vhost_user_block_device() {
+        // Each test backend serves a single connection.
+        let backend = TestBackend::new(vec![0u8; CONFIG_SPACE_SIZE], 0x1000);
+        let mut config = VhostUserBlockDeviceConfig {
+            drive_id: String::from("1"),
+            socket: backend.socket_path().to_str().unwrap().to_string(),
+            num_queues: 0,
+        };
+
+        let mut block_devs = VhostUserBlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(config.clone()).unwrap_err(),
+            DriveError::InvalidNumQueues(0)
+        );
+        config.num_queues = NUM_QUEUES;
+        block_devs.insert(config.clone()).unwrap();
+        assert_eq!(block_devs.list.len(), 1);
+        assert_eq!(block_devs.configs(), vec![config.clone()]);
+
+        // Inserting a drive with the same id overwrites it.
+        let backend = TestBackend::new(vec![0u8; CONFIG_SPACE_SIZE], 0x1000);
+        config.socket = backend.socket_path().to_str().unwrap().to_string();
+        block_devs.insert(config.clone()).unwrap();
+        assert_eq!(block_devs.configs(), vec![config.clone()]);
+
+        block_devs.remove("2");
+        assert_eq!(block_devs.list.len(), 1);
+        block_devs.remove("1");
+        assert!(block_devs.list.is_empty());
+
+        // The backend has to be reachable.
+        config.socket = String::from("/invalid/socket");
+        match block_devs.insert(config) {
+            Err(DriveError::CreateVhostUserBlockDevice(_)) => (),
+            _ => panic!("Expected a CreateVhostUserBlockDevice error."),
+        }
+    }
 }
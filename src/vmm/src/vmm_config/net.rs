@@ -3,13 +3,18 @@
 
 use std::convert::TryInto;
 use std::fmt;
+use std::net::Ipv4Addr;
 use std::result;
 use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
-use devices::virtio::net::TapError;
+use devices::virtio::net::{
+    DhcpLease, TapError, MAX_CAPTURE_SNAPLEN, MAX_DHCP_DNS_SERVERS, MAX_NUM_QUEUE_PAIRS, MIN_MTU,
+    NUM_QUEUE_PAIRS,
+};
 use devices::virtio::Net;
+use logger::{IncMetric, NetDeviceMetrics};
 use utils::net::mac::MacAddr;
 
 use serde::{Deserialize, Serialize};
@@ -23,7 +28,13 @@ pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
     /// Host level path for the guest network interface.
+    #[serde(default)]
     pub host_dev_name: String,
+    /// If this field is set instead of `host_dev_name`, the frames are exchanged with the
+    /// userspace switch listening on the unix socket found at this path, instead of a TAP
+    /// device. Such interfaces only have one queue pair, and cannot be handled by vhost-net.
+    #[serde(default)]
+    pub host_socket_path: Option<String>,
     /// Guest MAC address.
     pub guest_mac: Option<MacAddr>,
     /// Rate Limiter for received packages.
@@ -37,6 +48,97 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
+    /// Number of RX/TX queue pairs exposed to the guest. Each pair is served by its own
+    /// queue of the TAP device, which is opened in multi-queue mode when there are several.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: u16,
+    /// If this field is set, the virtqueues are processed by the host kernel vhost-net driver
+    /// instead of the device model. Such interfaces cannot serve MMDS requests, nor be rate
+    /// limited, and only have one queue pair.
+    #[serde(default)]
+    pub vhost_net: bool,
+    /// If this field is set, the frames sent by the guest are dropped unless they come from
+    /// `guest_mac` and, for IPv4 packets and ARP messages, from one of the allowed addresses.
+    #[serde(default)]
+    pub anti_spoofing: Option<AntiSpoofingConfig>,
+    /// If this field is set, the device model answers the DHCP requests sent by the guest with
+    /// the given network configuration, and does not forward them to the TAP device.
+    #[serde(default)]
+    pub dhcp: Option<DhcpConfig>,
+    /// MTU advertised to the guest, which cannot exceed the MTU of the TAP device.
+    #[serde(default)]
+    pub mtu: Option<u16>,
+}
+
+/// Network configuration handed out to the guest by the DHCP responder of its interface.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DhcpConfig {
+    /// Address assigned to the guest.
+    pub ipv4_addr: Ipv4Addr,
+    /// Length of the network prefix of the guest subnet.
+    pub prefix_len: u8,
+    /// Default gateway, which also identifies the DHCP server.
+    pub gateway: Ipv4Addr,
+    /// DNS servers, in order of preference.
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// MTU of the guest interface.
+    pub mtu: Option<u16>,
+}
+
+impl DhcpConfig {
+    fn validate(&self) -> result::Result<(), &'static str> {
+        if self.prefix_len == 0 || self.prefix_len > 31 {
+            return Err("the prefix length must be between 1 and 31");
+        }
+        let mask = u32::MAX << (32 - u32::from(self.prefix_len));
+        if u32::from(self.gateway) & mask != u32::from(self.ipv4_addr) & mask {
+            return Err("the gateway is outside of the guest subnet");
+        }
+        if self.gateway == self.ipv4_addr {
+            return Err("the gateway address is the guest address");
+        }
+        if self.dns_servers.len() > MAX_DHCP_DNS_SERVERS {
+            return Err("too many DNS servers");
+        }
+        if self.mtu.map_or(false, |mtu| mtu < MIN_MTU) {
+            return Err("the MTU must be at least 68");
+        }
+        Ok(())
+    }
+}
+
+impl From<DhcpConfig> for DhcpLease {
+    fn from(cfg: DhcpConfig) -> Self {
+        DhcpLease {
+            ipv4_addr: cfg.ipv4_addr,
+            prefix_len: cfg.prefix_len,
+            gateway: cfg.gateway,
+            dns_servers: cfg.dns_servers,
+            mtu: cfg.mtu,
+        }
+    }
+}
+
+impl From<&DhcpLease> for DhcpConfig {
+    fn from(lease: &DhcpLease) -> Self {
+        DhcpConfig {
+            ipv4_addr: lease.ipv4_addr,
+            prefix_len: lease.prefix_len,
+            gateway: lease.gateway,
+            dns_servers: lease.dns_servers.clone(),
+            mtu: lease.mtu,
+        }
+    }
+}
+
+/// Source addresses the guest is allowed to send frames from, besides its MAC address.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AntiSpoofingConfig {
+    /// IPv4 addresses assigned to the guest interface.
+    pub allowed_ipv4_addrs: Vec<Ipv4Addr>,
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -46,10 +148,20 @@ impl From<&Net> for NetworkInterfaceConfig {
         NetworkInterfaceConfig {
             iface_id: net.id().clone(),
             host_dev_name: net.iface_name(),
+            host_socket_path: net.socket_path().map(String::from),
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
             allow_mmds_requests: net.mmds_enabled(),
+            num_queue_pairs: net.num_queue_pairs(),
+            vhost_net: net.vhost_net_enabled(),
+            anti_spoofing: net
+                .anti_spoofing_ipv4_addrs()
+                .map(|addrs| AntiSpoofingConfig {
+                    allowed_ipv4_addrs: addrs.to_vec(),
+                }),
+            dhcp: net.dhcp_lease().map(DhcpConfig::from),
+            mtu: net.mtu(),
         }
     }
 }
@@ -61,8 +173,12 @@ fn default_allow_mmds_requests() -> bool {
     false
 }
 
+fn default_num_queue_pairs() -> u16 {
+    NUM_QUEUE_PAIRS
+}
+
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
+/// and the link status can be updated.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
@@ -74,6 +190,77 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Brings the link of the iface up or down, as if the cable was plugged in or out.
+    pub link_up: Option<bool>,
+}
+
+/// The data fed into a network iface capture request. The frames received and sent by the
+/// interface are written to a pcap file while the capture is ongoing.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceCaptureConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// Host level path of the capture file, replaced if it exists. The ongoing capture is
+    /// stopped when missing.
+    pub path_on_host: Option<String>,
+    /// Size limit of the capture file, in bytes. Required when starting a capture.
+    pub max_size_bytes: Option<u64>,
+    /// Largest number of bytes captured from each frame.
+    pub snaplen: Option<u32>,
+}
+
+impl NetworkInterfaceCaptureConfig {
+    /// Number of bytes captured from each frame.
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen.unwrap_or(MAX_CAPTURE_SNAPLEN)
+    }
+}
+
+/// Counters of a network interface, accumulated since the interface was created.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NetworkInterfaceStats {
+    /// Number of bytes received by the guest.
+    pub rx_bytes: u64,
+    /// Number of frames received by the guest.
+    pub rx_packets: u64,
+    /// Number of frames which could not be delivered to the guest.
+    pub rx_dropped: u64,
+    /// Number of times the RX rate limiter stopped frames from reaching the guest.
+    pub rx_throttled: u64,
+    /// Number of bytes sent by the guest on the tap.
+    pub tx_bytes: u64,
+    /// Number of frames sent by the guest on the tap.
+    pub tx_packets: u64,
+    /// Number of frames sent by the guest which were dropped.
+    pub tx_dropped: u64,
+    /// Number of times the TX rate limiter stopped frames sent by the guest.
+    pub tx_throttled: u64,
+    /// Number of frames sent by the guest which were detoured to MMDS.
+    pub mmds_requests: u64,
+    /// Number of frames sent by MMDS to the guest.
+    pub mmds_replies: u64,
+}
+
+impl From<&NetDeviceMetrics> for NetworkInterfaceStats {
+    fn from(metrics: &NetDeviceMetrics) -> Self {
+        NetworkInterfaceStats {
+            rx_bytes: metrics.rx_bytes_count.count() as u64,
+            rx_packets: metrics.rx_packets_count.count() as u64,
+            rx_dropped: (metrics.rx_fails.count() + metrics.rx_link_down_frames_dropped.count())
+                as u64,
+            rx_throttled: metrics.rx_rate_limiter_throttled.count() as u64,
+            tx_bytes: metrics.tx_bytes_count.count() as u64,
+            tx_packets: metrics.tx_packets_count.count() as u64,
+            tx_dropped: (metrics.tx_malformed_frames.count()
+                + metrics.tx_spoofed_frames_dropped.count()
+                + metrics.tx_link_down_frames_dropped.count()
+                + metrics.tap_write_fails.count()) as u64,
+            tx_throttled: metrics.tx_rate_limiter_throttled.count() as u64,
+            mmds_requests: metrics.mmds_requests_count.count() as u64,
+            mmds_replies: metrics.mmds_replies_count.count() as u64,
+        }
+    }
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -85,8 +272,26 @@ pub enum NetworkInterfaceError {
     CreateRateLimiter(std::io::Error),
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs(u16),
+    /// The interface option is not available when the queues are processed by vhost-net.
+    VhostNetUnsupported(&'static str),
+    /// The host side of the interface is missing or ambiguous.
+    InvalidBackend(&'static str),
+    /// The interface option is not available when the frames go through a unix socket.
+    SocketBackendUnsupported(&'static str),
+    /// The anti-spoofing filter needs the guest MAC address.
+    AntiSpoofingMacMissing,
+    /// The network configuration handed out by the DHCP responder is invalid.
+    InvalidDhcpConfig(&'static str),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
+    /// Cannot start or stop the interface capture.
+    Capture(VmmError),
+    /// The size limit of the capture file is missing.
+    CaptureMaxSizeMissing,
+    /// Cannot retrieve the interface statistics.
+    Stats(VmmError),
     /// Cannot open/create tap device.
     OpenTap(TapError),
 }
@@ -102,7 +307,30 @@ impl fmt::Display for NetworkInterfaceError {
                 "{}",
                 format!("The guest MAC address {} is already in use.", mac_addr)
             ),
+            InvalidNumQueuePairs(num_queue_pairs) => write!(
+                f,
+                "Invalid number of queue pairs: {}. It must be between 1 and {}.",
+                num_queue_pairs, MAX_NUM_QUEUE_PAIRS
+            ),
+            VhostNetUnsupported(option) => {
+                write!(f, "vhost-net interfaces do not support {}.", option)
+            }
+            InvalidBackend(reason) => write!(f, "Invalid interface backend: {}.", reason),
+            SocketBackendUnsupported(option) => {
+                write!(f, "Unix socket interfaces do not support {}.", option)
+            }
+            AntiSpoofingMacMissing => write!(
+                f,
+                "The guest MAC address is required by the anti-spoofing filter."
+            ),
+            InvalidDhcpConfig(reason) => write!(f, "Invalid DHCP configuration: {}.", reason),
             DeviceUpdate(e) => write!(f, "Error during interface update (patch): {}", e),
+            Capture(e) => write!(f, "Cannot update the interface capture: {}", e),
+            CaptureMaxSizeMissing => write!(
+                f,
+                "The size limit of the capture file is required to start a capture."
+            ),
+            Stats(e) => write!(f, "Cannot retrieve the interface statistics: {}", e),
             OpenTap(e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -184,6 +412,62 @@ impl NetBuilder {
 
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
+        if cfg.num_queue_pairs == 0 || cfg.num_queue_pairs > MAX_NUM_QUEUE_PAIRS {
+            return Err(NetworkInterfaceError::InvalidNumQueuePairs(
+                cfg.num_queue_pairs,
+            ));
+        }
+        match (cfg.host_dev_name.is_empty(), cfg.host_socket_path.is_some()) {
+            (true, false) => {
+                return Err(NetworkInterfaceError::InvalidBackend(
+                    "either a TAP device or a unix socket is required",
+                ));
+            }
+            (false, true) => {
+                return Err(NetworkInterfaceError::InvalidBackend(
+                    "a TAP device and a unix socket cannot be used together",
+                ));
+            }
+            _ => (),
+        }
+        if cfg.host_socket_path.is_some() {
+            // The switch is handed complete frames, one queue pair at a time.
+            if cfg.vhost_net {
+                return Err(NetworkInterfaceError::SocketBackendUnsupported("vhost-net"));
+            }
+            if cfg.num_queue_pairs > 1 {
+                return Err(NetworkInterfaceError::SocketBackendUnsupported(
+                    "multiple queue pairs",
+                ));
+            }
+        }
+        if cfg.vhost_net {
+            // The frames handled by vhost-net never go through the device model.
+            if cfg.allow_mmds_requests {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("MMDS requests"));
+            }
+            if cfg.rx_rate_limiter.is_some() || cfg.tx_rate_limiter.is_some() {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("rate limiting"));
+            }
+            if cfg.num_queue_pairs > 1 {
+                return Err(NetworkInterfaceError::VhostNetUnsupported(
+                    "multiple queue pairs",
+                ));
+            }
+            if cfg.anti_spoofing.is_some() {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("anti-spoofing"));
+            }
+            if cfg.dhcp.is_some() {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("DHCP"));
+            }
+        }
+        if let Some(dhcp) = cfg.dhcp.as_ref() {
+            dhcp.validate()
+                .map_err(NetworkInterfaceError::InvalidDhcpConfig)?;
+        }
+        if cfg.anti_spoofing.is_some() && cfg.guest_mac.is_none() {
+            return Err(NetworkInterfaceError::AntiSpoofingMacMissing);
+        }
         let rx_rate_limiter = cfg
             .rx_rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -196,15 +480,42 @@ impl NetBuilder {
             .map_err(NetworkInterfaceError::CreateRateLimiter)?;
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
-            cfg.iface_id,
-            cfg.host_dev_name.clone(),
-            cfg.guest_mac.as_ref(),
-            rx_rate_limiter.unwrap_or_default(),
-            tx_rate_limiter.unwrap_or_default(),
-            cfg.allow_mmds_requests,
-        )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = match cfg.host_socket_path {
+            Some(socket_path) => devices::virtio::net::Net::new_with_socket(
+                cfg.iface_id,
+                socket_path,
+                cfg.guest_mac.as_ref(),
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+                cfg.allow_mmds_requests,
+            ),
+            None => devices::virtio::net::Net::new_with_tap(
+                cfg.iface_id,
+                cfg.host_dev_name.clone(),
+                cfg.guest_mac.as_ref(),
+                cfg.num_queue_pairs,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+                cfg.allow_mmds_requests,
+            ),
+        }
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if cfg.vhost_net {
+            net.enable_vhost_net()
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        if let Some(anti_spoofing) = cfg.anti_spoofing {
+            net.enable_anti_spoofing(anti_spoofing.allowed_ipv4_addrs)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        if let Some(dhcp) = cfg.dhcp {
+            net.enable_dhcp(DhcpLease::from(dhcp));
+        }
+        if let Some(mtu) = cfg.mtu {
+            net.set_mtu(mtu)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -219,9 +530,11 @@ impl NetBuilder {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::net::UnixDatagram;
     use std::str;
 
     use super::*;
+    use utils::tempfile::TempFile;
 
     impl NetBuilder {
         pub fn len(&self) -> usize {
@@ -237,10 +550,16 @@ mod tests {
         NetworkInterfaceConfig {
             iface_id: String::from(id),
             host_dev_name: String::from(name),
+            host_socket_path: None,
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
             allow_mmds_requests: false,
+            num_queue_pairs: NUM_QUEUE_PAIRS,
+            vhost_net: false,
+            anti_spoofing: None,
+            dhcp: None,
+            mtu: None,
         }
     }
 
@@ -249,10 +568,16 @@ mod tests {
             NetworkInterfaceConfig {
                 iface_id: self.iface_id.clone(),
                 host_dev_name: self.host_dev_name.clone(),
+                host_socket_path: self.host_socket_path.clone(),
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: self.allow_mmds_requests,
+                num_queue_pairs: self.num_queue_pairs,
+                vhost_net: self.vhost_net,
+                anti_spoofing: self.anti_spoofing.clone(),
+                dhcp: self.dhcp.clone(),
+                mtu: self.mtu,
             }
         }
     }
@@ -370,6 +695,281 @@ mod tests {
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidNumQueuePairs(0),
+            NetworkInterfaceError::InvalidNumQueuePairs(0)
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostNetUnsupported("rate limiting"),
+            NetworkInterfaceError::VhostNetUnsupported("rate limiting")
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidBackend("no backend"),
+            NetworkInterfaceError::InvalidBackend("no backend")
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::SocketBackendUnsupported("vhost-net"),
+            NetworkInterfaceError::SocketBackendUnsupported("vhost-net")
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::Capture(VmmError::VcpuExit),
+            NetworkInterfaceError::Capture(VmmError::VcpuExit)
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::CaptureMaxSizeMissing,
+            NetworkInterfaceError::CaptureMaxSizeMissing
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::Stats(VmmError::VcpuExit),
+            NetworkInterfaceError::Stats(VmmError::VcpuExit)
+        );
+    }
+
+    #[test]
+    fn test_network_interface_stats() {
+        let metrics = NetDeviceMetrics::default();
+        metrics.rx_bytes_count.add(100);
+        metrics.rx_packets_count.inc();
+        metrics.rx_fails.inc();
+        metrics.rx_link_down_frames_dropped.inc();
+        metrics.tx_spoofed_frames_dropped.add(2);
+        metrics.tap_write_fails.inc();
+        metrics.tx_rate_limiter_throttled.inc();
+        metrics.mmds_requests_count.inc();
+
+        let stats = NetworkInterfaceStats::from(&metrics);
+        assert_eq!(
+            stats,
+            NetworkInterfaceStats {
+                rx_bytes: 100,
+                rx_packets: 1,
+                rx_dropped: 2,
+                tx_dropped: 3,
+                tx_throttled: 1,
+                mmds_requests: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_num_queue_pairs() {
+        let mut net_builder = NetBuilder::new();
+
+        let mut netif = create_netif("id_mq", "mq-dev", "01:23:45:67:89:0c");
+        netif.num_queue_pairs = 0;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "Invalid number of queue pairs: 0. It must be between 1 and 16."
+        );
+        netif.num_queue_pairs = MAX_NUM_QUEUE_PAIRS + 1;
+        assert!(net_builder.build(netif.clone()).is_err());
+        assert!(net_builder.is_empty());
+
+        netif.num_queue_pairs = 4;
+        assert!(net_builder.build(netif.clone()).is_ok());
+        assert_eq!(net_builder.configs(), vec![netif]);
+    }
+
+    #[test]
+    fn test_vhost_net_options() {
+        let mut net_builder = NetBuilder::new();
+
+        let mut netif = create_netif("id_vhost", "vhost-dev", "01:23:45:67:89:0d");
+        netif.vhost_net = true;
+        netif.allow_mmds_requests = true;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "vhost-net interfaces do not support MMDS requests."
+        );
+
+        netif.allow_mmds_requests = false;
+        netif.rx_rate_limiter = Some(RateLimiterConfig::default());
+        assert_eq!(
+            net_builder.build(netif).err().unwrap().to_string(),
+            "vhost-net interfaces do not support rate limiting."
+        );
+
+        let mut netif = create_netif("id_vhost", "vhost-dev", "01:23:45:67:89:0d");
+        netif.vhost_net = true;
+        netif.num_queue_pairs = 2;
+        assert_eq!(
+            net_builder.build(netif).err().unwrap().to_string(),
+            "vhost-net interfaces do not support multiple queue pairs."
+        );
+        assert!(net_builder.is_empty());
+    }
+
+    #[test]
+    fn test_anti_spoofing_options() {
+        let mut net_builder = NetBuilder::new();
+        let anti_spoofing = AntiSpoofingConfig {
+            allowed_ipv4_addrs: vec![Ipv4Addr::new(10, 0, 0, 2)],
+        };
+
+        let mut netif = create_netif("id_spoof", "spoof-dev", "01:23:45:67:89:0e");
+        netif.guest_mac = None;
+        netif.anti_spoofing = Some(anti_spoofing.clone());
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "The guest MAC address is required by the anti-spoofing filter."
+        );
+
+        netif.guest_mac = Some(MacAddr::parse_str("01:23:45:67:89:0e").unwrap());
+        netif.vhost_net = true;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "vhost-net interfaces do not support anti-spoofing."
+        );
+        assert!(net_builder.is_empty());
+
+        netif.vhost_net = false;
+        assert!(net_builder.build(netif.clone()).is_ok());
+        assert_eq!(net_builder.configs()[0].anti_spoofing, Some(anti_spoofing));
+    }
+
+    #[test]
+    fn test_dhcp_options() {
+        let mut net_builder = NetBuilder::new();
+        let valid_dhcp = DhcpConfig {
+            ipv4_addr: Ipv4Addr::new(10, 0, 0, 2),
+            prefix_len: 24,
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+            dns_servers: vec![Ipv4Addr::new(10, 0, 0, 1)],
+            mtu: Some(1500),
+        };
+        let mut netif = create_netif("id_dhcp", "dhcp-dev", "01:23:45:67:89:0f");
+
+        let invalid_configs = [
+            (
+                DhcpConfig {
+                    prefix_len: 32,
+                    ..valid_dhcp.clone()
+                },
+                "the prefix length must be between 1 and 31",
+            ),
+            (
+                DhcpConfig {
+                    gateway: Ipv4Addr::new(10, 0, 1, 1),
+                    ..valid_dhcp.clone()
+                },
+                "the gateway is outside of the guest subnet",
+            ),
+            (
+                DhcpConfig {
+                    gateway: valid_dhcp.ipv4_addr,
+                    ..valid_dhcp.clone()
+                },
+                "the gateway address is the guest address",
+            ),
+            (
+                DhcpConfig {
+                    dns_servers: vec![Ipv4Addr::new(10, 0, 0, 1); MAX_DHCP_DNS_SERVERS + 1],
+                    ..valid_dhcp.clone()
+                },
+                "too many DNS servers",
+            ),
+            (
+                DhcpConfig {
+                    mtu: Some(67),
+                    ..valid_dhcp.clone()
+                },
+                "the MTU must be at least 68",
+            ),
+        ];
+        for (dhcp, reason) in invalid_configs.iter() {
+            netif.dhcp = Some(dhcp.clone());
+            assert_eq!(
+                net_builder.build(netif.clone()).err().unwrap().to_string(),
+                format!("Invalid DHCP configuration: {}.", reason)
+            );
+        }
+
+        netif.dhcp = Some(valid_dhcp.clone());
+        netif.vhost_net = true;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "vhost-net interfaces do not support DHCP."
+        );
+        assert!(net_builder.is_empty());
+
+        netif.vhost_net = false;
+        assert!(net_builder.build(netif).is_ok());
+        assert_eq!(net_builder.configs()[0].dhcp, Some(valid_dhcp));
+    }
+
+    #[test]
+    fn test_mtu_option() {
+        let mut net_builder = NetBuilder::new();
+        let mut netif = create_netif("id_mtu", "mtu-dev", "01:23:45:67:89:10");
+
+        // The MTU cannot exceed the one of the TAP device.
+        netif.mtu = Some(9000);
+        assert!(matches!(
+            net_builder.build(netif.clone()),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidMtu(9000)
+            ))
+        ));
+        assert!(net_builder.is_empty());
+
+        netif.mtu = Some(1450);
+        assert!(net_builder.build(netif).is_ok());
+        assert_eq!(net_builder.configs()[0].mtu, Some(1450));
+    }
+
+    #[test]
+    fn test_socket_backend_options() {
+        let mut net_builder = NetBuilder::new();
+        let tmp = TempFile::new().unwrap();
+        let socket_path = tmp.as_path().to_str().unwrap().to_string();
+        drop(tmp);
+
+        let mut netif = create_netif("id_socket", "", "01:23:45:67:89:11");
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "Invalid interface backend: either a TAP device or a unix socket is required."
+        );
+        netif.host_dev_name = "socket-dev".to_string();
+        netif.host_socket_path = Some(socket_path.clone());
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "Invalid interface backend: a TAP device and a unix socket cannot be used together."
+        );
+
+        netif.host_dev_name = String::new();
+        netif.vhost_net = true;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "Unix socket interfaces do not support vhost-net."
+        );
+        netif.vhost_net = false;
+        netif.num_queue_pairs = 2;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            "Unix socket interfaces do not support multiple queue pairs."
+        );
+        netif.num_queue_pairs = NUM_QUEUE_PAIRS;
+        // Nobody listens on the socket yet.
+        assert!(matches!(
+            net_builder.build(netif.clone()),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::SocketConnect(_)
+            ))
+        ));
+        assert!(net_builder.is_empty());
+
+        let _switch = UnixDatagram::bind(&socket_path).unwrap();
+        assert!(net_builder.build(netif.clone()).is_ok());
+        assert_eq!(net_builder.configs(), vec![netif]);
+        std::fs::remove_file(&socket_path).unwrap();
     }
 
     #[test]
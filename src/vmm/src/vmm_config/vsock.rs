@@ -6,6 +6,7 @@ use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use devices::virtio::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+pub use devices::virtio::{VsockPortAcl, VsockTcpPort};
 
 use serde::{Deserialize, Serialize};
 
@@ -45,11 +46,25 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// Path to local unix socket.
     pub uds_path: String,
+    /// Guest ports whose connections are forwarded to TCP ports on 127.0.0.1, instead of
+    /// the unix sockets next to `uds_path`.
+    #[serde(default)]
+    pub tcp_forwards: Vec<VsockTcpPort>,
+    /// TCP ports on 127.0.0.1 on which connections to guest ports are accepted.
+    #[serde(default)]
+    pub tcp_listeners: Vec<VsockTcpPort>,
+    /// Ports to which connections can be established, from the guest to the host and from
+    /// the host to the guest.
+    #[serde(default)]
+    pub port_acl: VsockPortAcl,
 }
 
 struct VsockAndUnixPath {
     vsock: MutexVsockUnix,
     uds_path: String,
+    tcp_forwards: Vec<VsockTcpPort>,
+    tcp_listeners: Vec<VsockTcpPort>,
+    port_acl: VsockPortAcl,
 }
 
 impl From<&VsockAndUnixPath> for VsockDeviceConfig {
@@ -59,6 +74,9 @@ impl From<&VsockAndUnixPath> for VsockDeviceConfig {
             vsock_id: vsock_lock.id().to_string(),
             guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
             uds_path: vsock.uds_path.clone(),
+            tcp_forwards: vsock.tcp_forwards.clone(),
+            tcp_listeners: vsock.tcp_listeners.clone(),
+            port_acl: vsock.port_acl.clone(),
         }
     }
 }
@@ -86,6 +104,9 @@ impl VsockBuilder {
         }
         self.inner = Some(VsockAndUnixPath {
             uds_path: cfg.uds_path.clone(),
+            tcp_forwards: cfg.tcp_forwards.clone(),
+            tcp_listeners: cfg.tcp_listeners.clone(),
+            port_acl: cfg.port_acl.clone(),
             vsock: Arc::new(Mutex::new(Self::create_unixsock_vsock(cfg)?)),
         });
         Ok(())
@@ -98,8 +119,14 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_unixsock_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockUnixBackend>> {
-        let backend = VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.uds_path)
-            .map_err(VsockConfigError::CreateVsockBackend)?;
+        let backend = VsockUnixBackend::new(
+            u64::from(cfg.guest_cid),
+            cfg.uds_path,
+            cfg.tcp_forwards,
+            cfg.tcp_listeners,
+            cfg.port_acl,
+        )
+        .map_err(VsockConfigError::CreateVsockBackend)?;
 
         Vsock::new(u64::from(cfg.guest_cid), backend).map_err(VsockConfigError::CreateVsockDevice)
     }
@@ -120,6 +147,9 @@ pub(crate) mod tests {
             vsock_id: "vsock".to_string(),
             guest_cid: 3,
             uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+            tcp_forwards: vec![],
+            tcp_listeners: vec![],
+            port_acl: VsockPortAcl::default(),
         }
     }
 
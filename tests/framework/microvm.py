@@ -715,7 +715,8 @@ class Microvm:
             allow_mmds_requests=False,
             tx_rate_limiter=None,
             rx_rate_limiter=None,
-            tapname=None
+            tapname=None,
+            num_queue_pairs=None
     ):
         """Create a host tap device and a guest network interface.
 
@@ -729,6 +730,8 @@ class Microvm:
         intercepted and processed by the device model.
         :param tx_rate_limiter: limit the tx rate
         :param rx_rate_limiter: limit the rx rate
+        :param num_queue_pairs: the number of RX/TX queue pairs of the
+        interface, served by a multi-queue tap device when set
         :return: an instance of the tap which needs to be kept around until
         cleanup is desired, the configured guest and host ips, respectively.
         """
@@ -738,7 +741,8 @@ class Microvm:
         tap = self.create_tap_and_ssh_config(host_ip,
                                              guest_ip,
                                              network_config.get_netmask_len(),
-                                             tapname)
+                                             tapname,
+                                             num_queue_pairs is not None)
         guest_mac = net_tools.mac_from_ip(guest_ip)
 
         response = self.network.put(
@@ -747,7 +751,8 @@ class Microvm:
             guest_mac=guest_mac,
             allow_mmds_requests=allow_mmds_requests,
             tx_rate_limiter=tx_rate_limiter,
-            rx_rate_limiter=rx_rate_limiter
+            rx_rate_limiter=rx_rate_limiter,
+            num_queue_pairs=num_queue_pairs
         )
         assert self._api_session.is_status_no_content(response.status_code)
 
@@ -758,7 +763,8 @@ class Microvm:
             host_ip,
             guest_ip,
             netmask_len,
-            tapname=None
+            tapname=None,
+            multi_queue=False
     ):
         """Create tap device and configure ssh."""
         assert tapname is not None
@@ -768,7 +774,8 @@ class Microvm:
             ip="{}/{}".format(
                 host_ip,
                 netmask_len
-            )
+            ),
+            multi_queue=multi_queue
         )
         self.config_ssh(guest_ip)
         return tap
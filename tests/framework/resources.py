@@ -641,7 +641,8 @@ class Network():
             guest_mac=None,
             allow_mmds_requests=None,
             rx_rate_limiter=None,
-            tx_rate_limiter=None):
+            tx_rate_limiter=None,
+            num_queue_pairs=None):
         """Create the json for the net specific API request."""
         datax = {
             'iface_id': iface_id
@@ -662,6 +663,9 @@ class Network():
         if rx_rate_limiter is not None:
             datax['rx_rate_limiter'] = rx_rate_limiter
 
+        if num_queue_pairs is not None:
+            datax['num_queue_pairs'] = num_queue_pairs
+
         return datax
 
 
@@ -305,7 +305,7 @@ def get_guest_net_if_name(ssh_connection, guest_ip):
 class Tap:
     """Functionality for creating a tap and cleaning up after it."""
 
-    def __init__(self, name, netns, ip=None):
+    def __init__(self, name, netns, ip=None, multi_queue=False):
         """Set up the name and network namespace for this tap interface.
 
         It also creates a new tap device, and brings it up. The tap will
@@ -315,7 +315,10 @@ class Tap:
         The function also moves the interface to the specified
         namespace.
         """
-        utils.run_cmd('ip tuntap add mode tap name ' + name)
+        utils.run_cmd('ip tuntap add mode tap name {}{}'.format(
+            name,
+            ' multi_queue' if multi_queue else ''
+        ))
         utils.run_cmd('ip link set {} netns {}'.format(name, netns))
         if ip:
             utils.run_cmd('ip netns exec {} ifconfig {} {} up'.format(
@@ -50,3 +50,30 @@ def test_high_ingress_traffic(test_microvm_with_ssh, network_config):
     # ssh commands.
     exit_code, _, _ = ssh_connection.execute_command('echo success\n')
     assert exit_code == 0
+
+
+def test_multi_queue(test_microvm_with_ssh, network_config):
+    """Check that the guest can enable several queue pairs."""
+    test_microvm = test_microvm_with_ssh
+    test_microvm.spawn()
+
+    # The guest driver enables one queue pair per vCPU through the control
+    # queue, which is served by the VMM thread under its seccomp filter.
+    test_microvm.basic_config(vcpu_count=2)
+    _tap, _, _ = test_microvm.ssh_network_config(
+        network_config,
+        '1',
+        num_queue_pairs=2
+    )
+    test_microvm.start()
+
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    exit_code, stdout, _ = ssh_connection.execute_command(
+        'ls /sys/class/net/eth0/queues'
+    )
+    assert exit_code == 0
+    assert stdout.read().split() == ['rx-0', 'rx-1', 'tx-0', 'tx-1']
+
+    # The VMM survived the command and the interface still works.
+    exit_code, _, _ = ssh_connection.execute_command('echo success\n')
+    assert exit_code == 0